@@ -7,7 +7,7 @@ use std::{
     fs::File,
     io::{self, BufRead, BufReader, Error, ErrorKind},
     net::{IpAddr, SocketAddr},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
@@ -139,11 +139,14 @@ pub struct AccessControl {
     black_list: Rules,
     white_list: Rules,
     mode: Mode,
+    file_path: PathBuf,
 }
 
 impl AccessControl {
     /// Load ACL rules from a file
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AccessControl> {
+        let file_path = p.as_ref().to_owned();
+
         let fp = File::open(p)?;
         let r = BufReader::new(fp);
 
@@ -259,9 +262,15 @@ impl AccessControl {
             black_list: Rules::new(bypass_ipv4, bypass_ipv6, bypass_regex),
             white_list: Rules::new(proxy_ipv4, proxy_ipv6, proxy_regex),
             mode,
+            file_path,
         })
     }
 
+    /// Path of the file that rules are loaded from
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
     /// Check if target address should be bypassed (for client)
     ///
     /// FIXME: This function may perform a DNS resolution
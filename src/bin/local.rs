@@ -4,18 +4,29 @@
 //! or you could specify a configuration file. The format of configuration file is defined
 //! in mod `config`.
 
-use clap::{App, Arg, ArgGroup};
-use futures::future::{self, Either};
+use std::time::Duration;
+
+use clap::{App, Arg, ArgGroup, ArgMatches};
+use futures::{
+    future::{self, Either},
+    StreamExt,
+};
 use log::{error, info};
-use tokio::{self, runtime::Builder};
+use tokio::{
+    self,
+    runtime::Builder,
+    sync::{mpsc, oneshot},
+};
 
 use shadowsocks::{
     acl::AccessControl,
+    config::BalancerStrategy,
     crypto::CipherType,
     plugin::PluginConfig,
-    run_local,
+    run_local_with_signals,
     Config,
     ConfigType,
+    ManagerAddr,
     Mode,
     ServerAddr,
     ServerConfig,
@@ -114,11 +125,35 @@ fn main() {
                 .takes_value(false)
                 .help("Set no-delay option for socket"),
         )
+        .arg(
+            Arg::with_name("FAST_OPEN")
+                .long("fast-open")
+                .takes_value(false)
+                .help("Enable TCP Fast Open (Linux only)"),
+        )
+        .arg(
+            Arg::with_name("MUX")
+                .long("mux")
+                .takes_value(false)
+                .help("Multiplex connections to servers, falls back to normal connections if servers don't support it"),
+        )
+        .arg(
+            Arg::with_name("FIRST_PAYLOAD_WINDOW")
+                .long("first-payload-window")
+                .takes_value(true)
+                .help("Milliseconds waiting for the client's first payload, which is sent with the address header to servers"),
+        )
+        .arg(
+            Arg::with_name("IDLE_CONNECTIONS")
+                .long("idle-connections")
+                .takes_value(true)
+                .help("Idle connections kept for each server, connected before clients need them"),
+        )
         .arg(
             Arg::with_name("PROTOCOL")
                 .long("protocol")
                 .takes_value(true)
-                .help("Protocol that uses to communicates with clients, `socks5` or `http`, default is `socks5`"),
+                .help("Protocol that uses to communicates with clients, `socks5`, `http` or `mixed` (socks5 and http on the same port), default is `socks5`"),
         )
         .arg(
             Arg::with_name("NOFILE")
@@ -127,6 +162,37 @@ fn main() {
                 .takes_value(true)
                 .help("Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)"),
         )
+        .arg(
+            Arg::with_name("METRICS_ADDR")
+                .long("metrics-addr")
+                .takes_value(true)
+                .help("Address of the HTTP endpoint exporting metrics in Prometheus format, \"IP:Port\""),
+        )
+        .arg(
+            Arg::with_name("CONTROL_ADDRESS")
+                .long("control-address")
+                .takes_value(true)
+                .help("Control socket of load balancers, could be a loopback \"IP:Port\", \"Domain:Port\" or \"/path/to/unix.sock\""),
+        )
+        .arg(
+            Arg::with_name("BALANCER_STRATEGY")
+                .long("balancer-strategy")
+                .takes_value(true)
+                .possible_values(&[
+                    "lowest_score",
+                    "round_robin",
+                    "weighted_random",
+                    "least_connections",
+                    "consistent_hash",
+                ])
+                .help("Strategy for choosing servers, default is lowest_score"),
+        )
+        .arg(
+            Arg::with_name("FAILOVER_ATTEMPTS")
+                .long("failover-attempts")
+                .takes_value(true)
+                .help("Servers tried for a proxied connection until one of them is connected, default is 3, 1 disables failover"),
+        )
         .arg(
             Arg::with_name("ACL")
                 .long("acl")
@@ -184,16 +250,88 @@ fn main() {
     let config_type = match matches.value_of("PROTOCOL") {
         Some("socks5") => ConfigType::Socks5Local,
         Some("http") => ConfigType::HttpLocal,
-        Some(..) => panic!("`protocol` only supports `socks5` or `http`"),
+        Some("mixed") => ConfigType::MixedLocal,
+        Some(..) => panic!("`protocol` only supports `socks5`, `http` or `mixed`"),
         None => ConfigType::Socks5Local,
     };
 
+    let config = match load_config(&matches, config_type) {
+        Some(c) => c,
+        None => return,
+    };
+
+    if config.local.is_none() {
+        eprintln!(
+            "missing `local_address`, consider specifying it by --local-addr command line option, \
+             or \"local_address\" and \"local_port\" in configuration file"
+        );
+        println!("{}", matches.usage());
+        return;
+    }
+
+    if config.server.is_empty() {
+        eprintln!(
+            "missing proxy servers, consider specifying it by \
+             --server-addr, --encrypt-method, --password command line option, \
+                or --server-url command line option, \
+                or configuration file, check more details in https://shadowsocks.org/en/config/quick-guide.html"
+        );
+        println!("{}", matches.usage());
+        return;
+    }
+
+    info!("shadowsocks {}", shadowsocks::VERSION);
+
+    let mut builder = Builder::new();
+    if cfg!(feature = "single-threaded") {
+        builder.basic_scheduler();
+    } else {
+        builder.threaded_scheduler();
+    }
+    let mut runtime = builder.enable_all().build().expect("create tokio Runtime");
+    let rt_handle = runtime.handle().clone();
+    runtime.block_on(async move {
+        let abort_signal = monitor::create_signal_monitor();
+        let (reload_tx, reload_rx) = mpsc::channel(1);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(reload_task(matches, config_type, reload_tx));
+
+        let server = run_local_with_signals(config, rt_handle, reload_rx, shutdown_rx);
+
+        tokio::pin!(abort_signal);
+        tokio::pin!(server);
+
+        match future::select(server.as_mut(), abort_signal).await {
+            // Server future resolved without an error. This should never happen.
+            Either::Left((Ok(..), ..)) => panic!("server exited unexpectly"),
+            // Server future resolved with error, which are listener errors in most cases
+            Either::Left((Err(err), ..)) => panic!("server exited unexpectly with {}", err),
+            // The abort signal future resolved. Shuts down gracefully, exits immediately if signaled again.
+            Either::Right(_) => {
+                info!("received shutdown signal, shutting down");
+                let _ = shutdown_tx.send(());
+
+                let abort_signal = monitor::create_signal_monitor();
+                tokio::pin!(abort_signal);
+
+                if let Either::Left((Err(err), ..)) = future::select(server, abort_signal).await {
+                    error!("server exited with error while shutting down, {}", err);
+                }
+            }
+        }
+    })
+}
+
+/// Builds `Config` from the configuration file and command line options
+///
+/// Called again for reloading configuration
+fn load_config(matches: &ArgMatches<'_>, config_type: ConfigType) -> Option<Config> {
     let mut config = match matches.value_of("CONFIG") {
         Some(cpath) => match Config::load_from_file(cpath, config_type) {
             Ok(cfg) => cfg,
             Err(err) => {
                 error!("{:?}", err);
-                return;
+                return None;
             }
         },
         None => Config::new(config_type),
@@ -203,13 +341,21 @@ fn main() {
         let password = matches.value_of("PASSWORD").expect("password");
         let method = matches.value_of("ENCRYPT_METHOD").expect("encrypt-method");
 
-        let method = match method.parse() {
+        let method = match method.parse::<CipherType>() {
             Ok(m) => m,
             Err(err) => {
                 panic!("does not support {:?} method: {:?}", method, err);
             }
         };
 
+        if !method.check_key(password.as_bytes()) {
+            panic!(
+                "{} requires `password` to be a base64 encoded key with {} bytes",
+                method,
+                method.key_size()
+            );
+        }
+
         let sc = ServerConfig::new(
             svr_addr
                 .parse::<ServerAddr>()
@@ -284,6 +430,27 @@ fn main() {
         config.no_delay = true;
     }
 
+    if matches.is_present("FAST_OPEN") {
+        config.fast_open = true;
+    }
+
+    if let Some(n) = matches.value_of("IDLE_CONNECTIONS") {
+        config.idle_connections = n.parse::<usize>().expect("an unsigned integer for `idle-connections`");
+    }
+
+    if matches.is_present("MUX") {
+        for svr in config.server.iter_mut() {
+            svr.set_mux(true);
+        }
+    }
+
+    if let Some(w) = matches.value_of("FIRST_PAYLOAD_WINDOW") {
+        let w = w.parse::<u64>().expect("milliseconds for `first-payload-window`");
+        if w > 0 {
+            config.first_payload_window = Some(Duration::from_millis(w));
+        }
+    }
+
     if let Some(p) = matches.value_of("PLUGIN") {
         let plugin = PluginConfig {
             plugin: p.to_owned(),
@@ -300,55 +467,63 @@ fn main() {
         config.nofile = Some(nofile.parse::<u64>().expect("an unsigned integer for `nofile`"));
     }
 
-    if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = AccessControl::load_from_file(acl_file).expect("Load ACL file");
-        config.acl = Some(acl);
+    if let Some(metrics_addr) = matches.value_of("METRICS_ADDR") {
+        use std::net::SocketAddr;
+
+        let addr = metrics_addr
+            .parse::<SocketAddr>()
+            .expect("\"IP:Port\" for `metrics-addr`");
+        config.metrics_addr = Some(addr);
     }
 
-    if config.local.is_none() {
-        eprintln!(
-            "missing `local_address`, consider specifying it by --local-addr command line option, \
-             or \"local_address\" and \"local_port\" in configuration file"
+    if let Some(c) = matches.value_of("CONTROL_ADDRESS") {
+        config.control_address = Some(
+            c.parse::<ManagerAddr>()
+                .expect("\"IP:Port\", \"Domain:Port\" or \"/path/to/unix.sock\" for `control-address`"),
         );
-        println!("{}", matches.usage());
-        return;
     }
 
-    if config.server.is_empty() {
-        eprintln!(
-            "missing proxy servers, consider specifying it by \
-             --server-addr, --encrypt-method, --password command line option, \
-                or --server-url command line option, \
-                or configuration file, check more details in https://shadowsocks.org/en/config/quick-guide.html"
-        );
-        println!("{}", matches.usage());
-        return;
+    if let Some(s) = matches.value_of("BALANCER_STRATEGY") {
+        config.balancer_strategy = s.parse::<BalancerStrategy>().expect("balancer strategy");
     }
 
-    info!("shadowsocks {}", shadowsocks::VERSION);
+    if let Some(n) = matches.value_of("FAILOVER_ATTEMPTS") {
+        let n = n.parse::<usize>().expect("an unsigned integer for `failover-attempts`");
+        assert!(n > 0, "`failover-attempts` must be greater than 0");
+        config.failover_attempts = n;
+    }
 
-    let mut builder = Builder::new();
-    if cfg!(feature = "single-threaded") {
-        builder.basic_scheduler();
-    } else {
-        builder.threaded_scheduler();
+    if let Some(acl_file) = matches.value_of("ACL") {
+        let acl = match AccessControl::load_from_file(acl_file) {
+            Ok(acl) => acl,
+            Err(err) => {
+                error!("failed to load ACL file {}, error: {}", acl_file, err);
+                return None;
+            }
+        };
+        config.acl = Some(acl);
     }
-    let mut runtime = builder.enable_all().build().expect("create tokio Runtime");
-    let rt_handle = runtime.handle().clone();
-    runtime.block_on(async move {
-        let abort_signal = monitor::create_signal_monitor();
-        let server = run_local(config, rt_handle);
 
-        tokio::pin!(abort_signal);
-        tokio::pin!(server);
+    Some(config)
+}
 
-        match future::select(server, abort_signal).await {
-            // Server future resolved without an error. This should never happen.
-            Either::Left((Ok(..), ..)) => panic!("server exited unexpectly"),
-            // Server future resolved with error, which are listener errors in most cases
-            Either::Left((Err(err), ..)) => panic!("server exited unexpectly with {}", err),
-            // The abort signal future resolved. Means we should just exit.
-            Either::Right(_) => (),
+/// Reloads configuration and sends it to the running local every time the reload signal is received
+async fn reload_task(matches: ArgMatches<'static>, config_type: ConfigType, mut reload_tx: mpsc::Sender<Config>) {
+    let mut reload_signal = match monitor::create_reload_monitor() {
+        Ok(s) => s,
+        Err(err) => {
+            error!("failed to monitor reload signal, error: {}", err);
+            return;
         }
-    })
+    };
+
+    while reload_signal.next().await.is_some() {
+        info!("received reload signal, reloading configuration");
+
+        if let Some(config) = load_config(&matches, config_type) {
+            if reload_tx.send(config).await.is_err() {
+                break;
+            }
+        }
+    }
 }
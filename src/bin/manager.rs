@@ -68,6 +68,12 @@ fn main() {
                 .takes_value(false)
                 .help("Set no-delay option for socket"),
         )
+        .arg(
+            Arg::with_name("FAST_OPEN")
+                .long("fast-open")
+                .takes_value(false)
+                .help("Enable TCP Fast Open (Linux only)"),
+        )
         .arg(
             Arg::with_name("MANAGER_ADDRESS")
                 .long("manager-address")
@@ -143,6 +149,10 @@ fn main() {
         config.no_delay = true;
     }
 
+    if matches.is_present("FAST_OPEN") {
+        config.fast_open = true;
+    }
+
     if let Some(m) = matches.value_of("MANAGER_ADDRESS") {
         config.manager_address = Some(
             m.parse::<ManagerAddr>()
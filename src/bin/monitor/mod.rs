@@ -10,4 +10,4 @@ mod imp;
 #[path = "other.rs"]
 mod imp;
 
-pub use self::imp::create_signal_monitor;
+pub use self::imp::{create_reload_monitor, create_signal_monitor};
@@ -1,4 +1,8 @@
-use futures;
+use futures::{
+    self,
+    stream::{self, BoxStream},
+    StreamExt,
+};
 use std::io;
 
 pub async fn create_signal_monitor() -> io::Result<()> {
@@ -6,3 +10,9 @@ pub async fn create_signal_monitor() -> io::Result<()> {
     // Blocks forever
     futures::empty::<(), io::Error>().await
 }
+
+#[allow(dead_code)] // Not all binaries support reloading
+pub fn create_reload_monitor() -> io::Result<BoxStream<'static, ()>> {
+    // Never yields
+    Ok(stream::pending().boxed())
+}
@@ -1,4 +1,8 @@
-use futures::future::{self, Either, FutureExt};
+use futures::{
+    future::{self, Either, FutureExt},
+    stream::BoxStream,
+    StreamExt,
+};
 use log::info;
 use std::io;
 use tokio::signal::unix::{signal, SignalKind};
@@ -17,3 +21,10 @@ pub async fn create_signal_monitor() -> io::Result<()> {
 
     Ok(())
 }
+
+/// Creates a stream yielding every time SIGHUP is received, for reloading configuration
+#[allow(dead_code)] // Not all binaries support reloading
+pub fn create_reload_monitor() -> io::Result<BoxStream<'static, ()>> {
+    let sighup = signal(SignalKind::hangup())?;
+    Ok(sighup.boxed())
+}
@@ -1,5 +1,6 @@
 use futures::{
     future::{self, Either, FutureExt},
+    stream::{self, BoxStream},
     StreamExt,
 };
 use log::info;
@@ -19,3 +20,9 @@ pub async fn create_signal_monitor() -> io::Result<()> {
 
     Ok(())
 }
+
+/// Reloading configuration by signal is not supported on Windows, the stream never yields
+#[allow(dead_code)] // Not all binaries support reloading
+pub fn create_reload_monitor() -> io::Result<BoxStream<'static, ()>> {
+    Ok(stream::pending().boxed())
+}
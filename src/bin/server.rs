@@ -9,16 +9,23 @@
 
 use std::net::{IpAddr, SocketAddr};
 
-use clap::{App, Arg, ArgGroup};
-use futures::future::{self, Either};
+use clap::{App, Arg, ArgGroup, ArgMatches};
+use futures::{
+    future::{self, Either},
+    StreamExt,
+};
 use log::{error, info};
-use tokio::{self, runtime::Builder};
+use tokio::{
+    self,
+    runtime::Builder,
+    sync::{mpsc, oneshot},
+};
 
 use shadowsocks::{
     acl::AccessControl,
     crypto::CipherType,
     plugin::PluginConfig,
-    run_server,
+    run_server_with_signals,
     Config,
     ConfigType,
     ManagerAddr,
@@ -114,6 +121,24 @@ fn main() {
                 .takes_value(false)
                 .help("Set no-delay option for socket"),
         )
+        .arg(
+            Arg::with_name("FAST_OPEN")
+                .long("fast-open")
+                .takes_value(false)
+                .help("Enable TCP Fast Open (Linux only)"),
+        )
+        .arg(
+            Arg::with_name("ALLOW_BIND")
+                .long("allow-bind")
+                .takes_value(false)
+                .help("Accept SOCKS5 BIND requests, which listen on random ports for clients"),
+        )
+        .arg(
+            Arg::with_name("MUX")
+                .long("mux")
+                .takes_value(false)
+                .help("Accept multiplexed connections from clients"),
+        )
         .arg(
             Arg::with_name("NOFILE")
                 .short("n")
@@ -121,6 +146,12 @@ fn main() {
                 .takes_value(true)
                 .help("Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)"),
         )
+        .arg(
+            Arg::with_name("METRICS_ADDR")
+                .long("metrics-addr")
+                .takes_value(true)
+                .help("Address of the HTTP endpoint exporting metrics in Prometheus format, \"IP:Port\""),
+        )
         .arg(
             Arg::with_name("ACL")
                 .long("acl")
@@ -134,12 +165,74 @@ fn main() {
     let debug_level = matches.occurrences_of("VERBOSE");
     logging::init(debug_level, "ssserver");
 
+    let config = match load_config(&matches) {
+        Some(c) => c,
+        None => return,
+    };
+
+    if config.server.is_empty() {
+        eprintln!(
+            "missing proxy servers, consider specifying it by \
+             --server-addr, --encrypt-method, --password command line option, \
+                or configuration file, check more details in https://shadowsocks.org/en/config/quick-guide.html"
+        );
+        println!("{}", matches.usage());
+        return;
+    }
+
+    info!("shadowsocks {}", shadowsocks::VERSION);
+
+    let mut builder = Builder::new();
+    if cfg!(feature = "single-threaded") {
+        builder.basic_scheduler();
+    } else {
+        builder.threaded_scheduler();
+    }
+    let mut runtime = builder.enable_all().build().expect("create tokio Runtime");
+    let rt_handle = runtime.handle().clone();
+
+    runtime.block_on(async move {
+        let abort_signal = monitor::create_signal_monitor();
+        let (reload_tx, reload_rx) = mpsc::channel(1);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(reload_task(matches, reload_tx));
+
+        let server = run_server_with_signals(config, rt_handle, reload_rx, shutdown_rx);
+
+        tokio::pin!(abort_signal);
+        tokio::pin!(server);
+
+        match future::select(server.as_mut(), abort_signal).await {
+            // Server future resolved without an error. This should never happen.
+            Either::Left((Ok(..), ..)) => panic!("server exited unexpectly"),
+            // Server future resolved with error, which are listener errors in most cases
+            Either::Left((Err(err), ..)) => panic!("server exited unexpectly with {}", err),
+            // The abort signal future resolved. Shuts down gracefully, exits immediately if signaled again.
+            Either::Right(_) => {
+                info!("received shutdown signal, shutting down");
+                let _ = shutdown_tx.send(());
+
+                let abort_signal = monitor::create_signal_monitor();
+                tokio::pin!(abort_signal);
+
+                if let Either::Left((Err(err), ..)) = future::select(server, abort_signal).await {
+                    error!("server exited with error while shutting down, {}", err);
+                }
+            }
+        }
+    })
+}
+
+/// Builds `Config` from the configuration file and command line options
+///
+/// Called again for reloading configuration
+fn load_config(matches: &ArgMatches<'_>) -> Option<Config> {
     let mut config = match matches.value_of("CONFIG") {
         Some(cpath) => match Config::load_from_file(cpath, ConfigType::Server) {
             Ok(cfg) => cfg,
             Err(err) => {
                 error!("{:?}", err);
-                return;
+                return None;
             }
         },
         None => Config::new(ConfigType::Server),
@@ -149,13 +242,21 @@ fn main() {
         let password = matches.value_of("PASSWORD").expect("password");
         let method = matches.value_of("ENCRYPT_METHOD").expect("encrypt-method");
 
-        let method = match method.parse() {
+        let method = match method.parse::<CipherType>() {
             Ok(m) => m,
             Err(err) => {
                 panic!("does not support {:?} method: {:?}", method, err);
             }
         };
 
+        if !method.check_key(password.as_bytes()) {
+            panic!(
+                "{} requires `password` to be a base64 encoded key with {} bytes",
+                method,
+                method.key_size()
+            );
+        }
+
         let sc = ServerConfig::new(
             svr_addr
                 .parse::<ServerAddr>()
@@ -194,6 +295,22 @@ fn main() {
         config.no_delay = true;
     }
 
+    if matches.is_present("FAST_OPEN") {
+        config.fast_open = true;
+    }
+
+    if matches.is_present("ALLOW_BIND") {
+        for svr in config.server.iter_mut() {
+            svr.set_allow_bind(true);
+        }
+    }
+
+    if matches.is_present("MUX") {
+        for svr in config.server.iter_mut() {
+            svr.set_mux(true);
+        }
+    }
+
     if let Some(p) = matches.value_of("PLUGIN") {
         let plugin = PluginConfig {
             plugin: p.to_owned(),
@@ -217,46 +334,44 @@ fn main() {
         config.nofile = Some(nofile.parse::<u64>().expect("an unsigned integer for `nofile`"));
     }
 
-    if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = AccessControl::load_from_file(acl_file).expect("load ACL file");
-        config.acl = Some(acl);
+    if let Some(metrics_addr) = matches.value_of("METRICS_ADDR") {
+        let addr = metrics_addr
+            .parse::<SocketAddr>()
+            .expect("\"IP:Port\" for `metrics-addr`");
+        config.metrics_addr = Some(addr);
     }
 
-    if config.server.is_empty() {
-        eprintln!(
-            "missing proxy servers, consider specifying it by \
-             --server-addr, --encrypt-method, --password command line option, \
-                or configuration file, check more details in https://shadowsocks.org/en/config/quick-guide.html"
-        );
-        println!("{}", matches.usage());
-        return;
+    if let Some(acl_file) = matches.value_of("ACL") {
+        let acl = match AccessControl::load_from_file(acl_file) {
+            Ok(acl) => acl,
+            Err(err) => {
+                error!("failed to load ACL file {}, error: {}", acl_file, err);
+                return None;
+            }
+        };
+        config.acl = Some(acl);
     }
 
-    info!("shadowsocks {}", shadowsocks::VERSION);
-
-    let mut builder = Builder::new();
-    if cfg!(feature = "single-threaded") {
-        builder.basic_scheduler();
-    } else {
-        builder.threaded_scheduler();
-    }
-    let mut runtime = builder.enable_all().build().expect("create tokio Runtime");
-    let rt_handle = runtime.handle().clone();
+    Some(config)
+}
 
-    runtime.block_on(async move {
-        let abort_signal = monitor::create_signal_monitor();
-        let server = run_server(config, rt_handle);
+/// Reloads configuration and sends it to the running server every time the reload signal is received
+async fn reload_task(matches: ArgMatches<'static>, mut reload_tx: mpsc::Sender<Config>) {
+    let mut reload_signal = match monitor::create_reload_monitor() {
+        Ok(s) => s,
+        Err(err) => {
+            error!("failed to monitor reload signal, error: {}", err);
+            return;
+        }
+    };
 
-        tokio::pin!(abort_signal);
-        tokio::pin!(server);
+    while reload_signal.next().await.is_some() {
+        info!("received reload signal, reloading configuration");
 
-        match future::select(server, abort_signal).await {
-            // Server future resolved without an error. This should never happen.
-            Either::Left((Ok(..), ..)) => panic!("server exited unexpectly"),
-            // Server future resolved with error, which are listener errors in most cases
-            Either::Left((Err(err), ..)) => panic!("server exited unexpectly with {}", err),
-            // The abort signal future resolved. Means we should just exit.
-            Either::Right(_) => (),
+        if let Some(config) = load_config(&matches) {
+            if reload_tx.send(config).await.is_err() {
+                break;
+            }
         }
-    })
+    }
 }
@@ -4,6 +4,8 @@
 //! or you could specify a configuration file. The format of configuration file is defined
 //! in mod `config`.
 
+use std::time::Duration;
+
 use clap::{App, Arg, ArgGroup};
 use futures::future::{self, Either};
 use log::{error, info};
@@ -122,6 +124,18 @@ fn main() {
                 .takes_value(false)
                 .help("Set no-delay option for socket"),
         )
+        .arg(
+            Arg::with_name("FAST_OPEN")
+                .long("fast-open")
+                .takes_value(false)
+                .help("Enable TCP Fast Open (Linux only)"),
+        )
+        .arg(
+            Arg::with_name("FIRST_PAYLOAD_WINDOW")
+                .long("first-payload-window")
+                .takes_value(true)
+                .help("Milliseconds waiting for the client's first payload, which is sent with the address header to servers"),
+        )
         .arg(
             Arg::with_name("NOFILE")
                 .short("n")
@@ -151,13 +165,21 @@ fn main() {
         let password = matches.value_of("PASSWORD").expect("password");
         let method = matches.value_of("ENCRYPT_METHOD").expect("encrypt-method");
 
-        let method = match method.parse() {
+        let method = match method.parse::<CipherType>() {
             Ok(m) => m,
             Err(err) => {
                 panic!("does not support {:?} method: {:?}", method, err);
             }
         };
 
+        if !method.check_key(password.as_bytes()) {
+            panic!(
+                "{} requires `password` to be a base64 encoded key with {} bytes",
+                method,
+                method.key_size()
+            );
+        }
+
         let sc = ServerConfig::new(
             svr_addr
                 .parse::<ServerAddr>()
@@ -206,6 +228,17 @@ fn main() {
         config.no_delay = true;
     }
 
+    if matches.is_present("FAST_OPEN") {
+        config.fast_open = true;
+    }
+
+    if let Some(w) = matches.value_of("FIRST_PAYLOAD_WINDOW") {
+        let w = w.parse::<u64>().expect("milliseconds for `first-payload-window`");
+        if w > 0 {
+            config.first_payload_window = Some(Duration::from_millis(w));
+        }
+    }
+
     if let Some(p) = matches.value_of("PLUGIN") {
         let plugin = PluginConfig {
             plugin: p.to_owned(),
@@ -42,6 +42,7 @@
 //! These defined server will be used with a load balancing algorithm.
 
 use std::{
+    collections::HashMap,
     convert::From,
     default::Default,
     error,
@@ -50,20 +51,20 @@ use std::{
     io::{self, Read},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     option::Option,
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
     string::ToString,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-#[cfg(unix)]
-use std::path::PathBuf;
-
-use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use base64::{decode_config, encode_config, STANDARD, URL_SAFE_NO_PAD};
 use bytes::Bytes;
 use cfg_if::cfg_if;
 use log::error;
+use ring::constant_time;
 use serde::{Deserialize, Serialize};
+use spin::RwLock;
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig};
 use url::{self, Url};
@@ -71,11 +72,14 @@ use url::{self, Url};
 use crate::{
     acl::AccessControl,
     context::Context,
-    crypto::cipher::CipherType,
+    crypto::cipher::{CipherCategory, CipherType},
     plugin::PluginConfig,
     relay::{dns_resolver::resolve_bind_addr, socks5::Address},
 };
 
+/// Servers tried for a proxied connection by default
+const DEFAULT_FAILOVER_ATTEMPTS: usize = 3;
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -91,6 +95,14 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     manager_port: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     password: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     method: Option<String>,
@@ -103,15 +115,49 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_timeout: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    shutdown_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     servers: Option<Vec<SSServerExtConfig>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     dns: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    balancer_strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failover_attempts: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     no_delay: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    fast_open: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_payload_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     nofile: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_auth: Option<Vec<SSLocalUserConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locals: Option<Vec<SSLocalExtConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_tls_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_tls_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users: Option<Vec<SSServerUserConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    traffic_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_limit: Option<SSRateLimitConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_rate_limit: Option<SSRateLimitConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    global_rate_limit: Option<SSRateLimitConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_check: Option<SSHealthCheckConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -126,6 +172,88 @@ struct SSServerExtConfig {
     plugin_opts: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users: Option<Vec<SSServerUserConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    traffic_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_limit: Option<SSRateLimitConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_rate_limit: Option<SSRateLimitConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mux: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow_bind: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSRateLimitConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSHealthCheckConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp: Option<SSProbeConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp: Option<SSProbeConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSProbeConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expect: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expect_base64: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSServerUserConfig {
+    name: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalUserConfig {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSLocalExtConfig {
+    local_address: String,
+    local_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_tls_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_tls_key: Option<String>,
 }
 
 /// Server address
@@ -211,6 +339,141 @@ impl<I: Into<String>> From<(I, u16)> for ServerAddr {
     }
 }
 
+/// An user of a multi-user server
+///
+/// Each user has its own password (key), servers identify users by trying their keys on the first AEAD chunk
+#[derive(Debug)]
+pub struct ServerUser {
+    name: String,
+    password: String,
+    enc_key: Bytes,
+}
+
+impl ServerUser {
+    /// Creates a new user with `password` as the key for `method`
+    pub fn new<N, P>(name: N, password: P, method: CipherType) -> ServerUser
+    where
+        N: Into<String>,
+        P: Into<String>,
+    {
+        let password = password.into();
+        let enc_key = method.bytes_to_key(password.as_bytes());
+        ServerUser {
+            name: name.into(),
+            password,
+            enc_key,
+        }
+    }
+
+    /// Get name of this user
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get password
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// Get encryption key
+    pub fn key(&self) -> &[u8] {
+        &self.enc_key[..]
+    }
+
+    /// Clone encryption key
+    pub fn clone_key(&self) -> Bytes {
+        self.enc_key.clone()
+    }
+}
+
+/// Users sharing one server port
+///
+/// Clones share the same user table, so users could be added or removed while the server is running
+#[derive(Clone, Debug, Default)]
+pub struct ServerUserManager {
+    users: Arc<RwLock<Vec<Arc<ServerUser>>>>,
+}
+
+impl ServerUserManager {
+    /// Creates an empty user table
+    pub fn new() -> ServerUserManager {
+        ServerUserManager::default()
+    }
+
+    /// Add an user, replacing the existing one with the same name
+    pub fn add_user(&self, user: ServerUser) {
+        let mut users = self.users.write();
+        users.retain(|u| u.name() != user.name());
+        users.push(Arc::new(user));
+    }
+
+    /// Remove user by name, returns `false` if it doesn't exist
+    pub fn remove_user(&self, name: &str) -> bool {
+        let mut users = self.users.write();
+        let len = users.len();
+        users.retain(|u| u.name() != name);
+        users.len() != len
+    }
+
+    /// Get user by name
+    pub fn get_user(&self, name: &str) -> Option<Arc<ServerUser>> {
+        self.users.read().iter().find(|u| u.name() == name).cloned()
+    }
+
+    /// Snapshot of all users
+    pub fn users(&self) -> Vec<Arc<ServerUser>> {
+        self.users.read().clone()
+    }
+
+    /// Check if there is no user in the table
+    pub fn is_empty(&self) -> bool {
+        self.users.read().is_empty()
+    }
+}
+
+/// Bandwidth limits in bytes per second
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimitConfig {
+    /// Traffic from clients to server
+    pub upload: Option<u64>,
+    /// Traffic from server to clients
+    pub download: Option<u64>,
+}
+
+impl RateLimitConfig {
+    /// Check if there is no limit at all
+    pub fn is_unlimited(&self) -> bool {
+        self.upload.is_none() && self.download.is_none()
+    }
+
+    fn from_ssconfig(c: SSRateLimitConfig) -> Result<RateLimitConfig, Error> {
+        if c.upload == Some(0) || c.download == Some(0) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "invalid rate limit",
+                Some("`upload` and `download` must be greater than 0".to_owned()),
+            );
+            return Err(err);
+        }
+
+        Ok(RateLimitConfig {
+            upload: c.upload,
+            download: c.download,
+        })
+    }
+
+    fn to_ssconfig(&self) -> Option<SSRateLimitConfig> {
+        if self.is_unlimited() {
+            return None;
+        }
+
+        Some(SSRateLimitConfig {
+            upload: self.upload,
+            download: self.download,
+        })
+    }
+}
+
 /// Configuration for a server
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -228,6 +491,24 @@ pub struct ServerConfig {
     plugin: Option<PluginConfig>,
     /// Plugin address
     plugin_addr: Option<ServerAddr>,
+    /// Users of multi-user server, besides the `password` of the server itself
+    users: ServerUserManager,
+    /// Maximum bytes transferred (both directions, TCP and UDP) by this server
+    ///
+    /// It limits the whole port, shared by all `users`
+    traffic_limit: Option<u64>,
+    /// Server stops serving after this time
+    expire_at: Option<SystemTime>,
+    /// Bandwidth limits shared by all connections of this server
+    rate_limit: RateLimitConfig,
+    /// Bandwidth limits of each connection (or UDP association)
+    connection_rate_limit: RateLimitConfig,
+    /// Weight for load balancing, used by `weighted_random` and `consistent_hash` strategies
+    weight: u32,
+    /// Multiplexing many connections over a few long-lived connections
+    mux: bool,
+    /// Accept SOCKS5 BIND requests from clients, which listen on random ports of the server
+    allow_bind: bool,
 }
 
 impl ServerConfig {
@@ -248,6 +529,14 @@ impl ServerConfig {
             enc_key,
             plugin,
             plugin_addr: None,
+            users: ServerUserManager::new(),
+            traffic_limit: None,
+            expire_at: None,
+            rate_limit: RateLimitConfig::default(),
+            connection_rate_limit: RateLimitConfig::default(),
+            weight: 1,
+            mux: false,
+            allow_bind: false,
         }
     }
 
@@ -318,6 +607,87 @@ impl ServerConfig {
         self.plugin_addr.as_ref()
     }
 
+    /// Get users of multi-user server
+    pub fn users(&self) -> &ServerUserManager {
+        &self.users
+    }
+
+    /// Set traffic quota in bytes
+    pub fn set_traffic_limit(&mut self, limit: u64) {
+        self.traffic_limit = Some(limit);
+    }
+
+    /// Get traffic quota in bytes
+    pub fn traffic_limit(&self) -> Option<u64> {
+        self.traffic_limit
+    }
+
+    /// Set expire time
+    pub fn set_expire_at(&mut self, t: SystemTime) {
+        self.expire_at = Some(t);
+    }
+
+    /// Get expire time
+    pub fn expire_at(&self) -> Option<SystemTime> {
+        self.expire_at
+    }
+
+    /// Set bandwidth limits shared by all connections
+    pub fn set_rate_limit(&mut self, limit: RateLimitConfig) {
+        self.rate_limit = limit;
+    }
+
+    /// Get bandwidth limits shared by all connections
+    pub fn rate_limit(&self) -> &RateLimitConfig {
+        &self.rate_limit
+    }
+
+    /// Set bandwidth limits of each connection
+    pub fn set_connection_rate_limit(&mut self, limit: RateLimitConfig) {
+        self.connection_rate_limit = limit;
+    }
+
+    /// Get bandwidth limits of each connection
+    pub fn connection_rate_limit(&self) -> &RateLimitConfig {
+        &self.connection_rate_limit
+    }
+
+    /// Set weight for load balancing
+    ///
+    /// Servers with 0 weight are not chosen, unless all of the available servers have 0 weight
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+
+    /// Get weight for load balancing, default is 1
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Enable multiplexing
+    ///
+    /// Clients carry connections in multiplexing sessions, servers accept sessions besides normal connections
+    pub fn set_mux(&mut self, mux: bool) {
+        self.mux = mux;
+    }
+
+    /// Check if multiplexing is enabled
+    pub fn mux(&self) -> bool {
+        self.mux
+    }
+
+    /// Allow clients to request BIND (for server)
+    ///
+    /// Disabled by default, any client with the key could make the server listen on ports
+    pub fn set_allow_bind(&mut self, allow_bind: bool) {
+        self.allow_bind = allow_bind;
+    }
+
+    /// Check if BIND requests are accepted
+    pub fn allow_bind(&self) -> bool {
+        self.allow_bind
+    }
+
     /// Get server's external address
     pub fn external_addr(&self) -> &ServerAddr {
         self.plugin_addr.as_ref().unwrap_or(&self.addr)
@@ -425,7 +795,21 @@ impl ServerConfig {
             }
         }
 
-        let svrconfig = ServerConfig::new(addr, pwd.to_owned(), method.parse().unwrap(), None, plugin);
+        let method = match method.parse::<CipherType>() {
+            Ok(m) => m,
+            Err(..) => {
+                error!("Unsupported method \"{}\" in URL", method);
+                return Err(UrlParseError::InvalidAuthInfo);
+            }
+        };
+
+        // 2022 methods require a base64 encoded key, it must be checked before creating ServerConfig
+        if !method.check_key(pwd.as_bytes()) {
+            error!("Invalid password for method \"{}\" in URL", method);
+            return Err(UrlParseError::InvalidAuthInfo);
+        }
+
+        let svrconfig = ServerConfig::new(addr, pwd.to_owned(), method, None, plugin);
 
         Ok(svrconfig)
     }
@@ -572,6 +956,61 @@ impl error::Error for UrlParseError {
 /// Listening address
 pub type ClientConfig = ServerAddr;
 
+/// Username/Password authentication for local servers
+///
+/// Used by SOCKS5 local with [RFC1929](https://tools.ietf.org/html/rfc1929)
+#[derive(Clone, Debug, Default)]
+pub struct LocalAuthConfig {
+    users: HashMap<String, String>,
+}
+
+impl LocalAuthConfig {
+    /// Creates an empty user table
+    pub fn new() -> LocalAuthConfig {
+        LocalAuthConfig::default()
+    }
+
+    /// Add an user, replacing the password if the user already exists
+    pub fn add_user<U, P>(&mut self, username: U, password: P)
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.users.insert(username.into(), password.into());
+    }
+
+    /// Check if `username` exists and its password is `password`
+    ///
+    /// Passwords are compared in constant time
+    pub fn check_user(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(pwd) => constant_time::verify_slices_are_equal(pwd.as_bytes(), password.as_bytes()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Check if there is no user in the table
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Iterate over all `(username, password)` pairs
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.users.iter().map(|(u, p)| (&u[..], &p[..]))
+    }
+}
+
+/// TLS certificate and private key for local servers
+///
+/// Used by HTTP local for serving as an HTTPS proxy
+#[derive(Clone, Debug)]
+pub struct LocalTlsConfig {
+    /// Path to the certificate chain, in PEM format
+    pub cert_path: PathBuf,
+    /// Path to the private key (PKCS#8 or RSA), in PEM format
+    pub key_path: PathBuf,
+}
+
 /// Server config type
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ConfigType {
@@ -595,6 +1034,11 @@ pub enum ConfigType {
     /// Requires `local` configuration
     RedirLocal,
 
+    /// Config for socks5 and HTTP local, serving both protocols on the same port
+    ///
+    /// Requires `local` configuration
+    MixedLocal,
+
     /// Config for server
     Server,
 
@@ -606,7 +1050,11 @@ impl ConfigType {
     /// Check if it is local server type
     pub fn is_local(self) -> bool {
         match self {
-            ConfigType::Socks5Local | ConfigType::HttpLocal | ConfigType::TunnelLocal | ConfigType::RedirLocal => true,
+            ConfigType::Socks5Local
+            | ConfigType::HttpLocal
+            | ConfigType::TunnelLocal
+            | ConfigType::RedirLocal
+            | ConfigType::MixedLocal => true,
             ConfigType::Server | ConfigType::Manager => false,
         }
     }
@@ -614,7 +1062,11 @@ impl ConfigType {
     /// Check if it is remote server type
     pub fn is_server(self) -> bool {
         match self {
-            ConfigType::Socks5Local | ConfigType::HttpLocal | ConfigType::TunnelLocal | ConfigType::RedirLocal => false,
+            ConfigType::Socks5Local
+            | ConfigType::HttpLocal
+            | ConfigType::TunnelLocal
+            | ConfigType::RedirLocal
+            | ConfigType::MixedLocal => false,
             ConfigType::Manager => false,
             ConfigType::Server => true,
         }
@@ -627,50 +1079,404 @@ impl ConfigType {
             _ => false,
         }
     }
+
+    /// Protocol name of local server type, used in `locals`
+    pub fn local_protocol(self) -> Option<&'static str> {
+        match self {
+            ConfigType::Socks5Local => Some("socks5"),
+            ConfigType::HttpLocal => Some("http"),
+            ConfigType::TunnelLocal => Some("tunnel"),
+            ConfigType::RedirLocal => Some("redir"),
+            ConfigType::MixedLocal => Some("mixed"),
+            ConfigType::Server | ConfigType::Manager => None,
+        }
+    }
+
+    /// Get local server type by protocol name, reverse of `local_protocol`
+    pub fn from_local_protocol(protocol: &str) -> Option<ConfigType> {
+        match protocol {
+            "socks5" => Some(ConfigType::Socks5Local),
+            "http" => Some(ConfigType::HttpLocal),
+            "tunnel" => Some(ConfigType::TunnelLocal),
+            "redir" => Some(ConfigType::RedirLocal),
+            "mixed" => Some(ConfigType::MixedLocal),
+            _ => None,
+        }
+    }
+}
+
+/// Configuration of one local server
+///
+/// Multiple local servers could run in the same process, sharing remote servers
+#[derive(Clone, Debug)]
+pub struct LocalConfig {
+    /// Local server's bind address
+    pub addr: ClientConfig,
+    /// Local server type, must be one of the local types
+    pub config_type: ConfigType,
+    /// Local server mode, `tcp_only`, `tcp_and_udp`, and `udp_only`
+    pub mode: Mode,
+    /// Destination address for tunnel
+    pub forward: Option<Address>,
+    /// TLS configuration, only for HTTP local
+    pub tls: Option<LocalTlsConfig>,
+}
+
+impl LocalConfig {
+    /// Create a local server configuration
+    pub fn new(addr: ClientConfig, config_type: ConfigType, mode: Mode) -> LocalConfig {
+        assert!(config_type.is_local());
+
+        LocalConfig {
+            addr,
+            config_type,
+            mode,
+            forward: None,
+            tls: None,
+        }
+    }
+
+    /// Check if TCP listener is required
+    pub fn enable_tcp(&self) -> bool {
+        match self.config_type {
+            // Socks5 always true, because UDP associate command also requires a TCP connection
+            ConfigType::Socks5Local => true,
+            // Tunnel mode controlled by this flag
+            ConfigType::TunnelLocal => self.mode.enable_tcp(),
+            // HTTP must be TCP
+            ConfigType::HttpLocal => true,
+            // Redir mode controlled by this flag
+            ConfigType::RedirLocal => self.mode.enable_tcp(),
+            // Same as Socks5, HTTP shares the TCP port
+            ConfigType::MixedLocal => true,
+
+            ConfigType::Server | ConfigType::Manager => false,
+        }
+    }
+
+    /// Check if UDP relay is required
+    pub fn enable_udp(&self) -> bool {
+        match self.config_type {
+            ConfigType::Socks5Local | ConfigType::TunnelLocal | ConfigType::RedirLocal | ConfigType::MixedLocal => {
+                self.mode.enable_udp()
+            }
+            ConfigType::HttpLocal | ConfigType::Server | ConfigType::Manager => false,
+        }
+    }
+}
+
+/// Server mode
+#[derive(Clone, Copy, Debug)]
+pub enum Mode {
+    TcpOnly,
+    TcpAndUdp,
+    UdpOnly,
+}
+
+impl Mode {
+    pub fn enable_udp(self) -> bool {
+        match self {
+            Mode::UdpOnly | Mode::TcpAndUdp => true,
+            _ => false,
+        }
+    }
+
+    pub fn enable_tcp(self) -> bool {
+        match self {
+            Mode::TcpOnly | Mode::TcpAndUdp => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mode::TcpOnly => f.write_str("tcp_only"),
+            Mode::TcpAndUdp => f.write_str("tcp_and_udp"),
+            Mode::UdpOnly => f.write_str("udp_only"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp_only" => Ok(Mode::TcpOnly),
+            "tcp_and_udp" => Ok(Mode::TcpAndUdp),
+            "udp_only" => Ok(Mode::UdpOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Mode of health check probes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HealthCheckMode {
+    /// Sends a request to the probe target via servers, and checks the response
+    Request,
+    /// Only sends the address header of `target` through TCP servers, without any request
+    ///
+    /// Servers never respond to wrong keys, but close or reset connections after failing to decrypt the header,
+    /// connections that are still open after a short wait are regarded as accepted.
+    /// UDP servers are still checked with requests, there is no connection in UDP.
+    ConnectOnly,
+}
+
+impl fmt::Display for HealthCheckMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HealthCheckMode::Request => f.write_str("request"),
+            HealthCheckMode::ConnectOnly => f.write_str("connect_only"),
+        }
+    }
+}
+
+impl FromStr for HealthCheckMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "request" => Ok(HealthCheckMode::Request),
+            "connect_only" => Ok(HealthCheckMode::ConnectOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Request sent to a probe target via servers
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeConfig {
+    /// Destination of the request
+    pub target: Address,
+    /// Payload sent to `target`
+    pub request: Vec<u8>,
+    /// Expected prefix of the response, any response is accepted if empty
+    pub expect: Vec<u8>,
+}
+
+impl ProbeConfig {
+    fn merge_ssconfig(&mut self, c: SSProbeConfig) -> Result<(), Error> {
+        if let Some(target) = c.target {
+            self.target = match target.parse::<Address>() {
+                Ok(t) => t,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "malformed `target` of `health_check`",
+                        Some(format!("`{}` is not a valid \"host:port\"", target)),
+                    );
+                    return Err(err);
+                }
+            };
+        }
+
+        if let Some(request) = decode_probe_payload(c.request, c.request_base64, "request")? {
+            self.request = request;
+        }
+        if let Some(expect) = decode_probe_payload(c.expect, c.expect_base64, "expect")? {
+            self.expect = expect;
+        }
+
+        Ok(())
+    }
+
+    fn to_ssconfig(&self) -> SSProbeConfig {
+        let (request, request_base64) = encode_probe_payload(&self.request);
+        let (expect, expect_base64) = if self.expect.is_empty() {
+            (None, None)
+        } else {
+            encode_probe_payload(&self.expect)
+        };
+
+        SSProbeConfig {
+            target: Some(self.target.to_string()),
+            request,
+            request_base64,
+            expect,
+            expect_base64,
+        }
+    }
+}
+
+fn decode_probe_payload(
+    text: Option<String>,
+    encoded: Option<String>,
+    key: &'static str,
+) -> Result<Option<Vec<u8>>, Error> {
+    match (text, encoded) {
+        (Some(..), Some(..)) => {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "conflicted payloads in `health_check`",
+                Some(format!("`{}` and `{}_base64` couldn't be set together", key, key)),
+            );
+            Err(err)
+        }
+        (Some(text), None) => Ok(Some(text.into_bytes())),
+        (None, Some(encoded)) => match decode_config(&encoded, STANDARD) {
+            Ok(p) => Ok(Some(p)),
+            Err(..) => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "malformed payload in `health_check`",
+                    Some(format!("`{}_base64` is not a valid base64 string", key)),
+                );
+                Err(err)
+            }
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+// Text payloads are kept readable, others are encoded with base64
+fn encode_probe_payload(payload: &[u8]) -> (Option<String>, Option<String>) {
+    match String::from_utf8(payload.to_vec()) {
+        Ok(text) => (Some(text), None),
+        Err(..) => (None, Some(encode_config(payload, STANDARD))),
+    }
+}
+
+/// Health check probes of servers in load balancers
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheckConfig {
+    /// Mode of probes, default is `request`
+    pub mode: HealthCheckMode,
+    /// Probe of TCP servers, default is `GET /generate_204` to `dl.google.com:80`
+    pub tcp: ProbeConfig,
+    /// Probe of UDP servers, default is a DNS query to `8.8.8.8:53`
+    pub udp: ProbeConfig,
+    /// Interval between probes of a server, default is 6 seconds
+    pub interval: Duration,
+    /// Probes not finished in time are treated as slow, with `timeout` as their latencies, default is 2 seconds
+    pub timeout: Duration,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> HealthCheckConfig {
+        HealthCheckConfig {
+            mode: HealthCheckMode::Request,
+            tcp: ProbeConfig {
+                target: Address::DomainNameAddress("dl.google.com".to_owned(), 80),
+                request: b"GET /generate_204 HTTP/1.1\r\nHost: dl.google.com\r\nConnection: close\r\nAccept: */*\r\n\r\n"
+                    .to_vec(),
+                expect: Vec::new(),
+            },
+            udp: ProbeConfig {
+                target: Address::SocketAddress(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53)),
+                request: b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x05\x62\x61\x69\x64\x75\x03\x63\x6f\x6d\x00\x00\x01\x00\x01"
+                    .to_vec(),
+                expect: Vec::new(),
+            },
+            interval: Duration::from_secs(6),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    fn from_ssconfig(c: SSHealthCheckConfig) -> Result<HealthCheckConfig, Error> {
+        let mut hc = HealthCheckConfig::default();
+
+        if let Some(mode) = c.mode {
+            hc.mode = match mode.parse::<HealthCheckMode>() {
+                Ok(m) => m,
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "malformed `mode` in `health_check`, must be one of `request` and `connect_only`",
+                        None,
+                    );
+                    return Err(err);
+                }
+            };
+        }
+
+        if c.interval == Some(0) || c.timeout == Some(0) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "invalid `health_check`",
+                Some("`interval` and `timeout` must be greater than 0".to_owned()),
+            );
+            return Err(err);
+        }
+        if let Some(interval) = c.interval {
+            hc.interval = Duration::from_secs(interval);
+        }
+        if let Some(timeout) = c.timeout {
+            hc.timeout = Duration::from_secs(timeout);
+        }
+
+        if let Some(tcp) = c.tcp {
+            hc.tcp.merge_ssconfig(tcp)?;
+        }
+        if let Some(udp) = c.udp {
+            hc.udp.merge_ssconfig(udp)?;
+        }
+
+        Ok(hc)
+    }
+
+    fn to_ssconfig(&self) -> Option<SSHealthCheckConfig> {
+        if *self == HealthCheckConfig::default() {
+            return None;
+        }
+
+        Some(SSHealthCheckConfig {
+            mode: Some(self.mode.to_string()),
+            interval: Some(self.interval.as_secs()),
+            timeout: Some(self.timeout.as_secs()),
+            tcp: Some(self.tcp.to_ssconfig()),
+            udp: Some(self.udp.to_ssconfig()),
+        })
+    }
 }
 
-/// Server mode
-#[derive(Clone, Copy, Debug)]
-pub enum Mode {
-    TcpOnly,
-    TcpAndUdp,
-    UdpOnly,
+/// Strategy for choosing servers in load balancers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BalancerStrategy {
+    /// Always chooses the server with the lowest score (latency, stdev and failure rate)
+    LowestScore,
+    /// Chooses servers in turn
+    RoundRobin,
+    /// Chooses servers randomly, in proportion to their `weight`
+    WeightedRandom,
+    /// Chooses the server with the fewest active connections
+    LeastConnections,
+    /// Chooses servers by hashing the destination host, so the same host always goes to the same server
+    ConsistentHash,
 }
 
-impl Mode {
-    pub fn enable_udp(self) -> bool {
-        match self {
-            Mode::UdpOnly | Mode::TcpAndUdp => true,
-            _ => false,
-        }
-    }
-
-    pub fn enable_tcp(self) -> bool {
-        match self {
-            Mode::TcpOnly | Mode::TcpAndUdp => true,
-            _ => false,
-        }
+impl Default for BalancerStrategy {
+    fn default() -> BalancerStrategy {
+        BalancerStrategy::LowestScore
     }
 }
 
-impl fmt::Display for Mode {
+impl fmt::Display for BalancerStrategy {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Mode::TcpOnly => f.write_str("tcp_only"),
-            Mode::TcpAndUdp => f.write_str("tcp_and_udp"),
-            Mode::UdpOnly => f.write_str("udp_only"),
+            BalancerStrategy::LowestScore => f.write_str("lowest_score"),
+            BalancerStrategy::RoundRobin => f.write_str("round_robin"),
+            BalancerStrategy::WeightedRandom => f.write_str("weighted_random"),
+            BalancerStrategy::LeastConnections => f.write_str("least_connections"),
+            BalancerStrategy::ConsistentHash => f.write_str("consistent_hash"),
         }
     }
 }
 
-impl FromStr for Mode {
+impl FromStr for BalancerStrategy {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "tcp_only" => Ok(Mode::TcpOnly),
-            "tcp_and_udp" => Ok(Mode::TcpAndUdp),
-            "udp_only" => Ok(Mode::UdpOnly),
+            "lowest_score" => Ok(BalancerStrategy::LowestScore),
+            "round_robin" => Ok(BalancerStrategy::RoundRobin),
+            "weighted_random" => Ok(BalancerStrategy::WeightedRandom),
+            "least_connections" => Ok(BalancerStrategy::LeastConnections),
+            "consistent_hash" => Ok(BalancerStrategy::ConsistentHash),
             _ => Err(()),
         }
     }
@@ -854,22 +1660,54 @@ pub struct Config {
     pub dns: Option<String>,
     /// Server mode, `tcp_only`, `tcp_and_udp`, and `udp_only`
     pub mode: Mode,
+    /// Strategy for choosing servers of `sslocal`'s load balancers
+    pub balancer_strategy: BalancerStrategy,
+    /// Servers tried for a proxied connection of `sslocal` until one of them is connected, default is 3, `1` disables failover
+    pub failover_attempts: usize,
+    /// Health check probes of servers in `sslocal`'s load balancers
+    pub health_check: HealthCheckConfig,
     /// Set `TCP_NODELAY` socket option
     pub no_delay: bool,
+    /// Enable TCP Fast Open on listeners and outbound connections, only supported on Linux
+    pub fast_open: bool,
+    /// Window for waiting for clients' first payload, which is sent with the address header in the same chunk
+    ///
+    /// `None` sends the address header immediately after connected to servers
+    pub first_payload_window: Option<Duration>,
+    /// Idle connections kept for each server by `sslocal`, connected before clients need them, `0` disables the pool
+    pub idle_connections: usize,
     /// Address of `ss-manager`. Send servers' statistic data to the manager server
     pub manager_address: Option<ManagerAddr>,
     /// Manager's default method
     pub manager_method: Option<CipherType>,
+    /// Address of the HTTP endpoint exporting metrics in Prometheus text format, disabled if not specified
+    pub metrics_addr: Option<SocketAddr>,
+    /// Address of the control socket for load balancers of `sslocal`, disabled if not specified
+    pub control_address: Option<ManagerAddr>,
     /// Config is for Client or Server
     pub config_type: ConfigType,
     /// Timeout for UDP Associations, default is 5 minutes
     pub udp_timeout: Option<Duration>,
+    /// Time to wait for active TCP connections to finish while shutting down, exits immediately if not specified
+    pub shutdown_timeout: Option<Duration>,
     /// `RLIMIT_NOFILE` option for *nix systems
     pub nofile: Option<u64>,
     /// Timeout for TCP connections, could be replaced by server*.timeout
     pub timeout: Option<Duration>,
     /// ACL configuration
+    ///
+    /// Initial rules, they will be reloaded when the file is modified
     pub acl: Option<AccessControl>,
+    /// Users for local servers' authentication, authentication is disabled if not specified
+    pub local_auth: Option<LocalAuthConfig>,
+    /// Local servers running in the same process, replaces `local`, `config_type`, `mode` and `forward` if not empty
+    pub locals: Vec<LocalConfig>,
+    /// TLS configuration for HTTP local, clients have to connect with TLS if specified
+    ///
+    /// Only for the local server configured by `local`, local servers in `locals` have their own
+    pub local_tls: Option<LocalTlsConfig>,
+    /// Bandwidth limits shared by all servers in this process
+    pub global_rate_limit: RateLimitConfig,
     /// Path to stat callback unix address, only for Android
     /// TCP Transparent Proxy type
     pub tcp_redir: RedirType,
@@ -959,14 +1797,27 @@ impl Config {
             forward: None,
             dns: None,
             mode: Mode::TcpOnly,
+            balancer_strategy: BalancerStrategy::default(),
+            failover_attempts: DEFAULT_FAILOVER_ATTEMPTS,
+            health_check: HealthCheckConfig::default(),
             no_delay: false,
+            fast_open: false,
+            first_payload_window: None,
+            idle_connections: 0,
             manager_address: None,
             manager_method: None,
+            metrics_addr: None,
+            control_address: None,
             config_type,
             udp_timeout: None,
+            shutdown_timeout: None,
             nofile: None,
             timeout: None,
             acl: None,
+            local_auth: None,
+            locals: Vec::new(),
+            local_tls: None,
+            global_rate_limit: RateLimitConfig::default(),
             tcp_redir: RedirType::tcp_default(),
             udp_redir: RedirType::udp_default(),
             stat_path: None,
@@ -1038,6 +1889,19 @@ impl Config {
                     }
                 };
 
+                if !method.check_key(pwd.as_bytes()) {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid password",
+                        Some(format!(
+                            "`{}` requires a base64 encoded key with {} bytes",
+                            method,
+                            method.key_size()
+                        )),
+                    );
+                    return Err(err);
+                }
+
                 let plugin = match config.plugin {
                     None => None,
                     Some(plugin) => Some(PluginConfig {
@@ -1047,7 +1911,24 @@ impl Config {
                 };
 
                 let timeout = config.timeout.map(Duration::from_secs);
-                let nsvr = ServerConfig::new(addr, pwd, method, timeout, plugin);
+                let mut nsvr = ServerConfig::new(addr, pwd, method, timeout, plugin);
+
+                if let Some(limit) = config.traffic_limit {
+                    nsvr.set_traffic_limit(limit);
+                }
+                if let Some(t) = config.expire_at {
+                    nsvr.set_expire_at(UNIX_EPOCH + Duration::from_secs(t));
+                }
+                if let Some(limit) = config.rate_limit {
+                    nsvr.set_rate_limit(RateLimitConfig::from_ssconfig(limit)?);
+                }
+                if let Some(limit) = config.connection_rate_limit {
+                    nsvr.set_connection_rate_limit(RateLimitConfig::from_ssconfig(limit)?);
+                }
+
+                if let Some(users) = config.users {
+                    load_server_users(&nsvr, users)?;
+                }
 
                 nconfig.server.push(nsvr);
             }
@@ -1085,6 +1966,19 @@ impl Config {
                     }
                 };
 
+                if !method.check_key(svr.password.as_bytes()) {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid password",
+                        Some(format!(
+                            "`{}` requires a base64 encoded key with {} bytes",
+                            method,
+                            method.key_size()
+                        )),
+                    );
+                    return Err(err);
+                }
+
                 let plugin = match svr.plugin {
                     None => None,
                     Some(p) => Some(PluginConfig {
@@ -1094,7 +1988,33 @@ impl Config {
                 };
 
                 let timeout = svr.timeout.or(config.timeout).map(Duration::from_secs);
-                let nsvr = ServerConfig::new(addr, svr.password, method, timeout, plugin);
+                let mut nsvr = ServerConfig::new(addr, svr.password, method, timeout, plugin);
+
+                if let Some(limit) = svr.traffic_limit {
+                    nsvr.set_traffic_limit(limit);
+                }
+                if let Some(t) = svr.expire_at {
+                    nsvr.set_expire_at(UNIX_EPOCH + Duration::from_secs(t));
+                }
+                if let Some(limit) = svr.rate_limit {
+                    nsvr.set_rate_limit(RateLimitConfig::from_ssconfig(limit)?);
+                }
+                if let Some(limit) = svr.connection_rate_limit {
+                    nsvr.set_connection_rate_limit(RateLimitConfig::from_ssconfig(limit)?);
+                }
+                if let Some(weight) = svr.weight {
+                    nsvr.set_weight(weight);
+                }
+                if let Some(mux) = svr.mux {
+                    nsvr.set_mux(mux);
+                }
+                if let Some(allow_bind) = svr.allow_bind {
+                    nsvr.set_allow_bind(allow_bind);
+                }
+
+                if let Some(users) = svr.users {
+                    load_server_users(&nsvr, users)?;
+                }
 
                 nconfig.server.push(nsvr);
             }
@@ -1102,28 +2022,43 @@ impl Config {
 
         // Manager Address
         if let Some(ma) = config.manager_address {
-            let manager = match config.manager_port {
-                Some(port) => {
-                    match ma.parse::<IpAddr>() {
-                        Ok(ip) => ManagerAddr::from(SocketAddr::new(ip, port)),
-                        Err(..) => {
-                            // treated as domain
-                            ManagerAddr::from((ma, port))
-                        }
-                    }
-                }
-                #[cfg(unix)]
-                None => ManagerAddr::from(PathBuf::from(ma)),
-                #[cfg(not(unix))]
-                None => {
-                    let e = Error::new(ErrorKind::MissingField, "missing `manager_port`", None);
-                    return Err(e);
-                }
-            };
-
+            let manager = parse_manager_addr(ma, config.manager_port, "missing `manager_port`")?;
             nconfig.manager_address = Some(manager);
         }
 
+        // Control socket of load balancers
+        if let Some(ca) = config.control_address {
+            let control = parse_manager_addr(ca, config.control_port, "missing `control_port`")?;
+            nconfig.control_address = Some(control);
+        }
+
+        // Metrics
+        match (config.metrics_address, config.metrics_port) {
+            (Some(addr), Some(port)) => {
+                let ip = match addr.parse::<IpAddr>() {
+                    Ok(ip) => ip,
+                    Err(..) => {
+                        let e = Error::new(
+                            ErrorKind::Malformed,
+                            "malformed `metrics_address`, must be an IP address",
+                            None,
+                        );
+                        return Err(e);
+                    }
+                };
+                nconfig.metrics_addr = Some(SocketAddr::new(ip, port));
+            }
+            (None, None) => {}
+            _ => {
+                let e = Error::new(
+                    ErrorKind::Malformed,
+                    "`metrics_address` and `metrics_port` must be provided together",
+                    None,
+                );
+                return Err(e);
+            }
+        }
+
         // DNS
         nconfig.dns = config.dns;
 
@@ -1142,14 +2077,64 @@ impl Config {
             }
         }
 
+        // Load balancing strategy
+        if let Some(s) = config.balancer_strategy {
+            match s.parse::<BalancerStrategy>() {
+                Ok(bs) => nconfig.balancer_strategy = bs,
+                Err(..) => {
+                    let e = Error::new(
+                        ErrorKind::Malformed,
+                        "malformed `balancer_strategy`, must be one of `lowest_score`, `round_robin`, \
+                         `weighted_random`, `least_connections` and `consistent_hash`",
+                        None,
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
+        // Failover of proxied connections
+        if let Some(n) = config.failover_attempts {
+            if n == 0 {
+                let e = Error::new(ErrorKind::Invalid, "`failover_attempts` must be greater than 0", None);
+                return Err(e);
+            }
+            nconfig.failover_attempts = n;
+        }
+
+        // Health check probes of load balancers
+        if let Some(hc) = config.health_check {
+            nconfig.health_check = HealthCheckConfig::from_ssconfig(hc)?;
+        }
+
         // TCP nodelay
         if let Some(b) = config.no_delay {
             nconfig.no_delay = b;
         }
 
+        // TCP Fast Open
+        if let Some(b) = config.fast_open {
+            nconfig.fast_open = b;
+        }
+
+        // Sends the first payload with the address header, in milliseconds
+        if let Some(w) = config.first_payload_window {
+            if w > 0 {
+                nconfig.first_payload_window = Some(Duration::from_millis(w));
+            }
+        }
+
+        // Pre-connected idle connections to each server
+        if let Some(n) = config.idle_connections {
+            nconfig.idle_connections = n;
+        }
+
         // UDP
         nconfig.udp_timeout = config.udp_timeout.map(Duration::from_secs);
 
+        // Graceful shutdown
+        nconfig.shutdown_timeout = config.shutdown_timeout.map(Duration::from_secs);
+
         // RLIMIT_NOFILE
         nconfig.nofile = config.nofile;
 
@@ -1157,6 +2142,160 @@ impl Config {
         // This is mostly used for manager for creating new servers
         nconfig.timeout = config.timeout.map(Duration::from_secs);
 
+        // Local authentication
+        if let Some(users) = config.local_auth {
+            let mut auth = LocalAuthConfig::new();
+            for user in users {
+                if user.username.is_empty() || user.username.len() > 255 || user.password.len() > 255 {
+                    let e = Error::new(
+                        ErrorKind::Invalid,
+                        "invalid `local_auth` user",
+                        Some(format!(
+                            "username must be 1 to 255 bytes and password must be shorter than 256 bytes, user: `{}`",
+                            user.username
+                        )),
+                    );
+                    return Err(e);
+                }
+
+                auth.add_user(user.username, user.password);
+            }
+            nconfig.local_auth = Some(auth);
+        }
+
+        // TLS for local servers
+        match (config.local_tls_cert, config.local_tls_key) {
+            (Some(cert), Some(key)) => {
+                nconfig.local_tls = Some(LocalTlsConfig {
+                    cert_path: PathBuf::from(cert),
+                    key_path: PathBuf::from(key),
+                });
+            }
+            (None, None) => {}
+            _ => {
+                let e = Error::new(
+                    ErrorKind::Malformed,
+                    "`local_tls_cert` and `local_tls_key` must be provided together",
+                    None,
+                );
+                return Err(e);
+            }
+        }
+
+        // Bandwidth limits for all servers
+        if let Some(limit) = config.global_rate_limit {
+            nconfig.global_rate_limit = RateLimitConfig::from_ssconfig(limit)?;
+        }
+
+        // Multiple local servers
+        if let Some(locals) = config.locals {
+            for local in locals {
+                let addr = match local.local_address.parse::<IpAddr>() {
+                    Ok(ip) => ServerAddr::from(SocketAddr::new(ip, local.local_port)),
+                    Err(..) => {
+                        // treated as domain
+                        ServerAddr::from((local.local_address, local.local_port))
+                    }
+                };
+
+                let local_type = match local.protocol {
+                    None if config_type.is_local() => config_type,
+                    None => {
+                        let e = Error::new(ErrorKind::MissingField, "missing `protocol` in `locals`", None);
+                        return Err(e);
+                    }
+                    Some(p) => match ConfigType::from_local_protocol(&p) {
+                        Some(t) => t,
+                        None => {
+                            let e = Error::new(
+                                ErrorKind::Invalid,
+                                "invalid `protocol` in `locals`",
+                                Some(format!(
+                                    "`{}` is not one of `socks5`, `http`, `tunnel`, `redir` and `mixed`",
+                                    p
+                                )),
+                            );
+                            return Err(e);
+                        }
+                    },
+                };
+
+                let mode = match local.mode {
+                    None => nconfig.mode,
+                    Some(m) => match m.parse::<Mode>() {
+                        Ok(xm) => xm,
+                        Err(..) => {
+                            let e = Error::new(
+                                ErrorKind::Malformed,
+                                "malformed `mode` in `locals`, must be one of `tcp_only`, `udp_only` and `tcp_and_udp`",
+                                None,
+                            );
+                            return Err(e);
+                        }
+                    },
+                };
+
+                let mut nlocal = LocalConfig::new(addr, local_type, mode);
+
+                match (local.local_tls_cert, local.local_tls_key) {
+                    (Some(cert), Some(key)) => {
+                        if local_type != ConfigType::HttpLocal {
+                            let e = Error::new(
+                                ErrorKind::Invalid,
+                                "`local_tls_cert` and `local_tls_key` in `locals` are only for `http`",
+                                None,
+                            );
+                            return Err(e);
+                        }
+
+                        nlocal.tls = Some(LocalTlsConfig {
+                            cert_path: PathBuf::from(cert),
+                            key_path: PathBuf::from(key),
+                        });
+                    }
+                    (None, None) => {}
+                    _ => {
+                        let e = Error::new(
+                            ErrorKind::Malformed,
+                            "`local_tls_cert` and `local_tls_key` in `locals` must be provided together",
+                            None,
+                        );
+                        return Err(e);
+                    }
+                }
+
+                match (local.forward_address, local.forward_port) {
+                    (Some(fa), Some(port)) => {
+                        let forward = match fa.parse::<IpAddr>() {
+                            Ok(ip) => Address::from(SocketAddr::new(ip, port)),
+                            Err(..) => Address::from((fa, port)),
+                        };
+                        nlocal.forward = Some(forward);
+                    }
+                    (None, None) => {
+                        if let ConfigType::TunnelLocal = local_type {
+                            let e = Error::new(
+                                ErrorKind::MissingField,
+                                "missing `forward_address` and `forward_port` for `tunnel` in `locals`",
+                                None,
+                            );
+                            return Err(e);
+                        }
+                    }
+                    _ => {
+                        let e = Error::new(
+                            ErrorKind::Malformed,
+                            "`forward_address` and `forward_port` in `locals` must be provided together",
+                            None,
+                        );
+                        return Err(e);
+                    }
+                }
+
+                nconfig.locals.push(nlocal);
+            }
+        }
+
         Ok(nconfig)
     }
 
@@ -1210,6 +2349,26 @@ impl Config {
         })
     }
 
+    /// All local servers that should be started
+    ///
+    /// Uses `locals` if it is not empty, otherwise the only local server is built from `local`, `config_type`,
+    /// `mode` and `forward`
+    pub fn local_configs(&self) -> Vec<LocalConfig> {
+        if !self.locals.is_empty() {
+            return self.locals.clone();
+        }
+
+        match self.local {
+            Some(ref addr) if self.config_type.is_local() => {
+                let mut local = LocalConfig::new(addr.clone(), self.config_type, self.mode);
+                local.forward = self.forward.clone();
+                local.tls = self.local_tls.clone();
+                vec![local]
+            }
+            _ => Vec::new(),
+        }
+    }
+
     /// Check if there are any plugin are enabled with servers
     pub fn has_server_plugins(&self) -> bool {
         for server in &self.server {
@@ -1222,14 +2381,23 @@ impl Config {
 
     /// Check if all required fields are already set
     pub fn check_integrity(&self) -> Result<(), Error> {
+        if self.local_tls.is_some() && (!self.locals.is_empty() || self.config_type != ConfigType::HttpLocal) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`local_tls_cert` and `local_tls_key` are only for `http` local",
+                Some("set them for each `http` local server in `locals`".to_owned()),
+            );
+            return Err(err);
+        }
+
         if self.config_type.is_local() {
-            if self.local.is_some() {
+            if self.local.is_some() || !self.locals.is_empty() {
                 return Ok(());
             }
 
             let err = Error::new(
                 ErrorKind::MissingField,
-                "missing `local_address` and `local_port` for client configuration",
+                "missing `local_address` and `local_port` (or `locals`) for client configuration",
                 None,
             );
             return Err(err);
@@ -1265,6 +2433,90 @@ impl Config {
     }
 }
 
+fn load_server_users(svr: &ServerConfig, users: Vec<SSServerUserConfig>) -> Result<(), Error> {
+    let method = svr.method();
+
+    // Users are identified by trial decryption, which requires authentication of AEAD ciphers
+    if method.category() != CipherCategory::Aead {
+        let err = Error::new(
+            ErrorKind::Invalid,
+            "`users` requires an AEAD method",
+            Some(format!("`{}` couldn't identify users", method)),
+        );
+        return Err(err);
+    }
+
+    for user in users {
+        if !method.check_key(user.password.as_bytes()) {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "invalid password",
+                Some(format!(
+                    "user `{}`, `{}` requires a base64 encoded key with {} bytes",
+                    user.name,
+                    method,
+                    method.key_size()
+                )),
+            );
+            return Err(err);
+        }
+
+        svr.users().add_user(ServerUser::new(user.name, user.password, method));
+    }
+
+    Ok(())
+}
+
+fn server_users_to_ssconfig(svr: &ServerConfig) -> Option<Vec<SSServerUserConfig>> {
+    let users = svr.users().users();
+    if users.is_empty() {
+        return None;
+    }
+
+    Some(
+        users
+            .iter()
+            .map(|u| SSServerUserConfig {
+                name: u.name().to_owned(),
+                password: u.password().to_owned(),
+            })
+            .collect(),
+    )
+}
+
+// `port` is required for addresses other than unix socket paths
+#[cfg_attr(unix, allow(unused_variables))]
+fn parse_manager_addr(addr: String, port: Option<u16>, missing_port: &'static str) -> Result<ManagerAddr, Error> {
+    match port {
+        Some(port) => {
+            match addr.parse::<IpAddr>() {
+                Ok(ip) => Ok(ManagerAddr::from(SocketAddr::new(ip, port))),
+                Err(..) => {
+                    // treated as domain
+                    Ok(ManagerAddr::from((addr, port)))
+                }
+            }
+        }
+        #[cfg(unix)]
+        None => Ok(ManagerAddr::from(PathBuf::from(addr))),
+        #[cfg(not(unix))]
+        None => Err(Error::new(ErrorKind::MissingField, missing_port, None)),
+    }
+}
+
+fn manager_addr_to_ssconfig(addr: &ManagerAddr) -> (String, Option<u16>) {
+    match *addr {
+        ManagerAddr::SocketAddr(ref saddr) => (saddr.ip().to_string(), Some(saddr.port())),
+        ManagerAddr::DomainName(ref dname, port) => (dname.clone(), Some(port)),
+        #[cfg(unix)]
+        ManagerAddr::UnixSocketAddr(ref path) => (path.display().to_string(), None),
+    }
+}
+
+fn unix_timestamp(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // Convert to json
@@ -1304,6 +2556,11 @@ impl fmt::Display for Config {
                 jconf.plugin = svr.plugin().map(|p| p.plugin.to_string());
                 jconf.plugin_opts = svr.plugin().and_then(|p| p.plugin_opt.clone());
                 jconf.timeout = svr.timeout().or(self.timeout).map(|t| t.as_secs());
+                jconf.users = server_users_to_ssconfig(svr);
+                jconf.traffic_limit = svr.traffic_limit();
+                jconf.expire_at = svr.expire_at().map(unix_timestamp);
+                jconf.rate_limit = svr.rate_limit().to_ssconfig();
+                jconf.connection_rate_limit = svr.connection_rate_limit().to_ssconfig();
             }
             _ => {
                 let mut vsvr = Vec::new();
@@ -1323,6 +2580,14 @@ impl fmt::Display for Config {
                         plugin: svr.plugin().map(|p| p.plugin.to_string()),
                         plugin_opts: svr.plugin().and_then(|p| p.plugin_opt.clone()),
                         timeout: svr.timeout().map(|t| t.as_secs()),
+                        users: server_users_to_ssconfig(svr),
+                        traffic_limit: svr.traffic_limit(),
+                        expire_at: svr.expire_at().map(unix_timestamp),
+                        rate_limit: svr.rate_limit().to_ssconfig(),
+                        connection_rate_limit: svr.connection_rate_limit().to_ssconfig(),
+                        weight: if svr.weight() == 1 { None } else { Some(svr.weight()) },
+                        mux: if svr.mux() { Some(true) } else { None },
+                        allow_bind: if svr.allow_bind() { Some(true) } else { None },
                     });
                 }
 
@@ -1332,35 +2597,235 @@ impl fmt::Display for Config {
         }
 
         if let Some(ref ma) = self.manager_address {
-            jconf.manager_address = Some(match *ma {
-                ManagerAddr::SocketAddr(ref saddr) => saddr.ip().to_string(),
-                ManagerAddr::DomainName(ref dname, ..) => dname.clone(),
-                #[cfg(unix)]
-                ManagerAddr::UnixSocketAddr(ref path) => path.display().to_string(),
-            });
-
-            jconf.manager_port = match *ma {
-                ManagerAddr::SocketAddr(ref saddr) => Some(saddr.port()),
-                ManagerAddr::DomainName(.., port) => Some(port),
-                #[cfg(unix)]
-                ManagerAddr::UnixSocketAddr(..) => None,
-            };
+            let (addr, port) = manager_addr_to_ssconfig(ma);
+            jconf.manager_address = Some(addr);
+            jconf.manager_port = port;
+        }
+
+        if let Some(ref ca) = self.control_address {
+            let (addr, port) = manager_addr_to_ssconfig(ca);
+            jconf.control_address = Some(addr);
+            jconf.control_port = port;
+        }
+
+        if let Some(ref addr) = self.metrics_addr {
+            jconf.metrics_address = Some(addr.ip().to_string());
+            jconf.metrics_port = Some(addr.port());
         }
 
         jconf.mode = Some(self.mode.to_string());
 
+        if self.balancer_strategy != BalancerStrategy::default() {
+            jconf.balancer_strategy = Some(self.balancer_strategy.to_string());
+        }
+        if self.failover_attempts != DEFAULT_FAILOVER_ATTEMPTS {
+            jconf.failover_attempts = Some(self.failover_attempts);
+        }
+        jconf.health_check = self.health_check.to_ssconfig();
+
         if self.no_delay {
             jconf.no_delay = Some(self.no_delay);
         }
 
+        if self.fast_open {
+            jconf.fast_open = Some(self.fast_open);
+        }
+
+        if let Some(w) = self.first_payload_window {
+            jconf.first_payload_window = Some(w.as_millis() as u64);
+        }
+
+        if self.idle_connections > 0 {
+            jconf.idle_connections = Some(self.idle_connections);
+        }
+
         if let Some(ref dns) = self.dns {
             jconf.dns = Some(dns.to_string());
         }
 
         jconf.udp_timeout = self.udp_timeout.map(|t| t.as_secs());
 
+        jconf.shutdown_timeout = self.shutdown_timeout.map(|t| t.as_secs());
+
         jconf.nofile = self.nofile;
 
+        if let Some(ref auth) = self.local_auth {
+            let mut users = Vec::new();
+            for (username, password) in auth.iter() {
+                users.push(SSLocalUserConfig {
+                    username: username.to_owned(),
+                    password: password.to_owned(),
+                });
+            }
+            jconf.local_auth = Some(users);
+        }
+
+        jconf.global_rate_limit = self.global_rate_limit.to_ssconfig();
+
+        if let Some(ref tls) = self.local_tls {
+            jconf.local_tls_cert = Some(tls.cert_path.display().to_string());
+            jconf.local_tls_key = Some(tls.key_path.display().to_string());
+        }
+
+        if !self.locals.is_empty() {
+            let mut locals = Vec::new();
+            for local in &self.locals {
+                let (local_address, local_port) = match local.addr {
+                    ServerAddr::SocketAddr(ref sa) => (sa.ip().to_string(), sa.port()),
+                    ServerAddr::DomainName(ref dname, port) => (dname.to_owned(), port),
+                };
+
+                let (forward_address, forward_port) = match local.forward {
+                    None => (None, None),
+                    Some(Address::SocketAddress(ref sa)) => (Some(sa.ip().to_string()), Some(sa.port())),
+                    Some(Address::DomainNameAddress(ref dname, port)) => (Some(dname.to_owned()), Some(port)),
+                };
+
+                locals.push(SSLocalExtConfig {
+                    local_address,
+                    local_port,
+                    protocol: local.config_type.local_protocol().map(ToOwned::to_owned),
+                    mode: Some(local.mode.to_string()),
+                    forward_address,
+                    forward_port,
+                    local_tls_cert: local.tls.as_ref().map(|t| t.cert_path.display().to_string()),
+                    local_tls_key: local.tls.as_ref().map(|t| t.key_path.display().to_string()),
+                });
+            }
+            jconf.locals = Some(locals);
+        }
+
         write!(f, "{}", json5::to_string(&jconf).unwrap())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_local_auth_check_user() {
+        let mut auth = LocalAuthConfig::new();
+        auth.add_user("user", "password");
+
+        assert!(auth.check_user("user", "password"));
+        assert!(!auth.check_user("user", "passwore"));
+        assert!(!auth.check_user("user", "password1"));
+        assert!(!auth.check_user("user", ""));
+        assert!(!auth.check_user("other", "password"));
+    }
+
+    #[test]
+    fn test_server_zero_weight() {
+        let s = r#"{
+            "servers": [
+                {
+                    "address": "127.0.0.1",
+                    "port": 8388,
+                    "password": "password",
+                    "method": "aes-256-gcm",
+                    "weight": 3
+                },
+                {
+                    "address": "127.0.0.1",
+                    "port": 8389,
+                    "password": "password",
+                    "method": "aes-256-gcm",
+                    "weight": 0
+                }
+            ],
+            "local_address": "127.0.0.1",
+            "local_port": 1080
+        }"#;
+        let config = Config::load_from_str(s, ConfigType::Socks5Local).unwrap();
+        assert_eq!(config.server[0].weight(), 3);
+        assert_eq!(config.server[1].weight(), 0);
+
+        let loaded = Config::load_from_str(&config.to_string(), ConfigType::Socks5Local).unwrap();
+        assert_eq!(loaded.server[1].weight(), 0);
+    }
+
+    fn load_health_check(health_check: &str) -> Result<HealthCheckConfig, Error> {
+        let s = format!(
+            r#"{{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_address": "127.0.0.1",
+                "local_port": 1080,
+                "health_check": {}
+            }}"#,
+            health_check
+        );
+        Config::load_from_str(&s, ConfigType::Socks5Local).map(|c| c.health_check)
+    }
+
+    #[test]
+    fn test_health_check_default() {
+        let hc = load_health_check("{}").unwrap();
+        assert_eq!(hc, HealthCheckConfig::default());
+    }
+
+    #[test]
+    fn test_health_check_parse() {
+        let hc = load_health_check(
+            r#"{
+                "mode": "connect_only",
+                "interval": 10,
+                "timeout": 3,
+                "tcp": {
+                    "target": "example.com:80",
+                    "request": "HEAD / HTTP/1.1\r\n\r\n",
+                    "expect": "HTTP/1.1"
+                },
+                "udp": {
+                    "target": "1.1.1.1:53",
+                    "request_base64": "EjQBAA==",
+                    "expect_base64": "EjQ="
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(hc.mode, HealthCheckMode::ConnectOnly);
+        assert_eq!(hc.interval, Duration::from_secs(10));
+        assert_eq!(hc.timeout, Duration::from_secs(3));
+        assert_eq!(hc.tcp.target, Address::DomainNameAddress("example.com".to_owned(), 80));
+        assert_eq!(hc.tcp.request, b"HEAD / HTTP/1.1\r\n\r\n".to_vec());
+        assert_eq!(hc.tcp.expect, b"HTTP/1.1".to_vec());
+        assert_eq!(hc.udp.target, Address::SocketAddress("1.1.1.1:53".parse().unwrap()));
+        assert_eq!(hc.udp.request, vec![0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(hc.udp.expect, vec![0x12, 0x34]);
+
+        // Unspecified fields keep their defaults
+        let hc = load_health_check(r#"{"tcp": {"expect": "HTTP/1.1 204"}}"#).unwrap();
+        let default = HealthCheckConfig::default();
+        assert_eq!(hc.mode, default.mode);
+        assert_eq!(hc.tcp.target, default.tcp.target);
+        assert_eq!(hc.tcp.request, default.tcp.request);
+        assert_eq!(hc.tcp.expect, b"HTTP/1.1 204".to_vec());
+        assert_eq!(hc.udp, default.udp);
+    }
+
+    #[test]
+    fn test_health_check_invalid() {
+        assert!(load_health_check(r#"{"mode": "ping"}"#).is_err());
+        assert!(load_health_check(r#"{"interval": 0}"#).is_err());
+        assert!(load_health_check(r#"{"timeout": 0}"#).is_err());
+        assert!(load_health_check(r#"{"tcp": {"target": "example.com"}}"#).is_err());
+        assert!(load_health_check(r#"{"tcp": {"request": "GET", "request_base64": "R0VU"}}"#).is_err());
+        assert!(load_health_check(r#"{"udp": {"expect_base64": "not base64!"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_health_check_serialize() {
+        let mut config = Config::new(ConfigType::Socks5Local);
+        config.health_check.mode = HealthCheckMode::ConnectOnly;
+        config.health_check.interval = Duration::from_secs(10);
+        config.health_check.udp.expect = vec![0xff, 0xfe];
+
+        let loaded = Config::load_from_str(&config.to_string(), ConfigType::Socks5Local).unwrap();
+        assert_eq!(loaded.health_check, config.health_check);
+    }
+}
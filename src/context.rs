@@ -1,31 +1,45 @@
 //! Shadowsocks Server Context
 
 use std::{
+    collections::HashMap,
+    fs,
     io,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
+        Weak,
     },
+    time::{Duration, Instant, SystemTime},
 };
 
-#[cfg(feature = "dns-relay")]
-use std::net::IpAddr;
-
 #[cfg(feature = "dns-relay")]
 use lru_time_cache::LruCache;
 
 use bloomfilter::Bloom;
-use spin::Mutex;
-use tokio::runtime::Handle;
+use log::{error, info};
+use spin::{Mutex, RwLock};
+use tokio::{runtime::Handle, time};
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::TokioAsyncResolver;
 
 #[cfg(feature = "trust-dns")]
 use crate::relay::dns_resolver::create_resolver;
 use crate::{
+    acl::AccessControl,
     config::{Config, ConfigType, ServerConfig},
-    relay::{dns_resolver::resolve, flow::ServerFlowStatistic, socks5::Address},
+    relay::{
+        dns_resolver::resolve,
+        flow::ServerFlowStatistic,
+        metrics::Metrics,
+        rate_limit::BandwidthLimiter,
+        socks5::Address,
+        tcprelay::{
+            mux::{MuxPool, SharedMuxPool},
+            pool::{ConnectionPool, SharedConnectionPool},
+        },
+    },
 };
 
 // Entries for server's bloom filter
@@ -48,6 +62,15 @@ const BF_ERROR_RATE_FOR_SERVER: f64 = 1e-6;
 // Borrowed from shadowsocks-libev's default value
 const BF_ERROR_RATE_FOR_CLIENT: f64 = 1e-15;
 
+// Interval for checking modification of the ACL file
+const ACL_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// The ACL file is reloaded only if it is not modified again in this duration, it may be still being written
+const ACL_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+// Interval for checking active connections while draining
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
 // A bloom filter borrowed from shadowsocks-libev's `ppbloom`
 //
 // It contains 2 bloom filters and each one holds 1/2 entries.
@@ -117,6 +140,21 @@ impl PingPongBloom {
 pub struct ServerState {
     #[cfg(feature = "trust-dns")]
     dns_resolver: Option<TokioAsyncResolver>,
+
+    // Bandwidth limiters for all servers
+    global_rate_limiter: BandwidthLimiter,
+
+    // ACL rules for all servers, replaced when the ACL file is modified
+    acl: RwLock<Option<Arc<AccessControl>>>,
+
+    // Metrics of all servers
+    metrics: Metrics,
+
+    // Check for duplicated IV/Nonce, for prevent replay attack
+    // https://github.com/shadowsocks/shadowsocks-org/issues/44
+    //
+    // Shared by all servers, salts seen before servers are reloaded are still rejected
+    nonce_ppbloom: Mutex<PingPongBloom>,
 }
 
 impl ServerState {
@@ -127,14 +165,34 @@ impl ServerState {
                 Ok(resolver) => Some(resolver),
                 Err(..) => None,
             },
+            global_rate_limiter: BandwidthLimiter::new(&config.global_rate_limit),
+            acl: RwLock::new(config.acl.clone().map(Arc::new)),
+            metrics: Metrics::default(),
+            nonce_ppbloom: Mutex::new(PingPongBloom::new(config.config_type)),
         };
 
-        Arc::new(state)
+        ServerState::start_shared(state)
     }
 
     #[cfg(not(feature = "trust-dns"))]
-    pub async fn new_shared(_config: &Config, _rt: Handle) -> SharedServerState {
-        Arc::new(ServerState {})
+    pub async fn new_shared(config: &Config, _rt: Handle) -> SharedServerState {
+        let state = ServerState {
+            global_rate_limiter: BandwidthLimiter::new(&config.global_rate_limit),
+            acl: RwLock::new(config.acl.clone().map(Arc::new)),
+            metrics: Metrics::default(),
+            nonce_ppbloom: Mutex::new(PingPongBloom::new(config.config_type)),
+        };
+
+        ServerState::start_shared(state)
+    }
+
+    fn start_shared(state: ServerState) -> SharedServerState {
+        let state = Arc::new(state);
+
+        // ACL may also be set by reloading configuration later
+        tokio::spawn(acl_watcher_task(Arc::downgrade(&state)));
+
+        state
     }
 
     /// Get the global shared resolver
@@ -142,11 +200,135 @@ impl ServerState {
     pub fn dns_resolver(&self) -> Option<&TokioAsyncResolver> {
         self.dns_resolver.as_ref()
     }
+
+    /// Get the global bandwidth limiter
+    pub fn global_rate_limiter(&self) -> &BandwidthLimiter {
+        &self.global_rate_limiter
+    }
+
+    /// Get the current ACL rules
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.read().clone()
+    }
+
+    /// Replace ACL rules, only affects connections accepted afterwards
+    pub fn set_acl(&self, acl: Option<AccessControl>) {
+        *self.acl.write() = acl.map(Arc::new);
+    }
+
+    /// Get metrics of all servers
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
 }
 
 /// `ServerState` wrapped in `Arc`
 pub type SharedServerState = Arc<ServerState>;
 
+fn file_modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Watches the file of current ACL rules, which may be replaced by reloading configuration
+struct AclWatcher {
+    path: Option<PathBuf>,
+    last_modified: Option<SystemTime>,
+}
+
+impl AclWatcher {
+    fn new() -> AclWatcher {
+        AclWatcher {
+            path: None,
+            last_modified: None,
+        }
+    }
+
+    // Reloads ACL rules if the file is modified
+    async fn check(&mut self, state: &ServerState) {
+        let path = match state.acl() {
+            Some(acl) => acl.file_path().to_owned(),
+            None => {
+                self.path = None;
+                return;
+            }
+        };
+
+        // Rules are just loaded from another file
+        if self.path.as_ref() != Some(&path) {
+            self.last_modified = file_modified_time(&path);
+            self.path = Some(path);
+            return;
+        }
+
+        let modified = file_modified_time(&path);
+        if modified == self.last_modified {
+            return;
+        }
+
+        // Checked again in the next round if it is still being modified
+        time::delay_for(ACL_SETTLE_TIME).await;
+        if file_modified_time(&path) != modified {
+            return;
+        }
+        self.last_modified = modified;
+
+        match AccessControl::load_from_file(&path) {
+            Ok(acl) => {
+                // Rules may be replaced by reloading configuration while waiting
+                if state.acl().map_or(false, |a| a.file_path() == path) {
+                    state.set_acl(Some(acl));
+                    info!("reloaded ACL from modified file {}", path.display());
+                }
+            }
+            Err(err) => {
+                error!(
+                    "failed to reload ACL from {}, keep using the current rules, error: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+// Reloads ACL rules if the file is modified, exits after `ServerState` is dropped
+async fn acl_watcher_task(state: Weak<ServerState>) {
+    let mut watcher = AclWatcher::new();
+
+    loop {
+        match state.upgrade() {
+            Some(state) => watcher.check(&state).await,
+            None => break,
+        }
+
+        time::delay_for(ACL_CHECK_INTERVAL).await;
+    }
+}
+
+/// A client authenticated by a UDP ASSOCIATE connection, unmarked after it is dropped
+pub struct AuthenticatedClient<'a> {
+    context: &'a Context,
+    addr: SocketAddr,
+}
+
+impl Drop for AuthenticatedClient<'_> {
+    fn drop(&mut self) {
+        self.context.remove_authenticated_client(&self.addr);
+    }
+}
+
+struct ActiveConnectionCounter(Arc<AtomicUsize>);
+
+impl Drop for ActiveConnectionCounter {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// An active connection, counted until all of its clones are dropped
+#[derive(Clone)]
+pub struct ActiveConnection(Arc<ActiveConnectionCounter>);
+
 /// Shared basic configuration for the whole server
 pub struct Context {
     config: Config,
@@ -158,13 +340,26 @@ pub struct Context {
     // For killing all background jobs
     server_running: AtomicBool,
 
-    // Check for duplicated IV/Nonce, for prevent replay attack
-    // https://github.com/shadowsocks/shadowsocks-org/issues/44
-    nonce_ppbloom: Mutex<PingPongBloom>,
+    // Number of active TCP connections, for draining while shutting down
+    active_connections: Arc<AtomicUsize>,
 
     // For Android's flow stat report
     local_flow_statistic: ServerFlowStatistic,
 
+    // Bandwidth limiters shared by all connections of each server, in the same order as `config.server`
+    server_rate_limiters: Vec<BandwidthLimiter>,
+
+    // Clients that passed SOCKS5 authentication and are holding UDP ASSOCIATE connections
+    // One entry for each connection, because a client may associate multiple times.
+    // Ports are those declared in UDP ASSOCIATE requests, `0` if the client didn't know its port
+    authenticated_clients: Mutex<HashMap<IpAddr, Vec<u16>>>,
+
+    // Multiplexing sessions to servers, keyed by servers' addresses
+    mux_pools: Mutex<HashMap<String, SharedMuxPool>>,
+
+    // Idle connections to servers, keyed by servers' addresses
+    connection_pools: Mutex<HashMap<String, SharedConnectionPool>>,
+
     // For DNS relay's ACL domain name reverse lookup
     #[cfg(feature = "dns-relay")]
     reverse_lookup_cache: Mutex<LruCache<IpAddr, String>>,
@@ -176,16 +371,28 @@ pub type SharedContext = Arc<Context>;
 impl Context {
     /// Create a non-shared Context
     fn new(config: Config, server_state: SharedServerState) -> Context {
-        let nonce_ppbloom = Mutex::new(PingPongBloom::new(config.config_type));
         #[cfg(feature = "dns-relay")]
         let reverse_lookup_cache = Mutex::new(LruCache::<IpAddr, String>::with_capacity(8192));
+        let server_rate_limiters = config
+            .server
+            .iter()
+            .map(|svr_cfg| BandwidthLimiter::new(svr_cfg.rate_limit()))
+            .collect();
+
+        // Exported as metrics of the whole process, summed with other contexts
+        let active_connections = Arc::new(AtomicUsize::new(0));
+        server_state.metrics().register_tcp_connections(&active_connections);
 
         Context {
             config,
             server_state,
             server_running: AtomicBool::new(true),
-            nonce_ppbloom,
+            active_connections,
             local_flow_statistic: ServerFlowStatistic::new(),
+            server_rate_limiters,
+            authenticated_clients: Mutex::new(HashMap::new()),
+            mux_pools: Mutex::new(HashMap::new()),
+            connection_pools: Mutex::new(HashMap::new()),
             #[cfg(feature = "dns-relay")]
             reverse_lookup_cache,
         }
@@ -223,6 +430,16 @@ impl Context {
         &mut self.config.server[idx]
     }
 
+    /// Get the bandwidth limiter shared by all connections of server by index
+    pub fn server_rate_limiter(&self, idx: usize) -> &BandwidthLimiter {
+        &self.server_rate_limiters[idx]
+    }
+
+    /// Get the global bandwidth limiter
+    pub fn global_rate_limiter(&self) -> &BandwidthLimiter {
+        self.server_state.global_rate_limiter()
+    }
+
     #[cfg(feature = "trust-dns")]
     /// Get the global shared resolver
     pub fn dns_resolver(&self) -> Option<&TokioAsyncResolver> {
@@ -244,6 +461,45 @@ impl Context {
         self.server_running.store(false, Ordering::Release)
     }
 
+    /// Resolves after the server is stopped by `set_server_stopped`
+    pub async fn wait_server_stopped(&self) {
+        while self.server_running() {
+            time::delay_for(DRAIN_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Get metrics of all servers
+    pub fn metrics(&self) -> &Metrics {
+        self.server_state.metrics()
+    }
+
+    /// Counts a new active connection, until the returned `ActiveConnection` (and its clones) is dropped
+    pub fn new_active_connection(&self) -> ActiveConnection {
+        self.active_connections.fetch_add(1, Ordering::AcqRel);
+        ActiveConnection(Arc::new(ActiveConnectionCounter(self.active_connections.clone())))
+    }
+
+    /// Number of active connections
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Acquire)
+    }
+
+    /// Waits until all active connections are finished, or `timeout` elapsed
+    ///
+    /// Returns the number of connections that are still active
+    pub async fn drain_connections(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let active = self.active_connections();
+            if active == 0 || Instant::now() >= deadline {
+                return active;
+            }
+
+            time::delay_for(DRAIN_CHECK_INTERVAL).await;
+        }
+    }
+
     /// Check if nonce exist or not
     ///
     /// If not, set into the current bloom filter
@@ -254,31 +510,43 @@ impl Context {
             return false;
         }
 
-        let mut ppbloom = self.nonce_ppbloom.lock();
-        ppbloom.check_and_set(nonce)
+        let mut ppbloom = self.server_state.nonce_ppbloom.lock();
+        let exists = ppbloom.check_and_set(nonce);
+        if exists {
+            self.metrics().incr_replay_hits();
+        }
+        exists
     }
 
     /// Check client ACL (for server)
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
-        match self.config.acl {
+        let blocked = match self.server_state.acl() {
             None => false,
-            Some(ref a) => a.check_client_blocked(addr),
+            Some(a) => a.check_client_blocked(addr),
+        };
+        if blocked {
+            self.metrics().incr_acl_client_rejects();
         }
+        blocked
     }
 
     /// Check outbound address ACL (for server)
     pub fn check_outbound_blocked(&self, addr: &Address) -> bool {
-        match self.config.acl {
+        let blocked = match self.server_state.acl() {
             None => false,
-            Some(ref a) => a.check_outbound_blocked(addr),
+            Some(a) => a.check_outbound_blocked(addr),
+        };
+        if blocked {
+            self.metrics().incr_acl_outbound_rejects();
         }
+        blocked
     }
 
     /// Check resolved outbound address ACL (for server)
     pub fn check_resolved_outbound_blocked(&self, addr: &SocketAddr) -> bool {
-        match self.config.acl {
+        match self.server_state.acl() {
             None => false,
-            Some(ref a) => a.check_resolved_outbound_blocked(addr),
+            Some(a) => a.check_resolved_outbound_blocked(addr),
         }
     }
 
@@ -291,10 +559,10 @@ impl Context {
 
     /// Check target address ACL (for client)
     pub async fn check_target_bypassed(&self, target: &Address) -> bool {
-        match self.config.acl {
+        match self.server_state.acl() {
             // Proxy everything by default
             None => false,
-            Some(ref a) => {
+            Some(a) => {
                 #[cfg(feature = "dns-relay")]
                 {
                     match *target {
@@ -317,8 +585,172 @@ impl Context {
         }
     }
 
+    /// Check if local servers require authentication
+    pub fn local_auth_required(&self) -> bool {
+        match self.config.local_auth {
+            None => false,
+            Some(ref a) => !a.is_empty(),
+        }
+    }
+
+    /// Mark `addr` as authenticated (for client), until the returned guard is dropped
+    ///
+    /// Port of `addr` is the port that client is going to send UDP packets from, `0` allows any port of the host
+    pub fn add_authenticated_client(&self, addr: SocketAddr) -> AuthenticatedClient<'_> {
+        let mut clients = self.authenticated_clients.lock();
+        clients.entry(addr.ip()).or_insert_with(Vec::new).push(addr.port());
+
+        AuthenticatedClient { context: self, addr }
+    }
+
+    fn remove_authenticated_client(&self, addr: &SocketAddr) {
+        let mut clients = self.authenticated_clients.lock();
+        if let Some(ports) = clients.get_mut(&addr.ip()) {
+            if let Some(pos) = ports.iter().position(|p| *p == addr.port()) {
+                ports.swap_remove(pos);
+            }
+            if ports.is_empty() {
+                clients.remove(&addr.ip());
+            }
+        }
+    }
+
+    /// Check if `addr` is allowed to send UDP packets (for client)
+    ///
+    /// Always `true` if authentication is not required
+    pub fn check_client_authenticated(&self, addr: &SocketAddr) -> bool {
+        if !self.local_auth_required() {
+            return true;
+        }
+
+        let clients = self.authenticated_clients.lock();
+        match clients.get(&addr.ip()) {
+            Some(ports) => ports.iter().any(|p| *p == 0 || *p == addr.port()),
+            None => false,
+        }
+    }
+
     /// Get client flow statistics
     pub fn local_flow_statistic(&self) -> &ServerFlowStatistic {
         &self.local_flow_statistic
     }
+
+    /// Multiplexing sessions to `svr_cfg`
+    pub(crate) fn mux_pool(&self, svr_cfg: &ServerConfig) -> SharedMuxPool {
+        let mut pools = self.mux_pools.lock();
+        let pool = pools
+            .entry(svr_cfg.addr().to_string())
+            .or_insert_with(|| Arc::new(MuxPool::new(svr_cfg)));
+
+        if !pool.is_for(svr_cfg) {
+            *pool = Arc::new(MuxPool::new(svr_cfg));
+        }
+
+        pool.clone()
+    }
+
+    /// Idle connections to `svr_cfg`, `None` if `idle_connections` is not set
+    ///
+    /// Also `None` with `fast_open`, connections are not established until the first payload is sent,
+    /// so idle connections couldn't be kept
+    pub(crate) fn connection_pool(&self, svr_cfg: &ServerConfig) -> Option<SharedConnectionPool> {
+        let size = self.config.idle_connections;
+        if size == 0 || self.config.fast_open {
+            return None;
+        }
+
+        let timeout = svr_cfg.timeout().or(self.config.timeout);
+        let new_pool = || Arc::new(ConnectionPool::new(svr_cfg, size, timeout));
+
+        let mut pools = self.connection_pools.lock();
+        let pool = pools.entry(svr_cfg.addr().to_string()).or_insert_with(new_pool);
+
+        if !pool.is_for(svr_cfg) {
+            *pool = new_pool();
+        }
+
+        Some(pool.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::runtime::Builder;
+
+    #[test]
+    fn test_nonce_kept_by_server_state() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let config = Config::new(ConfigType::Server);
+            let state = ServerState::new_shared(&config, rt_handle).await;
+
+            let context = Context::new_shared(config.clone(), state.clone());
+            assert!(!context.check_nonce_and_set(b"test-salt"));
+            assert!(context.check_nonce_and_set(b"test-salt"));
+
+            // Server is restarted with a new context
+            drop(context);
+            let context = Context::new_shared(config, state);
+            assert!(context.check_nonce_and_set(b"test-salt"));
+            assert!(!context.check_nonce_and_set(b"another-test-salt"));
+        });
+    }
+
+    #[test]
+    fn test_acl_watcher_reload() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        // Per-run directory, tests may run concurrently in other processes
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("shadowsocks-acl-watcher-test-{}-{}", std::process::id(), nanos));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("watched.acl");
+        let other_path = dir.join("watched-other.acl");
+        fs::write(&path, "[accept_all]\n[black_list]\n10.0.0.1\n").unwrap();
+        fs::write(&other_path, "[accept_all]\n[black_list]\n10.0.0.3\n").unwrap();
+
+        let client1 = "10.0.0.1:1234".parse::<SocketAddr>().unwrap();
+        let client2 = "10.0.0.2:1234".parse::<SocketAddr>().unwrap();
+        let client3 = "10.0.0.3:1234".parse::<SocketAddr>().unwrap();
+
+        rt.block_on(async move {
+            let mut config = Config::new(ConfigType::Server);
+            config.acl = Some(AccessControl::load_from_file(&path).unwrap());
+            let state = ServerState::new_shared(&config, rt_handle).await;
+
+            let mut watcher = AclWatcher::new();
+            watcher.check(&state).await;
+            assert!(state.acl().unwrap().check_client_blocked(&client1));
+
+            // Modified file is reloaded
+            fs::write(&path, "[accept_all]\n[black_list]\n10.0.0.2\n").unwrap();
+            watcher.check(&state).await;
+            assert!(!state.acl().unwrap().check_client_blocked(&client1));
+            assert!(state.acl().unwrap().check_client_blocked(&client2));
+
+            // Invalid file is ignored, the current rules are kept
+            fs::write(&path, "[accept_all]\n[black_list]\n(invalid regex\n").unwrap();
+            watcher.check(&state).await;
+            assert!(state.acl().unwrap().check_client_blocked(&client2));
+
+            // Reloaded configuration has another ACL file, which is watched afterwards
+            state.set_acl(Some(AccessControl::load_from_file(&other_path).unwrap()));
+            watcher.check(&state).await;
+            fs::write(&other_path, "[accept_all]\n[black_list]\n10.0.0.1\n").unwrap();
+            watcher.check(&state).await;
+            assert!(state.acl().unwrap().check_client_blocked(&client1));
+            assert!(!state.acl().unwrap().check_client_blocked(&client3));
+        });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -2,11 +2,11 @@
 
 use crate::crypto::cipher::{CipherCategory, CipherResult, CipherType};
 
-use crate::crypto::ring::RingAeadCipher;
 #[cfg(feature = "miscreant")]
 use crate::crypto::siv::MiscreantCipher;
 #[cfg(feature = "sodium")]
 use crate::crypto::sodium::SodiumAeadCipher;
+use crate::crypto::{aead2022, ring::RingAeadCipher};
 
 use bytes::{Bytes, BytesMut};
 use hkdf::Hkdf;
@@ -45,9 +45,12 @@ pub fn new_aead_encryptor(t: CipherType, key: &[u8], nonce: &[u8]) -> BoxAeadEnc
     assert!(t.category() == CipherCategory::Aead);
 
     match t {
-        CipherType::Aes128Gcm | CipherType::Aes256Gcm | CipherType::ChaCha20IetfPoly1305 => {
-            Box::new(RingAeadCipher::new(t, key, nonce, true))
-        }
+        CipherType::Aes128Gcm
+        | CipherType::Aes256Gcm
+        | CipherType::ChaCha20IetfPoly1305
+        | CipherType::Aead2022Blake3Aes128Gcm
+        | CipherType::Aead2022Blake3Aes256Gcm
+        | CipherType::Aead2022Blake3ChaCha20Poly1305 => Box::new(RingAeadCipher::new(t, key, nonce, true)),
 
         #[cfg(feature = "sodium")]
         CipherType::XChaCha20IetfPoly1305 => Box::new(SodiumAeadCipher::new(t, key, nonce)),
@@ -64,9 +67,12 @@ pub fn new_aead_decryptor(t: CipherType, key: &[u8], nonce: &[u8]) -> BoxAeadDec
     assert!(t.category() == CipherCategory::Aead);
 
     match t {
-        CipherType::Aes128Gcm | CipherType::Aes256Gcm | CipherType::ChaCha20IetfPoly1305 => {
-            Box::new(RingAeadCipher::new(t, key, nonce, false))
-        }
+        CipherType::Aes128Gcm
+        | CipherType::Aes256Gcm
+        | CipherType::ChaCha20IetfPoly1305
+        | CipherType::Aead2022Blake3Aes128Gcm
+        | CipherType::Aead2022Blake3Aes256Gcm
+        | CipherType::Aead2022Blake3ChaCha20Poly1305 => Box::new(RingAeadCipher::new(t, key, nonce, false)),
 
         #[cfg(feature = "sodium")]
         CipherType::XChaCha20IetfPoly1305 => Box::new(SodiumAeadCipher::new(t, key, nonce)),
@@ -108,9 +114,15 @@ const SUBKEY_INFO: &[u8] = b"ss-subkey";
 /// 4. For each chunk, encrypt and authenticate payload using SK with a counting nonce
 ///    (starting from 0 and increment by 1 after each use)
 /// 5. Send encrypted chunk
+///
+/// AEAD 2022 ciphers (SIP022) derive the subkey with BLAKE3 instead, see `aead2022::derive_session_key`.
 pub fn make_skey(t: CipherType, key: &[u8], salt: &[u8]) -> Bytes {
     assert!(t.category() == CipherCategory::Aead);
 
+    if t.is_aead_2022() {
+        return aead2022::derive_session_key(t, key, salt);
+    }
+
     let hkdf = Hkdf::<Sha1>::new(Some(salt), key);
 
     let mut skey = BytesMut::with_capacity(key.len());
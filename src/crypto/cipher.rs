@@ -166,6 +166,10 @@ const CIPHER_CHACHA20_IETF_POLY1305: &str = "chacha20-ietf-poly1305";
 #[cfg(feature = "sodium")]
 const CIPHER_XCHACHA20_IETF_POLY1305: &str = "xchacha20-ietf-poly1305";
 
+const CIPHER_AEAD2022_BLAKE3_AES_128_GCM: &str = "2022-blake3-aes-128-gcm";
+const CIPHER_AEAD2022_BLAKE3_AES_256_GCM: &str = "2022-blake3-aes-256-gcm";
+const CIPHER_AEAD2022_BLAKE3_CHACHA20_POLY1305: &str = "2022-blake3-chacha20-poly1305";
+
 /// ShadowSocks cipher type
 #[derive(Clone, Debug, Copy, EnumIter)]
 pub enum CipherType {
@@ -256,6 +260,10 @@ pub enum CipherType {
     Aes128PmacSiv,
     #[cfg(feature = "miscreant")]
     Aes256PmacSiv,
+
+    Aead2022Blake3Aes128Gcm,
+    Aead2022Blake3Aes256Gcm,
+    Aead2022Blake3ChaCha20Poly1305,
 }
 
 /// Category of ciphers
@@ -366,6 +374,10 @@ impl CipherType {
             CipherType::Aes128PmacSiv => 32,
             #[cfg(feature = "miscreant")]
             CipherType::Aes256PmacSiv => 64,
+
+            CipherType::Aead2022Blake3Aes128Gcm => AES_128_GCM.key_len(),
+            CipherType::Aead2022Blake3Aes256Gcm => AES_256_GCM.key_len(),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => CHACHA20_POLY1305.key_len(),
         }
     }
 
@@ -401,10 +413,34 @@ impl CipherType {
     }
 
     /// Extends key to match the required key length
+    ///
+    /// AEAD 2022 ciphers don't derive keys from password, `key` is the base64 encoded PSK.
+    /// Returns an empty key if it is not a valid PSK, which should be checked by `check_key` in advance.
     pub fn bytes_to_key(self, key: &[u8]) -> Bytes {
+        if self.is_aead_2022() {
+            return match base64::decode(key) {
+                Ok(k) if k.len() == self.key_size() => Bytes::from(k),
+                _ => Bytes::new(),
+            };
+        }
+
         self.classic_bytes_to_key(key)
     }
 
+    /// Check if `key` could be used as the password of this cipher
+    ///
+    /// AEAD 2022 ciphers require a base64 encoded PSK with exactly `key_size` bytes
+    pub fn check_key(self, key: &[u8]) -> bool {
+        if self.is_aead_2022() {
+            return match base64::decode(key) {
+                Ok(k) => k.len() == self.key_size(),
+                Err(..) => false,
+            };
+        }
+
+        true
+    }
+
     /// Symmetric crypto initialize vector size
     pub fn iv_size(self) -> usize {
         match self {
@@ -537,6 +573,10 @@ impl CipherType {
             CipherType::Aes128PmacSiv => 8,
             #[cfg(feature = "miscreant")]
             CipherType::Aes256PmacSiv => 8,
+
+            CipherType::Aead2022Blake3Aes128Gcm => AES_128_GCM.nonce_len(),
+            CipherType::Aead2022Blake3Aes256Gcm => AES_256_GCM.nonce_len(),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => CHACHA20_POLY1305.nonce_len(),
         }
     }
 
@@ -568,10 +608,24 @@ impl CipherType {
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv => CipherCategory::Aead,
 
+            CipherType::Aead2022Blake3Aes128Gcm
+            | CipherType::Aead2022Blake3Aes256Gcm
+            | CipherType::Aead2022Blake3ChaCha20Poly1305 => CipherCategory::Aead,
+
             _ => CipherCategory::Stream,
         }
     }
 
+    /// Check if it is one of the AEAD 2022 ciphers (SIP022)
+    pub fn is_aead_2022(self) -> bool {
+        match self {
+            CipherType::Aead2022Blake3Aes128Gcm
+            | CipherType::Aead2022Blake3Aes256Gcm
+            | CipherType::Aead2022Blake3ChaCha20Poly1305 => true,
+            _ => false,
+        }
+    }
+
     /// Get tag size for AEAD Ciphers
     pub fn tag_size(self) -> usize {
         assert!(self.category() == CipherCategory::Aead);
@@ -586,6 +640,10 @@ impl CipherType {
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv => 16,
 
+            CipherType::Aead2022Blake3Aes128Gcm => AES_128_GCM.tag_len(),
+            CipherType::Aead2022Blake3Aes256Gcm => AES_256_GCM.tag_len(),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+
             _ => panic!("only support AEAD ciphers, found {:?}", self),
         }
     }
@@ -703,6 +761,10 @@ impl FromStr for CipherType {
             #[cfg(feature = "miscreant")]
             CIPHER_AES_256_PMAC_SIV => Ok(CipherType::Aes256PmacSiv),
 
+            CIPHER_AEAD2022_BLAKE3_AES_128_GCM => Ok(CipherType::Aead2022Blake3Aes128Gcm),
+            CIPHER_AEAD2022_BLAKE3_AES_256_GCM => Ok(CipherType::Aead2022Blake3Aes256Gcm),
+            CIPHER_AEAD2022_BLAKE3_CHACHA20_POLY1305 => Ok(CipherType::Aead2022Blake3ChaCha20Poly1305),
+
             _ => Err(Error::UnknownCipherType),
         }
     }
@@ -796,6 +858,10 @@ impl Display for CipherType {
             CipherType::Aes128PmacSiv => write!(f, "{}", CIPHER_AES_128_PMAC_SIV),
             #[cfg(feature = "miscreant")]
             CipherType::Aes256PmacSiv => write!(f, "{}", CIPHER_AES_256_PMAC_SIV),
+
+            CipherType::Aead2022Blake3Aes128Gcm => write!(f, "{}", CIPHER_AEAD2022_BLAKE3_AES_128_GCM),
+            CipherType::Aead2022Blake3Aes256Gcm => write!(f, "{}", CIPHER_AEAD2022_BLAKE3_AES_256_GCM),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => write!(f, "{}", CIPHER_AEAD2022_BLAKE3_CHACHA20_POLY1305),
         }
     }
 }
@@ -9,6 +9,7 @@ pub use self::{
 use ::openssl::symm;
 
 pub mod aead;
+pub mod aead2022;
 pub mod cipher;
 pub mod digest;
 pub mod dummy;
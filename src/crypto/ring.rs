@@ -82,6 +82,9 @@ impl RingAeadCipher {
             CipherType::Aes128Gcm => RingAeadCipher::new_crypt(&AES_128_GCM, key, is_seal),
             CipherType::Aes256Gcm => RingAeadCipher::new_crypt(&AES_256_GCM, key, is_seal),
             CipherType::ChaCha20IetfPoly1305 => RingAeadCipher::new_crypt(&CHACHA20_POLY1305, key, is_seal),
+            CipherType::Aead2022Blake3Aes128Gcm => RingAeadCipher::new_crypt(&AES_128_GCM, key, is_seal),
+            CipherType::Aead2022Blake3Aes256Gcm => RingAeadCipher::new_crypt(&AES_256_GCM, key, is_seal),
+            CipherType::Aead2022Blake3ChaCha20Poly1305 => RingAeadCipher::new_crypt(&CHACHA20_POLY1305, key, is_seal),
             _ => panic!("unsupported cipher in ring {:?}", t),
         }
     }
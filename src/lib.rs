@@ -88,9 +88,9 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub use self::{
     config::{ClientConfig, Config, ConfigType, ManagerAddr, Mode, ServerAddr, ServerConfig},
     relay::{
-        local::run as run_local,
+        local::{run as run_local, run_with_signals as run_local_with_signals},
         manager::run as run_manager,
-        server::run as run_server,
+        server::{run as run_server, run_with_signals as run_server_with_signals},
         tcprelay::client::Socks5Client,
     },
 };
@@ -104,7 +104,7 @@ pub mod relay;
 
 /// Start a ShadowSocks' server
 ///
-/// For `config.config_type` in `Socks5Local`, `HttpLocal` and `TunnelLocal`, server will run in Local mode.
+/// For `config.config_type` in `Socks5Local`, `HttpLocal`, `TunnelLocal`, `RedirLocal` and `MixedLocal`, server will run in Local mode.
 pub async fn run(config: Config, rt: Handle) -> io::Result<()> {
     if config.config_type.is_local() {
         run_local(config, rt).await
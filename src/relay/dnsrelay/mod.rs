@@ -17,6 +17,7 @@ use trust_dns_proto::{
 };
 
 use crate::{
+    config::ConfigType,
     context::SharedContext,
     relay::{
         socks5::{
@@ -181,8 +182,20 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
     let remote_addr = context.config().remote_dns_addr.expect("remote dns");
     debug!("Remote DNS server: {}", remote_addr);
 
-    let socks5_config = context.config().local.as_ref().expect("socks5");
-    let socks5_addr = socks5_config.bind_addr(&*context).await?;
+    // Queries are relayed by a SOCKS5 local server, which may be one of `locals`
+    let socks5_config = context.config().local_configs().into_iter().find(|l| {
+        l.enable_tcp() && (l.config_type == ConfigType::Socks5Local || l.config_type == ConfigType::MixedLocal)
+    });
+    let socks5_addr = match socks5_config {
+        Some(l) => l.addr.bind_addr(&*context).await?,
+        None => {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                "DNS relay requires a SOCKS5 local server with TCP enabled",
+            );
+            return Err(err);
+        }
+    };
     debug!("SOCKS5 server: {}", socks5_addr);
 
     let listen_addr = context.config().dns_relay_addr.expect("dns relay");
@@ -1,14 +1,18 @@
 //! Server network flow statistic
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
+    io,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
+    time::SystemTime,
 };
 
-use crate::config::Config;
+use spin::Mutex;
+
+use crate::config::{Config, ServerAddr, ServerConfig};
 
 /// Flow statistic for one server
 pub struct FlowStatistic {
@@ -56,6 +60,7 @@ impl Default for FlowStatistic {
 pub struct ServerFlowStatistic {
     tcp: FlowStatistic,
     udp: FlowStatistic,
+    users: Mutex<HashMap<String, SharedServerFlowStatistic>>,
 }
 
 /// Shared reference for ServerFlowStatistic
@@ -67,6 +72,7 @@ impl ServerFlowStatistic {
         ServerFlowStatistic {
             tcp: FlowStatistic::new(),
             udp: FlowStatistic::new(),
+            users: Mutex::new(HashMap::new()),
         }
     }
 
@@ -89,6 +95,37 @@ impl ServerFlowStatistic {
     pub fn trans_stat(&self) -> u64 {
         self.tcp().tx() + self.tcp().rx() + self.udp().tx() + self.udp.rx()
     }
+
+    /// Check if the server could still serve, returns error if the traffic quota of `svr_cfg` is used up,
+    /// or the server is already expired
+    pub fn check_quota(&self, svr_cfg: &ServerConfig) -> io::Result<()> {
+        ServerQuota::new(svr_cfg).check(self)
+    }
+
+    /// Flow statistic of an user in multi-user server, created if not exists
+    ///
+    /// Traffic of users are also counted in the statistic of server
+    pub fn user(&self, name: &str) -> SharedServerFlowStatistic {
+        self.users
+            .lock()
+            .entry(name.to_owned())
+            .or_insert_with(ServerFlowStatistic::new_shared)
+            .clone()
+    }
+
+    /// Remove flow statistic of an user
+    pub fn remove_user(&self, name: &str) {
+        self.users.lock().remove(name);
+    }
+
+    /// Transmission statistic of every users for manager
+    pub fn users_trans_stat(&self) -> BTreeMap<String, u64> {
+        self.users
+            .lock()
+            .iter()
+            .map(|(name, stat)| (name.clone(), stat.trans_stat()))
+            .collect()
+    }
 }
 
 impl Default for ServerFlowStatistic {
@@ -97,9 +134,54 @@ impl Default for ServerFlowStatistic {
     }
 }
 
+/// Traffic quota and expire time of a server
+#[derive(Clone, Copy, Debug)]
+pub struct ServerQuota {
+    traffic_limit: Option<u64>,
+    expire_at: Option<SystemTime>,
+}
+
+impl ServerQuota {
+    /// Quota configured in `svr_cfg`
+    pub fn new(svr_cfg: &ServerConfig) -> ServerQuota {
+        ServerQuota {
+            traffic_limit: svr_cfg.traffic_limit(),
+            expire_at: svr_cfg.expire_at(),
+        }
+    }
+
+    /// Check if there is no limit at all
+    pub fn is_unlimited(&self) -> bool {
+        self.traffic_limit.is_none() && self.expire_at.is_none()
+    }
+
+    /// Returns error if the quota is used up by `flow_stat`, or it is already expired
+    pub fn check(&self, flow_stat: &ServerFlowStatistic) -> io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        if let Some(limit) = self.traffic_limit {
+            if flow_stat.trans_stat() >= limit {
+                let err = Error::new(ErrorKind::Other, "traffic limit exceeded");
+                return Err(err);
+            }
+        }
+
+        if let Some(expire_at) = self.expire_at {
+            if expire_at <= SystemTime::now() {
+                let err = Error::new(ErrorKind::Other, "server expired");
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// FlowStatic for multiple servers
 pub struct MultiServerFlowStatistic {
     servers: BTreeMap<u16, SharedServerFlowStatistic>,
+    // Addresses of servers, for telling servers listening on the same port apart
+    addrs: BTreeMap<u16, ServerAddr>,
 }
 
 /// Shared reference for `MultiServerFlowStatistic`
@@ -109,11 +191,13 @@ impl MultiServerFlowStatistic {
     /// Create statistics for every servers in config
     pub fn new(config: &Config) -> MultiServerFlowStatistic {
         let mut servers = BTreeMap::new();
+        let mut addrs = BTreeMap::new();
         for svr_cfg in &config.server {
             servers.insert(svr_cfg.addr().port(), ServerFlowStatistic::new_shared());
+            addrs.insert(svr_cfg.addr().port(), svr_cfg.addr().clone());
         }
 
-        MultiServerFlowStatistic { servers }
+        MultiServerFlowStatistic { servers, addrs }
     }
 
     /// Create a new shared reference for MultiServerFlowStatistic
@@ -121,8 +205,31 @@ impl MultiServerFlowStatistic {
         Arc::new(MultiServerFlowStatistic::new(config))
     }
 
+    /// Create statistics for every servers in config, statistics in `prev` are kept for servers on the same ports
+    ///
+    /// Traffic usage of restarted servers continues, so their traffic quota couldn't be reset by reloading
+    pub fn new_shared_from(config: &Config, prev: &MultiServerFlowStatistic) -> SharedMultiServerFlowStatistic {
+        let mut stat = MultiServerFlowStatistic::new(config);
+        for (port, server) in stat.servers.iter_mut() {
+            if let Some(p) = prev.get(*port) {
+                *server = p.clone();
+            }
+        }
+        Arc::new(stat)
+    }
+
     /// Get ServerFlowStatistic by port
     pub fn get(&self, port: u16) -> Option<&SharedServerFlowStatistic> {
         self.servers.get(&port)
     }
+
+    /// Get ServerFlowStatistic of an user on port
+    pub fn get_user(&self, port: u16, name: &str) -> Option<SharedServerFlowStatistic> {
+        self.servers.get(&port).map(|s| s.user(name))
+    }
+
+    /// Iterate over statistics of all servers with their addresses, ordered by port
+    pub fn iter(&self) -> impl Iterator<Item = (&ServerAddr, &SharedServerFlowStatistic)> {
+        self.servers.iter().map(move |(port, stat)| (&self.addrs[port], stat))
+    }
 }
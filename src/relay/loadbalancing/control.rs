@@ -0,0 +1,254 @@
+//! Control socket of load balancers
+//!
+//! Accepts commands in the same format as the manager, `action: {JSON}`
+//!
+//! - `list`, statistic data of all servers
+//! - `pin: {"server": "host:port"}`, always picks the server
+//! - `unpin`, picks the best server automatically again
+//! - `probe`, probes all servers immediately
+//!
+//! Commands are not authenticated, only packets from loopback addresses (or unix sockets) are accepted
+
+use std::{
+    io::{self, Error, ErrorKind},
+    str,
+    sync::Arc,
+};
+
+use byte_string::ByteStr;
+use log::{error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ManagerAddr,
+    context::SharedContext,
+    relay::{
+        manager::{ManagerDatagram, ManagerSocketAddr},
+        udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
+    },
+};
+
+use super::{
+    server::{PingBalancer, ServerData, ServerType},
+    Balancers,
+};
+
+#[derive(Serialize, Debug)]
+struct ServerStatus {
+    protocol: &'static str,
+    server: String,
+    score: u64,
+    rtt: u64,
+    stdev: f64,
+    fail_rate: f64,
+    best: bool,
+    pinned: bool,
+    down: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct PinRequest {
+    server: String,
+}
+
+async fn list_balancer<S>(status: &mut Vec<ServerStatus>, balancer: &PingBalancer<S>)
+where
+    S: ServerData + 'static,
+{
+    let protocol = match balancer.server_type() {
+        ServerType::Tcp => "tcp",
+        ServerType::Udp => "udp",
+    };
+
+    let best = balancer.best_server();
+    let pinned = balancer.pinned_server();
+
+    for server in balancer.servers() {
+        let snapshot = server.snapshot().await;
+
+        status.push(ServerStatus {
+            protocol,
+            server: server.server_config().addr().to_string(),
+            score: snapshot.score,
+            rtt: snapshot.rtt,
+            stdev: snapshot.stdev,
+            fail_rate: snapshot.fail_rate,
+            best: Arc::ptr_eq(&server, &best),
+            pinned: pinned.as_ref().map(|p| Arc::ptr_eq(&server, p)).unwrap_or(false),
+            down: server.is_down(),
+        });
+    }
+}
+
+struct ControlService {
+    socket: ManagerDatagram,
+    balancers: Balancers,
+}
+
+impl ControlService {
+    async fn serve(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+
+        loop {
+            let (recv_len, src_addr) = self.socket.recv_from(&mut buf).await?;
+
+            if let ManagerSocketAddr::SocketAddr(ref addr) = src_addr {
+                if !addr.ip().is_loopback() {
+                    warn!(
+                        "dropped a control packet ({} bytes) from non-local address {}",
+                        recv_len, addr
+                    );
+                    continue;
+                }
+            }
+
+            let resp_pkt = self.handle_packet(&buf[..recv_len]).await;
+
+            if src_addr.is_unnamed() {
+                trace!(
+                    "received a packet ({} bytes) from an unnamed unix-socket client, \
+                     unsound because we are unable to send response back to it",
+                    recv_len
+                );
+                continue;
+            }
+
+            if let Err(err) = self.socket.send_to(&resp_pkt, &src_addr).await {
+                error!("response send_to failed, destination: {:?}, error: {}", src_addr, err);
+            }
+        }
+    }
+
+    async fn handle_packet(&self, pkt: &[u8]) -> Vec<u8> {
+        trace!("CONTROL REQUEST: {:?}", ByteStr::new(pkt));
+
+        let pkt = match str::from_utf8(pkt) {
+            Ok(p) => p,
+            Err(..) => {
+                error!("received non-UTF8 encoded packet: {:?}", ByteStr::new(pkt));
+                return b"invalid encoding".to_vec();
+            }
+        };
+
+        let (action, param) = match pkt.find(':') {
+            None => (pkt.trim(), ""),
+            Some(idx) => {
+                let (action, param) = pkt.split_at(idx);
+                (action.trim(), param[1..].trim())
+            }
+        };
+
+        match self.dispatch_command(action, param).await {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to handle control action \"{}\", error: {}", action, err);
+                Vec::from(err.to_string())
+            }
+        }
+    }
+
+    async fn dispatch_command(&self, action: &str, param: &str) -> io::Result<Vec<u8>> {
+        match action {
+            "list" => self.handle_list().await,
+            "pin" => {
+                let p: PinRequest = match serde_json::from_str(param) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        let err = Error::new(ErrorKind::InvalidData, err);
+                        return Err(err);
+                    }
+                };
+
+                self.handle_pin(&p)
+            }
+            "unpin" => {
+                if let Some(ref balancer) = self.balancers.tcp {
+                    balancer.unpin_server();
+                }
+                if let Some(ref balancer) = self.balancers.udp {
+                    balancer.unpin_server();
+                }
+
+                info!("unpinned servers, choosing automatically");
+                Ok(b"ok\n".to_vec())
+            }
+            "probe" => {
+                if let Some(ref balancer) = self.balancers.tcp {
+                    balancer.probe_servers().await;
+                }
+                if let Some(ref balancer) = self.balancers.udp {
+                    balancer.probe_servers().await;
+                }
+
+                Ok(b"ok\n".to_vec())
+            }
+            _ => {
+                let err = Error::new(ErrorKind::InvalidData, format!("unrecognized command \"{}\"", action));
+                Err(err)
+            }
+        }
+    }
+
+    async fn handle_list(&self) -> io::Result<Vec<u8>> {
+        let mut status = Vec::new();
+        if let Some(ref balancer) = self.balancers.tcp {
+            list_balancer(&mut status, balancer).await;
+        }
+        if let Some(ref balancer) = self.balancers.udp {
+            list_balancer(&mut status, balancer).await;
+        }
+
+        let mut buf = serde_json::to_string(&status).expect("convert server status into JSON");
+        buf += "\n";
+
+        trace!("CONTROL ACTION \"list\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(buf.into_bytes())
+    }
+
+    fn handle_pin(&self, p: &PinRequest) -> io::Result<Vec<u8>> {
+        trace!("CONTROL ACTION \"pin\" {:?}", p);
+
+        // Servers of TCP and UDP balancers may be different, pinned in whichever has it
+        let mut found = false;
+        if let Some(ref balancer) = self.balancers.tcp {
+            found |= balancer.pin_server(&p.server);
+        }
+        if let Some(ref balancer) = self.balancers.udp {
+            found |= balancer.pin_server(&p.server);
+        }
+
+        if !found {
+            let err = Error::new(ErrorKind::Other, format!("server {} not found", p.server));
+            return Err(err);
+        }
+
+        info!("pinned server {}", p.server);
+        Ok(b"ok\n".to_vec())
+    }
+}
+
+/// Starts the control socket of `balancers` listening on `addr`
+///
+/// `addr` must be a loopback address or a unix socket path, because commands are not authenticated
+pub(crate) async fn run(context: SharedContext, addr: ManagerAddr, balancers: Balancers) -> io::Result<()> {
+    if let ManagerAddr::SocketAddr(ref saddr) = addr {
+        if !saddr.ip().is_loopback() {
+            let err = Error::new(
+                ErrorKind::Other,
+                format!(
+                    "control socket {} is reachable from other hosts, use a loopback address",
+                    saddr
+                ),
+            );
+            error!("failed to start load balancer control, error: {}", err);
+            return Err(err);
+        }
+    }
+
+    let socket = ManagerDatagram::bind(&addr, &*context).await?;
+    info!("shadowsocks load balancer control listening on {}", addr);
+
+    let mut service = ControlService { socket, balancers };
+    service.serve().await
+}
@@ -1,3 +1,15 @@
 //! Load balancer
 
+use crate::relay::tcprelay::local::TcpServerBalancer;
+
+use self::server::PlainPingBalancer;
+
+pub(crate) mod control;
 pub mod server;
+
+/// Load balancers of `sslocal`, `None` if the protocol is not enabled
+#[derive(Clone, Default)]
+pub(crate) struct Balancers {
+    pub(crate) tcp: Option<TcpServerBalancer>,
+    pub(crate) udp: Option<PlainPingBalancer>,
+}
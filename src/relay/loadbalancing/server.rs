@@ -1,41 +1,48 @@
 use std::{
-    collections::VecDeque,
+    cmp,
+    collections::{hash_map::DefaultHasher, VecDeque},
     fmt,
-    io,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    hash::{Hash, Hasher},
+    io::{self, Error, ErrorKind},
+    mem,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
 };
 
 use crate::{
-    config::{Config, ServerConfig},
+    config::{BalancerStrategy, Config, HealthCheckConfig, HealthCheckMode, ProbeConfig, ServerConfig},
     context::{Context, SharedContext},
     relay::{
+        metrics::{Gauge, GaugeGuard},
         socks5::Address,
         tcprelay::client::ServerClient as TcpServerClient,
         udprelay::client::ServerClient as UdpServerClient,
     },
 };
 
+use futures::future;
 use log::{debug, info, trace};
+use rand::{self, Rng};
+use spin::{Mutex as SpinMutex, RwLock};
 use tokio::{
     self,
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::{Barrier, Mutex},
+    sync::{Barrier, Mutex, MutexGuard},
     time,
 };
 
 const MAX_LATENCY_QUEUE_SIZE: usize = 99;
-const DEFAULT_CHECK_INTERVAL_SEC: u64 = 6;
-const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 2; // Latency shouldn't greater than 2 secs, that's too long
-const MAX_SERVER_RTT: u64 = DEFAULT_CHECK_TIMEOUT_SEC * 1000;
+const MIN_DOWN_BACKOFF_MS: u64 = 1000;
+const MAX_DOWN_BACKOFF_MS: u64 = 60 * 1000;
+// Time for servers to close connections with wrong keys in `connect_only` health checks
+const CONNECT_ONLY_REJECT_WAIT: Duration = Duration::from_millis(500);
 
 /// Identifier of a valid server
 pub trait ServerData: Send + Sync {
-    fn create_server(context: &SharedContext, server_idx: usize, data: &SharedServerStatisticData) -> Self;
+    fn create_server(context: &SharedContext, svr_cfg: &ServerConfig, data: &SharedServerStatisticData) -> Self;
 }
 
 #[derive(Debug)]
@@ -53,10 +60,12 @@ struct ServerStatisticData {
     latency_stdev: f64,
     /// Score's average
     latency_mean: f64,
+    /// Timeout of probes (in millisec), latency shouldn't be greater than it
+    max_rtt: u64,
 }
 
-fn max_latency_stdev() -> f64 {
-    let mrtt = MAX_SERVER_RTT as f64;
+fn max_latency_stdev(max_rtt: u64) -> f64 {
+    let mrtt = max_rtt as f64;
     let avg = (0.0 + mrtt) / 2.0;
     let diff1 = (0.0 - avg) * (0.0 - avg);
     let diff2 = (mrtt - avg) * (mrtt - avg);
@@ -65,22 +74,23 @@ fn max_latency_stdev() -> f64 {
 }
 
 impl ServerStatisticData {
-    fn new() -> ServerStatisticData {
+    fn new(max_rtt: u64) -> ServerStatisticData {
         ServerStatisticData {
-            rtt: MAX_SERVER_RTT,
+            rtt: max_rtt,
             fail_rate: 1.0,
             latency_queue: VecDeque::new(),
             latency_stdev: 0.0,
             latency_mean: 0.0,
+            max_rtt,
         }
     }
 
     fn score(&self) -> u64 {
         // Normalize rtt
-        let nrtt = self.rtt as f64 / MAX_SERVER_RTT as f64;
+        let nrtt = self.rtt as f64 / self.max_rtt as f64;
 
         // Normalize stdev
-        let nstdev = self.latency_stdev / max_latency_stdev();
+        let nstdev = self.latency_stdev / max_latency_stdev(self.max_rtt);
 
         const SCORE_RTT_WEIGHT: f64 = 1.0;
         const SCORE_FAIL_WEIGHT: f64 = 3.0;
@@ -163,34 +173,173 @@ impl ServerStatisticData {
     pub fn report_failure(&mut self) -> u64 {
         self.push_score(Score::Errored)
     }
+
+    fn snapshot(&self) -> ServerStatisticSnapshot {
+        ServerStatisticSnapshot {
+            score: self.score(),
+            rtt: self.rtt,
+            stdev: self.latency_stdev,
+            fail_rate: self.fail_rate,
+        }
+    }
+}
+
+/// Current statistic data of a server
+#[derive(Debug, Clone, Copy)]
+pub struct ServerStatisticSnapshot {
+    /// Score of the server, the lower the better
+    pub score: u64,
+    /// Median of latency time (in millisec)
+    pub rtt: u64,
+    /// Standard deviation of latency time
+    pub stdev: f64,
+    /// Total_Fail / Total_Probe
+    pub fail_rate: f64,
+}
+
+/// Availability of a server, checked while picking servers without locking `ServerStatisticData`
+#[derive(Debug)]
+struct ServerHealth {
+    /// Latest score of `ServerStatisticData`
+    score: u64,
+    /// Failures since the last success
+    failures: u32,
+    /// Server is marked down until then, it will be retried after that
+    down_until: Option<Instant>,
+}
+
+impl ServerHealth {
+    fn report_success(&mut self, score: u64) {
+        self.score = score;
+        self.report_alive();
+    }
+
+    fn report_alive(&mut self) {
+        self.failures = 0;
+        self.down_until = None;
+    }
+
+    fn report_failure(&mut self, score: u64) {
+        self.score = score;
+        self.failures = self.failures.saturating_add(1);
+        self.down_until = Some(Instant::now() + down_backoff(self.failures));
+    }
+
+    fn is_down(&self) -> bool {
+        match self.down_until {
+            Some(t) => Instant::now() < t,
+            None => false,
+        }
+    }
+}
+
+// Backoff doubles for every failure in a row
+fn down_backoff(failures: u32) -> Duration {
+    let shift = failures.saturating_sub(1).min(16);
+    Duration::from_millis((MIN_DOWN_BACKOFF_MS << shift).min(MAX_DOWN_BACKOFF_MS))
 }
 
 /// Shared handle for mutating server's statistic data
 #[derive(Clone)]
-pub struct SharedServerStatisticData(Arc<Mutex<ServerStatisticData>>);
+pub struct SharedServerStatisticData {
+    data: Arc<Mutex<ServerStatisticData>>,
+    health: Arc<SpinMutex<ServerHealth>>,
+    // Failures reported without locking `data`, they are pushed into `data` when it is locked next time
+    deferred_failures: Arc<AtomicUsize>,
+}
 
 impl SharedServerStatisticData {
-    fn new() -> SharedServerStatisticData {
-        SharedServerStatisticData(Arc::new(Mutex::new(ServerStatisticData::new())))
+    fn new(max_rtt: u64) -> SharedServerStatisticData {
+        let data = ServerStatisticData::new(max_rtt);
+        let health = ServerHealth {
+            score: data.score(),
+            failures: 0,
+            down_until: None,
+        };
+
+        SharedServerStatisticData {
+            data: Arc::new(Mutex::new(data)),
+            health: Arc::new(SpinMutex::new(health)),
+            deferred_failures: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    async fn lock_data(&self) -> MutexGuard<'_, ServerStatisticData> {
+        let mut data = self.data.lock().await;
+
+        for _ in 0..self.deferred_failures.swap(0, Ordering::Relaxed) {
+            let score = data.report_failure();
+            self.health.lock().score = score;
+        }
+
+        data
     }
 
+    /// Reports a failed connection or probe, server will be marked down immediately
     pub async fn report_failure(&self) -> u64 {
-        let mut data = self.0.lock().await;
-        data.report_failure()
+        let mut data = self.lock_data().await;
+        let score = data.report_failure();
+        self.health.lock().report_failure(score);
+        score
     }
 
-    async fn push_score(&self, score: Score) -> u64 {
-        let mut data = self.0.lock().await;
-        data.push_score(score)
+    /// Same as `report_failure`, but could be called without awaiting, such as in `poll_*` methods
+    ///
+    /// Server is marked down immediately, the failure is counted in its score later
+    pub fn report_failure_deferred(&self) {
+        self.deferred_failures.fetch_add(1, Ordering::Relaxed);
+
+        let mut health = self.health.lock();
+        let score = health.score;
+        health.report_failure(score);
+    }
+
+    /// Reports a server that responded to a proxied connection or association, its down mark is cleared
+    ///
+    /// Latency is recorded by `report_connect_latency` when the connection is made
+    pub fn report_success(&self) {
+        self.health.lock().report_alive();
+    }
+
+    /// Reports connect time (in millisec) of a new connection to the server
+    ///
+    /// Only the score is updated, the down mark is kept until the server responds
+    pub async fn report_connect_latency(&self, latency: u64) -> u64 {
+        let mut data = self.lock_data().await;
+        let score = data.push_score(Score::Latency(latency));
+        self.health.lock().score = score;
+        score
+    }
+
+    /// Reports a succeeded probe with its latency (in millisec)
+    pub async fn report_latency(&self, latency: u64) -> u64 {
+        let mut data = self.lock_data().await;
+        let score = data.push_score(Score::Latency(latency));
+        self.health.lock().report_success(score);
+        score
     }
 
     pub async fn score(&self) -> u64 {
-        let data = self.0.lock().await;
+        let data = self.lock_data().await;
         data.score()
     }
 
+    /// Check if server is marked down by recent failures
+    pub fn is_down(&self) -> bool {
+        self.health.lock().is_down()
+    }
+
+    fn cached_score(&self) -> u64 {
+        self.health.lock().score
+    }
+
+    async fn snapshot(&self) -> ServerStatisticSnapshot {
+        let data = self.lock_data().await;
+        data.snapshot()
+    }
+
     async fn debug_string(&self) -> String {
-        format!("{:?}", self.0.lock().await)
+        format!("{:?}, {:?}", self.lock_data().await, self.health.lock())
     }
 }
 
@@ -198,30 +347,34 @@ impl SharedServerStatisticData {
 pub struct ServerStatistic<S: ServerData> {
     server: S,
     context: SharedContext,
-    server_idx: usize,
+    // Owned, servers could be replaced by reloading while connections are still using them
+    svr_cfg: ServerConfig,
     data: SharedServerStatisticData,
+    // Connections (or UDP associations) currently relayed through this server
+    connections: Gauge,
 }
 
 pub type SharedServerStatistic<S> = Arc<ServerStatistic<S>>;
 
 impl<S: ServerData> ServerStatistic<S> {
-    fn new(context: SharedContext, server_idx: usize) -> ServerStatistic<S> {
-        let data = SharedServerStatisticData::new();
+    fn new(context: SharedContext, svr_cfg: ServerConfig, max_rtt: u64) -> ServerStatistic<S> {
+        let data = SharedServerStatisticData::new(max_rtt);
 
         ServerStatistic {
-            server: S::create_server(&context, server_idx, &data),
+            server: S::create_server(&context, &svr_cfg, &data),
             context,
-            server_idx,
+            svr_cfg,
             data,
+            connections: Gauge::default(),
         }
     }
 
-    fn new_shared(context: SharedContext, server_idx: usize) -> SharedServerStatistic<S> {
-        Arc::new(ServerStatistic::new(context, server_idx))
+    fn new_shared(context: SharedContext, svr_cfg: ServerConfig, max_rtt: u64) -> SharedServerStatistic<S> {
+        Arc::new(ServerStatistic::new(context, svr_cfg, max_rtt))
     }
 
     pub fn server_config(&self) -> &ServerConfig {
-        self.context.server_config(self.server_idx)
+        &self.svr_cfg
     }
 
     pub fn server(&self) -> &S {
@@ -240,8 +393,9 @@ impl<S: ServerData> ServerStatistic<S> {
         self.context.config()
     }
 
-    async fn push_score(&self, score: Score) -> u64 {
-        self.data.push_score(score).await
+    /// Handle for reporting outcomes of connections to this server
+    pub fn data(&self) -> &SharedServerStatisticData {
+        &self.data
     }
 
     pub async fn score(&self) -> u64 {
@@ -252,6 +406,30 @@ impl<S: ServerData> ServerStatistic<S> {
         self.data.report_failure().await
     }
 
+    pub async fn report_latency(&self, latency: u64) -> u64 {
+        self.data.report_latency(latency).await
+    }
+
+    /// Check if server is marked down by recent failures
+    pub fn is_down(&self) -> bool {
+        self.data.is_down()
+    }
+
+    /// Counts a connection relayed through this server until the returned guard is dropped
+    pub fn track_connection(&self) -> GaugeGuard {
+        self.connections.track()
+    }
+
+    /// Number of connections currently relayed through this server
+    pub fn connections(&self) -> usize {
+        self.connections.get()
+    }
+
+    /// Current statistic data of this server
+    pub async fn snapshot(&self) -> ServerStatisticSnapshot {
+        self.data.snapshot().await
+    }
+
     async fn data_debug_string(&self) -> String {
         self.data.debug_string().await
     }
@@ -278,30 +456,199 @@ impl fmt::Display for ServerType {
     }
 }
 
+// `pinned_idx` for choosing servers automatically
+const NOT_PINNED: usize = usize::max_value();
+
 struct BestServer<S: ServerData> {
     servers: Vec<SharedServerStatistic<S>>,
+    strategy: BalancerStrategy,
+    health_check: HealthCheckConfig,
     best_idx: AtomicUsize,
+    // Server pinned by operators, always picked instead of the best one
+    pinned_idx: AtomicUsize,
+    // Next server for `round_robin` strategy
+    next_idx: AtomicUsize,
+    // Cleared after being replaced by reloaded servers, stops probing tasks
+    active: AtomicBool,
 }
 
 type SharedBestServer<S> = Arc<BestServer<S>>;
 
 impl<S: ServerData> BestServer<S> {
-    fn new(servers: Vec<SharedServerStatistic<S>>) -> BestServer<S> {
+    fn new(
+        servers: Vec<SharedServerStatistic<S>>,
+        strategy: BalancerStrategy,
+        health_check: HealthCheckConfig,
+    ) -> BestServer<S> {
         BestServer {
             servers,
+            strategy,
+            health_check,
             best_idx: AtomicUsize::new(0),
+            pinned_idx: AtomicUsize::new(NOT_PINNED),
+            next_idx: AtomicUsize::new(0),
+            active: AtomicBool::new(true),
         }
     }
 
-    fn new_shared(servers: Vec<SharedServerStatistic<S>>) -> SharedBestServer<S> {
-        Arc::new(BestServer::new(servers))
+    fn new_shared(
+        servers: Vec<SharedServerStatistic<S>>,
+        strategy: BalancerStrategy,
+        health_check: HealthCheckConfig,
+    ) -> SharedBestServer<S> {
+        Arc::new(BestServer::new(servers, strategy, health_check))
     }
 
-    fn pick_server(&self) -> SharedServerStatistic<S> {
-        let idx = self.best_idx.load(Ordering::Relaxed);
+    fn best_server(&self) -> SharedServerStatistic<S> {
+        let idx = match self.pinned_idx() {
+            Some(idx) => idx,
+            None => self.best_idx.load(Ordering::Relaxed),
+        };
         self.servers[idx].clone()
     }
 
+    fn pick_server(&self, target: &Address) -> SharedServerStatistic<S> {
+        self.pick_server_excluding(target, &[])
+            .expect("load balancer requires at least 1 server")
+    }
+
+    // Picks a server not in `excluded`, returns `None` if all of them are excluded
+    fn pick_server_excluding(
+        &self,
+        target: &Address,
+        excluded: &[SharedServerStatistic<S>],
+    ) -> Option<SharedServerStatistic<S>> {
+        let is_excluded = |svr: &SharedServerStatistic<S>| excluded.iter().any(|e| Arc::ptr_eq(svr, e));
+
+        if self.servers.len() == 1 {
+            let svr = &self.servers[0];
+            return if is_excluded(svr) { None } else { Some(svr.clone()) };
+        }
+
+        // The pinned server is always picked first, others are only picked for failing over from it
+        if let Some(idx) = self.pinned_idx() {
+            let svr = &self.servers[idx];
+            if !is_excluded(svr) {
+                return Some(svr.clone());
+            }
+        }
+
+        let candidates: Vec<bool> = self.servers.iter().map(|s| !is_excluded(s)).collect();
+        if !candidates.contains(&true) {
+            return None;
+        }
+
+        // Servers marked down are skipped, unless all of the candidates are down
+        let mut up: Vec<bool> = self
+            .servers
+            .iter()
+            .zip(&candidates)
+            .map(|(s, &c)| c && !s.is_down())
+            .collect();
+        if !up.contains(&true) {
+            up = candidates;
+        }
+
+        let idx = match self.strategy {
+            BalancerStrategy::LowestScore => self.lowest_score_idx(&up),
+            BalancerStrategy::RoundRobin => self.round_robin_idx(&up),
+            BalancerStrategy::WeightedRandom => self.weighted_random_idx(&up),
+            BalancerStrategy::LeastConnections => self.least_connections_idx(&up),
+            BalancerStrategy::ConsistentHash => self.consistent_hash_idx(target, &up),
+        };
+        Some(self.servers[idx].clone())
+    }
+
+    fn lowest_score_idx(&self, up: &[bool]) -> usize {
+        let best_idx = self.best_idx.load(Ordering::Relaxed);
+        if up[best_idx] {
+            return best_idx;
+        }
+
+        // The best server is down before it is replaced by the next probe
+        let scores: Vec<u64> = self.servers.iter().map(|s| s.data.cached_score()).collect();
+        min_key_idx(&scores, up)
+    }
+
+    fn round_robin_idx(&self, up: &[bool]) -> usize {
+        loop {
+            let idx = self.next_idx.fetch_add(1, Ordering::Relaxed) % self.servers.len();
+            if up[idx] {
+                return idx;
+            }
+        }
+    }
+
+    fn weighted_random_idx(&self, up: &[bool]) -> usize {
+        let weights = up_weights(&self.weights(), up);
+        let total = weights.iter().map(|&w| u64::from(w)).sum();
+
+        weighted_idx(&weights, rand::thread_rng().gen_range(0, total))
+    }
+
+    fn least_connections_idx(&self, up: &[bool]) -> usize {
+        let best_idx = self.best_idx.load(Ordering::Relaxed);
+
+        // Ties are broken by preferring the best server, and then the lower index
+        let keys: Vec<(usize, bool)> = self
+            .servers
+            .iter()
+            .enumerate()
+            .map(|(idx, svr)| (svr.connections(), idx != best_idx))
+            .collect();
+        min_key_idx(&keys, up)
+    }
+
+    // Rendezvous hashing, only targets of the removed server are moved to other servers
+    // while servers are reloaded
+    fn consistent_hash_idx(&self, target: &Address, up: &[bool]) -> usize {
+        let hashes: Vec<u64> = self
+            .servers
+            .iter()
+            .map(|svr| {
+                let mut hasher = DefaultHasher::new();
+                match *target {
+                    Address::SocketAddress(ref saddr) => saddr.ip().hash(&mut hasher),
+                    Address::DomainNameAddress(ref dname, _) => dname.hash(&mut hasher),
+                }
+                svr.server_config().addr().to_string().hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect();
+
+        rendezvous_idx(&hashes, &up_weights(&self.weights(), up))
+    }
+
+    fn weights(&self) -> Vec<u32> {
+        self.servers.iter().map(|s| s.server_config().weight()).collect()
+    }
+
+    fn pinned_idx(&self) -> Option<usize> {
+        match self.pinned_idx.load(Ordering::Relaxed) {
+            NOT_PINNED => None,
+            idx => Some(idx),
+        }
+    }
+
+    // Pins the server with address `addr`, returns `false` if it doesn't exist
+    fn pin(&self, addr: &str) -> bool {
+        match self
+            .servers
+            .iter()
+            .position(|s| s.server_config().addr().to_string() == addr)
+        {
+            Some(idx) => {
+                self.pinned_idx.store(idx, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn unpin(&self) {
+        self.pinned_idx.store(NOT_PINNED, Ordering::Relaxed);
+    }
+
     async fn recalculate_best_server(&self) -> Option<(usize, usize)> {
         let current_best_idx = self.best_idx.load(Ordering::Relaxed);
 
@@ -328,37 +675,211 @@ impl<S: ServerData> BestServer<S> {
     fn best_server_idx(&self) -> usize {
         self.best_idx.load(Ordering::Relaxed)
     }
+
+    fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    fn set_inactive(&self) {
+        self.active.store(false, Ordering::Release)
+    }
+}
+
+// Checks if `response` of the probe request starts with the expected prefix
+fn check_response(probe: &ProbeConfig, response: &[u8]) -> io::Result<()> {
+    if response.starts_with(&probe.expect) {
+        Ok(())
+    } else {
+        let err = Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected response from probe target {}", probe.target),
+        );
+        Err(err)
+    }
+}
+
+// Weights of servers that are up, others are 0
+//
+// Servers that are up are weighted equally if all of them have 0 weight
+fn up_weights(weights: &[u32], up: &[bool]) -> Vec<u32> {
+    let mut up_weights: Vec<u32> = weights.iter().zip(up).map(|(&w, &u)| if u { w } else { 0 }).collect();
+    if up_weights.iter().all(|&w| w == 0) {
+        up_weights = up.iter().map(|&u| if u { 1 } else { 0 }).collect();
+    }
+    up_weights
+}
+
+// Index of the server that `n` falls into, `n` must be less than the total weight
+fn weighted_idx(weights: &[u32], mut n: u64) -> usize {
+    for (idx, &weight) in weights.iter().enumerate() {
+        let weight = u64::from(weight);
+        if n < weight {
+            return idx;
+        }
+        n -= weight;
+    }
+
+    unreachable!("random number exceeds the total weight");
+}
+
+// Index of the server with the highest rendezvous key, servers with 0 weight are never chosen
+fn rendezvous_idx(hashes: &[u64], weights: &[u32]) -> usize {
+    let mut best_idx = None;
+    let mut best_key = 0.0;
+
+    for (idx, (&hash, &weight)) in hashes.iter().zip(weights).enumerate() {
+        if weight == 0 {
+            continue;
+        }
+
+        // Uniformly distributed in (0, 1), weighted by -weight / ln(h), which is always positive
+        let h = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        let key = -f64::from(weight) / h.ln();
+        if best_idx.is_none() || key > best_key {
+            best_idx = Some(idx);
+            best_key = key;
+        }
+    }
+
+    best_idx.expect("load balancer requires at least 1 server")
+}
+
+// Index of the server that is up with the minimum key
+fn min_key_idx<K: Ord>(keys: &[K], up: &[bool]) -> usize {
+    keys.iter()
+        .enumerate()
+        .filter(|&(idx, _)| up[idx])
+        .min_by_key(|&(_, key)| key)
+        .map(|(idx, _)| idx)
+        .expect("load balancer requires at least 1 server")
 }
 
 /// Load balancer based on pinging latencies of all servers
-#[derive(Clone)]
 pub struct PingBalancer<S: ServerData> {
-    best: SharedBestServer<S>,
+    context: SharedContext,
+    server_type: ServerType,
+    best: Arc<RwLock<SharedBestServer<S>>>,
+}
+
+// Not derived, `S` doesn't have to be `Clone`
+impl<S: ServerData> Clone for PingBalancer<S> {
+    fn clone(&self) -> PingBalancer<S> {
+        PingBalancer {
+            context: self.context.clone(),
+            server_type: self.server_type,
+            best: self.best.clone(),
+        }
+    }
 }
 
 impl<S: ServerData + 'static> PingBalancer<S> {
     /// Create a PingBalancer
     pub async fn new(context: SharedContext, server_type: ServerType) -> PingBalancer<S> {
-        let server_count = context.config().server.len();
-        let mut servers = Vec::with_capacity(server_count);
+        let best = PingBalancer::start_best_server(&context, context.config(), server_type).await;
+
+        PingBalancer {
+            context,
+            server_type,
+            best: Arc::new(RwLock::new(best)),
+        }
+    }
+
+    /// Replace all servers, strategy and health check probes with `config`, probing tasks of the old servers will be stopped
+    ///
+    /// Connections that have already picked a server are not affected
+    pub async fn reset_servers(&self, config: &Config) {
+        let best = PingBalancer::start_best_server(&self.context, config, self.server_type).await;
+
+        let mut current = self.best.write();
+
+        // Keep the pinned server if it still exists, it is pinned before new servers could be picked
+        if let Some(idx) = current.pinned_idx() {
+            let addr = current.servers[idx].server_config().addr().to_string();
+            if !best.pin(&addr) {
+                info!(
+                    "pinned {} server {} is removed, choosing automatically",
+                    self.server_type, addr
+                );
+            }
+        }
+
+        let old_best = mem::replace(&mut *current, best);
+        old_best.set_inactive();
+    }
+
+    /// Always picks the server with address `addr` (`host:port`), returns `false` if it is not in this balancer
+    pub fn pin_server(&self, addr: &str) -> bool {
+        self.best.read().pin(addr)
+    }
+
+    /// Picks the best server automatically again
+    pub fn unpin_server(&self) {
+        self.best.read().unpin()
+    }
+
+    /// The pinned server, `None` if servers are picked automatically
+    pub fn pinned_server(&self) -> Option<SharedServerStatistic<S>> {
+        let best = self.best.read();
+        best.pinned_idx().map(|idx| best.servers[idx].clone())
+    }
+
+    /// Probes all servers immediately, and then chooses the best server with the updated statistic data
+    pub async fn probe_servers(&self) {
+        let best = self.best.read().clone();
+
+        let server_type = self.server_type;
+        future::join_all(
+            best.servers
+                .iter()
+                .map(|stat| PingBalancer::<S>::check_update_score(stat, server_type, &best.health_check)),
+        )
+        .await;
+
+        if let Some((old_idx, new_idx)) = best.recalculate_best_server().await {
+            info!(
+                "switched {} server from {} to {}",
+                server_type,
+                best.servers[old_idx].server_config().addr(),
+                best.servers[new_idx].server_config().addr()
+            );
+        }
+    }
+
+    async fn start_best_server(
+        context: &SharedContext,
+        config: &Config,
+        server_type: ServerType,
+    ) -> SharedBestServer<S> {
+        let servers = &config.server;
+        assert!(!servers.is_empty(), "load balancer requires at least 1 server");
+
+        let server_count = servers.len();
+        let health_check = config.health_check.clone();
+        let max_rtt = health_check.timeout.as_millis() as u64;
 
         // Check only required if servers count > 1, otherwise, always use the first one
         let check_required = server_count > 1;
         // Barrier count = current + probing tasks
         let check_barrier = Arc::new(Barrier::new(1 + server_count));
 
-        for idx in 0..server_count {
-            let stat = ServerStatistic::<S>::new_shared(context.clone(), idx);
+        let servers = servers
+            .iter()
+            .map(|svr_cfg| ServerStatistic::<S>::new_shared(context.clone(), svr_cfg.clone(), max_rtt))
+            .collect();
+
+        let best = BestServer::new_shared(servers, config.balancer_strategy, health_check);
 
-            if check_required {
+        if check_required {
+            for stat in &best.servers {
                 let stat = stat.clone();
                 let context = context.clone();
+                let best = best.clone();
                 let check_barrier = check_barrier.clone();
 
                 // Start a background task for probing
                 tokio::spawn(async move {
                     // Check once for initializing data
-                    PingBalancer::<S>::check_update_score(&stat, server_type).await;
+                    PingBalancer::<S>::check_update_score(&stat, server_type, &best.health_check).await;
 
                     trace!(
                         "started latency probing task for server {}, initial score {}",
@@ -368,9 +889,9 @@ impl<S: ServerData + 'static> PingBalancer<S> {
 
                     check_barrier.wait().await;
 
-                    while context.server_running() {
-                        PingBalancer::<S>::check_update_score(&stat, server_type).await;
-                        time::delay_for(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)).await;
+                    while context.server_running() && best.is_active() {
+                        PingBalancer::<S>::check_update_score(&stat, server_type, &best.health_check).await;
+                        time::delay_for(best.health_check.interval).await;
                     }
 
                     debug!(
@@ -381,12 +902,6 @@ impl<S: ServerData + 'static> PingBalancer<S> {
                 });
             }
 
-            servers.push(stat);
-        }
-
-        let best = BestServer::new_shared(servers);
-
-        if check_required {
             // Wait all tasks start (run at least one round)
             check_barrier.wait().await;
             trace!("all latency probing tasks are started, creating best server choosing task");
@@ -410,17 +925,17 @@ impl<S: ServerData + 'static> PingBalancer<S> {
 
                     check_barrier.wait().await;
 
-                    while context.server_running() {
+                    while context.server_running() && best.is_active() {
                         if let Some((old_idx, new_idx)) = best.recalculate_best_server().await {
                             info!(
                                 "switched {} server from {} to {}",
                                 server_type,
-                                context.server_config(old_idx).addr(),
-                                context.server_config(new_idx).addr()
+                                best.servers[old_idx].server_config().addr(),
+                                best.servers[new_idx].server_config().addr()
                             );
                         }
 
-                        time::delay_for(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)).await;
+                        time::delay_for(best.health_check.interval).await;
                     }
                 });
             }
@@ -429,13 +944,13 @@ impl<S: ServerData + 'static> PingBalancer<S> {
             check_barrier.wait().await;
         }
 
-        PingBalancer { best }
+        best
     }
 
-    async fn check_update_score(stat: &ServerStatistic<S>, server_type: ServerType) {
-        let score = match PingBalancer::<S>::check_delay(stat, server_type).await {
-            Ok(d) => stat.push_score(Score::Latency(d)).await,
-            Err(..) => stat.push_score(Score::Errored).await, // Penalty
+    async fn check_update_score(stat: &ServerStatistic<S>, server_type: ServerType, health_check: &HealthCheckConfig) {
+        let score = match PingBalancer::<S>::check_delay(stat, server_type, health_check).await {
+            Ok(d) => stat.report_latency(d).await,
+            Err(..) => stat.report_failure().await, // Penalty, and marked down
         };
 
         debug!(
@@ -453,48 +968,94 @@ impl<S: ServerData + 'static> PingBalancer<S> {
         );
     }
 
-    async fn check_request_tcp(stat: &ServerStatistic<S>) -> io::Result<()> {
-        static GET_BODY: &[u8] =
-            b"GET /generate_204 HTTP/1.1\r\nHost: dl.google.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+    // Checks if the server accepts the address header, without sending any request to the target
+    //
+    // Servers never respond to clients with wrong keys, but they close or reset the connections after failing to
+    // decrypt the header. Connections that are still open after `wait` are accepted. Returns the time spent waiting,
+    // which is not a part of the latency
+    async fn check_connect(stat: &ServerStatistic<S>, probe: &ProbeConfig, wait: Duration) -> io::Result<Duration> {
+        // Finished after the address header is sent
+        let mut stream = TcpServerClient::connect(stat.clone_context(), &probe.target, stat.server_config()).await?;
+        stream.flush().await?;
 
-        let addr = Address::DomainNameAddress("dl.google.com".to_owned(), 80);
+        let start = Instant::now();
+        let mut buf = [0u8; 1];
+        match time::timeout(wait, stream.read(&mut buf)).await {
+            Err(..) => Ok(wait),
+            // Target responded without any request
+            Ok(Ok(n)) if n > 0 => Ok(Instant::now() - start),
+            Ok(Ok(..)) => {
+                let err = Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "server closed the connection, may be wrong method or key",
+                );
+                Err(err)
+            }
+            Ok(Err(err)) => Err(err),
+        }
+    }
 
-        let mut stream = TcpServerClient::connect(stat.clone_context(), &addr, stat.server_config()).await?;
-        stream.write_all(GET_BODY).await?;
+    async fn check_request_tcp(stat: &ServerStatistic<S>, probe: &ProbeConfig) -> io::Result<()> {
+        let mut stream = TcpServerClient::connect(stat.clone_context(), &probe.target, stat.server_config()).await?;
+        stream.write_all(&probe.request).await?;
 
-        let mut buf = [0u8; 1];
+        // Read the first byte if any response is accepted
+        let mut buf = vec![0u8; probe.expect.len().max(1)];
         stream.read_exact(&mut buf).await?;
 
-        Ok(())
+        check_response(probe, &buf)
     }
 
-    async fn check_request_udp(stat: &ServerStatistic<S>) -> io::Result<()> {
-        static DNS_QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x05\x62\x61\x69\x64\x75\x03\x63\x6f\x6d\x00\x00\x01\x00\x01";
-
-        let addr = Address::SocketAddress(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53));
-
+    async fn check_request_udp(stat: &ServerStatistic<S>, probe: &ProbeConfig) -> io::Result<()> {
         let mut client = UdpServerClient::new(stat.server_config()).await?;
-        client.send_to(stat.context(), &addr, DNS_QUERY).await?;
-        let _ = client.recv_from(stat.context()).await?;
+        client.send_to(stat.context(), &probe.target, &probe.request).await?;
+        let (_, payload) = client.recv_from(stat.context()).await?;
 
-        Ok(())
+        check_response(probe, &payload)
     }
 
-    async fn check_request(stat: &ServerStatistic<S>, server_type: ServerType) -> io::Result<()> {
-        match server_type {
-            ServerType::Tcp => PingBalancer::<S>::check_request_tcp(stat).await,
-            ServerType::Udp => PingBalancer::<S>::check_request_udp(stat).await,
+    // Returns the time spent waiting for servers to reject, which is excluded from the latency
+    async fn check_request(
+        stat: &ServerStatistic<S>,
+        server_type: ServerType,
+        health_check: &HealthCheckConfig,
+    ) -> io::Result<Duration> {
+        match (health_check.mode, server_type) {
+            (HealthCheckMode::ConnectOnly, ServerType::Tcp) => {
+                // Leaves at least a half of the timeout for connecting
+                let wait = cmp::min(CONNECT_ONLY_REJECT_WAIT, health_check.timeout / 2);
+                PingBalancer::<S>::check_connect(stat, &health_check.tcp, wait).await
+            }
+            (HealthCheckMode::Request, ServerType::Tcp) => {
+                PingBalancer::<S>::check_request_tcp(stat, &health_check.tcp)
+                    .await
+                    .map(|_| Duration::from_secs(0))
+            }
+            // There is no connection in UDP, UDP servers could only be checked with requests
+            (_, ServerType::Udp) => PingBalancer::<S>::check_request_udp(stat, &health_check.udp)
+                .await
+                .map(|_| Duration::from_secs(0)),
         }
     }
 
-    async fn check_delay(stat: &ServerStatistic<S>, server_type: ServerType) -> io::Result<u64> {
+    async fn check_delay(
+        stat: &ServerStatistic<S>,
+        server_type: ServerType,
+        health_check: &HealthCheckConfig,
+    ) -> io::Result<u64> {
         let start = Instant::now();
 
-        // Send HTTP GET and read the first byte
-        let timeout = Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC);
-        let res = time::timeout(timeout, PingBalancer::<S>::check_request(stat, server_type)).await;
+        // Send the probe request and read the response
+        let res = time::timeout(
+            health_check.timeout,
+            PingBalancer::<S>::check_request(stat, server_type, health_check),
+        )
+        .await;
 
-        let elapsed = Instant::now() - start;
+        let mut elapsed = Instant::now() - start;
+        if let Ok(Ok(waited)) = res {
+            elapsed = elapsed.checked_sub(waited).unwrap_or(elapsed);
+        }
         let elapsed = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()); // Converted to ms
         match res {
             Ok(Ok(..)) => {
@@ -535,11 +1096,42 @@ impl<S: ServerData + 'static> PingBalancer<S> {
 }
 
 impl<S: ServerData> PingBalancer<S> {
-    /// Pick the best server with current known statistic data
+    /// Pick a server for connecting to `target` with the configured strategy
     ///
     /// Return a `Arc` shared server statistic reference
-    pub fn pick_server(&self) -> SharedServerStatistic<S> {
-        self.best.pick_server()
+    pub fn pick_server(&self, target: &Address) -> SharedServerStatistic<S> {
+        self.best.read().pick_server(target)
+    }
+
+    /// Pick the next server for connecting to `target` after servers in `tried` failed
+    ///
+    /// Return `None` if there is no other server to fail over to
+    pub fn pick_next_server(
+        &self,
+        target: &Address,
+        tried: &[SharedServerStatistic<S>],
+    ) -> Option<SharedServerStatistic<S>> {
+        self.best.read().pick_server_excluding(target, tried)
+    }
+
+    /// The server with the lowest score, or the pinned server
+    pub fn best_server(&self) -> SharedServerStatistic<S> {
+        self.best.read().best_server()
+    }
+
+    /// Shared context of this balancer
+    pub fn context(&self) -> &Context {
+        &*self.context
+    }
+
+    /// Type of servers in this balancer
+    pub fn server_type(&self) -> ServerType {
+        self.server_type
+    }
+
+    /// All servers in this balancer
+    pub fn servers(&self) -> Vec<SharedServerStatistic<S>> {
+        self.best.read().servers.clone()
     }
 }
 
@@ -547,7 +1139,7 @@ impl<S: ServerData> PingBalancer<S> {
 pub struct EmptyServerData;
 
 impl ServerData for EmptyServerData {
-    fn create_server(_: &SharedContext, _: usize, _: &SharedServerStatisticData) -> EmptyServerData {
+    fn create_server(_: &SharedContext, _: &ServerConfig, _: &SharedServerStatisticData) -> EmptyServerData {
         EmptyServerData
     }
 }
@@ -557,3 +1149,304 @@ pub type PlainPingBalancer = PingBalancer<EmptyServerData>;
 
 /// Shared PlainServerStatistic
 pub type SharedPlainServerStatistic = SharedServerStatistic<EmptyServerData>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::{net::TcpListener, runtime::Builder};
+
+    use crate::{config::ConfigType, context::ServerState, crypto::CipherType};
+
+    #[test]
+    fn test_down_backoff() {
+        let backoffs: Vec<u64> = (1..=8).map(|n| down_backoff(n).as_secs()).collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(down_backoff(u32::max_value()), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_health_mark_down() {
+        let mut health = ServerHealth {
+            score: 0,
+            failures: 0,
+            down_until: None,
+        };
+        assert!(!health.is_down());
+
+        health.report_failure(1000);
+        assert!(health.is_down());
+        assert_eq!(health.failures, 1);
+
+        // Failures in a row make the backoff longer
+        health.report_failure(1000);
+        assert_eq!(health.failures, 2);
+        let remaining = health.down_until.unwrap() - Instant::now();
+        assert!(remaining > Duration::from_secs(1) && remaining <= Duration::from_secs(2));
+
+        health.report_success(500);
+        assert!(!health.is_down());
+        assert_eq!(health.failures, 0);
+        assert_eq!(health.score, 500);
+
+        // Retried after the backoff
+        health.down_until = Some(Instant::now());
+        assert!(!health.is_down());
+    }
+
+    #[test]
+    fn test_passive_report() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+
+        rt.block_on(async move {
+            let stat = SharedServerStatisticData::new(2000);
+            stat.report_latency(100).await;
+            assert!(!stat.is_down());
+
+            stat.report_failure().await;
+            assert!(stat.is_down());
+
+            // Passive successes clear the down mark without recording latencies
+            stat.report_success();
+            assert!(!stat.is_down());
+
+            let snapshot = stat.snapshot().await;
+            assert_eq!(snapshot.rtt, 100);
+            assert!((snapshot.fail_rate - 0.5).abs() < 1e-9);
+
+            // Deferred failures mark the server down at once, and are counted when the data is locked
+            stat.report_failure_deferred();
+            assert!(stat.is_down());
+
+            let snapshot = stat.snapshot().await;
+            assert!((snapshot.fail_rate - 2.0 / 3.0).abs() < 1e-9);
+            assert_eq!(stat.cached_score(), snapshot.score);
+
+            // Connect times are recorded, but servers are not marked up by connecting
+            let score = stat.report_connect_latency(300).await;
+            assert!(stat.is_down());
+            assert_eq!(stat.cached_score(), score);
+
+            let snapshot = stat.snapshot().await;
+            assert_eq!(snapshot.rtt, 200);
+            assert!((snapshot.fail_rate - 0.5).abs() < 1e-9);
+        });
+    }
+
+    #[test]
+    fn test_check_response() {
+        let mut probe = ProbeConfig {
+            target: Address::DomainNameAddress("example.com".to_owned(), 80),
+            request: b"HEAD / HTTP/1.1\r\n\r\n".to_vec(),
+            expect: b"HTTP/1.1".to_vec(),
+        };
+
+        assert!(check_response(&probe, b"HTTP/1.1 204 No Content\r\n").is_ok());
+        assert!(check_response(&probe, b"HTTP/1.0 200 OK\r\n").is_err());
+        assert!(check_response(&probe, b"HTTP").is_err());
+
+        // Any response is accepted without `expect`
+        probe.expect.clear();
+        assert!(check_response(&probe, b"\x12\x34").is_ok());
+    }
+
+    #[test]
+    fn test_up_weights() {
+        assert_eq!(up_weights(&[1, 2, 3], &[true, false, true]), vec![1, 0, 3]);
+
+        // All servers that are up have 0 weight
+        assert_eq!(up_weights(&[0, 2, 0], &[true, false, true]), vec![1, 0, 1]);
+        assert_eq!(up_weights(&[0, 0], &[true, true]), vec![1, 1]);
+    }
+
+    #[test]
+    fn test_weighted_idx() {
+        let weights = [1, 0, 3];
+
+        let picked: Vec<usize> = (0..4).map(|n| weighted_idx(&weights, n)).collect();
+        assert_eq!(picked, vec![0, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_weighted_idx_zero_weights() {
+        let weights = up_weights(&[0, 0, 0], &[false, true, true]);
+        let total: u64 = weights.iter().map(|&w| u64::from(w)).sum();
+        assert_eq!(total, 2);
+
+        let picked: Vec<usize> = (0..total).map(|n| weighted_idx(&weights, n)).collect();
+        assert_eq!(picked, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_rendezvous_idx() {
+        let hashes = [0x1234_5678_9abc_def0, 0x0fed_cba9_8765_4321, 0x5555_aaaa_5555_aaaa];
+
+        let idx = rendezvous_idx(&hashes, &[1, 1, 1]);
+        assert_eq!(rendezvous_idx(&hashes, &[1, 1, 1]), idx);
+
+        // Servers with 0 weight are never chosen
+        let mut weights = [1, 1, 1];
+        weights[idx] = 0;
+        let other = rendezvous_idx(&hashes, &weights);
+        assert_ne!(other, idx);
+
+        // Only one server is left
+        let mut weights = [0, 0, 0];
+        weights[2] = 1;
+        assert_eq!(rendezvous_idx(&hashes, &weights), 2);
+    }
+
+    #[test]
+    fn test_rendezvous_idx_removed_server() {
+        // Targets are moved only if their servers are removed
+        for target in 0u64..64 {
+            let hashes: Vec<u64> = (0u64..4)
+                .map(|svr| {
+                    let mut hasher = DefaultHasher::new();
+                    target.hash(&mut hasher);
+                    svr.hash(&mut hasher);
+                    hasher.finish()
+                })
+                .collect();
+
+            let idx = rendezvous_idx(&hashes, &[1, 1, 1, 1]);
+            let removed = if idx == 3 { 0 } else { 3 };
+
+            let mut weights = [1, 1, 1, 1];
+            weights[removed] = 0;
+            assert_eq!(rendezvous_idx(&hashes, &weights), idx);
+        }
+    }
+
+    #[test]
+    fn test_min_key_idx() {
+        assert_eq!(min_key_idx(&[3, 1, 2], &[true, true, true]), 1);
+        assert_eq!(min_key_idx(&[3, 1, 2], &[true, false, true]), 2);
+
+        // Ties are broken by the lower index
+        assert_eq!(
+            min_key_idx(&[(1, true), (1, false), (1, false)], &[true, true, true]),
+            1
+        );
+        assert_eq!(min_key_idx(&[2, 1, 1], &[true, true, true]), 1);
+    }
+
+    #[test]
+    fn test_pick_server_pinned_failover() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let config = Config::new(ConfigType::Socks5Local);
+            let state = ServerState::new_shared(&config, rt_handle).await;
+            let context = Context::new_shared(config, state);
+
+            let servers: Vec<SharedServerStatistic<EmptyServerData>> = ["127.0.0.1:8001", "127.0.0.1:8002"]
+                .iter()
+                .map(|addr| {
+                    let svr_cfg =
+                        ServerConfig::basic(addr.parse().unwrap(), "test-password".to_owned(), CipherType::Aes256Gcm);
+                    ServerStatistic::new_shared(context.clone(), svr_cfg, 2000)
+                })
+                .collect();
+            let best = BestServer::new(
+                servers.clone(),
+                BalancerStrategy::LowestScore,
+                HealthCheckConfig::default(),
+            );
+            let target = Address::DomainNameAddress("example.com".to_owned(), 80);
+
+            assert!(best.pin("127.0.0.1:8002"));
+            let picked = best.pick_server(&target);
+            assert!(Arc::ptr_eq(&picked, &servers[1]));
+
+            // Pinned server doesn't stop failing over to the others
+            let next = best.pick_server_excluding(&target, &[picked]).unwrap();
+            assert!(Arc::ptr_eq(&next, &servers[0]));
+            assert!(best.pick_server_excluding(&target, &servers).is_none());
+        });
+    }
+
+    #[test]
+    fn test_pick_server_zero_weight_standby() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let config = Config::new(ConfigType::Socks5Local);
+            let state = ServerState::new_shared(&config, rt_handle).await;
+            let context = Context::new_shared(config, state);
+
+            let servers: Vec<SharedServerStatistic<EmptyServerData>> = [("127.0.0.1:8001", 1), ("127.0.0.1:8002", 0)]
+                .iter()
+                .map(|&(addr, weight)| {
+                    let mut svr_cfg =
+                        ServerConfig::basic(addr.parse().unwrap(), "test-password".to_owned(), CipherType::Aes256Gcm);
+                    svr_cfg.set_weight(weight);
+                    ServerStatistic::new_shared(context.clone(), svr_cfg, 2000)
+                })
+                .collect();
+
+            for &strategy in [BalancerStrategy::WeightedRandom, BalancerStrategy::ConsistentHash].iter() {
+                let best = BestServer::new(servers.clone(), strategy, HealthCheckConfig::default());
+
+                // Standby server is only chosen if the other one can't be used
+                for n in 0..20 {
+                    let target = Address::DomainNameAddress(format!("{}.example.com", n), 80);
+                    assert!(Arc::ptr_eq(&best.pick_server(&target), &servers[0]));
+
+                    let standby = best.pick_server_excluding(&target, &servers[..1]).unwrap();
+                    assert!(Arc::ptr_eq(&standby, &servers[1]));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_check_connect_rejected() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let config = Config::new(ConfigType::Socks5Local);
+            let state = ServerState::new_shared(&config, rt_handle).await;
+            let context = Context::new_shared(config, state);
+
+            // Server keeps connections open, as if the header is accepted and the target is silent
+            let mut silent_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let silent_addr = silent_listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let mut streams = Vec::new();
+                loop {
+                    let (stream, _) = silent_listener.accept().await.unwrap();
+                    streams.push(stream);
+                }
+            });
+
+            // Server closes connections after reading the header, as if it fails to decrypt it
+            let mut closing_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let closing_addr = closing_listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = closing_listener.accept().await.unwrap();
+                    let mut buf = [0u8; 1];
+                    let _ = stream.read(&mut buf).await;
+                }
+            });
+
+            let probe = HealthCheckConfig::default().tcp;
+            let wait = Duration::from_millis(200);
+            for &(addr, accepted) in [(silent_addr, true), (closing_addr, false)].iter() {
+                let svr_cfg = ServerConfig::basic(addr, "test-password".to_owned(), CipherType::Aes256Gcm);
+                let stat = ServerStatistic::<EmptyServerData>::new_shared(context.clone(), svr_cfg, 2000);
+
+                let res = PingBalancer::<EmptyServerData>::check_connect(&stat, &probe, wait).await;
+                match res {
+                    Ok(waited) if accepted => assert_eq!(waited, wait),
+                    Err(..) if !accepted => {}
+                    res => panic!("unexpected result {:?} of server {}", res, addr),
+                }
+            }
+        });
+    }
+}
@@ -2,22 +2,58 @@
 
 use std::io::{self, ErrorKind};
 
-use futures::{future::select_all, FutureExt};
-use log::{debug, error, trace, warn};
-use tokio::runtime::Handle;
+use futures::{future, FutureExt};
+use log::{debug, error, info, trace, warn};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+};
 
 use crate::{
-    config::{Config, ConfigType},
+    config::Config,
     context::{Context, ServerState, SharedContext},
     plugin::{PluginMode, Plugins},
-    relay::{tcprelay::local::run as run_tcp, udprelay::local::run as run_udp, utils::set_nofile},
+    relay::{
+        loadbalancing::{
+            control::run as run_control,
+            server::{PingBalancer, PlainPingBalancer, ServerType},
+            Balancers,
+        },
+        metrics::run as run_metrics,
+        tcprelay::local::{run as run_tcp, TcpServerBalancer},
+        udprelay::local::run as run_udp,
+        utils::{serve_until_shutdown, set_nofile},
+    },
 };
 
 #[cfg(feature = "dns-relay")]
 use crate::relay::dnsrelay::run as run_dns_relay;
 
 /// Relay server running under local environment.
-pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
+pub async fn run(config: Config, rt: Handle) -> io::Result<()> {
+    run_with(config, rt, None, None).await
+}
+
+/// Relay server running under local environment, controlled by signals from the caller
+///
+/// - Reloads servers with configurations received from `reload_rx`. Only the server list is reloaded,
+///   other options take effect after restarting.
+/// - Shuts down gracefully after receiving from `shutdown_rx`, returns `Ok(())` after connections are drained.
+pub async fn run_with_signals(
+    config: Config,
+    rt: Handle,
+    reload_rx: mpsc::Receiver<Config>,
+    shutdown_rx: oneshot::Receiver<()>,
+) -> io::Result<()> {
+    run_with(config, rt, Some(reload_rx), Some(shutdown_rx)).await
+}
+
+async fn run_with(
+    mut config: Config,
+    rt: Handle,
+    reload_rx: Option<mpsc::Receiver<Config>>,
+    shutdown_rx: Option<oneshot::Receiver<()>>,
+) -> io::Result<()> {
     trace!("initializing local server with {:?}", config);
 
     assert!(config.config_type.is_local());
@@ -40,26 +76,20 @@ pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
         }
     }
 
-    let config_type = config.config_type;
-    let mode = config.mode;
+    if config.fast_open && config.idle_connections > 0 {
+        warn!("`idle_connections` is ignored with `fast_open`, connections are established by the first payload");
+    }
+
+    let local_configs = config.local_configs();
 
     // Create a context containing a DNS resolver and server running state flag.
     let state = ServerState::new_shared(&config, rt).await;
 
     let mut vf = Vec::new();
+    let mut plugins_fut = None;
 
-    let enable_tcp = match config_type {
-        // Socks5 always true, because UDP associate command also requires a TCP connection
-        ConfigType::Socks5Local => true,
-        // Tunnel mode controlled by this flag
-        ConfigType::TunnelLocal => mode.enable_tcp(),
-        // HTTP must be TCP
-        ConfigType::HttpLocal => true,
-        // Redir mode controlled by this flag
-        ConfigType::RedirLocal => mode.enable_tcp(),
-
-        _ => false,
-    };
+    let enable_tcp = local_configs.iter().any(|l| l.enable_tcp());
+    let mut tcp_balancer = None;
 
     let context = if enable_tcp {
         // Run TCP local server if
@@ -74,12 +104,15 @@ pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
             // Some plugins require quite a lot bootstrap time
             Plugins::check_plugins_started(&config).await?;
 
-            vf.push(plugins.into_future().boxed());
+            plugins_fut = Some(plugins.into_future().boxed());
         }
 
         let context = Context::new_shared(config, state);
 
-        let tcp_fut = run_tcp(context.clone());
+        let balancer: TcpServerBalancer = PingBalancer::new(context.clone(), ServerType::Tcp).await;
+        tcp_balancer = Some(balancer.clone());
+
+        let tcp_fut = run_tcp(context.clone(), balancer);
         vf.push(tcp_fut.boxed());
 
         context
@@ -87,18 +120,39 @@ pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
         Context::new_shared(config, state)
     };
 
-    let enable_udp = match config_type {
-        ConfigType::Socks5Local | ConfigType::TunnelLocal | ConfigType::RedirLocal => mode.enable_udp(),
-        _ => false,
-    };
+    let enable_udp = local_configs.iter().any(|l| l.enable_udp());
+    let mut udp_balancer = None;
 
     if enable_udp {
         // Run UDP relay before starting plugins
         // Because plugins doesn't support UDP relay
-        let udp_fut = run_udp(context.clone());
+        let balancer = PlainPingBalancer::new(context.clone(), ServerType::Udp).await;
+        udp_balancer = Some(balancer.clone());
+
+        let udp_fut = run_udp(context.clone(), balancer);
         vf.push(udp_fut.boxed());
     }
 
+    let balancers = Balancers {
+        tcp: tcp_balancer.clone(),
+        udp: udp_balancer.clone(),
+    };
+
+    if let Some(addr) = context.config().metrics_addr {
+        let metrics_fut = run_metrics(addr, context.clone_server_state(), balancers.clone());
+        vf.push(metrics_fut.boxed());
+    }
+
+    if let Some(ref addr) = context.config().control_address {
+        let control_fut = run_control(context.clone(), addr.clone(), balancers);
+        vf.push(control_fut.boxed());
+    }
+
+    if let Some(reload_rx) = reload_rx {
+        let reload_fut = reload_task(context.clone(), reload_rx, tcp_balancer, udp_balancer);
+        vf.push(reload_fut.boxed());
+    }
+
     #[cfg(feature = "dns-relay")]
     {
         // For Android's local resolver
@@ -113,13 +167,48 @@ pub async fn run(mut config: Config, rt: Handle) -> io::Result<()> {
         vf.push(report_fut.boxed());
     }
 
-    let (res, ..) = select_all(vf.into_iter()).await;
-    error!("one of servers exited unexpectly, result: {:?}", res);
+    serve_until_shutdown(&context, vf, plugins_fut, shutdown_rx).await
+}
+
+async fn reload_task(
+    context: SharedContext,
+    mut reload_rx: mpsc::Receiver<Config>,
+    tcp_balancer: Option<TcpServerBalancer>,
+    udp_balancer: Option<PlainPingBalancer>,
+) -> io::Result<()> {
+    while let Some(mut config) = reload_rx.recv().await {
+        // Nothing is applied, including ACL, unless the whole configuration is valid
+        if config.server.is_empty() {
+            error!("reloaded configuration doesn't have any servers, ignored");
+            continue;
+        }
+
+        if let Err(err) = config.check_integrity() {
+            error!("reloaded configuration is invalid, ignored, error: {}", err);
+            continue;
+        }
+
+        // Plugins are bound to servers while starting, they couldn't be replaced on the fly
+        if config.has_server_plugins() {
+            error!("reloading servers with plugins is not supported, restart to apply the new configuration");
+            continue;
+        }
+
+        context.clone_server_state().set_acl(config.acl.take());
+
+        if let Some(ref balancer) = tcp_balancer {
+            balancer.reset_servers(&config).await;
+        }
+
+        if let Some(ref balancer) = udp_balancer {
+            balancer.reset_servers(&config).await;
+        }
 
-    // Tells all detached tasks to exit
-    context.set_server_stopped();
+        info!("reloaded {} servers", config.server.len());
+    }
 
-    Err(io::Error::new(io::ErrorKind::Other, "server exited unexpectly"))
+    // Reloading is not available anymore, keep running with current servers
+    future::pending().await
 }
 
 #[cfg(target_os = "android")]
@@ -9,6 +9,7 @@ use std::{
     io::{self, Error, ErrorKind},
     net::{IpAddr, Ipv4Addr, SocketAddr},
     str,
+    time::{Duration, UNIX_EPOCH},
 };
 
 use byte_string::ByteStr;
@@ -19,12 +20,12 @@ use tokio::net::UnixDatagram;
 use tokio::{self, net::UdpSocket, runtime::Handle, sync::oneshot};
 
 use crate::{
-    config::{Config, ConfigType, ManagerAddr, Mode, ServerAddr, ServerConfig},
+    config::{Config, ConfigType, ManagerAddr, Mode, ServerAddr, ServerConfig, ServerUser},
     context::{Context, ServerState, SharedContext, SharedServerState},
-    crypto::CipherType,
+    crypto::{CipherCategory, CipherType},
     plugin::PluginConfig,
     relay::{
-        flow::{MultiServerFlowStatistic, SharedServerFlowStatistic},
+        flow::{MultiServerFlowStatistic, SharedMultiServerFlowStatistic, SharedServerFlowStatistic},
         sys::create_udp_socket,
         udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
         utils::set_nofile,
@@ -50,28 +51,52 @@ mod protocol {
         pub plugin_opt: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub mode: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub user: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub users: Option<Vec<ServerUser>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub traffic_limit: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub expire_at: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ServerUser {
+        pub name: String,
+        pub password: String,
     }
 
     #[derive(Deserialize, Debug)]
     pub struct RemoveRequest {
         pub server_port: u16,
+        pub user: Option<String>,
     }
 }
 
 struct ServerInstance {
     config: Config,
     flow_stat: SharedServerFlowStatistic,
+    // Kept for restarting the server on the same port
+    flow_stats: SharedMultiServerFlowStatistic,
     #[allow(dead_code)] // This is not dead_code, dropping watcher_tx will inform server task to quit
     watcher_tx: oneshot::Sender<()>,
 }
 
 impl ServerInstance {
-    async fn start_server(config: Config, server_state: SharedServerState) -> io::Result<ServerInstance> {
+    async fn start_server(
+        config: Config,
+        server_state: SharedServerState,
+        prev_flow_stat: Option<SharedMultiServerFlowStatistic>,
+    ) -> io::Result<ServerInstance> {
         let server_port = config.server[0].addr().port();
 
         let (watcher_tx, watcher_rx) = oneshot::channel::<()>();
 
-        let flow_stat = MultiServerFlowStatistic::new_shared(&config);
+        let flow_stats = match prev_flow_stat {
+            Some(prev) => MultiServerFlowStatistic::new_shared_from(&config, &prev),
+            None => MultiServerFlowStatistic::new_shared(&config),
+        };
 
         {
             // Run server in current process, sharing the same tokio runtime
@@ -80,10 +105,10 @@ impl ServerInstance {
             // which means that this is not a good decision
 
             let config = config.clone();
-            let flow_stat = flow_stat.clone();
+            let flow_stats = flow_stats.clone();
 
             tokio::spawn(async move {
-                let server = server::run_with(config, flow_stat, server_state);
+                let server = server::run_with(config, flow_stats, server_state, None);
 
                 tokio::pin!(server);
                 tokio::pin!(watcher_rx);
@@ -93,7 +118,7 @@ impl ServerInstance {
             });
         }
 
-        let flow_stat = flow_stat
+        let flow_stat = flow_stats
             .get(server_port)
             .expect("port not existed in multi-server flow statistic")
             .clone();
@@ -103,6 +128,7 @@ impl ServerInstance {
         Ok(ServerInstance {
             config,
             flow_stat,
+            flow_stats,
             watcher_tx,
         })
     }
@@ -110,6 +136,31 @@ impl ServerInstance {
     fn flow_trans_stat(&self) -> u64 {
         self.flow_stat.trans_stat()
     }
+
+    fn server_config(&self) -> &ServerConfig {
+        &self.config.server[0]
+    }
+}
+
+/// Check and create an user for multi-user server
+fn new_server_user(method: CipherType, name: String, password: String) -> io::Result<ServerUser> {
+    if method.category() != CipherCategory::Aead {
+        let err = Error::new(
+            ErrorKind::Other,
+            format!("method \"{}\" couldn't identify users, requires an AEAD method", method),
+        );
+        return Err(err);
+    }
+
+    if !method.check_key(password.as_bytes()) {
+        let err = Error::new(
+            ErrorKind::Other,
+            format!("method \"{}\" requires a base64 encoded key as password", method),
+        );
+        return Err(err);
+    }
+
+    Ok(ServerUser::new(name, password, method))
 }
 
 /// Datagram socket for manager
@@ -384,6 +435,42 @@ impl ManagerService {
 
         let server_port = p.server_port;
 
+        // Add an user to the running server
+        if let Some(name) = p.user {
+            let inst = match self.servers.get(&server_port) {
+                Some(inst) => inst,
+                None => {
+                    let err = Error::new(ErrorKind::Other, format!("server on port {} not found", server_port));
+                    return Err(err);
+                }
+            };
+
+            let svr_cfg = inst.server_config();
+            if let Some(method) = p.method {
+                let same_method = match method.parse::<CipherType>() {
+                    Ok(m) => m.to_string() == svr_cfg.method().to_string(),
+                    Err(..) => false,
+                };
+
+                if !same_method {
+                    let err = Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "server on port {} is using method \"{}\"",
+                            server_port,
+                            svr_cfg.method()
+                        ),
+                    );
+                    return Err(err);
+                }
+            }
+
+            let user = new_server_user(svr_cfg.method(), name, p.password)?;
+            svr_cfg.users().add_user(user);
+
+            return Ok(Some(b"ok\n".to_vec()));
+        }
+
         let method = match p.method {
             None => self.context
                 .config()
@@ -400,8 +487,16 @@ impl ManagerService {
             },
         };
 
+        if !method.check_key(p.password.as_bytes()) {
+            let err = Error::new(
+                ErrorKind::Other,
+                format!("method \"{}\" requires a base64 encoded key as password", method),
+            );
+            return Err(err);
+        }
+
         let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), p.server_port);
-        let svr_cfg = ServerConfig::new(
+        let mut svr_cfg = ServerConfig::new(
             ServerAddr::from(bind_addr),
             p.password,
             method,
@@ -415,6 +510,19 @@ impl ManagerService {
             },
         );
 
+        if let Some(limit) = p.traffic_limit {
+            svr_cfg.set_traffic_limit(limit);
+        }
+        if let Some(t) = p.expire_at {
+            svr_cfg.set_expire_at(UNIX_EPOCH + Duration::from_secs(t));
+        }
+
+        for user in p.users.unwrap_or_default() {
+            svr_cfg
+                .users()
+                .add_user(new_server_user(method, user.name, user.password)?);
+        }
+
         let mut config = Config::new(ConfigType::Server);
         config.server.push(svr_cfg);
 
@@ -448,15 +556,21 @@ impl ManagerService {
         // FIXME: AccessControl structure may be quite expensive to copy
         config.acl = self.context.config().acl.clone();
 
-        // Close it first
-        let _ = self.servers.remove(&server_port);
-        self.start_server_with_config(server_port, config).await?;
+        // Close it first, traffic usage continues on the new server
+        let prev_flow_stat = self.servers.remove(&server_port).map(|inst| inst.flow_stats);
+        self.start_server_with_config(server_port, config, prev_flow_stat)
+            .await?;
 
         Ok(Some(b"ok\n".to_vec()))
     }
 
-    async fn start_server_with_config(&mut self, server_port: u16, config: Config) -> io::Result<()> {
-        let server = ServerInstance::start_server(config, self.context.clone_server_state()).await?;
+    async fn start_server_with_config(
+        &mut self,
+        server_port: u16,
+        config: Config,
+        prev_flow_stat: Option<SharedMultiServerFlowStatistic>,
+    ) -> io::Result<()> {
+        let server = ServerInstance::start_server(config, self.context.clone_server_state(), prev_flow_stat).await?;
         self.servers.insert(server_port, server);
 
         Ok(())
@@ -465,7 +579,19 @@ impl ManagerService {
     async fn handle_remove(&mut self, p: &protocol::RemoveRequest) -> io::Result<Option<Vec<u8>>> {
         trace!("ACTION \"remove\" {:?}", p);
 
-        let _ = self.servers.remove(&p.server_port);
+        match p.user {
+            // Remove an user from the running server, connections already established are not affected
+            Some(ref name) => {
+                if let Some(inst) = self.servers.get(&p.server_port) {
+                    inst.server_config().users().remove_user(name);
+                    inst.flow_stat.remove_user(name);
+                }
+            }
+            None => {
+                let _ = self.servers.remove(&p.server_port);
+            }
+        }
+
         Ok(Some(b"ok\n".to_vec()))
     }
 
@@ -474,8 +600,9 @@ impl ManagerService {
         buf += "[";
         let mut is_first = true;
         for (_, inst) in self.servers.iter() {
-            let config = &inst.config;
-            let svr_cfg = &config.server[0];
+            let svr_cfg = inst.server_config();
+
+            let users = svr_cfg.users().users();
 
             let p = protocol::ServerConfig {
                 server_port: svr_cfg.addr().port(),
@@ -485,6 +612,25 @@ impl ManagerService {
                 plugin: None,
                 plugin_opt: None,
                 mode: None,
+                user: None,
+                traffic_limit: svr_cfg.traffic_limit(),
+                expire_at: svr_cfg
+                    .expire_at()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+                users: if users.is_empty() {
+                    None
+                } else {
+                    Some(
+                        users
+                            .iter()
+                            .map(|u| protocol::ServerUser {
+                                name: u.name().to_owned(),
+                                password: u.password().to_owned(),
+                            })
+                            .collect(),
+                    )
+                },
             };
 
             if is_first {
@@ -514,6 +660,12 @@ impl ManagerService {
             }
 
             buf += &format!("\"{}\":{}", port, inst.flow_trans_stat());
+
+            // Users of multi-user server, keyed by "port:name"
+            for (name, stat) in inst.flow_stat.users_trans_stat() {
+                let key = serde_json::to_string(&format!("{}:{}", port, name)).expect("convert user name into JSON");
+                buf += &format!(",{}:{}", key, stat);
+            }
         }
         buf += "}\n";
 
@@ -582,7 +734,7 @@ pub async fn run(config: Config, rt: Handle) -> io::Result<()> {
             clean_config.server.push(svr_cfg.clone());
 
             service
-                .start_server_with_config(svr_cfg.addr().port(), clean_config)
+                .start_server_with_config(svr_cfg.addr().port(), clean_config, None)
                 .await?;
         }
     }
@@ -0,0 +1,320 @@
+//! Metrics of servers, exported with Prometheus text format
+
+use std::{
+    convert::Infallible,
+    fmt::{Display, Write},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Weak,
+    },
+};
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{error, info};
+use spin::Mutex;
+
+use crate::{
+    context::SharedServerState,
+    relay::{
+        flow::{FlowStatistic, MultiServerFlowStatistic, SharedMultiServerFlowStatistic},
+        loadbalancing::{
+            server::{PingBalancer, ServerData, ServerType},
+            Balancers,
+        },
+    },
+};
+
+/// Number of active objects, such as connections
+#[derive(Default)]
+pub struct Gauge(Arc<AtomicUsize>);
+
+impl Gauge {
+    /// Current value
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Increments the gauge, it will be decremented after the returned guard is dropped
+    pub fn track(&self) -> GaugeGuard {
+        self.0.fetch_add(1, Ordering::AcqRel);
+        GaugeGuard(self.0.clone())
+    }
+}
+
+/// Counted in a `Gauge` until dropped
+pub struct GaugeGuard(Arc<AtomicUsize>);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Metrics of all servers running in the same process
+#[derive(Default)]
+pub struct Metrics {
+    // Active connections of contexts, entries are dropped after contexts and their connections are dropped
+    tcp_connections: Mutex<Vec<Weak<AtomicUsize>>>,
+    udp_associations: Gauge,
+    aead_decrypt_failures: AtomicU64,
+    replay_hits: AtomicU64,
+    acl_client_rejects: AtomicU64,
+    acl_outbound_rejects: AtomicU64,
+    // Registered by servers, entries are dropped after servers stopped
+    flow_stats: Mutex<Vec<Weak<MultiServerFlowStatistic>>>,
+}
+
+impl Metrics {
+    /// Exports active connections counted by `counter` until it is dropped
+    pub fn register_tcp_connections(&self, counter: &Arc<AtomicUsize>) {
+        let mut tcp_connections = self.tcp_connections.lock();
+        tcp_connections.retain(|c| c.strong_count() > 0);
+        tcp_connections.push(Arc::downgrade(counter));
+    }
+
+    fn active_tcp_connections(&self) -> usize {
+        self.tcp_connections
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|c| c.load(Ordering::Acquire))
+            .sum()
+    }
+
+    /// Active UDP associations
+    pub fn udp_associations(&self) -> &Gauge {
+        &self.udp_associations
+    }
+
+    /// Counts a chunk or packet that couldn't be decrypted with AEAD ciphers
+    pub fn incr_aead_decrypt_failures(&self) {
+        self.aead_decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a repeated IV, salt or packet ID
+    pub fn incr_replay_hits(&self) {
+        self.replay_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client rejected by ACL rules
+    pub fn incr_acl_client_rejects(&self) {
+        self.acl_client_rejects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an outbound address rejected by ACL rules
+    pub fn incr_acl_outbound_rejects(&self) {
+        self.acl_outbound_rejects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Exports traffic of servers in `flow_stat` until it is dropped
+    pub fn register_flow_stat(&self, flow_stat: &SharedMultiServerFlowStatistic) {
+        let mut flow_stats = self.flow_stats.lock();
+        flow_stats.retain(|s| s.strong_count() > 0);
+        flow_stats.push(Arc::downgrade(flow_stat));
+    }
+
+    fn write_to(&self, output: &mut String) {
+        write_metric(
+            output,
+            "shadowsocks_active_tcp_connections",
+            "gauge",
+            "Active TCP connections",
+            &[("", self.active_tcp_connections())],
+        );
+        write_metric(
+            output,
+            "shadowsocks_active_udp_associations",
+            "gauge",
+            "Active UDP associations",
+            &[("", self.udp_associations.get())],
+        );
+        write_metric(
+            output,
+            "shadowsocks_aead_decrypt_failures_total",
+            "counter",
+            "Chunks or packets failed to be decrypted with AEAD ciphers",
+            &[("", self.aead_decrypt_failures.load(Ordering::Relaxed))],
+        );
+        write_metric(
+            output,
+            "shadowsocks_replay_hits_total",
+            "counter",
+            "Repeated IVs, salts or packet IDs",
+            &[("", self.replay_hits.load(Ordering::Relaxed))],
+        );
+        write_metric(
+            output,
+            "shadowsocks_acl_rejects_total",
+            "counter",
+            "Clients or outbound addresses rejected by ACL rules",
+            &[
+                ("target=\"client\"", self.acl_client_rejects.load(Ordering::Relaxed)),
+                ("target=\"outbound\"", self.acl_outbound_rejects.load(Ordering::Relaxed)),
+            ],
+        );
+
+        let flow_stats: Vec<_> = self.flow_stats.lock().iter().filter_map(Weak::upgrade).collect();
+        if flow_stats.is_empty() {
+            return;
+        }
+
+        let counters: [(&str, &str, fn(&FlowStatistic) -> u64); 2] = [
+            (
+                "shadowsocks_server_tx_bytes_total",
+                "Bytes sent by servers",
+                FlowStatistic::tx,
+            ),
+            (
+                "shadowsocks_server_rx_bytes_total",
+                "Bytes received by servers",
+                FlowStatistic::rx,
+            ),
+        ];
+
+        for &(name, help, value) in &counters {
+            let mut samples = Vec::new();
+            for flow_stat in &flow_stats {
+                for (addr, stat) in flow_stat.iter() {
+                    samples.push((format!("server=\"{}\",protocol=\"tcp\"", addr), value(stat.tcp())));
+                    samples.push((format!("server=\"{}\",protocol=\"udp\"", addr), value(stat.udp())));
+                }
+            }
+            write_metric(output, name, "counter", help, &samples);
+        }
+    }
+}
+
+fn write_metric<L, V>(output: &mut String, name: &str, ty: &str, help: &str, samples: &[(L, V)])
+where
+    L: AsRef<str>,
+    V: Display,
+{
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, ty);
+
+    for (labels, value) in samples {
+        let labels = labels.as_ref();
+        if labels.is_empty() {
+            let _ = writeln!(output, "{} {}", name, value);
+        } else {
+            let _ = writeln!(output, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+async fn write_balancer<S>(output: &mut String, balancer: &PingBalancer<S>)
+where
+    S: ServerData + 'static,
+{
+    let protocol = match balancer.server_type() {
+        ServerType::Tcp => "tcp",
+        ServerType::Udp => "udp",
+    };
+
+    let mut scores = Vec::new();
+    let mut rtts = Vec::new();
+    let mut fail_rates = Vec::new();
+
+    for server in balancer.servers() {
+        let snapshot = server.snapshot().await;
+        let labels = format!("server=\"{}\"", server.server_config().addr());
+
+        scores.push((labels.clone(), snapshot.score));
+        rtts.push((labels.clone(), snapshot.rtt));
+        fail_rates.push((labels, snapshot.fail_rate));
+    }
+
+    write_metric(
+        output,
+        &format!("shadowsocks_balancer_{}_server_score", protocol),
+        "gauge",
+        "Score of servers in load balancer, the lower the better",
+        &scores,
+    );
+    write_metric(
+        output,
+        &format!("shadowsocks_balancer_{}_server_rtt_milliseconds", protocol),
+        "gauge",
+        "Median of probing latencies of servers in load balancer",
+        &rtts,
+    );
+    write_metric(
+        output,
+        &format!("shadowsocks_balancer_{}_server_fail_rate", protocol),
+        "gauge",
+        "Rate of failed probes of servers in load balancer",
+        &fail_rates,
+    );
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    server_state: SharedServerState,
+    balancers: Balancers,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+
+    let mut output = String::new();
+    server_state.metrics().write_to(&mut output);
+
+    if let Some(ref balancer) = balancers.tcp {
+        write_balancer(&mut output, balancer).await;
+    }
+    if let Some(ref balancer) = balancers.udp {
+        write_balancer(&mut output, balancer).await;
+    }
+
+    let resp = Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(output))
+        .unwrap();
+    Ok(resp)
+}
+
+/// Starts a HTTP server exporting metrics on `/metrics`
+///
+/// Statistic data of servers in `balancers` are also exported
+pub(crate) async fn run(addr: SocketAddr, server_state: SharedServerState, balancers: Balancers) -> io::Result<()> {
+    let make_service = make_service_fn(|_| {
+        let server_state = server_state.clone();
+        let balancers = balancers.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                handle_request(req, server_state.clone(), balancers.clone())
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.http1_only(true).serve(make_service),
+        Err(err) => {
+            error!("failed to listen on {} for metrics, {}", addr, err);
+            return Err(io::Error::new(ErrorKind::Other, err));
+        }
+    };
+    info!("shadowsocks metrics listening on {}", server.local_addr());
+
+    if let Err(err) = server.await {
+        error!("metrics server exited with error: {}", err);
+        return Err(io::Error::new(ErrorKind::Other, err));
+    }
+
+    Ok(())
+}
@@ -7,8 +7,11 @@ pub(crate) mod flow;
 pub(crate) mod loadbalancing;
 pub mod local;
 pub mod manager;
+pub(crate) mod metrics;
+pub(crate) mod rate_limit;
 pub(crate) mod redir;
 pub mod server;
+pub mod socks4;
 pub mod socks5;
 pub(crate) mod sys;
 pub mod tcprelay;
@@ -0,0 +1,292 @@
+//! Token bucket rate limiters for bandwidth of servers
+
+use std::{
+    cmp,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+    u64,
+};
+
+use futures::{future, ready};
+use spin::Mutex;
+use tokio::time::{self, Delay};
+
+use crate::{config::RateLimitConfig, context::Context as SsContext};
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket that allows `rate` bytes per second, with bursts up to 1 second
+///
+/// Tokens could be overdrawn, following traffic will wait until the debt is paid off
+pub struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+/// Shared reference for `RateLimiter`
+pub type SharedRateLimiter = Arc<RateLimiter>;
+
+impl RateLimiter {
+    /// Create a limiter with `rate` bytes per second
+    pub fn new(rate: u64) -> RateLimiter {
+        assert!(rate > 0, "rate limit must be greater than 0");
+
+        RateLimiter {
+            rate: rate as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Create a new shared reference of RateLimiter
+    pub fn new_shared(rate: u64) -> SharedRateLimiter {
+        Arc::new(RateLimiter::new(rate))
+    }
+
+    /// Bytes allowed to be transferred now, or the duration to wait until there are some
+    fn available(&self) -> Result<u64, Duration> {
+        let mut bucket = self.bucket.lock();
+
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            Ok(bucket.tokens as u64)
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn consume(&self, n: usize) {
+        self.bucket.lock().tokens -= n as f64;
+    }
+}
+
+/// Limiters for both directions, `None` means unlimited
+#[derive(Clone, Default)]
+pub struct BandwidthLimiter {
+    upload: Option<SharedRateLimiter>,
+    download: Option<SharedRateLimiter>,
+}
+
+impl BandwidthLimiter {
+    /// Create limiters from configuration
+    pub fn new(config: &RateLimitConfig) -> BandwidthLimiter {
+        BandwidthLimiter {
+            upload: config.upload.map(RateLimiter::new_shared),
+            download: config.download.map(RateLimiter::new_shared),
+        }
+    }
+}
+
+/// Rate limiters for one direction of traffic, traffic is allowed only if all of them have tokens
+#[derive(Default)]
+pub struct RateLimiters {
+    limiters: Vec<SharedRateLimiter>,
+    delay: Option<Delay>,
+}
+
+impl RateLimiters {
+    fn push(&mut self, limiter: Option<&SharedRateLimiter>) {
+        if let Some(limiter) = limiter {
+            self.limiters.push(limiter.clone());
+        }
+    }
+
+    fn available(&self) -> Result<u64, Duration> {
+        let mut allowed = u64::MAX;
+        for limiter in &self.limiters {
+            allowed = cmp::min(allowed, limiter.available()?);
+        }
+        Ok(allowed)
+    }
+
+    /// Polls until all limiters have tokens, returns the number of bytes allowed to be transferred (at most `n`)
+    ///
+    /// Bytes actually transferred have to be reported by `consume`
+    pub fn poll_acquire(&mut self, cx: &mut Context<'_>, n: usize) -> Poll<usize> {
+        loop {
+            if let Some(ref mut delay) = self.delay {
+                ready!(Pin::new(delay).poll(cx));
+                self.delay = None;
+            }
+
+            match self.available() {
+                Ok(allowed) => return Poll::Ready(cmp::min(n as u64, allowed) as usize),
+                Err(wait) => self.delay = Some(time::delay_for(wait)),
+            }
+        }
+    }
+
+    /// Consumes `n` bytes from all limiters
+    pub fn consume(&self, n: usize) {
+        for limiter in &self.limiters {
+            limiter.consume(n);
+        }
+    }
+
+    /// Waits until all limiters have tokens, and consumes `n` bytes
+    ///
+    /// For packets that couldn't be split, such as UDP packets
+    pub async fn acquire(&mut self, n: usize) {
+        future::poll_fn(|cx| self.poll_acquire(cx, n)).await;
+        self.consume(n);
+    }
+}
+
+/// Rate limiters of a client connection (or UDP association) of server
+#[derive(Default)]
+pub struct ConnectionRateLimiter {
+    upload: RateLimiters,
+    download: RateLimiters,
+}
+
+impl ConnectionRateLimiter {
+    /// Limiters for a new connection of server `svr_idx`
+    ///
+    /// Includes limiters of the connection itself, limiters shared by the server, and global limiters
+    pub fn new(context: &SsContext, svr_idx: usize) -> ConnectionRateLimiter {
+        let connection = BandwidthLimiter::new(context.server_config(svr_idx).connection_rate_limit());
+        let server = context.server_rate_limiter(svr_idx);
+        let global = context.global_rate_limiter();
+
+        let mut limiter = ConnectionRateLimiter::default();
+        for l in &[&connection, server, global] {
+            limiter.upload.push(l.upload.as_ref());
+            limiter.download.push(l.download.as_ref());
+        }
+        limiter
+    }
+
+    /// Limiters for traffic from clients to server
+    pub fn upload(&mut self) -> &mut RateLimiters {
+        &mut self.upload
+    }
+
+    /// Limiters for traffic from server to clients
+    pub fn download(&mut self) -> &mut RateLimiters {
+        &mut self.download
+    }
+
+    /// Split into limiters of upload and download traffic
+    pub fn split(self) -> (RateLimiters, RateLimiters) {
+        (self.upload, self.download)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::runtime::Builder;
+
+    // Pretends that `elapsed` has passed since the last refill
+    fn rewind(limiter: &RateLimiter, elapsed: Duration) {
+        let mut bucket = limiter.bucket.lock();
+        bucket.last_refill -= elapsed;
+    }
+
+    #[test]
+    fn test_burst() {
+        let limiter = RateLimiter::new(1000);
+        assert_eq!(limiter.available(), Ok(1000));
+
+        // Tokens are never accumulated more than 1 second
+        rewind(&limiter, Duration::from_secs(10));
+        assert_eq!(limiter.available(), Ok(1000));
+
+        limiter.consume(1000);
+        let wait = limiter.available().unwrap_err();
+        assert!(wait <= Duration::from_millis(1), "{:?}", wait);
+    }
+
+    #[test]
+    fn test_refill() {
+        let limiter = RateLimiter::new(1000);
+        limiter.consume(1000);
+
+        rewind(&limiter, Duration::from_millis(500));
+        let allowed = limiter.available().unwrap();
+        assert!(allowed >= 500 && allowed <= 510, "{}", allowed);
+
+        limiter.consume(allowed as usize);
+        assert!(limiter.available().is_err());
+    }
+
+    #[test]
+    fn test_overdrawn() {
+        let limiter = RateLimiter::new(1000);
+
+        // Large writes overdraw the bucket, the debt has to be paid off first
+        limiter.consume(3000);
+        let wait = limiter.available().unwrap_err();
+        assert!(
+            wait > Duration::from_millis(1990) && wait <= Duration::from_millis(2001),
+            "{:?}",
+            wait
+        );
+
+        rewind(&limiter, Duration::from_secs(1));
+        let wait = limiter.available().unwrap_err();
+        assert!(
+            wait > Duration::from_millis(990) && wait <= Duration::from_millis(1001),
+            "{:?}",
+            wait
+        );
+
+        rewind(&limiter, Duration::from_secs(2));
+        assert_eq!(limiter.available(), Ok(1000));
+    }
+
+    #[test]
+    fn test_acquire_waits() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+
+        rt.block_on(async {
+            let limiter = RateLimiter::new_shared(10_000);
+
+            let mut limiters = RateLimiters::default();
+            limiters.push(Some(&limiter));
+
+            // Whole bucket is allowed immediately
+            let start = Instant::now();
+            limiters.acquire(10_000).await;
+            assert!(start.elapsed() < Duration::from_millis(50));
+
+            // Then 2000 bytes take 200ms
+            let start = Instant::now();
+            limiters.acquire(1000).await;
+            limiters.acquire(1000).await;
+            limiters.acquire(1).await;
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        });
+    }
+
+    #[test]
+    fn test_limiters_take_minimum() {
+        let fast = RateLimiter::new_shared(1000);
+        let slow = RateLimiter::new_shared(100);
+
+        let mut limiters = RateLimiters::default();
+        limiters.push(Some(&fast));
+        limiters.push(None);
+        limiters.push(Some(&slow));
+
+        assert_eq!(limiters.available(), Ok(100));
+
+        limiters.consume(100);
+        assert!(limiters.available().is_err());
+        assert_eq!(fast.available(), Ok(900));
+    }
+}
@@ -1,13 +1,23 @@
 //! Server side
 
 use std::{
+    collections::HashMap,
     io::{self, ErrorKind},
+    mem,
     time::Duration,
 };
 
-use futures::future::{select_all, FutureExt};
-use log::{debug, error, trace, warn};
-use tokio::{runtime::Handle, time};
+use futures::{
+    future::{self, Either, FutureExt},
+    select,
+};
+use log::{debug, error, info, trace, warn};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time,
+};
 
 use crate::{
     config::Config,
@@ -15,10 +25,12 @@ use crate::{
     plugin::{PluginMode, Plugins},
     relay::{
         flow::{MultiServerFlowStatistic, SharedMultiServerFlowStatistic},
+        loadbalancing::Balancers,
         manager::ManagerDatagram,
+        metrics::run as run_metrics,
         tcprelay::server::run as run_tcp,
         udprelay::server::run as run_udp,
-        utils::set_nofile,
+        utils::{serve_until_shutdown, set_nofile, wait_shutdown},
     },
 };
 
@@ -33,13 +45,221 @@ pub async fn run(config: Config, rt: Handle) -> io::Result<()> {
     // This is for statistic purpose for [Manage Multiple Users](https://github.com/shadowsocks/shadowsocks/wiki/Manage-Multiple-Users) APIs
     let flow_stat = MultiServerFlowStatistic::new_shared(&config);
 
-    run_with(config, flow_stat, server_state).await
+    start_metrics(&config, &server_state);
+
+    run_with(config, flow_stat, server_state, None).await
+}
+
+/// Runs Relay server on server side, controlled by signals from the caller
+///
+/// Each server runs separately.
+///
+/// - Reloads servers with configurations received from `reload_rx`. Servers with unchanged configuration
+///   keep running, removed or changed servers are stopped without interrupting their established connections.
+/// - Shuts down gracefully after receiving from `shutdown_rx`, returns `Ok(())` after connections are drained.
+pub async fn run_with_signals(
+    config: Config,
+    rt: Handle,
+    mut reload_rx: mpsc::Receiver<Config>,
+    shutdown_rx: oneshot::Receiver<()>,
+) -> io::Result<()> {
+    // Create a context containing a DNS resolver and server running state flag.
+    let server_state = ServerState::new_shared(&config, rt).await;
+
+    start_metrics(&config, &server_state);
+
+    let (exit_tx, mut exit_rx) = mpsc::unbounded_channel();
+    let mut servers = ServerInstances {
+        servers: HashMap::new(),
+        server_state,
+        exit_tx,
+    };
+    servers.reload(config).await;
+
+    let mut shutdown = wait_shutdown(Some(shutdown_rx)).boxed().fuse();
+    let mut reload_closed = false;
+
+    loop {
+        let reload = if reload_closed {
+            future::pending().boxed()
+        } else {
+            reload_rx.recv().boxed()
+        };
+
+        select! {
+            config = reload.fuse() => match config {
+                Some(config) => {
+                    // Nothing is applied, including ACL, unless the whole configuration is valid
+                    if config.server.is_empty() {
+                        error!("reloaded configuration doesn't have any servers, ignored");
+                    } else if let Err(err) = config.check_integrity() {
+                        error!("reloaded configuration is invalid, ignored, error: {}", err);
+                    } else {
+                        servers.server_state.set_acl(config.acl.clone());
+                        servers.reload(config).await;
+                        info!("reloaded {} servers", servers.servers.len());
+                    }
+                }
+                // Reloading is not available anymore, keep running with current servers
+                None => reload_closed = true,
+            },
+            res = exit_rx.recv().boxed().fuse() => {
+                error!("one of servers exited unexpectly, result: {:?}", res);
+                return Err(io::Error::new(io::ErrorKind::Other, "server exited unexpectly"));
+            }
+            _ = shutdown => {
+                servers.shutdown().await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Metrics are shared by all servers, the endpoint keeps running while servers are reloaded
+fn start_metrics(config: &Config, server_state: &SharedServerState) {
+    if let Some(addr) = config.metrics_addr {
+        tokio::spawn(run_metrics(addr, server_state.clone(), Balancers::default()));
+    }
+}
+
+struct ServerInstance {
+    // For detecting changes while reloading
+    config_str: String,
+    // Dropping watcher_tx will inform server task to quit
+    watcher_tx: oneshot::Sender<()>,
+    shutdown_tx: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+    // Kept for restarting the server with changed configuration
+    flow_stat: SharedMultiServerFlowStatistic,
+}
+
+impl ServerInstance {
+    fn start(
+        config: Config,
+        server_state: SharedServerState,
+        exit_tx: mpsc::UnboundedSender<io::Result<()>>,
+        prev_flow_stat: Option<SharedMultiServerFlowStatistic>,
+    ) -> ServerInstance {
+        let config_str = config.to_string();
+        let server_addr = config.server[0].addr().clone();
+
+        let (watcher_tx, watcher_rx) = oneshot::channel::<()>();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let flow_stat = match prev_flow_stat {
+            Some(prev) => MultiServerFlowStatistic::new_shared_from(&config, &prev),
+            None => MultiServerFlowStatistic::new_shared(&config),
+        };
+
+        let server_flow_stat = flow_stat.clone();
+        let handle = tokio::spawn(async move {
+            let server = run_with(config, server_flow_stat, server_state, Some(shutdown_rx));
+
+            tokio::pin!(server);
+
+            match future::select(server, watcher_rx).await {
+                Either::Left((res, ..)) => {
+                    let _ = exit_tx.send(res);
+                }
+                Either::Right(..) => debug!("server {} stopped", server_addr),
+            }
+        });
+
+        ServerInstance {
+            config_str,
+            watcher_tx,
+            shutdown_tx,
+            handle,
+            flow_stat,
+        }
+    }
+
+    /// Stops the server, connections already established are kept running
+    async fn stop(self) {
+        drop(self.watcher_tx);
+
+        // Wait until listeners are closed, their addresses may be reused immediately
+        let _ = self.handle.await;
+    }
+
+    /// Shuts down the server gracefully, waits until its connections are drained
+    async fn shutdown(self) {
+        let _ = self.shutdown_tx.send(());
+        let _ = self.handle.await;
+    }
+}
+
+struct ServerInstances {
+    // Keyed by server address
+    servers: HashMap<String, ServerInstance>,
+    server_state: SharedServerState,
+    exit_tx: mpsc::UnboundedSender<io::Result<()>>,
+}
+
+impl ServerInstances {
+    async fn reload(&mut self, mut config: Config) {
+        // Each server runs with its own Config
+        let server_configs = mem::replace(&mut config.server, Vec::new());
+        let configs = server_configs
+            .into_iter()
+            .map(|svr_cfg| {
+                let mut config = config.clone();
+                config.server.push(svr_cfg);
+                (config.server[0].addr().to_string(), config)
+            })
+            .collect::<Vec<_>>();
+
+        // Stop removed servers first, new servers may listen on the same ports
+        let removed = self
+            .servers
+            .keys()
+            .filter(|addr| configs.iter().all(|(a, ..)| a != *addr))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for addr in removed {
+            if let Some(inst) = self.servers.remove(&addr) {
+                info!("stopping removed server {}", addr);
+                inst.stop().await;
+            }
+        }
+
+        for (addr, config) in configs {
+            let prev_flow_stat = match self.servers.remove(&addr) {
+                Some(inst) if inst.config_str == config.to_string() => {
+                    self.servers.insert(addr, inst);
+                    continue;
+                }
+                Some(inst) => {
+                    info!("restarting server {} with changed configuration", addr);
+
+                    // Traffic usage continues on the restarted server
+                    let flow_stat = inst.flow_stat.clone();
+                    inst.stop().await;
+                    Some(flow_stat)
+                }
+                None => {
+                    debug!("starting server {}", addr);
+                    None
+                }
+            };
+
+            let inst = ServerInstance::start(config, self.server_state.clone(), self.exit_tx.clone(), prev_flow_stat);
+            self.servers.insert(addr, inst);
+        }
+    }
+
+    /// Shuts down all servers gracefully, they are drained concurrently
+    async fn shutdown(self) {
+        future::join_all(self.servers.into_iter().map(|(_, inst)| inst.shutdown())).await;
+    }
 }
 
 pub(crate) async fn run_with(
     mut config: Config,
     flow_stat: SharedMultiServerFlowStatistic,
     server_stat: SharedServerState,
+    shutdown_rx: Option<oneshot::Receiver<()>>,
 ) -> io::Result<()> {
     trace!("initializing server with {:?}", config);
 
@@ -66,11 +286,12 @@ pub(crate) async fn run_with(
     let mode = config.mode;
 
     let mut vf = Vec::new();
+    let mut plugins_fut = None;
 
     let context = if mode.enable_tcp() {
         if config.has_server_plugins() {
             let plugins = Plugins::launch_plugins(&mut config, PluginMode::Client)?;
-            vf.push(plugins.into_future().boxed());
+            plugins_fut = Some(plugins.into_future().boxed());
         }
 
         let context = Context::new_shared(config, server_stat);
@@ -83,6 +304,8 @@ pub(crate) async fn run_with(
         Context::new_shared(config, server_stat)
     };
 
+    context.metrics().register_flow_stat(&flow_stat);
+
     if mode.enable_udp() {
         // Run UDP relay before starting plugins
         // Because plugins doesn't support UDP relay
@@ -98,13 +321,7 @@ pub(crate) async fn run_with(
         vf.push(report_fut.boxed());
     }
 
-    let (res, ..) = select_all(vf.into_iter()).await;
-    error!("one of servers exited unexpectly, result: {:?}", res);
-
-    // Tells all detached tasks to exit
-    context.set_server_stopped();
-
-    Err(io::Error::new(io::ErrorKind::Other, "server exited unexpectly"))
+    serve_until_shutdown(&context, vf, plugins_fut, shutdown_rx).await
 }
 
 async fn manager_report_task(context: SharedContext, flow_stat: SharedMultiServerFlowStatistic) -> io::Result<()> {
@@ -152,3 +369,90 @@ async fn manager_report_task(context: SharedContext, flow_stat: SharedMultiServe
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use tokio::{net::TcpStream, runtime::Builder};
+
+    use crate::{
+        config::{ConfigType, Mode, ServerConfig},
+        crypto::CipherType,
+    };
+
+    fn server_config(servers: &[(&str, &str)]) -> Config {
+        let mut config = Config::new(ConfigType::Server);
+        config.mode = Mode::TcpOnly;
+        for &(addr, password) in servers {
+            config.server.push(ServerConfig::basic(
+                addr.parse().unwrap(),
+                password.to_owned(),
+                CipherType::Aes256Gcm,
+            ));
+        }
+        config
+    }
+
+    #[test]
+    fn test_reload_servers() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let config = server_config(&[
+                ("127.0.0.1:8401", "test-password"),
+                ("127.0.0.1:8402", "test-password"),
+                ("127.0.0.1:8404", "test-password"),
+            ]);
+
+            let server_state = ServerState::new_shared(&config, rt_handle).await;
+            let (exit_tx, _exit_rx) = mpsc::unbounded_channel();
+            let mut servers = ServerInstances {
+                servers: HashMap::new(),
+                server_state,
+                exit_tx,
+            };
+
+            servers.reload(config).await;
+            time::delay_for(Duration::from_millis(200)).await;
+
+            assert_eq!(servers.servers.len(), 3);
+            TcpStream::connect("127.0.0.1:8404").await.unwrap();
+
+            let unchanged_stat = servers.servers["127.0.0.1:8401"].flow_stat.clone();
+            let changed_stat = servers.servers["127.0.0.1:8402"].flow_stat.clone();
+            changed_stat.get(8402).unwrap().tcp().incr_tx(100);
+
+            // 8401 is unchanged, 8402 is changed, 8403 is added and 8404 is removed
+            let config = server_config(&[
+                ("127.0.0.1:8401", "test-password"),
+                ("127.0.0.1:8402", "changed-test-password"),
+                ("127.0.0.1:8403", "test-password"),
+            ]);
+            servers.reload(config).await;
+            time::delay_for(Duration::from_millis(200)).await;
+
+            let mut addrs = servers.servers.keys().cloned().collect::<Vec<_>>();
+            addrs.sort();
+            assert_eq!(addrs, ["127.0.0.1:8401", "127.0.0.1:8402", "127.0.0.1:8403"]);
+
+            // Unchanged server keeps running
+            assert!(Arc::ptr_eq(
+                &servers.servers["127.0.0.1:8401"].flow_stat,
+                &unchanged_stat
+            ));
+
+            // Changed server is restarted, traffic usage continues
+            let restarted_stat = &servers.servers["127.0.0.1:8402"].flow_stat;
+            assert!(!Arc::ptr_eq(restarted_stat, &changed_stat));
+            assert_eq!(restarted_stat.get(8402).unwrap().trans_stat(), 100);
+
+            TcpStream::connect("127.0.0.1:8402").await.unwrap();
+            TcpStream::connect("127.0.0.1:8403").await.unwrap();
+            assert!(TcpStream::connect("127.0.0.1:8404").await.is_err());
+        });
+    }
+}
@@ -0,0 +1,389 @@
+//! Socks4a protocol definition
+//!
+//! Implements [SOCKS Protocol Version 4](https://www.openssh.com/txt/socks4.protocol) and
+//! [SOCKS 4A](https://www.openssh.com/txt/socks4a.protocol) proxy protocol
+
+use std::{
+    fmt,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+use bytes::{BufMut, BytesMut};
+use tokio::{net::TcpStream, prelude::*};
+
+use super::socks5::Address;
+
+pub use self::consts::SOCKS4_VERSION;
+
+#[rustfmt::skip]
+mod consts {
+    pub const SOCKS4_VERSION:                                   u8 = 0x04;
+
+    pub const SOCKS4_COMMAND_CONNECT:                           u8 = 0x01;
+    pub const SOCKS4_COMMAND_BIND:                              u8 = 0x02;
+
+    pub const SOCKS4_RESULT_REQUEST_GRANTED:                    u8 = 0x5a;
+    pub const SOCKS4_RESULT_REQUEST_REJECTED_OR_FAILED:         u8 = 0x5b;
+    pub const SOCKS4_RESULT_REQUEST_REJECTED_CANNOT_CONNECT:    u8 = 0x5c;
+    pub const SOCKS4_RESULT_REQUEST_REJECTED_DIFFERENT_USER_ID: u8 = 0x5d;
+}
+
+// Limits of USERID and domain name, which are both NULL terminated
+const MAX_USER_ID_LEN: usize = 255;
+const MAX_DOMAIN_NAME_LEN: usize = 255;
+
+/// SOCKS4 command
+#[derive(Clone, Debug, Copy)]
+pub enum Command {
+    /// CONNECT command
+    Connect,
+    /// BIND command
+    Bind,
+}
+
+impl Command {
+    #[inline]
+    #[rustfmt::skip]
+    fn as_u8(self) -> u8 {
+        match self {
+            Command::Connect => consts::SOCKS4_COMMAND_CONNECT,
+            Command::Bind    => consts::SOCKS4_COMMAND_BIND,
+        }
+    }
+
+    #[inline]
+    #[rustfmt::skip]
+    fn from_u8(code: u8) -> Option<Command> {
+        match code {
+            consts::SOCKS4_COMMAND_CONNECT => Some(Command::Connect),
+            consts::SOCKS4_COMMAND_BIND    => Some(Command::Bind),
+            _                              => None,
+        }
+    }
+}
+
+/// SOCKS4 result code
+#[derive(Clone, Debug, Copy)]
+pub enum ResultCode {
+    /// Request granted
+    RequestGranted,
+    /// Request rejected or failed
+    RequestRejectedOrFailed,
+    /// Request rejected because SOCKS server cannot connect to identd on the client
+    RequestRejectedCannotConnect,
+    /// Request rejected because the client program and identd report different user-ids
+    RequestRejectedDifferentUserId,
+    /// Other replies
+    Other(u8),
+}
+
+impl ResultCode {
+    #[inline]
+    #[rustfmt::skip]
+    fn as_u8(self) -> u8 {
+        match self {
+            ResultCode::RequestGranted                 => consts::SOCKS4_RESULT_REQUEST_GRANTED,
+            ResultCode::RequestRejectedOrFailed        => consts::SOCKS4_RESULT_REQUEST_REJECTED_OR_FAILED,
+            ResultCode::RequestRejectedCannotConnect   => consts::SOCKS4_RESULT_REQUEST_REJECTED_CANNOT_CONNECT,
+            ResultCode::RequestRejectedDifferentUserId => consts::SOCKS4_RESULT_REQUEST_REJECTED_DIFFERENT_USER_ID,
+            ResultCode::Other(c)                       => c,
+        }
+    }
+
+    #[inline]
+    #[rustfmt::skip]
+    fn from_u8(code: u8) -> ResultCode {
+        match code {
+            consts::SOCKS4_RESULT_REQUEST_GRANTED                    => ResultCode::RequestGranted,
+            consts::SOCKS4_RESULT_REQUEST_REJECTED_OR_FAILED         => ResultCode::RequestRejectedOrFailed,
+            consts::SOCKS4_RESULT_REQUEST_REJECTED_CANNOT_CONNECT    => ResultCode::RequestRejectedCannotConnect,
+            consts::SOCKS4_RESULT_REQUEST_REJECTED_DIFFERENT_USER_ID => ResultCode::RequestRejectedDifferentUserId,
+            _                                                        => ResultCode::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for ResultCode {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResultCode::RequestGranted                 => f.write_str("request granted"),
+            ResultCode::RequestRejectedOrFailed        => f.write_str("request rejected or failed"),
+            ResultCode::RequestRejectedCannotConnect   => f.write_str("request rejected because SOCKS server cannot connect to identd on the client"),
+            ResultCode::RequestRejectedDifferentUserId => f.write_str("request rejected because the client program and identd report different user-ids"),
+            ResultCode::Other(c)                       => write!(f, "other result code {}", c),
+        }
+    }
+}
+
+/// Read a NULL terminated string, the NULL is consumed but not included
+///
+/// Bytes are peeked in chunks and only the string is consumed, data sent after it are kept in the stream
+async fn read_null_terminated(s: &mut TcpStream, max_len: usize) -> io::Result<Vec<u8>> {
+    use std::io::{Error, ErrorKind};
+
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 256];
+    loop {
+        let n = s.peek(&mut chunk).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        let (len, terminated) = match chunk[..n].iter().position(|b| *b == 0) {
+            Some(pos) => (pos, true),
+            None => (n, false),
+        };
+
+        if buf.len() + len > max_len {
+            let err = Error::new(ErrorKind::InvalidData, "NULL terminated string is too long");
+            return Err(err);
+        }
+        buf.extend_from_slice(&chunk[..len]);
+
+        if terminated {
+            let _ = s.read_exact(&mut chunk[..len + 1]).await?;
+            return Ok(buf);
+        }
+        let _ = s.read_exact(&mut chunk[..len]).await?;
+    }
+}
+
+/// SOCKS4 (SOCKS4a) handshake request packet
+///
+/// ```plain
+/// +----+----+---------+-------+----------+------+
+/// | VN | CD | DSTPORT | DSTIP |  USERID  | NULL |
+/// +----+----+---------+-------+----------+------+
+/// | 1  | 1  |    2    |   4   | Variable |  1   |
+/// +----+----+---------+-------+----------+------+
+/// ```
+///
+/// For SOCKS4a, `DSTIP` is set to `0.0.0.x` (`x` is nonzero), and the domain name follows the `USERID`
+///
+/// ```plain
+/// +----+----+---------+---------+----------+------+------------+------+
+/// | VN | CD | DSTPORT |  DSTIP  |  USERID  | NULL |  HOSTNAME  | NULL |
+/// +----+----+---------+---------+----------+------+------------+------+
+/// | 1  | 1  |    2    | 0.0.0.x | Variable |  1   |  Variable  |  1   |
+/// +----+----+---------+---------+----------+------+------------+------+
+/// ```
+#[derive(Clone, Debug)]
+pub struct HandshakeRequest {
+    pub cd: Command,
+    pub dst: Address,
+    pub user_id: Vec<u8>,
+}
+
+impl HandshakeRequest {
+    /// Creates a handshake request
+    pub fn new(cd: Command, dst: Address, user_id: Vec<u8>) -> HandshakeRequest {
+        HandshakeRequest { cd, dst, user_id }
+    }
+
+    /// Read from a TCP stream
+    ///
+    /// Only the request is consumed, data sent by client after the request are kept in the stream
+    pub async fn read_from(r: &mut TcpStream) -> io::Result<HandshakeRequest> {
+        use std::io::{Error, ErrorKind};
+
+        let mut buf = [0u8; 8];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let vn = buf[0];
+        if vn != consts::SOCKS4_VERSION {
+            let err = Error::new(ErrorKind::InvalidData, format!("unsupported socks version {:#x}", vn));
+            return Err(err);
+        }
+
+        let cd = buf[1];
+        let command = match Command::from_u8(cd) {
+            Some(c) => c,
+            None => {
+                let err = Error::new(ErrorKind::InvalidData, format!("unsupported command {:#x}", cd));
+                return Err(err);
+            }
+        };
+
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+        let user_id = read_null_terminated(r, MAX_USER_ID_LEN).await?;
+
+        let octets = ip.octets();
+        let dst = if octets[0] == 0 && octets[1] == 0 && octets[2] == 0 && octets[3] != 0 {
+            // SOCKS4a, domain name follows the USERID
+            let host = read_null_terminated(r, MAX_DOMAIN_NAME_LEN).await?;
+            match String::from_utf8(host) {
+                Ok(host) => Address::DomainNameAddress(host, port),
+                Err(..) => {
+                    let err = Error::new(ErrorKind::InvalidData, "invalid address encoding");
+                    return Err(err);
+                }
+            }
+        } else {
+            Address::SocketAddress(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        };
+
+        Ok(HandshakeRequest {
+            cd: command,
+            dst,
+            user_id,
+        })
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    ///
+    /// Panics if `dst` is an IPv6 address, which is not supported by SOCKS4
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&[consts::SOCKS4_VERSION, self.cd.as_u8()]);
+
+        match self.dst {
+            Address::SocketAddress(SocketAddr::V4(ref saddr)) => {
+                buf.put_u16(saddr.port());
+                buf.put_slice(&saddr.ip().octets());
+                buf.put_slice(&self.user_id);
+                buf.put_u8(0);
+            }
+            Address::SocketAddress(SocketAddr::V6(..)) => panic!("SOCKS4 doesn't support IPv6 address"),
+            Address::DomainNameAddress(ref dname, port) => {
+                buf.put_u16(port);
+                buf.put_slice(&[0, 0, 0, 1]);
+                buf.put_slice(&self.user_id);
+                buf.put_u8(0);
+                buf.put_slice(dname.as_bytes());
+                buf.put_u8(0);
+            }
+        }
+    }
+
+    /// Length in bytes
+    pub fn serialized_len(&self) -> usize {
+        let mut len = 1 + 1 + 2 + 4 + self.user_id.len() + 1;
+        if let Address::DomainNameAddress(ref dname, _) = self.dst {
+            len += dname.len() + 1;
+        }
+        len
+    }
+}
+
+/// SOCKS4 handshake response packet
+///
+/// ```plain
+/// +----+----+---------+-------+
+/// | VN | CD | DSTPORT | DSTIP |
+/// +----+----+---------+-------+
+/// | 1  | 1  |    2    |   4   |
+/// +----+----+---------+-------+
+/// ```
+#[derive(Clone, Debug)]
+pub struct HandshakeResponse {
+    pub cd: ResultCode,
+    pub dst: SocketAddrV4,
+}
+
+impl HandshakeResponse {
+    /// Creates a response, `DSTPORT` and `DSTIP` are ignored by the client for CONNECT command
+    pub fn new(cd: ResultCode) -> HandshakeResponse {
+        HandshakeResponse {
+            cd,
+            dst: SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0),
+        }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<HandshakeResponse>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 8];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let vn = buf[0];
+        if vn != 0 {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(ErrorKind::InvalidData, format!("unsupported reply version {:#x}", vn));
+            return Err(err);
+        }
+
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+        Ok(HandshakeResponse {
+            cd: ResultCode::from_u8(buf[1]),
+            dst: SocketAddrV4::new(ip, port),
+        })
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        // VN in response is the version of the reply code, which is 0
+        buf.put_slice(&[0x00, self.cd.as_u8()]);
+        buf.put_u16(self.dst.port());
+        buf.put_slice(&self.dst.ip().octets());
+    }
+
+    /// Length in bytes
+    pub fn serialized_len(&self) -> usize {
+        8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::{net::TcpListener, runtime::Builder};
+
+    #[test]
+    fn test_handshake_request_read_from() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut c = TcpStream::connect(addr).await.unwrap();
+            let (mut s, _) = listener.accept().await.unwrap();
+
+            // Client sends its first payload without waiting for the response
+            let dst = Address::DomainNameAddress("a".repeat(MAX_DOMAIN_NAME_LEN), 80);
+            let req = HandshakeRequest::new(Command::Connect, dst.clone(), vec![b'u'; MAX_USER_ID_LEN]);
+            req.write_to(&mut c).await.unwrap();
+            c.write_all(b"HEllo").await.unwrap();
+
+            let req = HandshakeRequest::read_from(&mut s).await.unwrap();
+            assert_eq!(req.dst, dst);
+            assert_eq!(req.user_id, vec![b'u'; MAX_USER_ID_LEN]);
+
+            let mut buf = [0u8; 5];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"HEllo");
+
+            // USERID is too long
+            let req = HandshakeRequest::new(Command::Connect, dst, vec![b'u'; MAX_USER_ID_LEN + 1]);
+            req.write_to(&mut c).await.unwrap();
+            let err = HandshakeRequest::read_from(&mut s).await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+}
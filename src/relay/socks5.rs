@@ -21,6 +21,9 @@ pub use self::consts::{
     SOCKS5_AUTH_METHOD_NONE,
     SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
     SOCKS5_AUTH_METHOD_PASSWORD,
+    SOCKS5_AUTH_PASSWORD_FAILED,
+    SOCKS5_AUTH_PASSWORD_SUCCEEDED,
+    SOCKS5_VERSION,
 };
 
 #[rustfmt::skip]
@@ -32,9 +35,14 @@ mod consts {
     pub const SOCKS5_AUTH_METHOD_PASSWORD:             u8 = 0x02;
     pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE:       u8 = 0xff;
 
+    pub const SOCKS5_AUTH_PASSWORD_VERSION:            u8 = 0x01;
+    pub const SOCKS5_AUTH_PASSWORD_SUCCEEDED:          u8 = 0x00;
+    pub const SOCKS5_AUTH_PASSWORD_FAILED:             u8 = 0x01;
+
     pub const SOCKS5_CMD_TCP_CONNECT:                  u8 = 0x01;
     pub const SOCKS5_CMD_TCP_BIND:                     u8 = 0x02;
     pub const SOCKS5_CMD_UDP_ASSOCIATE:                u8 = 0x03;
+    pub const SOCKS5_CMD_MULTIPLEX:                    u8 = 0x7f;
 
     pub const SOCKS5_ADDR_TYPE_IPV4:                   u8 = 0x01;
     pub const SOCKS5_ADDR_TYPE_DOMAIN_NAME:            u8 = 0x03;
@@ -56,10 +64,12 @@ mod consts {
 pub enum Command {
     /// CONNECT command (TCP tunnel)
     TcpConnect,
-    /// BIND command (Not supported in ShadowSocks)
+    /// BIND command
     TcpBind,
     /// UDP ASSOCIATE command
     UdpAssociate,
+    /// Starts a multiplexing session, extension of shadowsocks, only sent through encrypted connections
+    Multiplex,
 }
 
 impl Command {
@@ -70,6 +80,7 @@ impl Command {
             Command::TcpConnect   => consts::SOCKS5_CMD_TCP_CONNECT,
             Command::TcpBind      => consts::SOCKS5_CMD_TCP_BIND,
             Command::UdpAssociate => consts::SOCKS5_CMD_UDP_ASSOCIATE,
+            Command::Multiplex    => consts::SOCKS5_CMD_MULTIPLEX,
         }
     }
 
@@ -80,6 +91,7 @@ impl Command {
             consts::SOCKS5_CMD_TCP_CONNECT   => Some(Command::TcpConnect),
             consts::SOCKS5_CMD_TCP_BIND      => Some(Command::TcpBind),
             consts::SOCKS5_CMD_UDP_ASSOCIATE => Some(Command::UdpAssociate),
+            consts::SOCKS5_CMD_MULTIPLEX     => Some(Command::Multiplex),
             _                                => None,
         }
     }
@@ -436,6 +448,24 @@ fn get_addr_len(atyp: &Address) -> usize {
     }
 }
 
+/// Get length of the serialized `Address` at the beginning of `buf`
+///
+/// Returns `None` if `buf` doesn't start with a complete `Address`
+pub fn peek_addr_len(buf: &[u8]) -> Option<usize> {
+    let addr_len = match *buf.first()? {
+        consts::SOCKS5_ADDR_TYPE_IPV4 => 1 + 4 + 2,
+        consts::SOCKS5_ADDR_TYPE_IPV6 => 1 + 8 * 2 + 2,
+        consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => 1 + 1 + *buf.get(1)? as usize + 2,
+        _ => return None,
+    };
+
+    if buf.len() < addr_len {
+        None
+    } else {
+        Some(addr_len)
+    }
+}
+
 /// TCP request header after handshake
 ///
 /// ```plain
@@ -721,6 +751,162 @@ impl HandshakeResponse {
     }
 }
 
+/// Username/Password authentication request (RFC1929)
+///
+/// ```plain
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+/// ```
+#[derive(Clone, Debug)]
+pub struct PasswdAuthRequest {
+    pub uname: Vec<u8>,
+    pub passwd: Vec<u8>,
+}
+
+impl PasswdAuthRequest {
+    /// Creates an authentication request
+    pub fn new<U, P>(uname: U, passwd: P) -> PasswdAuthRequest
+    where
+        U: Into<Vec<u8>>,
+        P: Into<Vec<u8>>,
+    {
+        PasswdAuthRequest {
+            uname: uname.into(),
+            passwd: passwd.into(),
+        }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<PasswdAuthRequest>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut ver_buf = [0u8; 1];
+        let _ = r.read_exact(&mut ver_buf).await?;
+
+        let ver = ver_buf[0];
+        if ver != consts::SOCKS5_AUTH_PASSWORD_VERSION {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported password authentication version {:#x}", ver),
+            );
+            return Err(err);
+        }
+
+        let mut len_buf = [0u8; 1];
+        let _ = r.read_exact(&mut len_buf).await?;
+
+        let mut uname = vec![0u8; len_buf[0] as usize];
+        let _ = r.read_exact(&mut uname).await?;
+
+        let _ = r.read_exact(&mut len_buf).await?;
+
+        let mut passwd = vec![0u8; len_buf[0] as usize];
+        let _ = r.read_exact(&mut passwd).await?;
+
+        Ok(PasswdAuthRequest { uname, passwd })
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        let PasswdAuthRequest { ref uname, ref passwd } = *self;
+
+        assert!(
+            uname.len() <= u8::MAX as usize && passwd.len() <= u8::MAX as usize,
+            "username and password must be shorter than 256 bytes"
+        );
+
+        buf.put_u8(consts::SOCKS5_AUTH_PASSWORD_VERSION);
+        buf.put_u8(uname.len() as u8);
+        buf.put_slice(uname);
+        buf.put_u8(passwd.len() as u8);
+        buf.put_slice(passwd);
+    }
+
+    /// Get length of bytes
+    pub fn serialized_len(&self) -> usize {
+        3 + self.uname.len() + self.passwd.len()
+    }
+}
+
+/// Username/Password authentication response (RFC1929)
+///
+/// ```plain
+/// +----+--------+
+/// |VER | STATUS |
+/// +----+--------+
+/// | 1  |   1    |
+/// +----+--------+
+/// ```
+#[derive(Clone, Debug, Copy)]
+pub struct PasswdAuthResponse {
+    pub status: u8,
+}
+
+impl PasswdAuthResponse {
+    /// Creates an authentication response
+    pub fn new(status: u8) -> PasswdAuthResponse {
+        PasswdAuthResponse { status }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<PasswdAuthResponse>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 2];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let ver = buf[0];
+        let status = buf[1];
+
+        if ver != consts::SOCKS5_AUTH_PASSWORD_VERSION {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported password authentication version {:#x}", ver),
+            );
+            Err(err)
+        } else {
+            Ok(PasswdAuthResponse { status })
+        }
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(self, buf: &mut B) {
+        buf.put_slice(&[consts::SOCKS5_AUTH_PASSWORD_VERSION, self.status]);
+    }
+
+    /// Length in bytes
+    pub fn serialized_len(self) -> usize {
+        2
+    }
+}
+
 /// UDP ASSOCIATE request header
 ///
 /// ```plain
@@ -1,4 +1,7 @@
+use std::{io, sync::Once};
+
 use cfg_if::cfg_if;
+use log::warn;
 
 cfg_if! {
     if #[cfg(unix)] {
@@ -9,3 +12,18 @@ cfg_if! {
         pub use self::windows::*;
     }
 }
+
+/// Warns that `TCP_FASTOPEN_CONNECT` couldn't be set, outbound connections are made without TCP Fast Open
+///
+/// It fails for every connection if the platform or the kernel doesn't support it, so it is only warned once
+pub fn warn_tcp_fastopen_connect(err: &io::Error) {
+    static WARN_ONCE: Once = Once::new();
+
+    WARN_ONCE.call_once(|| {
+        warn!(
+            "failed to set TCP_FASTOPEN_CONNECT, connecting without TCP Fast Open, \
+             it requires Linux 4.11+ with `net.ipv4.tcp_fastopen` set to 3, error: {}",
+            err
+        );
+    });
+}
@@ -6,7 +6,9 @@ use std::{
 };
 
 use cfg_if::cfg_if;
-use tokio::net::{TcpStream, UdpSocket};
+use log::warn;
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
 use crate::context::Context;
 
@@ -88,10 +90,76 @@ cfg_if! {
     }
 }
 
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        // Linux 4.11+, not defined in `libc` for all targets
+        const TCP_FASTOPEN_CONNECT: libc::c_int = 30;
+
+        // Maximum length of pending TFO requests, same as shadowsocks-libev
+        const TCP_FASTOPEN_QUEUE_LEN: libc::c_int = 5;
+
+        fn set_tcp_option(fd: RawFd, opt: libc::c_int, value: libc::c_int) -> io::Result<()> {
+            let ret = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::IPPROTO_TCP,
+                    opt,
+                    &value as *const _ as *const _,
+                    mem::size_of_val(&value) as libc::socklen_t,
+                )
+            };
+
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Accept data in SYN on a listening socket (`TCP_FASTOPEN`)
+        pub fn set_tcp_fastopen<S: AsRawFd>(socket: &S) -> io::Result<()> {
+            set_tcp_option(socket.as_raw_fd(), libc::TCP_FASTOPEN, TCP_FASTOPEN_QUEUE_LEN)
+        }
+
+        /// Send data of the first write in SYN on a socket before connecting (`TCP_FASTOPEN_CONNECT`)
+        ///
+        /// `connect` returns immediately on the socket, connect errors are returned from the first write or read
+        pub fn set_tcp_fastopen_connect<S: AsRawFd>(socket: &S) -> io::Result<()> {
+            set_tcp_option(socket.as_raw_fd(), TCP_FASTOPEN_CONNECT, 1)
+        }
+    } else {
+        /// TCP Fast Open is only supported on Linux, always fails on other platforms
+        pub fn set_tcp_fastopen<S: AsRawFd>(_socket: &S) -> io::Result<()> {
+            Err(Error::new(ErrorKind::Other, "TCP Fast Open is only supported on Linux"))
+        }
+
+        /// TCP Fast Open is only supported on Linux, always fails on other platforms
+        pub fn set_tcp_fastopen_connect<S: AsRawFd>(_socket: &S) -> io::Result<()> {
+            Err(Error::new(ErrorKind::Other, "TCP Fast Open is only supported on Linux"))
+        }
+    }
+}
+
 /// create a new TCP stream
+///
+/// With `fast_open`, the stream is returned before the server is actually connected,
+/// so connect errors (e.g. connection refused) are only returned from the first write or read.
 #[inline(always)]
 pub async fn tcp_stream_connect(saddr: &SocketAddr, context: &Context) -> io::Result<TcpStream> {
-    let stream = TcpStream::connect(saddr).await?;
+    let stream = if context.config().fast_open {
+        let socket = match *saddr {
+            SocketAddr::V4(..) => Socket::new(Domain::ipv4(), Type::stream(), None)?,
+            SocketAddr::V6(..) => Socket::new(Domain::ipv6(), Type::stream(), None)?,
+        };
+
+        if let Err(err) = set_tcp_fastopen_connect(&socket) {
+            super::warn_tcp_fastopen_connect(&err);
+        }
+
+        // Returns immediately, SYN will be sent with the first write
+        TcpStream::connect_std(socket.into_tcp_stream(), saddr).await?
+    } else {
+        TcpStream::connect(saddr).await?
+    };
 
     // Any traffic to localhost should be protected
     // This is a workaround for VPNService
@@ -102,6 +170,19 @@ pub async fn tcp_stream_connect(saddr: &SocketAddr, context: &Context) -> io::Re
     Ok(stream)
 }
 
+/// Create a `TcpListener` binded to `addr`, accepts data in SYN if TCP Fast Open is enabled
+pub async fn tcp_listener_bind(addr: &SocketAddr, context: &Context) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+
+    if context.config().fast_open {
+        if let Err(err) = set_tcp_fastopen(&listener) {
+            warn!("failed to set TCP_FASTOPEN on listener {}, error: {}", addr, err);
+        }
+    }
+
+    Ok(listener)
+}
+
 /// Create a `UdpSocket` binded to `addr`
 #[inline(always)]
 pub async fn create_udp_socket_with_context(addr: &SocketAddr, context: &Context) -> io::Result<UdpSocket> {
@@ -121,3 +202,84 @@ pub async fn create_udp_socket_with_context(addr: &SocketAddr, context: &Context
 pub async fn create_udp_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
     UdpSocket::bind(addr).await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::{prelude::*, runtime::Builder};
+
+    use crate::{
+        config::{Config, ConfigType},
+        context::ServerState,
+    };
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn get_tcp_option(fd: RawFd, opt: libc::c_int) -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of_val(&value) as libc::socklen_t;
+        let ret = unsafe { libc::getsockopt(fd, libc::IPPROTO_TCP, opt, &mut value as *mut _ as *mut _, &mut len) };
+
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(value)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_tcp_fastopen_connect_option() {
+        let socket = Socket::new(Domain::ipv4(), Type::stream(), None).unwrap();
+
+        // Kernels before 4.11 don't know the option
+        match set_tcp_fastopen_connect(&socket) {
+            Ok(..) => assert_eq!(get_tcp_option(socket.as_raw_fd(), TCP_FASTOPEN_CONNECT).unwrap(), 1),
+            Err(err) => assert_eq!(err.raw_os_error(), Some(libc::ENOPROTOOPT)),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    #[test]
+    fn test_tcp_fastopen_connect_option() {
+        let socket = Socket::new(Domain::ipv4(), Type::stream(), None).unwrap();
+        assert!(set_tcp_fastopen_connect(&socket).is_err());
+    }
+
+    #[test]
+    fn test_tcp_stream_connect_fast_open() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let mut config = Config::new(ConfigType::Socks5Local);
+            config.fast_open = true;
+            let state = ServerState::new_shared(&config, rt_handle).await;
+            let context = Context::new_shared(config, state);
+
+            let mut listener = tcp_listener_bind(&"127.0.0.1:0".parse().unwrap(), &context)
+                .await
+                .unwrap();
+            let listen_addr = listener.local_addr().unwrap();
+
+            // Connected whether the option is supported or not, data is sent with the first write
+            let mut stream = tcp_stream_connect(&listen_addr, &context).await.unwrap();
+            stream.write_all(b"HEllo WORld").await.unwrap();
+
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 11];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"HEllo WORld");
+
+            // Refused connections are returned from the first write or read, instead of connect
+            drop(listener);
+            let result = match tcp_stream_connect(&listen_addr, &context).await {
+                Ok(mut stream) => match stream.write_all(b"HEllo WORld").await {
+                    Ok(..) => stream.read(&mut buf).await.map(|_| ()),
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            };
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::ConnectionRefused);
+        });
+    }
+}
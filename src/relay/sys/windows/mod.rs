@@ -1,6 +1,13 @@
-use std::{io, mem, net::SocketAddr, os::windows::io::AsRawSocket, ptr};
+use std::{
+    io::{self, Error, ErrorKind},
+    mem,
+    net::SocketAddr,
+    os::windows::io::AsRawSocket,
+    ptr,
+};
 
-use tokio::net::{TcpStream, UdpSocket};
+use log::warn;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use winapi::{
     shared::minwindef::{BOOL, DWORD, FALSE, LPDWORD, LPVOID},
     um::{
@@ -48,8 +55,6 @@ pub async fn create_udp_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
         );
 
         if ret == SOCKET_ERROR {
-            use std::io::Error;
-
             // Error occurs
             let err_code = WSAGetLastError();
             return Err(Error::from_raw_os_error(err_code));
@@ -59,12 +64,44 @@ pub async fn create_udp_socket(addr: &SocketAddr) -> io::Result<UdpSocket> {
     UdpSocket::from_std(socket)
 }
 
+fn tcp_fastopen_unsupported() -> Error {
+    Error::new(ErrorKind::Other, "TCP Fast Open is only supported on Linux")
+}
+
+/// TCP Fast Open is only supported on Linux, always fails on Windows
+pub fn set_tcp_fastopen<S>(_socket: &S) -> io::Result<()> {
+    Err(tcp_fastopen_unsupported())
+}
+
+/// TCP Fast Open is only supported on Linux, always fails on Windows
+pub fn set_tcp_fastopen_connect<S>(_socket: &S) -> io::Result<()> {
+    Err(tcp_fastopen_unsupported())
+}
+
 /// create a new TCP stream
 #[inline(always)]
-pub async fn tcp_stream_connect(saddr: &SocketAddr, _context: &Context) -> io::Result<TcpStream> {
+pub async fn tcp_stream_connect(saddr: &SocketAddr, context: &Context) -> io::Result<TcpStream> {
+    if context.config().fast_open {
+        super::warn_tcp_fastopen_connect(&tcp_fastopen_unsupported());
+    }
+
     TcpStream::connect(saddr).await
 }
 
+/// Create a `TcpListener` binded to `addr`
+#[inline(always)]
+pub async fn tcp_listener_bind(addr: &SocketAddr, context: &Context) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+
+    if context.config().fast_open {
+        if let Err(err) = set_tcp_fastopen(&listener) {
+            warn!("failed to set TCP_FASTOPEN on listener {}, error: {}", addr, err);
+        }
+    }
+
+    Ok(listener)
+}
+
 /// Create a `UdpSocket` binded to `addr`
 #[inline(always)]
 pub async fn create_udp_socket_with_context(addr: &SocketAddr, _context: &Context) -> io::Result<UdpSocket> {
@@ -31,6 +31,27 @@
 //! |      2       |     Fixed     |   Variable   |   Fixed    |
 //! +--------------+---------------+--------------+------------+
 //! ```
+//!
+//! AEAD 2022 protocol (SIP022) sends fixed-length headers before chunks.
+//! Chunks are the same as above, except that `DataLen` could be up to 0xFFFF.
+//!
+//! ```plain
+//! TCP request header (before encryption)
+//! +------+-----------+--------+   +---------+---------------+---------+-----------------+
+//! | TYPE | TIMESTAMP | LENGTH |   | ADDRESS | PADDING_LEN   | PADDING | INITIAL PAYLOAD |
+//! +------+-----------+--------+   +---------+---------------+---------+-----------------+
+//! |  1   |     8     |   2    |   | Variable|       2       | Variable|     Variable    |
+//! +------+-----------+--------+   +---------+---------------+---------+-----------------+
+//!  Fixed-length header             Variable-length header, length = LENGTH
+//!
+//! TCP response header (before encryption)
+//! +------+-----------+--------------+--------+   +------------+
+//! | TYPE | TIMESTAMP | REQUEST SALT | LENGTH |   | FIRST DATA |
+//! +------+-----------+--------------+--------+   +------------+
+//! |  1   |     8     |     Fixed    |   2    |   |  Variable  |
+//! +------+-----------+--------------+--------+   +------------+
+//!  Fixed-length header                            Chunk without length, length = LENGTH
+//! ```
 
 use std::{
     cmp,
@@ -45,21 +66,72 @@ use std::{
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::ready;
+use rand::{self, Rng, RngCore};
 use tokio::prelude::*;
 
-use crate::crypto::{self, BoxAeadDecryptor, BoxAeadEncryptor, CipherType};
+use crate::{
+    crypto::{self, aead2022, BoxAeadDecryptor, BoxAeadEncryptor, CipherResult, CipherType},
+    relay::socks5,
+};
 
-use super::BUFFER_SIZE;
+use super::{crypto_io::StreamType, BUFFER_SIZE};
 
 /// AEAD packet payload must be smaller than 0x3FFF
 const MAX_PACKET_SIZE: usize = 0x3FFF;
 
+/// AEAD 2022 packet payload must be smaller than 0xFFFF
+const MAX_PACKET_SIZE_2022: usize = 0xFFFF;
+
+/// Maximum length of padding in AEAD 2022 request header
+const MAX_PADDING_SIZE: usize = 900;
+
+/// Header TYPE of AEAD 2022 requests
+const AEAD2022_CLIENT_STREAM_TYPE: u8 = 0;
+/// Header TYPE of AEAD 2022 responses
+const AEAD2022_SERVER_STREAM_TYPE: u8 = 1;
+
 #[derive(Debug)]
 enum DecryptReadStep {
+    FixedHeader,
+    VariableHeader(usize),
     Length,
     Data(usize),
 }
 
+fn invalid_header_error(desc: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, desc)
+}
+
+/// Length of the request header at the beginning of `buf`
+///
+/// It could be an `Address`, or a SOCKS5 request header for extended commands (BIND)
+fn request_header_len(buf: &[u8]) -> Option<usize> {
+    if buf.first() == Some(&socks5::SOCKS5_VERSION) {
+        // VER, CMD, RSV, Address
+        socks5::peek_addr_len(buf.get(3..)?).map(|n| n + 3)
+    } else {
+        socks5::peek_addr_len(buf)
+    }
+}
+
+/// Length of the first chunk of requests (after salt), which is enough for identifying users by trial decryption
+///
+/// It is the encrypted `HeaderLen`, or the fixed-length header of AEAD 2022 protocol
+pub fn request_first_chunk_len(t: CipherType) -> usize {
+    if t.is_aead_2022() {
+        1 + 8 + 2 + t.tag_size()
+    } else {
+        2 + t.tag_size()
+    }
+}
+
+/// Check if the first chunk of a request could be decrypted with `key`
+pub fn check_request_first_chunk(t: CipherType, key: &[u8], salt: &[u8], chunk: &[u8]) -> bool {
+    let mut cipher = crypto::new_aead_decryptor(t, key, salt);
+    let mut plain = vec![0u8; chunk.len() - t.tag_size()];
+    cipher.decrypt(chunk, &mut plain).is_ok()
+}
+
 /// Reader wrapper that will decrypt data automatically
 pub struct DecryptedReader {
     buffer: BytesMut,
@@ -69,6 +141,9 @@ pub struct DecryptedReader {
     tag_size: usize,
     steps: DecryptReadStep,
     got_final: bool,
+    stream_type: Option<StreamType>,
+    local_salt: Bytes,
+    decrypt_failed: bool,
 }
 
 impl DecryptedReader {
@@ -81,9 +156,46 @@ impl DecryptedReader {
             tag_size: t.tag_size(),
             steps: DecryptReadStep::Length,
             got_final: false,
+            stream_type: None,
+            local_salt: Bytes::new(),
+            decrypt_failed: false,
         }
     }
 
+    /// Creates a reader for AEAD 2022 ciphers, which will read and verify the fixed-length header first
+    ///
+    /// `local_salt` is the salt sent by this side, clients have to check if it is echoed in server's response header
+    pub fn new_2022(
+        t: CipherType,
+        key: &[u8],
+        salt: &[u8],
+        stream_type: StreamType,
+        local_salt: Bytes,
+    ) -> DecryptedReader {
+        let mut reader = DecryptedReader::new(t, key, salt);
+        reader.steps = DecryptReadStep::FixedHeader;
+        reader.stream_type = Some(stream_type);
+        reader.local_salt = local_salt;
+        reader
+    }
+
+    /// Set data that is already read from the stream, it will be decrypted before reading more
+    pub fn set_initial_buffer(&mut self, buf: &[u8]) {
+        self.buffer.extend_from_slice(buf);
+    }
+
+    /// Check if reading failed because data couldn't be decrypted, which means it is corrupted or forged
+    pub fn decrypt_failed(&self) -> bool {
+        self.decrypt_failed
+    }
+
+    fn check_decrypted(&mut self, res: CipherResult<()>) -> CipherResult<()> {
+        if res.is_err() {
+            self.decrypt_failed = true;
+        }
+        res
+    }
+
     pub fn poll_read_decrypted<R>(
         &mut self,
         ctx: &mut Context<'_>,
@@ -101,6 +213,8 @@ impl DecryptedReader {
 
             // Refill buffer
             match self.steps {
+                DecryptReadStep::FixedHeader => ready!(self.poll_read_fixed_header(ctx, r))?,
+                DecryptReadStep::VariableHeader(len) => ready!(self.poll_read_variable_header(ctx, r, len))?,
                 DecryptReadStep::Length => ready!(self.poll_read_decrypted_length(ctx, r))?,
                 DecryptReadStep::Data(len) => ready!(self.poll_read_decrypted_data(ctx, r, len))?,
             }
@@ -113,6 +227,104 @@ impl DecryptedReader {
         Poll::Ready(Ok(n))
     }
 
+    fn poll_read_fixed_header<R>(&mut self, ctx: &mut Context<'_>, r: &mut R) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let stream_type = self
+            .stream_type
+            .expect("fixed-length header is only for AEAD 2022 ciphers");
+
+        let header_len = match stream_type {
+            // Server reads request: TYPE, TIMESTAMP, LENGTH
+            StreamType::Server => 1 + 8 + 2,
+            // Client reads response: TYPE, TIMESTAMP, REQUEST SALT, LENGTH
+            StreamType::Client => 1 + 8 + self.local_salt.len() + 2,
+        };
+
+        ready!(self.poll_read_exact(ctx, r, header_len + self.tag_size, true))?;
+        if self.got_final {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut header = vec![0u8; header_len];
+        let res = self.cipher.decrypt(&self.buffer[..], &mut header);
+        self.check_decrypted(res)?;
+
+        let expected_type = match stream_type {
+            StreamType::Server => AEAD2022_CLIENT_STREAM_TYPE,
+            StreamType::Client => AEAD2022_SERVER_STREAM_TYPE,
+        };
+        if header[0] != expected_type {
+            return Poll::Ready(Err(invalid_header_error("invalid stream type in header")));
+        }
+
+        let timestamp = BigEndian::read_u64(&header[1..9]);
+        if !aead2022::check_timestamp(timestamp) {
+            return Poll::Ready(Err(invalid_header_error("timestamp in header is out of range")));
+        }
+
+        if stream_type == StreamType::Client && header[9..header_len - 2] != self.local_salt[..] {
+            return Poll::Ready(Err(invalid_header_error("request salt in header mismatched")));
+        }
+
+        let len = BigEndian::read_u16(&header[header_len - 2..]) as usize;
+
+        // Clear buffer before overwriting it
+        self.buffer.clear();
+        self.data.clear();
+        self.pos = 0;
+
+        // Next step, read the variable-length header for requests, or the first chunk of data for responses
+        self.steps = match stream_type {
+            StreamType::Server => DecryptReadStep::VariableHeader(len),
+            StreamType::Client => DecryptReadStep::Data(len),
+        };
+        self.buffer.reserve(len + self.tag_size);
+        self.data.reserve(len);
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_variable_header<R>(&mut self, ctx: &mut Context<'_>, r: &mut R, size: usize) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let buf_len = size + self.tag_size;
+        ready!(self.poll_read_exact(ctx, r, buf_len, false))?;
+
+        let mut header = vec![0u8; size];
+        let res = self.cipher.decrypt(&self.buffer[..], &mut header);
+        self.check_decrypted(res)?;
+
+        // ADDRESS, PADDING_LEN, PADDING, INITIAL PAYLOAD
+        let addr_len = match request_header_len(&header) {
+            Some(n) if n + 2 <= header.len() => n,
+            _ => return Poll::Ready(Err(invalid_header_error("invalid address in header"))),
+        };
+        let padding_len = BigEndian::read_u16(&header[addr_len..addr_len + 2]) as usize;
+        let payload_pos = addr_len + 2 + padding_len;
+        if payload_pos > header.len() {
+            return Poll::Ready(Err(invalid_header_error("invalid padding length in header")));
+        }
+
+        // Padding is dropped, readers get the address and initial payload just like the SIP004 protocol
+        self.data.extend_from_slice(&header[..addr_len]);
+        self.data.extend_from_slice(&header[payload_pos..]);
+
+        // Clear buffer before overwriting it
+        self.buffer.clear();
+
+        // Reset read position
+        self.pos = 0;
+
+        // Next step, read length
+        self.steps = DecryptReadStep::Length;
+        self.buffer.reserve(2 + self.tag_size);
+
+        Poll::Ready(Ok(()))
+    }
+
     fn poll_read_decrypted_length<R>(&mut self, ctx: &mut Context<'_>, r: &mut R) -> Poll<io::Result<()>>
     where
         R: AsyncRead + Unpin,
@@ -126,7 +338,8 @@ impl DecryptedReader {
         // Done reading, decrypt it
         let len = {
             let mut len_buf = [0u8; 2];
-            self.cipher.decrypt(&self.buffer[..], &mut len_buf)?;
+            let res = self.cipher.decrypt(&self.buffer[..], &mut len_buf);
+            self.check_decrypted(res)?;
             BigEndian::read_u16(&len_buf) as usize
         };
 
@@ -154,7 +367,8 @@ impl DecryptedReader {
         unsafe {
             // It has enough space, I am sure about that
             let buffer = slice::from_raw_parts_mut(self.data.bytes_mut().as_mut_ptr() as *mut u8, size);
-            self.cipher.decrypt(&self.buffer[..], buffer)?;
+            let res = self.cipher.decrypt(&self.buffer[..], buffer);
+            self.check_decrypted(res)?;
 
             // Move forward the pointer
             self.data.advance_mut(size);
@@ -218,6 +432,8 @@ pub struct EncryptedWriter {
     tag_size: usize,
     steps: EncryptWriteStep,
     nonce: Option<Bytes>,
+    stream_type: Option<StreamType>,
+    request_salt: Option<Bytes>,
 }
 
 impl EncryptedWriter {
@@ -228,9 +444,26 @@ impl EncryptedWriter {
             tag_size: t.tag_size(),
             steps: EncryptWriteStep::Nothing,
             nonce: Some(nonce),
+            stream_type: None,
+            request_salt: None,
         }
     }
 
+    /// Creates a writer for AEAD 2022 ciphers, which will send the fixed-length header with the first chunk
+    ///
+    /// The first chunk of clients must contain the request header (`Address`).
+    /// Servers have to set the salt of client's request by `set_request_salt` before writing anything.
+    pub fn new_2022(t: CipherType, key: &[u8], salt: Bytes, stream_type: StreamType) -> EncryptedWriter {
+        let mut writer = EncryptedWriter::new(t, key, salt);
+        writer.stream_type = Some(stream_type);
+        writer
+    }
+
+    /// Set salt of client's request, which will be sent back in response header
+    pub fn set_request_salt(&mut self, salt: Bytes) {
+        self.request_salt = Some(salt);
+    }
+
     pub fn poll_write_encrypted<W>(
         &mut self,
         ctx: &mut Context<'_>,
@@ -240,6 +473,27 @@ impl EncryptedWriter {
     where
         W: AsyncWrite + Unpin,
     {
+        if let Some(stream_type) = self.stream_type {
+            // AEAD 2022 allows chunks up to 0xFFFF, the first chunk in request header also contains padding length
+            let max_packet_size = match (stream_type, &self.nonce) {
+                (StreamType::Client, Some(..)) => MAX_PACKET_SIZE_2022 - 2,
+                _ => MAX_PACKET_SIZE_2022,
+            };
+            if data.len() > max_packet_size {
+                data = &data[..max_packet_size];
+            }
+
+            if let EncryptWriteStep::Nothing = self.steps {
+                if self.nonce.is_some() {
+                    let buf = self.make_header_2022(stream_type, data)?;
+                    self.steps = EncryptWriteStep::Writing(buf, 0);
+                }
+            }
+
+            ready!(self.poll_write_all_encrypted(ctx, w, data))?;
+            return Poll::Ready(Ok(data.len()));
+        }
+
         // Data.Len is a 16-bit big-endian integer indicating the length of Data. It must be smaller than 0x3FFF.
         if data.len() > MAX_PACKET_SIZE {
             data = &data[..MAX_PACKET_SIZE];
@@ -249,12 +503,78 @@ impl EncryptedWriter {
         Poll::Ready(Ok(data.len()))
     }
 
+    /// Build the first packet of AEAD 2022 protocol, which contains salt, headers and `data`
+    fn make_header_2022(&mut self, stream_type: StreamType, data: &[u8]) -> io::Result<BytesMut> {
+        let salt = self.nonce.take().expect("salt of the first packet");
+
+        let mut buf = BytesMut::with_capacity(BUFFER_SIZE);
+        buf.extend_from_slice(&salt);
+
+        let timestamp = aead2022::unix_timestamp();
+
+        match stream_type {
+            StreamType::Client => {
+                // ADDRESS, PADDING_LEN, PADDING, INITIAL PAYLOAD
+                let addr_len = match request_header_len(data) {
+                    Some(n) => n,
+                    None => return Err(invalid_header_error("first packet must start with address")),
+                };
+                let (addr, payload) = data.split_at(addr_len);
+
+                // Requests without initial payload have to be padded
+                let padding_len = if payload.is_empty() {
+                    rand::thread_rng().gen_range(1, MAX_PADDING_SIZE + 1)
+                } else {
+                    0
+                };
+
+                let mut var_header = BytesMut::with_capacity(addr_len + 2 + padding_len + payload.len());
+                var_header.put_slice(addr);
+                var_header.put_u16(padding_len as u16);
+                var_header.resize(var_header.len() + padding_len, 0);
+                rand::thread_rng().fill_bytes(&mut var_header[addr_len + 2..]);
+                var_header.put_slice(payload);
+
+                let mut header = BytesMut::with_capacity(1 + 8 + 2);
+                header.put_u8(AEAD2022_CLIENT_STREAM_TYPE);
+                header.put_u64(timestamp);
+                header.put_u16(var_header.len() as u16);
+
+                self.put_encrypted(&mut buf, &header);
+                self.put_encrypted(&mut buf, &var_header);
+            }
+            StreamType::Server => {
+                let request_salt = self
+                    .request_salt
+                    .take()
+                    .expect("request salt must be set before writing response");
+
+                let mut header = BytesMut::with_capacity(1 + 8 + request_salt.len() + 2);
+                header.put_u8(AEAD2022_SERVER_STREAM_TYPE);
+                header.put_u64(timestamp);
+                header.put_slice(&request_salt);
+                header.put_u16(data.len() as u16);
+
+                self.put_encrypted(&mut buf, &header);
+                self.put_encrypted(&mut buf, data);
+            }
+        }
+
+        Ok(buf)
+    }
+
+    fn put_encrypted(&mut self, buf: &mut BytesMut, data: &[u8]) {
+        let pos = buf.len();
+        buf.resize(pos + data.len() + self.tag_size, 0);
+        self.cipher.encrypt(data, &mut buf[pos..]);
+    }
+
     fn poll_write_all_encrypted<W>(&mut self, ctx: &mut Context<'_>, w: &mut W, data: &[u8]) -> Poll<io::Result<()>>
     where
         W: AsyncWrite + Unpin,
     {
         assert!(
-            data.len() <= MAX_PACKET_SIZE,
+            data.len() <= MAX_PACKET_SIZE || (self.stream_type.is_some() && data.len() <= MAX_PACKET_SIZE_2022),
             "buffer size too large, AEAD encryption protocol requires buffer to be smaller than 0x3FFF"
         );
 
@@ -16,6 +16,8 @@ use crate::relay::socks5::{
     Command,
     HandshakeRequest,
     HandshakeResponse,
+    PasswdAuthRequest,
+    PasswdAuthResponse,
     Reply,
     TcpRequestHeader,
     TcpResponseHeader,
@@ -35,18 +37,30 @@ impl Socks5Client {
     where
         Address: From<A>,
     {
-        let mut s = TcpStream::connect(proxy).await?;
-
-        // 1. Handshake
-        let hs = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
-        trace!("client connected, going to send handshake: {:?}", hs);
+        Socks5Client::connect_with_auth(addr, proxy, None).await
+    }
 
-        hs.write_to(&mut s).await?;
+    /// Connects to `addr` via `proxy`, authenticates with USERNAME/PASSWORD (RFC1929)
+    pub async fn connect_with_password<A>(
+        addr: A,
+        proxy: &SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> io::Result<Socks5Client>
+    where
+        Address: From<A>,
+    {
+        Socks5Client::connect_with_auth(addr, proxy, Some((username, password))).await
+    }
 
-        let hsp = HandshakeResponse::read_from(&mut s).await?;
+    async fn connect_with_auth<A>(addr: A, proxy: &SocketAddr, auth: Option<(&str, &str)>) -> io::Result<Socks5Client>
+    where
+        Address: From<A>,
+    {
+        let mut s = TcpStream::connect(proxy).await?;
 
-        trace!("got handshake response: {:?}", hsp);
-        assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+        // 1. Handshake
+        Socks5Client::handshake(&mut s, auth).await?;
 
         // 2. Send request header
         let h = TcpRequestHeader::new(Command::TcpConnect, From::from(addr));
@@ -67,6 +81,49 @@ impl Socks5Client {
         Ok(Socks5Client { stream: s })
     }
 
+    async fn handshake(s: &mut TcpStream, auth: Option<(&str, &str)>) -> io::Result<()> {
+        let method = match auth {
+            None => socks5::SOCKS5_AUTH_METHOD_NONE,
+            Some(..) => socks5::SOCKS5_AUTH_METHOD_PASSWORD,
+        };
+
+        let hs = HandshakeRequest::new(vec![method]);
+        trace!("client connected, going to send handshake: {:?}", hs);
+
+        hs.write_to(s).await?;
+        s.flush().await?;
+
+        let hsp = HandshakeResponse::read_from(s).await?;
+
+        trace!("got handshake response: {:?}", hsp);
+        if hsp.chosen_method != method {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!("proxy chose unexpected authentication method {:#x}", hsp.chosen_method),
+            );
+            return Err(err);
+        }
+
+        if let Some((username, password)) = auth {
+            let req = PasswdAuthRequest::new(username, password);
+            req.write_to(s).await?;
+            s.flush().await?;
+
+            let resp = PasswdAuthResponse::read_from(s).await?;
+
+            trace!("got password authentication response: {:?}", resp);
+            if resp.status != socks5::SOCKS5_AUTH_PASSWORD_SUCCEEDED {
+                let err = io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "USERNAME/PASSWORD authentication failed",
+                );
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
     /// UDP Associate `addr` via `proxy`
     pub async fn udp_associate<A>(addr: A, proxy: &SocketAddr) -> io::Result<(Socks5Client, Address)>
     where
@@ -75,16 +132,7 @@ impl Socks5Client {
         let mut s = TcpStream::connect(proxy).await?;
 
         // 1. Handshake
-        let hs = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
-        trace!("client connected, going to send handshake: {:?}", hs);
-
-        hs.write_to(&mut s).await?;
-        s.flush().await?;
-
-        let hsp = HandshakeResponse::read_from(&mut s).await?;
-
-        trace!("got handshake response: {:?}", hsp);
-        assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+        Socks5Client::handshake(&mut s, None).await?;
 
         // 2. Send request header
         let h = TcpRequestHeader::new(Command::UdpAssociate, From::from(addr));
@@ -134,8 +182,10 @@ pub struct ServerClient {
 
 impl ServerClient {
     /// Connect to target address via shadowsocks' server
+    ///
+    /// Always with a new connection of its own, multiplexing sessions and idle connections in the pool are not used
     pub async fn connect(context: SharedContext, addr: &Address, svr_cfg: &ServerConfig) -> io::Result<ServerClient> {
-        let stream = ProxyStream::connect_proxied(context, svr_cfg, addr).await?;
+        let stream = ProxyStream::connect_proxied_new(context, svr_cfg, addr).await?;
         Ok(ServerClient { stream })
     }
 }
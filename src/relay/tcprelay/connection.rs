@@ -87,6 +87,16 @@ where
     pub fn get_ref(&self) -> &S {
         self.stream.get_ref()
     }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        self.stream.get_mut()
+    }
+
+    /// Data that have been read from the underlying stream but not consumed
+    pub fn buffer(&self) -> &[u8] {
+        self.stream.buffer()
+    }
 }
 
 impl<S> Connection<S> {
@@ -4,6 +4,7 @@ use std::{
     io,
     marker::Unpin,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 
@@ -17,16 +18,27 @@ use tokio::{
 };
 
 use crate::{
-    config::ServerConfig,
+    config::{ServerConfig, ServerUser},
     context::SharedContext,
     crypto::{CipherCategory, CipherType},
 };
 
 use super::{
-    aead::{DecryptedReader as AeadDecryptedReader, EncryptedWriter as AeadEncryptedWriter},
+    aead::{self, DecryptedReader as AeadDecryptedReader, EncryptedWriter as AeadEncryptedWriter},
     stream::{DecryptedReader as StreamDecryptedReader, EncryptedWriter as StreamEncryptedWriter},
 };
 
+/// Which side of a shadowsocks connection the `CryptoStream` is on
+///
+/// Requests and responses have different headers in AEAD 2022 protocol
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StreamType {
+    /// `sslocal`, connected to a shadowsocks server
+    Client,
+    /// `ssserver`, accepted from a shadowsocks client
+    Server,
+}
+
 enum DecryptedReader {
     Aead(AeadDecryptedReader),
     Stream(StreamDecryptedReader),
@@ -41,8 +53,10 @@ enum EncryptedWriter {
 enum ReadStatus {
     /// Waiting for initializing vector (or nonce for AEAD ciphers)
     ///
-    /// (context, Buffer, already_read_bytes, method, key)
-    WaitIv(SharedContext, Vec<u8>, usize, CipherType, Bytes),
+    /// For multi-user servers, the first chunk after salt is also read for identifying users
+    ///
+    /// (context, Buffer, already_read_bytes, method, key, local iv/salt)
+    WaitIv(SharedContext, Vec<u8>, usize, CipherType, Bytes, Bytes),
 
     /// Connection is established, DecryptedReader is initialized
     Established,
@@ -50,20 +64,43 @@ enum ReadStatus {
 
 /// A bidirectional stream for communicating with ShadowSocks' server
 pub struct CryptoStream<S> {
+    context: SharedContext,
     stream: S,
     dec: Option<DecryptedReader>,
     enc: EncryptedWriter,
     read_status: ReadStatus,
+    stream_type: StreamType,
+    users: Vec<Arc<ServerUser>>,
+    user: Option<Arc<ServerUser>>,
 }
 
 impl<S: Unpin> Unpin for CryptoStream<S> {}
 
+fn new_encrypted_writer(method: CipherType, key: &[u8], iv: Bytes, stream_type: StreamType) -> EncryptedWriter {
+    match method.category() {
+        CipherCategory::Stream => EncryptedWriter::Stream(StreamEncryptedWriter::new(method, key, iv)),
+        CipherCategory::Aead if method.is_aead_2022() => {
+            EncryptedWriter::Aead(AeadEncryptedWriter::new_2022(method, key, iv, stream_type))
+        }
+        CipherCategory::Aead => EncryptedWriter::Aead(AeadEncryptedWriter::new(method, key, iv)),
+    }
+}
+
 impl<S> CryptoStream<S> {
     /// Create a new CryptoStream with the underlying stream connection
-    pub fn new(context: SharedContext, stream: S, svr_cfg: &ServerConfig) -> CryptoStream<S> {
+    ///
+    /// Servers with `users` will identify the user of this connection while reading the first chunk
+    pub fn new(context: SharedContext, stream: S, svr_cfg: &ServerConfig, stream_type: StreamType) -> CryptoStream<S> {
         let method = svr_cfg.method();
+
+        let users = match (stream_type, method.category()) {
+            (StreamType::Server, CipherCategory::Aead) => svr_cfg.users().users(),
+            _ => Vec::new(),
+        };
+
         let prev_len = match method.category() {
             CipherCategory::Stream => method.iv_size(),
+            CipherCategory::Aead if !users.is_empty() => method.salt_size() + aead::request_first_chunk_len(method),
             CipherCategory::Aead => method.salt_size(),
         };
 
@@ -94,17 +131,17 @@ impl<S> CryptoStream<S> {
             }
         };
 
-        let method = svr_cfg.method();
-        let enc = match method.category() {
-            CipherCategory::Stream => EncryptedWriter::Stream(StreamEncryptedWriter::new(method, svr_cfg.key(), iv)),
-            CipherCategory::Aead => EncryptedWriter::Aead(AeadEncryptedWriter::new(method, svr_cfg.key(), iv)),
-        };
+        let enc = new_encrypted_writer(method, svr_cfg.key(), iv.clone(), stream_type);
 
         CryptoStream {
+            context: context.clone(),
             stream,
             dec: None,
             enc,
-            read_status: ReadStatus::WaitIv(context, vec![0u8; prev_len], 0usize, method, svr_cfg.clone_key()),
+            read_status: ReadStatus::WaitIv(context, vec![0u8; prev_len], 0usize, method, svr_cfg.clone_key(), iv),
+            stream_type,
+            users,
+            user: None,
         }
     }
 
@@ -112,6 +149,18 @@ impl<S> CryptoStream<S> {
     pub fn get_ref(&self) -> &S {
         &self.stream
     }
+
+    /// Return a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// User of multi-user server, available after the first chunk is read
+    ///
+    /// `None` if the connection is encrypted with the key of server itself
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        self.user.as_ref()
+    }
 }
 
 impl<S> CryptoStream<S>
@@ -119,7 +168,7 @@ where
     S: AsyncRead + Unpin,
 {
     fn poll_read_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        if let ReadStatus::WaitIv(ref ctx, ref mut buf, ref mut pos, method, ref key) = self.read_status {
+        if let ReadStatus::WaitIv(ref ctx, ref mut buf, ref mut pos, method, ref key, ref local_iv) = self.read_status {
             while *pos < buf.len() {
                 let n = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf[*pos..]))?;
                 if n == 0 {
@@ -129,27 +178,82 @@ where
                 *pos += n;
             }
 
+            let iv_len = match method.category() {
+                CipherCategory::Stream => method.iv_size(),
+                CipherCategory::Aead => method.salt_size(),
+            };
+            let (iv, first_chunk) = buf.split_at(iv_len);
+
             // Got iv/salt, check if it is repeated
-            if ctx.check_nonce_and_set(buf) {
+            if ctx.check_nonce_and_set(iv) {
                 use std::io::{Error, ErrorKind};
 
-                debug!("detected repeated iv/salt {:?}", ByteStr::new(buf));
+                debug!("detected repeated iv/salt {:?}", ByteStr::new(iv));
 
                 let err = Error::new(ErrorKind::Other, "detected repeated iv/salt");
                 return Poll::Ready(Err(err));
             }
 
-            let dec = match method.category() {
+            // Multi-user server, find the key that could decrypt the first chunk
+            let mut key = key.clone();
+            if !first_chunk.is_empty() {
+                if !aead::check_request_first_chunk(method, &key, iv, first_chunk) {
+                    match self
+                        .users
+                        .iter()
+                        .find(|u| aead::check_request_first_chunk(method, u.key(), iv, first_chunk))
+                    {
+                        Some(user) => {
+                            trace!("identified user {}", user.name());
+
+                            key = user.clone_key();
+                            self.enc = new_encrypted_writer(method, &key, local_iv.clone(), self.stream_type);
+                            self.user = Some(user.clone());
+                        }
+                        None => {
+                            use std::io::{Error, ErrorKind};
+
+                            let err = Error::new(ErrorKind::Other, "no user matches, may be wrong method or key");
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                }
+
+                // Users are not needed anymore
+                self.users = Vec::new();
+            }
+
+            let mut dec = match method.category() {
                 CipherCategory::Stream => {
-                    trace!("got Stream cipher IV {:?}", ByteStr::new(&buf));
-                    DecryptedReader::Stream(StreamDecryptedReader::new(method, key, &buf))
+                    trace!("got Stream cipher IV {:?}", ByteStr::new(iv));
+                    DecryptedReader::Stream(StreamDecryptedReader::new(method, &key, iv))
+                }
+                CipherCategory::Aead if method.is_aead_2022() => {
+                    trace!("got AEAD 2022 cipher salt {:?}", ByteStr::new(iv));
+
+                    // Servers respond with the salt of request
+                    if let (StreamType::Server, EncryptedWriter::Aead(w)) = (self.stream_type, &mut self.enc) {
+                        w.set_request_salt(Bytes::copy_from_slice(iv));
+                    }
+
+                    DecryptedReader::Aead(AeadDecryptedReader::new_2022(
+                        method,
+                        &key,
+                        iv,
+                        self.stream_type,
+                        local_iv.clone(),
+                    ))
                 }
                 CipherCategory::Aead => {
-                    trace!("got AEAD cipher salt {:?}", ByteStr::new(&buf));
-                    DecryptedReader::Aead(AeadDecryptedReader::new(method, key, &buf))
+                    trace!("got AEAD cipher salt {:?}", ByteStr::new(iv));
+                    DecryptedReader::Aead(AeadDecryptedReader::new(method, &key, iv))
                 }
             };
 
+            if let DecryptedReader::Aead(ref mut r) = dec {
+                r.set_initial_buffer(first_chunk);
+            }
+
             self.dec = Some(dec);
             self.read_status = ReadStatus::Established;
         }
@@ -162,7 +266,13 @@ where
         ready!(this.poll_read_handshake(ctx))?;
 
         match *this.dec.as_mut().unwrap() {
-            DecryptedReader::Aead(ref mut r) => r.poll_read_decrypted(ctx, &mut this.stream, buf),
+            DecryptedReader::Aead(ref mut r) => {
+                let res = ready!(r.poll_read_decrypted(ctx, &mut this.stream, buf));
+                if res.is_err() && r.decrypt_failed() {
+                    this.context.metrics().incr_aead_decrypt_failures();
+                }
+                Poll::Ready(res)
+            }
             DecryptedReader::Stream(ref mut r) => r.poll_read_decrypted(ctx, &mut this.stream, buf),
         }
     }
@@ -5,22 +5,24 @@ use std::{
     future::Future,
     io,
     io::ErrorKind,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener as StdTcpListener},
     pin::Pin,
     str::FromStr,
+    sync::Arc,
     task::{self, Poll},
 };
 
 use futures::{
     future,
     future::{BoxFuture, Either},
+    pin_mut,
     FutureExt,
 };
 use http::uri::{Authority, Scheme};
 use hyper::{
     client::connect::{Connected, Connection},
     header::HeaderValue,
-    server::conn::AddrStream,
+    server::conn::{AddrStream, Http},
     service::{make_service_fn, service_fn},
     upgrade::Upgraded,
     Body,
@@ -34,20 +36,21 @@ use hyper::{
     Uri,
     Version,
 };
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    rustls::{internal::pemfile, NoClientAuth, ServerConfig},
+    TlsAcceptor,
+};
 
 use crate::{
-    context::SharedContext,
+    config::{LocalConfig, LocalTlsConfig, ServerConfig as SsServerConfig},
+    context::{Context, SharedContext},
     relay::{
-        loadbalancing::server::{
-            PingBalancer,
-            ServerData,
-            ServerType,
-            SharedServerStatistic,
-            SharedServerStatisticData,
-        },
+        loadbalancing::server::{PingBalancer, ServerData, SharedServerStatistic, SharedServerStatisticData},
         socks5::Address,
+        sys::{set_tcp_fastopen, tcp_listener_bind},
     },
 };
 
@@ -56,13 +59,17 @@ use super::ProxyStream;
 #[derive(Clone)]
 struct ShadowSocksConnector {
     context: SharedContext,
-    svr_idx: usize,
+    svr_cfg: Arc<SsServerConfig>,
     stat: SharedServerStatisticData,
 }
 
 impl ShadowSocksConnector {
-    fn new(context: SharedContext, svr_idx: usize, stat: SharedServerStatisticData) -> ShadowSocksConnector {
-        ShadowSocksConnector { context, svr_idx, stat }
+    fn new(context: SharedContext, svr_cfg: SsServerConfig, stat: SharedServerStatisticData) -> ShadowSocksConnector {
+        ShadowSocksConnector {
+            context,
+            svr_cfg: Arc::new(svr_cfg),
+            stat,
+        }
     }
 }
 
@@ -77,13 +84,11 @@ impl tower::Service<Uri> for ShadowSocksConnector {
 
     fn call(&mut self, dst: Uri) -> Self::Future {
         let context = self.context.clone();
-        let svr_idx = self.svr_idx;
+        let svr_cfg = self.svr_cfg.clone();
         let stat = self.stat.clone();
 
         ShadowSocksConnecting {
             fut: async move {
-                let svr_cfg = context.server_config(svr_idx);
-
                 match host_addr(&dst) {
                     None => {
                         use std::io::Error;
@@ -93,16 +98,7 @@ impl tower::Service<Uri> for ShadowSocksConnector {
                         let err = Error::new(ErrorKind::Other, "URI must be a valid Address");
                         Err(err)
                     }
-                    Some(addr) => {
-                        match ProxyStream::connect_proxied(context.clone(), svr_cfg, &addr).await {
-                            Ok(s) => Ok(s),
-                            Err(err) => {
-                                // Report failure to global statistic
-                                stat.report_failure().await;
-                                Err(err)
-                            }
-                        }
-                    }
+                    Some(addr) => ProxyStream::connect_proxied(context, &svr_cfg, &addr, Some(&stat)).await,
                 }
             }
             .boxed(),
@@ -125,7 +121,7 @@ impl Future for ShadowSocksConnecting {
 }
 
 #[derive(Clone)]
-struct DirectConnector {
+pub(super) struct DirectConnector {
     context: SharedContext,
 }
 
@@ -360,11 +356,40 @@ impl Connection for ProxyStream {
 }
 
 type ShadowSocksHttpClient = Client<ShadowSocksConnector, Body>;
-type DirectHttpClient = Client<DirectConnector, Body>;
+pub(super) type DirectHttpClient = Client<DirectConnector, Body>;
 
-async fn establish_connect_tunnel(upgraded: Upgraded, stream: ProxyStream, client_addr: SocketAddr, addr: Address) {
+async fn establish_connect_tunnel(
+    mut upgraded: Upgraded,
+    servers: PingBalancer<ServerScore>,
+    svr_score: SharedServerStatistic<ServerScore>,
+    stream: ProxyStream,
+    client_addr: SocketAddr,
+    addr: Address,
+) {
     use tokio::io::{copy, split};
 
+    // Client sends data after the tunnel is established, sends its first payload with the address header
+    let (svr_score, stream) = match stream
+        .send_first_payload_balanced(&servers, svr_score, &addr, &mut upgraded)
+        .await
+    {
+        Ok(s) => s,
+        Err(err) => {
+            error!(
+                "CONNECT relay {} -> {} failed to send first payload, error: {}",
+                client_addr, addr, err
+            );
+            return;
+        }
+    };
+
+    // Bypassed connections are not counted for the server
+    let _connection = if stream.is_proxied() {
+        Some(svr_score.track_connection())
+    } else {
+        None
+    };
+
     let (mut r, mut w) = split(upgraded);
     let (mut svr_r, mut svr_w) = stream.split();
 
@@ -401,6 +426,59 @@ fn make_bad_request() -> io::Result<Response<Body>> {
     Ok(resp)
 }
 
+fn make_proxy_auth_required() -> io::Result<Response<Body>> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(
+        "Proxy-Authenticate",
+        HeaderValue::from_static("Basic realm=\"shadowsocks\""),
+    );
+    Ok(resp)
+}
+
+/// Check `Proxy-Authorization` with `Basic` scheme
+///
+/// https://tools.ietf.org/html/rfc7617
+fn check_proxy_authorization(context: &Context, headers: &HeaderMap<HeaderValue>) -> bool {
+    let auth = match context.config().local_auth {
+        None => return true,
+        Some(ref a) if a.is_empty() => return true,
+        Some(ref a) => a,
+    };
+
+    for value in headers.get_all("Proxy-Authorization") {
+        let value = match value.to_str() {
+            Ok(v) => v.trim(),
+            Err(..) => continue,
+        };
+
+        // auth-scheme is case-insensitive
+        if value.len() < 6 || !value[..6].eq_ignore_ascii_case("basic ") {
+            continue;
+        }
+
+        let credentials = match base64::decode(value[6..].trim()) {
+            Ok(c) => c,
+            Err(..) => continue,
+        };
+
+        let credentials = match String::from_utf8(credentials) {
+            Ok(c) => c,
+            Err(..) => continue,
+        };
+
+        // user-id cannot contain ':', but password could
+        let mut sp = credentials.splitn(2, ':');
+        if let (Some(username), Some(password)) = (sp.next(), sp.next()) {
+            if auth.check_user(username, password) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
 fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
     // Try to be compatible as a transparent HTTP proxy
     match req.headers().get("Host") {
@@ -474,11 +552,35 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
 
 async fn server_dispatch(
     mut req: Request<Body>,
-    svr_score: SharedServerStatistic<ServerScore>,
+    servers: PingBalancer<ServerScore>,
     client_addr: SocketAddr,
     bypass_client: DirectHttpClient,
 ) -> io::Result<Response<Body>> {
-    let context = svr_score.context();
+    let context = servers.context();
+
+    // Requests are counted instead of connections, idle keep-alive connections shouldn't block draining
+    let active = context.new_active_connection();
+
+    // Authenticate before doing anything else
+    if !check_proxy_authorization(context, req.headers()) {
+        // Clients usually send requests without credentials first, and retry after 407
+        if req.headers().contains_key("Proxy-Authorization") {
+            warn!(
+                "HTTP {} {} from {} failed proxy authentication",
+                req.method(),
+                req.uri(),
+                client_addr
+            );
+        } else {
+            debug!(
+                "HTTP {} {} from {} requires proxy authentication",
+                req.method(),
+                req.uri(),
+                client_addr
+            );
+        }
+        return make_proxy_auth_required();
+    }
 
     // Parse URI
     //
@@ -501,8 +603,6 @@ async fn server_dispatch(
         Some(h) => h,
     };
 
-    let svr_cfg = svr_score.server_config();
-
     if Method::CONNECT == req.method() {
         // Establish a TCP tunnel
         // https://tools.ietf.org/html/draft-luotonen-web-proxy-tunneling-01
@@ -512,15 +612,9 @@ async fn server_dispatch(
         // Connect to Shadowsocks' remote
         //
         // FIXME: What STATUS should I return for connection error?
-        let stream = match ProxyStream::connect(svr_score.clone_context(), svr_cfg, &host).await {
+        let (svr_score, stream) = match ProxyStream::connect_balanced(&servers, &host).await {
             Ok(s) => s,
-            Err(err) => {
-                if err.is_proxied() {
-                    // Report failure to global statistic
-                    svr_score.report_failure().await;
-                }
-                return Err(err.into_inner());
-            }
+            Err(err) => return Err(err.into_inner()),
         };
 
         debug!("CONNECT relay connected {} <-> {}", client_addr, host);
@@ -531,11 +625,14 @@ async fn server_dispatch(
         // connection be upgraded, so we can't return a response inside
         // `on_upgrade` future.
         tokio::spawn(async move {
+            // Tunnel outlives the HTTP connection, it should be counted separately
+            let _active = active;
+
             match req.into_body().on_upgrade().await {
                 Ok(upgraded) => {
                     trace!("CONNECT tunnel upgrade success, {} <-> {}", client_addr, host);
 
-                    establish_connect_tunnel(upgraded, stream, client_addr, host).await
+                    establish_connect_tunnel(upgraded, servers, svr_score, stream, client_addr, host).await
                 }
                 Err(e) => {
                     error!(
@@ -581,6 +678,11 @@ async fn server_dispatch(
                 }
             }
         } else {
+            let svr_score = servers.pick_server(&host);
+            trace!("picked proxy server: {:?}", svr_score.server_config());
+
+            let _connection = svr_score.track_connection();
+
             // Keep connections for clients in ServerScore::client
             //
             // client instance is kept for Keep-Alive connections
@@ -616,49 +718,213 @@ async fn server_dispatch(
     }
 }
 
-struct ServerScore {
+pub(crate) struct ServerScore {
     proxy_client: ShadowSocksHttpClient,
 }
 
 impl ServerScore {
-    fn new(context: SharedContext, server_idx: usize, data: SharedServerStatisticData) -> ServerScore {
+    fn new(context: SharedContext, svr_cfg: SsServerConfig, data: SharedServerStatisticData) -> ServerScore {
         ServerScore {
             // Create HTTP clients for each remote servers
             // It may reuse keep-alive connections
-            proxy_client: Client::builder().build::<_, Body>(ShadowSocksConnector::new(context, server_idx, data)),
+            proxy_client: Client::builder().build::<_, Body>(ShadowSocksConnector::new(context, svr_cfg, data)),
         }
     }
 }
 
 impl ServerData for ServerScore {
-    fn create_server(context: &SharedContext, server_idx: usize, data: &SharedServerStatisticData) -> ServerScore {
-        ServerScore::new(context.clone(), server_idx, data.clone())
+    fn create_server(
+        context: &SharedContext,
+        svr_cfg: &SsServerConfig,
+        data: &SharedServerStatisticData,
+    ) -> ServerScore {
+        ServerScore::new(context.clone(), svr_cfg.clone(), data.clone())
     }
 }
 
-/// Starts a TCP local server with HTTP proxy protocol
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let local_addr = context.config().local.as_ref().expect("local config");
+/// Create a HTTP client for bypassed requests
+pub(super) fn new_bypass_client(context: SharedContext) -> DirectHttpClient {
+    Client::builder().build::<_, Body>(DirectConnector::new(context))
+}
+
+/// Serves HTTP proxy requests on an accepted connection
+pub(super) async fn serve_connection<S>(
+    stream: S,
+    client_addr: SocketAddr,
+    servers: PingBalancer<ServerScore>,
+    bypass_client: DirectHttpClient,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let balancer = servers.clone();
+
+    let service =
+        service_fn(move |req: Request<Body>| server_dispatch(req, servers.clone(), client_addr, bypass_client.clone()));
+
+    let conn = Http::new()
+        .http1_only(true)
+        .serve_connection(stream, service)
+        .with_upgrades();
+    pin_mut!(conn);
+
+    // Finishes the in-flight request and closes the connection after the server is stopped
+    let res = match future::select(conn.as_mut(), balancer.context().wait_server_stopped().boxed()).await {
+        Either::Left((res, ..)) => res,
+        Either::Right(..) => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+
+    if let Err(err) = res {
+        use std::io::Error;
+
+        return Err(Error::new(ErrorKind::Other, err));
+    }
+
+    Ok(())
+}
+
+/// Load certificate chain and private key for HTTPS proxy
+fn create_tls_acceptor(tls_config: &LocalTlsConfig) -> io::Result<TlsAcceptor> {
+    use std::{
+        fs::File,
+        io::{BufReader, Error},
+    };
+
+    let mut cert_reader = BufReader::new(File::open(&tls_config.cert_path)?);
+    let certs = match pemfile::certs(&mut cert_reader) {
+        Ok(certs) if !certs.is_empty() => certs,
+        _ => {
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid certificate {}", tls_config.cert_path.display()),
+            );
+            return Err(err);
+        }
+    };
+
+    // Try PKCS#8 first, then RSA
+    let mut key_reader = BufReader::new(File::open(&tls_config.key_path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut key_reader).unwrap_or_default();
+    if keys.is_empty() {
+        let mut key_reader = BufReader::new(File::open(&tls_config.key_path)?);
+        keys = pemfile::rsa_private_keys(&mut key_reader).unwrap_or_default();
+    }
+
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => {
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid private key {}", tls_config.key_path.display()),
+            );
+            return Err(err);
+        }
+    };
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    if let Err(err) = server_config.set_single_cert(certs, key) {
+        return Err(Error::new(ErrorKind::InvalidData, err));
+    }
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Starts a TCP local server with HTTPS proxy protocol
+async fn run_tls(
+    context: SharedContext,
+    local_config: LocalConfig,
+    servers: PingBalancer<ServerScore>,
+    tls_config: &LocalTlsConfig,
+) -> io::Result<()> {
+    let acceptor = create_tls_acceptor(tls_config)?;
+
+    let local_addr = &local_config.addr;
     let bind_addr = local_addr.bind_addr(&*context).await?;
 
-    let bypass_client = Client::builder().build::<_, Body>(DirectConnector::new(context.clone()));
-    let servers: PingBalancer<ServerScore> = PingBalancer::new(context, ServerType::Tcp).await;
+    let mut listener = tcp_listener_bind(&bind_addr, &*context)
+        .await
+        .unwrap_or_else(|err| panic!("failed to listen on {}, {}", local_addr, err));
+
+    let actual_local_addr = listener.local_addr().expect("determine port bound to");
+
+    let bypass_client = new_bypass_client(context.clone());
+
+    info!("shadowsocks HTTPS listening on {}", actual_local_addr);
+
+    loop {
+        let (socket, peer_addr) = listener.accept().await?;
+
+        trace!("got connection {}", peer_addr);
+
+        let servers = servers.clone();
+        let acceptor = acceptor.clone();
+        let bypass_client = bypass_client.clone();
+        tokio::spawn(async move {
+            // Handshake in the spawned task, slow clients shouldn't block the listener
+            let stream = match acceptor.accept(socket).await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("TLS handshake with {} failed, error: {}", peer_addr, err);
+                    return;
+                }
+            };
+
+            if let Err(err) = serve_connection(stream, peer_addr, servers, bypass_client).await {
+                error!("HTTPS client {} exited with error: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+/// Starts a TCP local server with HTTP proxy protocol
+pub async fn run(
+    context: SharedContext,
+    local_config: LocalConfig,
+    servers: PingBalancer<ServerScore>,
+) -> io::Result<()> {
+    if let Some(tls_config) = local_config.tls.clone() {
+        return run_tls(context, local_config, servers, &tls_config).await;
+    }
+
+    let bind_addr = local_config.addr.bind_addr(&*context).await?;
+
+    let bypass_client = new_bypass_client(context.clone());
 
     let make_service = make_service_fn(|socket: &AddrStream| {
         let client_addr = socket.remote_addr();
-        let svr_score = servers.pick_server();
+        let servers = servers.clone();
         let bypass_client = bypass_client.clone();
 
         async move {
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                server_dispatch(req, svr_score.clone(), client_addr, bypass_client.clone())
+                server_dispatch(req, servers.clone(), client_addr, bypass_client.clone())
             }))
         }
     });
 
-    let server = Server::bind(&bind_addr).http1_only(true).serve(make_service);
+    let builder = if context.config().fast_open {
+        // Listener has to be created manually for setting TCP_FASTOPEN
+        let listener = StdTcpListener::bind(&bind_addr)?;
+        listener.set_nonblocking(true)?;
+        if let Err(err) = set_tcp_fastopen(&listener) {
+            warn!("failed to set TCP_FASTOPEN on listener {}, error: {}", bind_addr, err);
+        }
+
+        Server::from_tcp(listener).map_err(|err| io::Error::new(ErrorKind::Other, err))?
+    } else {
+        Server::bind(&bind_addr)
+    };
+
+    let server = builder.http1_only(true).serve(make_service);
     info!("shadowsocks HTTP listening on {}", server.local_addr());
 
+    // Connections finish their in-flight requests and close after the server is stopped,
+    // or this future is dropped while shutting down
+    let server = server.with_graceful_shutdown(context.wait_server_stopped());
+
     if let Err(err) = server.await {
         use std::io::Error;
 
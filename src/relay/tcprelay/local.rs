@@ -2,18 +2,49 @@
 
 use std::io;
 
-use crate::{config::ConfigType, context::SharedContext};
-
-use super::{http_local, redir_local, socks5_local, tunnel_local};
-
-/// Starts a TCP local server
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    match context.config().config_type {
-        ConfigType::TunnelLocal => tunnel_local::run(context).await,
-        ConfigType::Socks5Local => socks5_local::run(context).await,
-        ConfigType::HttpLocal => http_local::run(context).await,
-        ConfigType::RedirLocal => redir_local::run(context).await,
-        ConfigType::Server => unreachable!(),
-        ConfigType::Manager => unreachable!(),
+use futures::{future::select_all, FutureExt};
+
+use crate::{config::ConfigType, context::SharedContext, relay::loadbalancing::server::PingBalancer};
+
+use super::{
+    http_local::{self, ServerScore},
+    mixed_local,
+    redir_local,
+    socks5_local,
+    tunnel_local,
+};
+
+/// Load balancer for TCP local servers
+pub(crate) type TcpServerBalancer = PingBalancer<ServerScore>;
+
+/// Starts TCP local servers
+///
+/// All local servers share the same load balancer
+pub(crate) async fn run(context: SharedContext, servers: TcpServerBalancer) -> io::Result<()> {
+    let local_configs = context
+        .config()
+        .local_configs()
+        .into_iter()
+        .filter(|l| l.enable_tcp())
+        .collect::<Vec<_>>();
+
+    let mut vf = Vec::with_capacity(local_configs.len());
+    for local_config in local_configs {
+        let context = context.clone();
+        let servers = servers.clone();
+
+        let fut = match local_config.config_type {
+            ConfigType::TunnelLocal => tunnel_local::run(context, local_config, servers).boxed(),
+            ConfigType::Socks5Local => socks5_local::run(context, local_config, servers).boxed(),
+            ConfigType::HttpLocal => http_local::run(context, local_config, servers).boxed(),
+            ConfigType::RedirLocal => redir_local::run(context, local_config, servers).boxed(),
+            ConfigType::MixedLocal => mixed_local::run(context, local_config, servers).boxed(),
+            ConfigType::Server => unreachable!(),
+            ConfigType::Manager => unreachable!(),
+        };
+        vf.push(fut);
     }
+
+    let (res, ..) = select_all(vf.into_iter()).await;
+    res
 }
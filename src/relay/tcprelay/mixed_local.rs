@@ -0,0 +1,87 @@
+//! Local server that accepts both SOCKS 5 (and SOCKS 4/4a) and HTTP proxy protocol on the same port
+
+use std::io;
+
+use log::{error, info, trace};
+use tokio::{self, net::TcpStream};
+
+use crate::{
+    config::LocalConfig,
+    context::SharedContext,
+    relay::{loadbalancing::server::PingBalancer, socks4, socks5, sys::tcp_listener_bind},
+};
+
+use super::{
+    http_local::{self, DirectHttpClient, ServerScore},
+    socks5_local::{self, SocksConfig},
+};
+
+async fn handle_client(
+    servers: PingBalancer<ServerScore>,
+    s: TcpStream,
+    socks_conf: SocksConfig,
+    bypass_client: DirectHttpClient,
+) -> io::Result<()> {
+    // Detect protocol by the first byte
+    //
+    // SOCKS requests start with the version number, which couldn't be the first character of an HTTP method
+    let mut first_byte = [0u8; 1];
+    let n = s.peek(&mut first_byte).await?;
+    if n == 0 {
+        // Closed without sending anything
+        return Ok(());
+    }
+
+    match first_byte[0] {
+        socks4::SOCKS4_VERSION | socks5::SOCKS5_VERSION => {
+            let _active = servers.context().new_active_connection();
+            socks5_local::handle_socks_client(&servers, s, socks_conf).await
+        }
+        _ => {
+            // HTTP requests are counted by the HTTP server, idle keep-alive connections are not active
+            let client_addr = s.peer_addr()?;
+            http_local::serve_connection(s, client_addr, servers, bypass_client).await
+        }
+    }
+}
+
+/// Starts a TCP local server with both Socks5 and HTTP proxy protocol
+pub async fn run(
+    context: SharedContext,
+    local_config: LocalConfig,
+    servers: PingBalancer<ServerScore>,
+) -> io::Result<()> {
+    let local_addr = &local_config.addr;
+    let bind_addr = local_addr.bind_addr(&*context).await?;
+
+    let mut listener = tcp_listener_bind(&bind_addr, &*context)
+        .await
+        .unwrap_or_else(|err| panic!("failed to listen on {}, {}", local_addr, err));
+
+    let actual_local_addr = listener.local_addr().expect("determine port bound to");
+
+    let socks_conf = SocksConfig {
+        enable_tcp: local_config.mode.enable_tcp(),
+        enable_udp: local_config.mode.enable_udp(),
+        client_addr: actual_local_addr,
+    };
+
+    let bypass_client = http_local::new_bypass_client(context.clone());
+
+    info!("shadowsocks TCP (socks5 and HTTP) listening on {}", actual_local_addr);
+
+    loop {
+        let (socket, peer_addr) = listener.accept().await?;
+
+        trace!("got connection {}", peer_addr);
+
+        let servers = servers.clone();
+        let socks_conf = socks_conf.clone();
+        let bypass_client = bypass_client.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_client(servers, socket, socks_conf, bypass_client).await {
+                error!("TCP mixed client exited with error: {}", err);
+            }
+        });
+    }
+}
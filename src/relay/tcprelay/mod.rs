@@ -4,7 +4,7 @@
 // Maybe removed in the future
 #![allow(clippy::unnecessary_mut_passed)]
 
-use std::{io, marker::Unpin};
+use std::{io, marker::Unpin, time::Duration};
 
 use futures::{future::FusedFuture, select, Future};
 use tokio::{
@@ -19,11 +19,15 @@ mod connection;
 mod crypto_io;
 mod http_local;
 pub mod local;
+mod mixed_local;
 mod monitor;
+pub(crate) mod mux;
+pub(crate) mod pool;
 mod proxy_stream;
 mod redir_local;
 pub mod server;
 mod socks5_local;
+mod splice;
 mod stream;
 mod sys;
 mod tunnel_local;
@@ -31,12 +35,15 @@ mod utils;
 
 pub use self::{
     connection::{Connection, TcpConnection},
-    crypto_io::CryptoStream,
+    crypto_io::{CryptoStream, StreamType},
     proxy_stream::ProxyStream,
 };
 
 const BUFFER_SIZE: usize = 8 * 1024; // 8K buffer
 
+/// Default timeout for waiting the inbound connection of BIND command
+const DEFAULT_BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
 /// Secured TcpStream
 pub type STcpStream = Connection<TcpStream>;
 
@@ -1,25 +1,95 @@
 //! Server traffic monitor
 
 use std::{
+    future::Future,
     io,
     marker::Unpin,
     ops::{Deref, DerefMut},
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{self, Delay},
+};
+
+use crate::relay::{
+    flow::{ServerQuota, SharedServerFlowStatistic},
+    rate_limit::ConnectionRateLimiter,
+};
 
-use crate::relay::flow::SharedServerFlowStatistic;
+/// Interval of checking quota while the connection is idle
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct TcpMonStream<S> {
     stream: S,
     flow_stat: SharedServerFlowStatistic,
+    user_flow_stat: Option<SharedServerFlowStatistic>,
+    tx: u64,
+    rx: u64,
+    quota: Option<ServerQuota>,
+    quota_timer: Option<Delay>,
+    rate_limiter: ConnectionRateLimiter,
 }
 
 impl<S> TcpMonStream<S> {
     pub fn new(flow_stat: SharedServerFlowStatistic, stream: S) -> TcpMonStream<S> {
-        TcpMonStream { stream, flow_stat }
+        TcpMonStream {
+            stream,
+            flow_stat,
+            user_flow_stat: None,
+            tx: 0,
+            rx: 0,
+            quota: None,
+            quota_timer: None,
+            rate_limiter: ConnectionRateLimiter::default(),
+        }
+    }
+
+    /// Enforces traffic quota and expire time of server
+    ///
+    /// Reads and writes will fail after the quota is used up or the server is expired,
+    /// pending reads and writes are woken up periodically, so idle connections are closed too
+    pub fn set_quota(&mut self, quota: ServerQuota) {
+        if !quota.is_unlimited() {
+            self.quota = Some(quota);
+        }
+    }
+
+    /// Limits bandwidth of this connection
+    pub fn set_rate_limiter(&mut self, rate_limiter: ConnectionRateLimiter) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    fn poll_check_quota(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let quota = match self.quota {
+            Some(ref quota) => quota,
+            None => return Ok(()),
+        };
+
+        quota.check(&self.flow_stat)?;
+
+        // Registers a wake up for checking again, in case the stream stays pending
+        loop {
+            if let Some(ref mut timer) = self.quota_timer {
+                if Pin::new(timer).poll(cx).is_pending() {
+                    return Ok(());
+                }
+            }
+            self.quota_timer = Some(time::delay_for(QUOTA_CHECK_INTERVAL));
+        }
+    }
+
+    /// Also counts traffic for an user of multi-user server
+    ///
+    /// Users are identified after the handshake, bytes transferred before are added immediately
+    pub fn set_user_flow_stat(&mut self, user_flow_stat: SharedServerFlowStatistic) {
+        user_flow_stat.tcp().incr_tx(self.tx);
+        user_flow_stat.tcp().incr_rx(self.rx);
+        self.user_flow_stat = Some(user_flow_stat);
     }
 }
 
@@ -28,11 +98,19 @@ where
     S: AsyncRead + Unpin,
 {
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
-        let n = match Pin::new(&mut self.stream).poll_read(cx, buf)? {
+        self.poll_check_quota(cx)?;
+
+        let allowed = ready!(self.rate_limiter.upload().poll_acquire(cx, buf.len()));
+        let n = match Pin::new(&mut self.stream).poll_read(cx, &mut buf[..allowed])? {
             Poll::Ready(n) => n,
             Poll::Pending => return Poll::Pending,
         };
+        self.rate_limiter.upload().consume(n);
         self.flow_stat.tcp().incr_rx(n as u64);
+        if let Some(ref user_flow_stat) = self.user_flow_stat {
+            user_flow_stat.tcp().incr_rx(n as u64);
+        }
+        self.rx += n as u64;
         Poll::Ready(Ok(n))
     }
 }
@@ -42,11 +120,19 @@ where
     S: AsyncWrite + Unpin,
 {
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        let n = match Pin::new(&mut self.stream).poll_write(cx, buf)? {
+        self.poll_check_quota(cx)?;
+
+        let allowed = ready!(self.rate_limiter.download().poll_acquire(cx, buf.len()));
+        let n = match Pin::new(&mut self.stream).poll_write(cx, &buf[..allowed])? {
             Poll::Ready(n) => n,
             Poll::Pending => return Poll::Pending,
         };
+        self.rate_limiter.download().consume(n);
         self.flow_stat.tcp().incr_tx(n as u64);
+        if let Some(ref user_flow_stat) = self.user_flow_stat {
+            user_flow_stat.tcp().incr_tx(n as u64);
+        }
+        self.tx += n as u64;
         Poll::Ready(Ok(n))
     }
 
@@ -72,3 +158,49 @@ impl<S> DerefMut for TcpMonStream<S> {
         &mut self.stream
     }
 }
+
+/// Limits bandwidth of a stream, without counting its traffic
+///
+/// Used for streams of multiplexing sessions, traffic is already counted by the session's `TcpMonStream`
+pub struct RateLimitedStream<S> {
+    stream: S,
+    rate_limiter: ConnectionRateLimiter,
+}
+
+impl<S> RateLimitedStream<S> {
+    pub fn new(stream: S, rate_limiter: ConnectionRateLimiter) -> RateLimitedStream<S> {
+        RateLimitedStream { stream, rate_limiter }
+    }
+}
+
+impl<S> AsyncRead for RateLimitedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let allowed = ready!(self.rate_limiter.upload().poll_acquire(cx, buf.len()));
+        let n = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf[..allowed]))?;
+        self.rate_limiter.upload().consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S> AsyncWrite for RateLimitedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let allowed = ready!(self.rate_limiter.download().poll_acquire(cx, buf.len()));
+        let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &buf[..allowed]))?;
+        self.rate_limiter.download().consume(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
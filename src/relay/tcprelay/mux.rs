@@ -0,0 +1,1102 @@
+//! Multiplexing many streams over a few long-lived connections between `sslocal` and `ssserver`
+//!
+//! Client starts a session by an extended request, a SOCKS5 request header with the `Multiplex` command.
+//! Server replies `Succeeded` if multiplexing is enabled, otherwise it replies `CommandNotSupported`
+//! (or closes the connection if it doesn't know the command), then client falls back to normal connections.
+//!
+//! Streams of a session are carried in frames:
+//!
+//! ```plain
+//! +-----+-----------+--------+----------+
+//! | CMD | STREAM ID | LENGTH |   DATA   |
+//! +-----+-----------+--------+----------+
+//! |  1  |     4     |   2    | Variable |
+//! +-----+-----------+--------+----------+
+//! ```
+//!
+//! - `SYN`, client opens a stream, `DATA` is the target `Address`
+//! - `PSH`, payload of the stream
+//! - `FIN`, sender won't send anything on the stream
+//! - `UPD`, `DATA` is a 4-bytes window increment, receiver has consumed these bytes
+//!
+//! At most `STREAM_WINDOW` bytes of each stream could be in flight, sender waits for `UPD` before sending more.
+//! At most `MAX_STREAMS` streams could be opened in a session at the same time, server replies `FIN` to
+//! the other `SYN`s immediately.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, Error, ErrorKind},
+    mem,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    future::{self, Either},
+    pin_mut,
+    FutureExt,
+};
+use log::{debug, trace};
+use spin::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time,
+};
+
+use crate::{
+    config::ServerConfig,
+    relay::socks5::{Address, Command, Reply, TcpRequestHeader, SOCKS5_VERSION},
+};
+
+/// Maximum bytes of a stream in flight, before receiver acknowledges them with `UPD`
+const STREAM_WINDOW: u32 = 256 * 1024;
+
+/// `CMD`, `STREAM ID` and `LENGTH`
+const FRAME_HEADER_SIZE: usize = 1 + 4 + 2;
+
+/// Maximum length of `DATA`, a frame fits in one AEAD chunk
+const MAX_FRAME_DATA_SIZE: usize = 0x3FFF - FRAME_HEADER_SIZE;
+
+/// Frames queued at the same time are sent in one write, up to this size
+const MAX_WRITE_SIZE: usize = 64 * 1024;
+
+/// Maximum sessions kept by clients for each server
+const MAX_SESSIONS: usize = 4;
+
+/// Maximum streams opened in a session at the same time
+const MAX_STREAMS: usize = 256;
+
+/// Sessions of clients without any streams are closed after this timeout
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Timeout of negotiating a session with server
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client doesn't try multiplexing again with a server that doesn't support it, until this interval passed
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameType {
+    Syn,
+    Fin,
+    Psh,
+    Upd,
+}
+
+impl FrameType {
+    fn as_u8(self) -> u8 {
+        match self {
+            FrameType::Syn => 0,
+            FrameType::Fin => 1,
+            FrameType::Psh => 2,
+            FrameType::Upd => 3,
+        }
+    }
+
+    fn from_u8(code: u8) -> Option<FrameType> {
+        match code {
+            0 => Some(FrameType::Syn),
+            1 => Some(FrameType::Fin),
+            2 => Some(FrameType::Psh),
+            3 => Some(FrameType::Upd),
+            _ => None,
+        }
+    }
+}
+
+struct Frame {
+    ty: FrameType,
+    id: u32,
+    data: Bytes,
+}
+
+impl Frame {
+    fn new(ty: FrameType, id: u32, data: Bytes) -> Frame {
+        Frame { ty, id, data }
+    }
+
+    fn write_to_buf(&self, buf: &mut BytesMut) {
+        buf.reserve(FRAME_HEADER_SIZE + self.data.len());
+        buf.put_u8(self.ty.as_u8());
+        buf.put_u32(self.id);
+        buf.put_u16(self.data.len() as u16);
+        buf.put_slice(&self.data);
+    }
+
+    async fn read_from<R>(r: &mut R) -> io::Result<Frame>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        r.read_exact(&mut header).await?;
+
+        let ty = match FrameType::from_u8(header[0]) {
+            Some(t) => t,
+            None => return Err(protocol_error(format!("unknown frame type {:#x}", header[0]))),
+        };
+        let id = BigEndian::read_u32(&header[1..5]);
+        let len = BigEndian::read_u16(&header[5..7]) as usize;
+
+        let mut data = vec![0u8; len];
+        r.read_exact(&mut data).await?;
+
+        Ok(Frame::new(ty, id, Bytes::from(data)))
+    }
+}
+
+fn protocol_error<S: Into<String>>(msg: S) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+fn session_closed_error() -> Error {
+    Error::new(ErrorKind::ConnectionAborted, "multiplexing session closed")
+}
+
+struct StreamState {
+    // Received but not read yet
+    recv_buf: BytesMut,
+    // Bytes that the peer is allowed to send
+    recv_window: u32,
+    // Read but not acknowledged yet
+    consumed: u32,
+    recv_fin: bool,
+    read_waker: Option<Waker>,
+    // Bytes that are allowed to be sent
+    send_window: u32,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> StreamState {
+        StreamState {
+            recv_buf: BytesMut::new(),
+            recv_window: STREAM_WINDOW,
+            consumed: 0,
+            recv_fin: false,
+            read_waker: None,
+            send_window: STREAM_WINDOW,
+            write_waker: None,
+        }
+    }
+
+    fn wake_all(&mut self) {
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+        if let Some(w) = self.write_waker.take() {
+            w.wake();
+        }
+    }
+}
+
+struct SessionState {
+    streams: HashMap<u32, StreamState>,
+    next_id: u32,
+    closed: bool,
+    // Time since the last stream was closed, `None` if there are streams opened
+    idle_since: Option<Instant>,
+}
+
+impl SessionState {
+    fn insert_stream(&mut self, id: u32) {
+        self.streams.insert(id, StreamState::new());
+        self.idle_since = None;
+    }
+
+    fn remove_stream(&mut self, id: u32) {
+        self.streams.remove(&id);
+        if self.streams.is_empty() {
+            self.idle_since = Some(Instant::now());
+        }
+    }
+}
+
+struct SessionInner {
+    state: Mutex<SessionState>,
+    // Frames are sent by the I/O task of session
+    tx: mpsc::UnboundedSender<Frame>,
+    // Local address of the connection carrying this session
+    local_addr: SocketAddr,
+}
+
+impl SessionInner {
+    fn send(&self, frame: Frame) -> io::Result<()> {
+        self.tx.send(frame).map_err(|_| session_closed_error())
+    }
+
+    /// All streams fail after the connection is closed
+    fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        for stream in state.streams.values_mut() {
+            stream.wake_all();
+        }
+    }
+}
+
+/// Streams opened by clients, with their target addresses
+pub type IncomingStreams = mpsc::UnboundedReceiver<(MuxStream, Address)>;
+
+/// A multiplexing session over one connection
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<SessionInner>,
+}
+
+impl Session {
+    /// Starts a session of client on `stream`
+    ///
+    /// The returned future does I/O of the session, the session is closed after it is finished
+    pub fn client<S>(stream: S, local_addr: SocketAddr) -> (Session, impl Future<Output = io::Result<()>>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Session::new(stream, local_addr, None)
+    }
+
+    /// Starts a session of server on `stream`, streams opened by client are sent to the returned receiver
+    ///
+    /// The returned future does I/O of the session, the session is closed after it is finished
+    pub fn server<S>(stream: S, local_addr: SocketAddr) -> (IncomingStreams, impl Future<Output = io::Result<()>>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (_, fut) = Session::new(stream, local_addr, Some(tx));
+        (rx, fut)
+    }
+
+    fn new<S>(
+        stream: S,
+        local_addr: SocketAddr,
+        incoming: Option<mpsc::UnboundedSender<(MuxStream, Address)>>,
+    ) -> (Session, impl Future<Output = io::Result<()>>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let inner = Arc::new(SessionInner {
+            state: Mutex::new(SessionState {
+                streams: HashMap::new(),
+                next_id: 1,
+                closed: false,
+                idle_since: Some(Instant::now()),
+            }),
+            tx,
+            local_addr,
+        });
+
+        let session = Session { inner: inner.clone() };
+        let fut = async move {
+            let (r, w) = tokio::io::split(stream);
+
+            let reader = read_frames(&inner, r, incoming);
+            let writer = write_frames(w, rx);
+            pin_mut!(reader, writer);
+
+            let result = match future::select(reader, writer).await {
+                Either::Left((r, _)) => r,
+                Either::Right((r, _)) => r,
+            };
+
+            inner.close();
+            result
+        };
+
+        (session, fut)
+    }
+
+    /// Opens a stream to `addr`
+    pub fn open(&self, addr: &Address) -> io::Result<MuxStream> {
+        let id = {
+            let mut state = self.inner.state.lock();
+            if state.closed || state.next_id == u32::max_value() {
+                return Err(session_closed_error());
+            }
+            if state.streams.len() >= MAX_STREAMS {
+                return Err(Error::new(ErrorKind::Other, "too many streams in multiplexing session"));
+            }
+
+            let id = state.next_id;
+            state.next_id += 1;
+            state.insert_stream(id);
+            id
+        };
+
+        // Stream is removed from session if SYN couldn't be sent
+        let stream = MuxStream::new(id, self.inner.clone());
+
+        let mut buf = BytesMut::with_capacity(addr.serialized_len());
+        addr.write_to_buf(&mut buf);
+        self.inner.send(Frame::new(FrameType::Syn, id, buf.freeze()))?;
+
+        Ok(stream)
+    }
+
+    /// Check if new streams could be opened
+    fn is_available(&self) -> bool {
+        let state = self.inner.state.lock();
+        !state.closed && state.next_id < u32::max_value()
+    }
+
+    fn stream_count(&self) -> usize {
+        self.inner.state.lock().streams.len()
+    }
+
+    /// Waits until there are no streams in the session for `timeout`, then closes it
+    ///
+    /// No more streams could be opened after it returns, and the I/O future of session should be dropped
+    pub async fn close_when_idle(&self, timeout: Duration) {
+        loop {
+            let wait = {
+                let mut state = self.inner.state.lock();
+                match state.idle_since.map(|t| t.elapsed()) {
+                    Some(elapsed) if elapsed >= timeout => {
+                        state.closed = true;
+                        return;
+                    }
+                    Some(elapsed) => timeout - elapsed,
+                    None => timeout,
+                }
+            };
+
+            time::delay_for(wait).await;
+        }
+    }
+}
+
+async fn read_frames<R>(
+    inner: &Arc<SessionInner>,
+    mut r: R,
+    incoming: Option<mpsc::UnboundedSender<(MuxStream, Address)>>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let frame = Frame::read_from(&mut r).await?;
+        trace!(
+            "multiplexing frame {:?} of stream {}, {} bytes",
+            frame.ty,
+            frame.id,
+            frame.data.len()
+        );
+
+        match frame.ty {
+            FrameType::Syn => {
+                let incoming = match incoming {
+                    Some(ref i) => i,
+                    None => return Err(protocol_error("streams couldn't be opened by server")),
+                };
+
+                let addr = Address::read_from(&mut &frame.data[..]).await?;
+
+                {
+                    let mut state = inner.state.lock();
+                    if state.streams.contains_key(&frame.id) {
+                        return Err(protocol_error(format!("stream {} is already opened", frame.id)));
+                    }
+
+                    // Refuses the stream, client will read EOF from it
+                    if state.streams.len() >= MAX_STREAMS {
+                        debug!("too many streams in multiplexing session, refused stream {}", frame.id);
+                        inner.send(Frame::new(FrameType::Fin, frame.id, Bytes::new()))?;
+                        continue;
+                    }
+
+                    state.insert_stream(frame.id);
+                }
+
+                let stream = MuxStream::new(frame.id, inner.clone());
+                if incoming.send((stream, addr)).is_err() {
+                    // Nobody is accepting streams
+                    return Ok(());
+                }
+            }
+            FrameType::Psh => {
+                let mut state = inner.state.lock();
+
+                // Stream may have been closed locally, data are dropped
+                if let Some(stream) = state.streams.get_mut(&frame.id) {
+                    let len = frame.data.len() as u32;
+                    if len > stream.recv_window {
+                        return Err(protocol_error(format!("stream {} exceeded its window", frame.id)));
+                    }
+
+                    stream.recv_window -= len;
+                    stream.recv_buf.extend_from_slice(&frame.data);
+                    if let Some(w) = stream.read_waker.take() {
+                        w.wake();
+                    }
+                }
+            }
+            FrameType::Fin => {
+                let mut state = inner.state.lock();
+                if let Some(stream) = state.streams.get_mut(&frame.id) {
+                    stream.recv_fin = true;
+                    if let Some(w) = stream.read_waker.take() {
+                        w.wake();
+                    }
+                }
+            }
+            FrameType::Upd => {
+                if frame.data.len() != 4 {
+                    return Err(protocol_error("invalid window update"));
+                }
+                let increment = BigEndian::read_u32(&frame.data);
+
+                let mut state = inner.state.lock();
+                if let Some(stream) = state.streams.get_mut(&frame.id) {
+                    stream.send_window = stream.send_window.saturating_add(increment);
+                    if let Some(w) = stream.write_waker.take() {
+                        w.wake();
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn write_frames<W>(mut w: W, mut rx: mpsc::UnboundedReceiver<Frame>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+
+    while let Some(frame) = rx.recv().await {
+        frame.write_to_buf(&mut buf);
+
+        while buf.len() < MAX_WRITE_SIZE {
+            match rx.recv().now_or_never() {
+                Some(Some(frame)) => frame.write_to_buf(&mut buf),
+                _ => break,
+            }
+        }
+
+        w.write_all(&buf).await?;
+        w.flush().await?;
+        buf.clear();
+    }
+
+    Ok(())
+}
+
+/// A stream in a multiplexing session
+pub struct MuxStream {
+    id: u32,
+    session: Arc<SessionInner>,
+    fin_sent: bool,
+}
+
+impl MuxStream {
+    fn new(id: u32, session: Arc<SessionInner>) -> MuxStream {
+        MuxStream {
+            id,
+            session,
+            fin_sent: false,
+        }
+    }
+
+    /// Local address of the connection carrying this stream
+    pub fn local_addr(&self) -> SocketAddr {
+        self.session.local_addr
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let mut state = this.session.state.lock();
+        let closed = state.closed;
+        let stream = match state.streams.get_mut(&this.id) {
+            Some(s) => s,
+            None => return Poll::Ready(Err(session_closed_error())),
+        };
+
+        if !stream.recv_buf.is_empty() {
+            let n = buf.len().min(stream.recv_buf.len());
+            buf[..n].copy_from_slice(&stream.recv_buf[..n]);
+            stream.recv_buf.advance(n);
+
+            // Acknowledges after half of the window is consumed
+            stream.consumed += n as u32;
+            if stream.consumed >= STREAM_WINDOW / 2 {
+                let increment = mem::replace(&mut stream.consumed, 0);
+                stream.recv_window += increment;
+
+                let mut data = [0u8; 4];
+                BigEndian::write_u32(&mut data, increment);
+                let frame = Frame::new(FrameType::Upd, this.id, Bytes::copy_from_slice(&data));
+                if let Err(err) = this.session.send(frame) {
+                    return Poll::Ready(Err(err));
+                }
+            }
+
+            return Poll::Ready(Ok(n));
+        }
+
+        if stream.recv_fin {
+            return Poll::Ready(Ok(0));
+        }
+        if closed {
+            return Poll::Ready(Err(session_closed_error()));
+        }
+
+        stream.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.fin_sent {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut state = this.session.state.lock();
+        if state.closed {
+            return Poll::Ready(Err(session_closed_error()));
+        }
+        let stream = match state.streams.get_mut(&this.id) {
+            Some(s) => s,
+            None => return Poll::Ready(Err(session_closed_error())),
+        };
+
+        if stream.send_window == 0 {
+            stream.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(stream.send_window as usize).min(MAX_FRAME_DATA_SIZE);
+        stream.send_window -= n as u32;
+
+        let frame = Frame::new(FrameType::Psh, this.id, Bytes::copy_from_slice(&buf[..n]));
+        match this.session.send(frame) {
+            Ok(..) => Poll::Ready(Ok(n)),
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are flushed by the session after they are written
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.fin_sent {
+            this.fin_sent = true;
+            this.session.send(Frame::new(FrameType::Fin, this.id, Bytes::new()))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.session.state.lock().remove_stream(self.id);
+
+        if !self.fin_sent {
+            let _ = self.session.send(Frame::new(FrameType::Fin, self.id, Bytes::new()));
+        }
+    }
+}
+
+/// Requests a multiplexing session on a connection to server
+///
+/// Returns `false` if the server doesn't support multiplexing, or it is not enabled. That is, server replies
+/// `CommandNotSupported`, or closes the connection right after the request if it doesn't know the command.
+/// Other failures are errors of the connection.
+pub async fn request_session<S>(stream: &mut S) -> io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let negotiate = async {
+        let dummy_address = Address::SocketAddress(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+        let header = TcpRequestHeader::new(Command::Multiplex, dummy_address);
+        let mut buf = BytesMut::with_capacity(header.serialized_len());
+        header.write_to_buf(&mut buf);
+        stream.write_all(&buf).await?;
+
+        // Reads VER, REP and RSV by hand, the EOF have to be distinguished from other errors
+        let mut buf = [0u8; 3];
+        match stream.read_exact(&mut buf).await {
+            Ok(..) => {}
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err),
+        }
+
+        if buf[0] != SOCKS5_VERSION {
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported socks version {:#x}", buf[0]),
+            );
+            return Err(err);
+        }
+
+        match Reply::from_u8(buf[1]) {
+            Reply::Succeeded => {
+                let _ = Address::read_from(stream).await?;
+                Ok(true)
+            }
+            Reply::CommandNotSupported => Ok(false),
+            reply => Err(Error::new(ErrorKind::Other, format!("server replied {}", reply))),
+        }
+    };
+
+    match time::timeout(NEGOTIATION_TIMEOUT, negotiate).await {
+        Ok(r) => r,
+        Err(..) => Err(Error::new(ErrorKind::TimedOut, "multiplexing negotiation timed out")),
+    }
+}
+
+struct PoolState {
+    sessions: Vec<Session>,
+    // Sessions are being created, they have reserved their places in the pool
+    connecting: usize,
+}
+
+/// Multiplexing sessions to a server, kept by clients
+pub struct MuxPool {
+    svr_cfg: ServerConfig,
+    state: Arc<Mutex<PoolState>>,
+    // Server doesn't support multiplexing, normal connections are used until this time
+    disabled_until: Mutex<Option<Instant>>,
+}
+
+/// Shared reference of `MuxPool`
+pub type SharedMuxPool = Arc<MuxPool>;
+
+impl MuxPool {
+    /// Creates an empty pool for `svr_cfg`
+    pub fn new(svr_cfg: &ServerConfig) -> MuxPool {
+        MuxPool {
+            svr_cfg: svr_cfg.clone(),
+            state: Arc::new(Mutex::new(PoolState {
+                sessions: Vec::new(),
+                connecting: 0,
+            })),
+            disabled_until: Mutex::new(None),
+        }
+    }
+
+    /// Check if the pool is created for `svr_cfg`, server with the same address may be changed by reloading
+    pub fn is_for(&self, svr_cfg: &ServerConfig) -> bool {
+        self.svr_cfg.addr().to_string() == svr_cfg.addr().to_string()
+            && self.svr_cfg.method().to_string() == svr_cfg.method().to_string()
+            && self.svr_cfg.key() == svr_cfg.key()
+    }
+
+    /// Picks the session with the fewest streams
+    ///
+    /// A place in the pool is reserved if a new session should be created, when all sessions are busy and the pool
+    /// is not full. Returns `None` if all sessions are full and there is no place for a new one.
+    pub fn pick(&self) -> Option<PickedSession> {
+        let mut state = self.state.lock();
+        state.sessions.retain(Session::is_available);
+
+        let session = state
+            .sessions
+            .iter()
+            .filter(|s| s.stream_count() < MAX_STREAMS)
+            .min_by_key(|s| s.stream_count())
+            .cloned();
+
+        match session {
+            Some(session) if session.stream_count() == 0 => Some(PickedSession::Existing(session)),
+            session => {
+                if state.sessions.len() + state.connecting < MAX_SESSIONS {
+                    state.connecting += 1;
+                    Some(PickedSession::New(SessionSlot {
+                        state: self.state.clone(),
+                    }))
+                } else {
+                    session.map(PickedSession::Existing)
+                }
+            }
+        }
+    }
+
+    /// Check if multiplexing is disabled because the server doesn't support it
+    pub fn is_disabled(&self) -> bool {
+        match *self.disabled_until.lock() {
+            Some(t) => Instant::now() < t,
+            None => false,
+        }
+    }
+
+    /// Uses normal connections for a while
+    pub fn disable(&self) {
+        *self.disabled_until.lock() = Some(Instant::now() + RETRY_INTERVAL);
+    }
+}
+
+/// Result of `MuxPool::pick`
+pub enum PickedSession {
+    /// Opens stream in an existed session
+    Existing(Session),
+    /// Creates a new session in the reserved place
+    New(SessionSlot),
+}
+
+/// A reserved place for a new session in `MuxPool`
+///
+/// The reservation is released if it is dropped without a session pushed
+pub struct SessionSlot {
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl SessionSlot {
+    /// Adds the newly created session into the reserved place
+    pub fn push(self, session: Session) {
+        // Reservation is released in drop
+        self.state.lock().sessions.push(session);
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.state.lock().connecting -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        runtime::Builder,
+    };
+
+    use crate::{crypto::CipherType, relay::socks5::TcpResponseHeader};
+
+    /// Connected sockets on loopback
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let c = TcpStream::connect(addr).await.unwrap();
+        let (s, _) = listener.accept().await.unwrap();
+        (c, s)
+    }
+
+    fn target_addr() -> Address {
+        Address::SocketAddress("127.0.0.1:80".parse().unwrap())
+    }
+
+    fn syn_frame(id: u32) -> Frame {
+        let mut buf = BytesMut::new();
+        target_addr().write_to_buf(&mut buf);
+        Frame::new(FrameType::Syn, id, buf.freeze())
+    }
+
+    async fn write_frame(stream: &mut TcpStream, frame: Frame) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        frame.write_to_buf(&mut buf);
+        stream.write_all(&buf).await
+    }
+
+    #[test]
+    fn test_frame_encode_decode() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let mut buf = BytesMut::new();
+            Frame::new(FrameType::Psh, 7, Bytes::from_static(b"HEllo")).write_to_buf(&mut buf);
+            Frame::new(FrameType::Fin, 7, Bytes::new()).write_to_buf(&mut buf);
+            assert_eq!(buf.len(), FRAME_HEADER_SIZE * 2 + 5);
+
+            let mut r = &buf[..];
+            let frame = Frame::read_from(&mut r).await.unwrap();
+            assert_eq!(frame.ty, FrameType::Psh);
+            assert_eq!(frame.id, 7);
+            assert_eq!(&frame.data[..], b"HEllo");
+
+            let frame = Frame::read_from(&mut r).await.unwrap();
+            assert_eq!(frame.ty, FrameType::Fin);
+            assert_eq!(frame.id, 7);
+            assert!(frame.data.is_empty());
+
+            // Unknown frame type
+            let raw = [0x09u8, 0, 0, 0, 1, 0, 0];
+            let err = Frame::read_from(&mut &raw[..]).await.err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        });
+    }
+
+    #[test]
+    fn test_stream_half_close() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let (c, s) = tcp_pair().await;
+            let local_addr = c.local_addr().unwrap();
+
+            let (session, client_fut) = Session::client(c, local_addr);
+            let (mut incoming, server_fut) = Session::server(s, local_addr);
+            tokio::spawn(client_fut);
+            tokio::spawn(server_fut);
+
+            let mut client = session.open(&target_addr()).unwrap();
+            let (mut server, addr) = incoming.recv().await.unwrap();
+            assert_eq!(addr, target_addr());
+
+            client.write_all(b"HEllo").await.unwrap();
+            client.shutdown().await.unwrap();
+            assert!(client.write_all(b"WORld").await.is_err());
+
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"HEllo");
+
+            // Server could still write after client's FIN
+            server.write_all(b"WORld").await.unwrap();
+            server.shutdown().await.unwrap();
+
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"WORld");
+        });
+    }
+
+    #[test]
+    fn test_stream_window_update() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let (c, s) = tcp_pair().await;
+            let local_addr = c.local_addr().unwrap();
+
+            let (session, client_fut) = Session::client(c, local_addr);
+            let (mut incoming, server_fut) = Session::server(s, local_addr);
+            tokio::spawn(client_fut);
+            tokio::spawn(server_fut);
+
+            let mut client = session.open(&target_addr()).unwrap();
+            let (mut server, _) = incoming.recv().await.unwrap();
+
+            let total = STREAM_WINDOW as usize + 1000;
+            let mut writer = tokio::spawn(async move {
+                client.write_all(&vec![1u8; total]).await.unwrap();
+                client
+            });
+
+            // Blocked until server acknowledges with UPD
+            assert!(time::timeout(Duration::from_millis(200), &mut writer).await.is_err());
+
+            let mut buf = vec![0u8; total];
+            server.read_exact(&mut buf).await.unwrap();
+            assert!(buf.iter().all(|b| *b == 1));
+
+            let _client = time::timeout(Duration::from_secs(1), writer).await.unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_stream_window_overrun() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let (mut c, s) = tcp_pair().await;
+            let local_addr = c.local_addr().unwrap();
+
+            let (_incoming, server_fut) = Session::server(s, local_addr);
+            let server = tokio::spawn(server_fut);
+
+            // Sender ignores its window
+            write_frame(&mut c, syn_frame(1)).await.unwrap();
+            let mut sent = 0;
+            while sent <= STREAM_WINDOW as usize {
+                let data = Bytes::from(vec![0u8; MAX_FRAME_DATA_SIZE]);
+                if write_frame(&mut c, Frame::new(FrameType::Psh, 1, data)).await.is_err() {
+                    break;
+                }
+                sent += MAX_FRAME_DATA_SIZE;
+            }
+
+            let err = time::timeout(Duration::from_secs(1), server)
+                .await
+                .unwrap()
+                .unwrap()
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        });
+    }
+
+    #[test]
+    fn test_close_wakes_streams() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let (c, mut s) = tcp_pair().await;
+            let local_addr = c.local_addr().unwrap();
+
+            let (session, client_fut) = Session::client(c, local_addr);
+            tokio::spawn(client_fut);
+
+            let mut client = session.open(&target_addr()).unwrap();
+            let reader = tokio::spawn(async move {
+                let mut buf = [0u8; 16];
+                client.read(&mut buf).await
+            });
+
+            // Server receives SYN, then closes the connection
+            let frame = Frame::read_from(&mut s).await.unwrap();
+            assert_eq!(frame.ty, FrameType::Syn);
+            drop(s);
+
+            let err = time::timeout(Duration::from_secs(1), reader)
+                .await
+                .unwrap()
+                .unwrap()
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+            assert!(session.open(&target_addr()).is_err());
+        });
+    }
+
+    #[test]
+    fn test_max_streams() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let (mut c, s) = tcp_pair().await;
+            let local_addr = c.local_addr().unwrap();
+
+            let (_incoming, server_fut) = Session::server(s, local_addr);
+            tokio::spawn(server_fut);
+
+            for id in 1..=MAX_STREAMS as u32 + 1 {
+                write_frame(&mut c, syn_frame(id)).await.unwrap();
+            }
+
+            // The extra stream is refused
+            let frame = Frame::read_from(&mut c).await.unwrap();
+            assert_eq!(frame.ty, FrameType::Fin);
+            assert_eq!(frame.id, MAX_STREAMS as u32 + 1);
+
+            // Clients couldn't open more streams either
+            let (c, _s) = tcp_pair().await;
+            let (session, _client_fut) = Session::client(c, local_addr);
+            let _streams = (0..MAX_STREAMS)
+                .map(|_| session.open(&target_addr()).unwrap())
+                .collect::<Vec<_>>();
+            assert!(session.open(&target_addr()).is_err());
+        });
+    }
+
+    #[test]
+    fn test_request_session() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let cases = [
+                (Some(Reply::Succeeded), true),
+                (Some(Reply::CommandNotSupported), false),
+                (None, false),
+            ];
+            for &(reply, expected) in &cases {
+                let (mut c, mut s) = tcp_pair().await;
+
+                tokio::spawn(async move {
+                    let header = TcpRequestHeader::read_from(&mut s).await.unwrap();
+                    match header.command {
+                        Command::Multiplex => {}
+                        cmd => panic!("unexpected command {:?}", cmd),
+                    }
+
+                    // Server doesn't know the command closes the connection
+                    if let Some(reply) = reply {
+                        let rh = TcpResponseHeader::new(reply, header.address);
+                        rh.write_to(&mut s).await.unwrap();
+                    }
+                });
+
+                assert_eq!(request_session(&mut c).await.unwrap(), expected);
+            }
+
+            // Other replies are errors
+            let (mut c, mut s) = tcp_pair().await;
+            tokio::spawn(async move {
+                let header = TcpRequestHeader::read_from(&mut s).await.unwrap();
+                let rh = TcpResponseHeader::new(Reply::GeneralFailure, header.address);
+                rh.write_to(&mut s).await.unwrap();
+            });
+            assert!(request_session(&mut c).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_close_when_idle() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let (c, _s) = tcp_pair().await;
+            let local_addr = c.local_addr().unwrap();
+            let (session, _client_fut) = Session::client(c, local_addr);
+
+            let stream = session.open(&target_addr()).unwrap();
+            let timeout = Duration::from_millis(50);
+            assert!(time::timeout(timeout * 3, session.close_when_idle(timeout))
+                .await
+                .is_err());
+
+            drop(stream);
+            time::timeout(Duration::from_secs(1), session.close_when_idle(timeout))
+                .await
+                .unwrap();
+            assert!(!session.is_available());
+            assert!(session.open(&target_addr()).is_err());
+        });
+    }
+
+    #[test]
+    fn test_pool_reserve() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let svr_cfg = ServerConfig::basic(
+                "127.0.0.1:8388".parse().unwrap(),
+                "test-password".to_owned(),
+                CipherType::Aes256Gcm,
+            );
+            let pool = MuxPool::new(&svr_cfg);
+
+            let mut slots = Vec::new();
+            for _ in 0..MAX_SESSIONS {
+                match pool.pick() {
+                    Some(PickedSession::New(slot)) => slots.push(slot),
+                    _ => panic!("expected a reserved slot"),
+                }
+            }
+
+            // Pool is full while sessions are being created
+            assert!(pool.pick().is_none());
+
+            // Failed creation releases the reservation
+            drop(slots.pop());
+            let slot = match pool.pick() {
+                Some(PickedSession::New(slot)) => slot,
+                _ => panic!("expected a reserved slot"),
+            };
+            assert!(pool.pick().is_none());
+
+            let (c, _s) = tcp_pair().await;
+            let local_addr = c.local_addr().unwrap();
+            let (session, _client_fut) = Session::client(c, local_addr);
+            slot.push(session);
+
+            // Idle session is reused
+            match pool.pick() {
+                Some(PickedSession::Existing(session)) => assert_eq!(session.stream_count(), 0),
+                _ => panic!("expected an existing session"),
+            }
+        });
+    }
+}
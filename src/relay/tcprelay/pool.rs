@@ -0,0 +1,347 @@
+//! Idle connections to servers, connected in advance
+//!
+//! Connections are plain TCP connections (or to plugins), nothing is sent until they are taken,
+//! so salts and address headers are sent by the clients taking them.
+//!
+//! Connections are replaced before they have been idle for half of `timeout`,
+//! the server closes connections which are idle longer than `timeout`.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use log::{debug, trace};
+use spin::Mutex;
+use tokio::{sync::mpsc, time};
+
+use crate::{config::ServerConfig, context::SharedContext};
+
+use super::{proxy_stream::connect_proxy_server_new, STcpStream};
+
+/// Idle time of connections if `timeout` is not set, connections may be dropped by NAT devices on the path
+const DEFAULT_MAX_IDLE: Duration = Duration::from_secs(60);
+
+struct IdleConnection {
+    stream: STcpStream,
+    connected_at: Instant,
+}
+
+impl IdleConnection {
+    /// Check if the connection is not expired, and it is not closed or reset by the server
+    ///
+    /// Server never sends anything before it receives a request, so the socket is readable only if it is closed.
+    /// Connections closed right after being checked are retried by `ProxyStream` with new connections.
+    fn is_usable(&mut self, max_idle: Duration) -> bool {
+        if self.connected_at.elapsed() >= max_idle {
+            return false;
+        }
+
+        let mut buf = [0u8; 1];
+        self.stream.get_mut().peek(&mut buf).now_or_never().is_none()
+    }
+}
+
+/// Idle connections to a server
+pub struct ConnectionPool {
+    svr_cfg: ServerConfig,
+    size: usize,
+    max_idle: Duration,
+    idle: Mutex<VecDeque<IdleConnection>>,
+    refill_tx: mpsc::UnboundedSender<()>,
+    // Taken by the task filling this pool, which is started by the first client
+    refill_rx: Mutex<Option<mpsc::UnboundedReceiver<()>>>,
+}
+
+/// Shared reference of `ConnectionPool`
+pub type SharedConnectionPool = Arc<ConnectionPool>;
+
+impl ConnectionPool {
+    /// Creates an empty pool keeping `size` idle connections to `svr_cfg`
+    pub fn new(svr_cfg: &ServerConfig, size: usize, timeout: Option<Duration>) -> ConnectionPool {
+        let (refill_tx, refill_rx) = mpsc::unbounded_channel();
+
+        ConnectionPool {
+            svr_cfg: svr_cfg.clone(),
+            size,
+            max_idle: timeout.map(|t| t / 2).unwrap_or(DEFAULT_MAX_IDLE),
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            refill_tx,
+            refill_rx: Mutex::new(Some(refill_rx)),
+        }
+    }
+
+    /// Check if the pool is created for `svr_cfg`, server with the same address may be changed by reloading
+    pub fn is_for(&self, svr_cfg: &ServerConfig) -> bool {
+        self.svr_cfg.addr().to_string() == svr_cfg.addr().to_string()
+            && self.svr_cfg.external_addr().to_string() == svr_cfg.external_addr().to_string()
+    }
+
+    /// Takes an idle connection, the pool will be refilled in background
+    ///
+    /// Connections are checked without holding the lock, checking sockets requires system calls
+    fn take(&self) -> Option<STcpStream> {
+        let mut taken = None;
+        loop {
+            let conn = self.idle.lock().pop_front();
+            match conn {
+                Some(mut conn) => {
+                    if conn.is_usable(self.max_idle) {
+                        taken = Some(conn.stream);
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        let _ = self.refill_tx.send(());
+        taken
+    }
+
+    /// Drops connections that have been idle for too long, or closed by the server
+    fn evict(&self) {
+        for _ in 0..self.idle_count() {
+            let conn = self.idle.lock().pop_front();
+            match conn {
+                Some(mut conn) => {
+                    if conn.is_usable(self.max_idle) {
+                        self.idle.lock().push_back(conn);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn idle_count(&self) -> usize {
+        self.idle.lock().len()
+    }
+
+    fn push(&self, stream: STcpStream) {
+        self.idle.lock().push_back(IdleConnection {
+            stream,
+            connected_at: Instant::now(),
+        });
+    }
+}
+
+/// Takes an idle connection to `svr_cfg`, returns `None` if the pool is disabled or empty
+///
+/// The pool is filled in background after it is used for the first time
+pub(crate) fn take_connection(context: &SharedContext, svr_cfg: &ServerConfig) -> Option<STcpStream> {
+    let pool = context.connection_pool(svr_cfg)?;
+
+    if let Some(refill_rx) = pool.refill_rx.lock().take() {
+        tokio::spawn(fill_pool(context.clone(), Arc::downgrade(&pool), refill_rx));
+    }
+
+    pool.take()
+}
+
+/// Keeps the pool filled, until the pool is dropped or the server is stopped
+async fn fill_pool(context: SharedContext, pool: Weak<ConnectionPool>, mut refill_rx: mpsc::UnboundedReceiver<()>) {
+    while context.server_running() {
+        let interval = match pool.upgrade() {
+            Some(pool) => {
+                pool.evict();
+
+                let missing = pool.size.saturating_sub(pool.idle_count());
+                for _ in 0..missing {
+                    match connect_proxy_server_new(&context, &pool.svr_cfg).await {
+                        Ok(s) => pool.push(s),
+                        Err(err) => {
+                            // Retried in the next round
+                            debug!(
+                                "failed to fill connection pool of {}, error: {}",
+                                pool.svr_cfg.addr(),
+                                err
+                            );
+                            break;
+                        }
+                    }
+                }
+
+                trace!(
+                    "connection pool of {} has {} idle connections",
+                    pool.svr_cfg.addr(),
+                    pool.idle_count()
+                );
+                pool.max_idle / 2
+            }
+            None => break,
+        };
+
+        // Wakes up after connections are taken, or for replacing connections before they expire
+        if let Ok(None) = time::timeout(interval, refill_rx.recv()).await {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        runtime::{Builder, Handle},
+    };
+
+    use crate::{
+        config::{Config, ConfigType, ServerAddr},
+        context::{Context, ServerState},
+        crypto::CipherType,
+    };
+
+    /// Server closes connections after they have been idle for `idle`, returns its address and count of accepted
+    async fn start_idle_closing_server(idle: Duration) -> (SocketAddr, Arc<AtomicUsize>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    time::delay_for(idle).await;
+                    drop(socket);
+                });
+            }
+        });
+
+        (addr, accepted)
+    }
+
+    fn server_config(addr: SocketAddr) -> ServerConfig {
+        ServerConfig::basic(addr, "test-password".to_owned(), CipherType::Aes256Gcm)
+    }
+
+    async fn new_context(svr_cfg: &ServerConfig, idle_connections: usize, rt_handle: Handle) -> SharedContext {
+        let mut config = Config::new(ConfigType::Socks5Local);
+        config.server = vec![svr_cfg.clone()];
+        config.idle_connections = idle_connections;
+        config.timeout = Some(Duration::from_secs(10));
+        let state = ServerState::new_shared(&config, rt_handle).await;
+        Context::new_shared(config, state)
+    }
+
+    async fn connect(addr: SocketAddr) -> STcpStream {
+        STcpStream::new(TcpStream::connect(addr).await.unwrap(), None)
+    }
+
+    #[test]
+    fn test_pool_refill() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let (addr, accepted) = start_idle_closing_server(Duration::from_secs(10)).await;
+            let svr_cfg = server_config(addr);
+            let context = new_context(&svr_cfg, 2, rt_handle).await;
+
+            // Pool is filled after it is used for the first time
+            assert!(take_connection(&context, &svr_cfg).is_none());
+            time::delay_for(Duration::from_millis(300)).await;
+
+            let pool = context.connection_pool(&svr_cfg).unwrap();
+            assert_eq!(pool.idle_count(), 2);
+            assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+            // Taken connection is replaced
+            assert!(take_connection(&context, &svr_cfg).is_some());
+            time::delay_for(Duration::from_millis(300)).await;
+            assert_eq!(pool.idle_count(), 2);
+            assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        });
+    }
+
+    #[test]
+    fn test_pool_evict_expired() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let (addr, _) = start_idle_closing_server(Duration::from_secs(10)).await;
+
+            // Connections expire after half of `timeout`
+            let pool = ConnectionPool::new(&server_config(addr), 2, Some(Duration::from_millis(200)));
+            pool.push(connect(addr).await);
+            pool.push(connect(addr).await);
+
+            pool.evict();
+            assert_eq!(pool.idle_count(), 2);
+
+            time::delay_for(Duration::from_millis(150)).await;
+            pool.evict();
+            assert_eq!(pool.idle_count(), 0);
+            assert!(pool.take().is_none());
+        });
+    }
+
+    #[test]
+    fn test_pool_evict_closed() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let (addr, _) = start_idle_closing_server(Duration::from_millis(100)).await;
+
+            let pool = ConnectionPool::new(&server_config(addr), 2, Some(Duration::from_secs(10)));
+            pool.push(connect(addr).await);
+            pool.push(connect(addr).await);
+
+            // Still opened by the server
+            assert!(pool.take().is_some());
+            assert_eq!(pool.idle_count(), 1);
+
+            // Closed by the server, socket becomes readable
+            time::delay_for(Duration::from_millis(300)).await;
+            pool.push(connect(addr).await);
+            pool.evict();
+            assert_eq!(pool.idle_count(), 1);
+
+            time::delay_for(Duration::from_millis(300)).await;
+            assert!(pool.take().is_none());
+            assert_eq!(pool.idle_count(), 0);
+        });
+    }
+
+    #[test]
+    fn test_pool_reload() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let svr_cfg = server_config("127.0.0.1:8388".parse().unwrap());
+            let context = new_context(&svr_cfg, 2, rt_handle.clone()).await;
+
+            let pool = context.connection_pool(&svr_cfg).unwrap();
+            assert!(Arc::ptr_eq(&pool, &context.connection_pool(&svr_cfg).unwrap()));
+
+            // Connections are plain, they could be used with another key
+            let mut new_key_cfg = svr_cfg.clone();
+            new_key_cfg.set_method(CipherType::ChaCha20IetfPoly1305, "another-password".to_owned());
+            assert!(Arc::ptr_eq(&pool, &context.connection_pool(&new_key_cfg).unwrap()));
+
+            // Server is reloaded with a plugin, connections are made to the plugin
+            let mut plugin_cfg = svr_cfg.clone();
+            plugin_cfg.set_plugin_addr(ServerAddr::from("127.0.0.1:8389".parse::<SocketAddr>().unwrap()));
+            let plugin_pool = context.connection_pool(&plugin_cfg).unwrap();
+            assert!(!Arc::ptr_eq(&pool, &plugin_pool));
+            assert!(plugin_pool.is_for(&plugin_cfg));
+            assert!(!plugin_pool.is_for(&svr_cfg));
+
+            // No pools with `fast_open`
+            let mut config = context.config().clone();
+            config.fast_open = true;
+            let state = ServerState::new_shared(&config, rt_handle).await;
+            let context = Context::new_shared(config, state);
+            assert!(context.connection_pool(&svr_cfg).is_none());
+        });
+    }
+}
@@ -2,25 +2,50 @@
 
 use std::{
     fmt::{self, Display, Formatter},
-    io::{self, Error},
+    future::Future,
+    io::{self, Error, ErrorKind},
     marker::Unpin,
     net::SocketAddr,
     pin::Pin,
+    sync::Mutex,
     task::{Context as TaskContext, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use bytes::BytesMut;
-use log::{debug, error, trace};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use bytes::{Buf, BytesMut};
+use futures::{
+    future::{self, BoxFuture, Either},
+    pin_mut,
+    ready,
+    FutureExt,
+};
+use log::{debug, error, info, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    time,
+};
 
 use crate::{
     config::{ConfigType, ServerAddr, ServerConfig},
     context::{Context, SharedContext},
-    relay::{socks5::Address, sys::tcp_stream_connect, utils::try_timeout},
+    relay::{
+        loadbalancing::server::{PingBalancer, ServerData, SharedServerStatistic, SharedServerStatisticData},
+        socks5::{Address, Command, TcpRequestHeader},
+        sys::tcp_stream_connect,
+        utils::try_timeout,
+    },
 };
 
-use super::{connection::Connection, CryptoStream, STcpStream};
+use super::{
+    connection::Connection,
+    mux::{self, MuxStream, PickedSession, Session},
+    pool,
+    splice::PlainTcpStream,
+    CryptoStream,
+    STcpStream,
+    StreamType,
+};
 
 /// Stream wrapper for both direct connections and proxied connections
 #[allow(clippy::large_enum_variant)]
@@ -32,9 +57,140 @@ pub enum ProxyStream {
     Proxied {
         stream: CryptoStream<STcpStream>,
         context: SharedContext,
+        // Resets are reported to the server's statistic until the first byte is received
+        stat: Option<SharedServerStatisticData>,
+        // Address header that hasn't been sent yet, it will be sent with the first payload
+        pending: Option<PendingHeader>,
+        // Connection is taken from the pool, it is retried with a new connection if it fails before the first byte
+        retry: Option<PooledRetry>,
+    },
+    Multiplexed {
+        stream: MuxStream,
+        context: SharedContext,
     },
 }
 
+/// Buffer size for reading clients' first payload
+const FIRST_PAYLOAD_BUFFER_SIZE: usize = 0x3FFF;
+
+/// Address header held back until the first payload is written (or the stream is flushed)
+pub struct PendingHeader {
+    buf: BytesMut,
+    // The first payload has been appended after the header, nothing could be appended since then
+    filled: bool,
+}
+
+impl PendingHeader {
+    fn new(addr: &Address) -> PendingHeader {
+        let mut buf = BytesMut::with_capacity(addr.serialized_len());
+        addr.write_to_buf(&mut buf);
+
+        PendingHeader { buf, filled: false }
+    }
+
+    /// Check if the header and the first payload have been written to the stream
+    fn is_sent(&self) -> bool {
+        self.filled && self.buf.is_empty()
+    }
+
+    /// Writes `payload` after the header
+    ///
+    /// The first payload is appended to the header, and it is done as soon as it is buffered.
+    /// The buffer is written before any other payload, which are written to `stream` directly.
+    fn poll_write<S>(&mut self, cx: &mut TaskContext<'_>, stream: &mut S, payload: &[u8]) -> Poll<io::Result<usize>>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        if !self.filled {
+            self.buf.extend_from_slice(payload);
+            self.filled = true;
+
+            // Sends it right away if possible, otherwise it will be sent by the following writes or flushes
+            if let Poll::Ready(Err(err)) = self.poll_write_buf(cx, stream) {
+                return Poll::Ready(Err(err));
+            }
+            return Poll::Ready(Ok(payload.len()));
+        }
+
+        ready!(self.poll_write_buf(cx, stream))?;
+        Pin::new(stream).poll_write(cx, payload)
+    }
+
+    /// Writes the buffered header (and the first payload), the header is sent alone if nothing has been appended
+    fn poll_flush_buf<S>(&mut self, cx: &mut TaskContext<'_>, stream: &mut S) -> Poll<io::Result<()>>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        self.filled = true;
+        self.poll_write_buf(cx, stream)
+    }
+
+    fn poll_write_buf<S>(&mut self, cx: &mut TaskContext<'_>, stream: &mut S) -> Poll<io::Result<()>>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        while !self.buf.is_empty() {
+            let n = ready!(Pin::new(&mut *stream).poll_write(cx, &self.buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.buf.advance(n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Maximum size of the header and payloads kept for retrying a connection taken from the pool
+const POOLED_RETRY_BUFFER_SIZE: usize = 0x3FFF;
+
+/// Retries a connection taken from the pool with a new connection
+///
+/// Idle connections may be closed by servers right before they are taken, which couldn't be told until they are used.
+/// Everything written is kept until the first byte is received, and written again to the new connection.
+pub struct PooledRetry {
+    svr_cfg: ServerConfig,
+    written: BytesMut,
+    // `Mutex` keeps `ProxyStream` `Sync`, it is never locked but accessed by `get_mut`
+    connecting: Option<Mutex<BoxFuture<'static, io::Result<CryptoStream<STcpStream>>>>>,
+}
+
+impl PooledRetry {
+    fn new(svr_cfg: &ServerConfig, header: &[u8]) -> PooledRetry {
+        PooledRetry {
+            svr_cfg: svr_cfg.clone(),
+            written: BytesMut::from(header),
+            connecting: None,
+        }
+    }
+
+    /// Keeps written payload, returns `false` if the buffer is full and the connection couldn't be retried
+    fn record(&mut self, payload: &[u8]) -> bool {
+        if self.written.len() + payload.len() > POOLED_RETRY_BUFFER_SIZE {
+            return false;
+        }
+        self.written.extend_from_slice(payload);
+        true
+    }
+
+    fn start(&mut self, context: SharedContext) {
+        debug!(
+            "idle connection to proxy {} failed, retrying with a new connection",
+            self.svr_cfg.addr()
+        );
+
+        let svr_cfg = self.svr_cfg.clone();
+        let written = self.written.split().freeze();
+        let fut = async move {
+            let server_stream = connect_proxy_server_new(&context, &svr_cfg).await?;
+            let mut stream = CryptoStream::new(context, server_stream, &svr_cfg, StreamType::Client);
+            stream.write_all(&written).await?;
+            Ok(stream)
+        };
+        self.connecting = Some(Mutex::new(fut.boxed()));
+    }
+}
+
 #[derive(Debug)]
 pub struct ProxyStreamError {
     inner: Error,
@@ -48,7 +204,7 @@ impl ProxyStreamError {
 
     /// Check if it is proxied
     pub fn is_proxied(&self) -> bool {
-        self.bypassed
+        !self.bypassed
     }
 
     /// Into internal `std::io::Error`
@@ -71,15 +227,64 @@ impl Display for ProxyStreamError {
 
 impl ProxyStream {
     /// Connect to remote by ACL rules
+    ///
+    /// Outcomes of proxied connections are reported to `stat`
     pub async fn connect(
         context: SharedContext,
         svr_cfg: &ServerConfig,
         addr: &Address,
+        stat: &SharedServerStatisticData,
     ) -> Result<ProxyStream, ProxyStreamError> {
         if context.check_target_bypassed(addr).await {
             ProxyStream::connect_direct_wrapped(context, addr).await
         } else {
-            ProxyStream::connect_proxied_wrapped(context, svr_cfg, addr).await
+            ProxyStream::connect_proxied_wrapped(context, svr_cfg, addr, stat).await
+        }
+    }
+
+    /// Connect to remote by ACL rules, via a server picked from `servers`
+    ///
+    /// If connecting or handshaking to the picked server fails, the next server is tried,
+    /// until `failover_attempts` servers have been tried
+    ///
+    /// Failures of sending the first payload are failed over by `send_first_payload_balanced`
+    pub async fn connect_balanced<S: ServerData>(
+        servers: &PingBalancer<S>,
+        addr: &Address,
+    ) -> Result<(SharedServerStatistic<S>, ProxyStream), ProxyStreamError> {
+        let max_attempts = servers.context().config().failover_attempts;
+
+        let mut server = servers.pick_server(addr);
+        let mut tried = Vec::new();
+
+        loop {
+            trace!("picked proxy server: {:?}", server.server_config());
+
+            let svr_cfg = server.server_config();
+            let res = ProxyStream::connect(server.clone_context(), svr_cfg, addr, server.data()).await;
+            let err = match res {
+                Ok(s) => return Ok((server, s)),
+                Err(err) => err,
+            };
+
+            // Bypassed connections have nothing to do with servers
+            if !err.is_proxied() || tried.len() + 1 >= max_attempts {
+                return Err(err);
+            }
+
+            tried.push(server);
+            server = match servers.pick_next_server(addr, &tried) {
+                Some(s) => s,
+                None => return Err(err),
+            };
+
+            debug!(
+                "failed to connect {} via {}, failing over to {}, error: {}",
+                addr,
+                tried[tried.len() - 1].server_config().addr(),
+                server.server_config().addr(),
+                err
+            );
         }
     }
 
@@ -117,11 +322,61 @@ impl ProxyStream {
 
     /// Connect to remote via proxy server
     ///
-    /// This is used for hosts that matches ACL proxied rules
+    /// This is used for hosts that matches ACL proxied rules.
+    /// Connect failures, and servers resetting connections before the first byte are reported to `stat`.
+    ///
+    /// Streams are carried by multiplexing sessions if `mux` is enabled for the server
     pub async fn connect_proxied(
         context: SharedContext,
         svr_cfg: &ServerConfig,
         addr: &Address,
+        stat: Option<&SharedServerStatisticData>,
+    ) -> io::Result<ProxyStream> {
+        if svr_cfg.mux() {
+            match connect_multiplexed(&context, svr_cfg, addr, stat).await {
+                Ok(Some(stream)) => return Ok(ProxyStream::Multiplexed { stream, context }),
+                Ok(None) => {}
+                Err(err) => {
+                    if let Some(stat) = stat {
+                        stat.report_failure().await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        ProxyStream::connect_proxied_dedicated(context, svr_cfg, addr, stat).await
+    }
+
+    /// Connect to remote via proxy server with a connection of its own, never multiplexed
+    ///
+    /// The connection may be an idle one taken from the pool
+    pub async fn connect_proxied_dedicated(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        addr: &Address,
+        stat: Option<&SharedServerStatisticData>,
+    ) -> io::Result<ProxyStream> {
+        ProxyStream::connect_proxied_with(context, svr_cfg, addr, stat, true).await
+    }
+
+    /// Connect to remote via proxy server with a new connection, never multiplexed or taken from the pool
+    ///
+    /// This is used by health checks, so idle connections are kept for clients, and the whole connect time is measured
+    pub async fn connect_proxied_new(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        addr: &Address,
+    ) -> io::Result<ProxyStream> {
+        ProxyStream::connect_proxied_with(context, svr_cfg, addr, None, false).await
+    }
+
+    async fn connect_proxied_with(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        addr: &Address,
+        stat: Option<&SharedServerStatisticData>,
+        pooled: bool,
     ) -> io::Result<ProxyStream> {
         debug!(
             "connect to {} via {} ({}) (proxied)",
@@ -130,12 +385,34 @@ impl ProxyStream {
             svr_cfg.external_addr()
         );
 
-        let server_stream = connect_proxy_server(&context, svr_cfg).await?;
-        let proxy_stream = proxy_server_handshake(context.clone(), server_stream, svr_cfg, addr).await?;
+        // Address header will be sent with the first payload if the window is enabled
+        let pending = context.config().first_payload_window.map(|_| PendingHeader::new(addr));
+
+        let mut header = BytesMut::with_capacity(addr.serialized_len());
+        addr.write_to_buf(&mut header);
+
+        let sent_header = if pending.is_some() { &[][..] } else { &header[..] };
+        let (proxy_stream, idle) =
+            match connect_proxy_server_with_header(&context, svr_cfg, sent_header, pooled, stat).await {
+                Ok(s) => s,
+                Err(err) => {
+                    if let Some(stat) = stat {
+                        stat.report_failure().await;
+                    }
+                    return Err(err);
+                }
+            };
 
         Ok(ProxyStream::Proxied {
             stream: proxy_stream,
             context,
+            stat: stat.cloned(),
+            pending,
+            retry: if idle {
+                Some(PooledRetry::new(svr_cfg, &header))
+            } else {
+                None
+            },
         })
     }
 
@@ -143,13 +420,176 @@ impl ProxyStream {
         context: SharedContext,
         svr_cfg: &ServerConfig,
         addr: &Address,
+        stat: &SharedServerStatisticData,
     ) -> Result<ProxyStream, ProxyStreamError> {
-        match ProxyStream::connect_proxied(context, svr_cfg, addr).await {
+        match ProxyStream::connect_proxied(context, svr_cfg, addr, Some(stat)).await {
             Ok(s) => Ok(s),
             Err(err) => Err(ProxyStreamError::new(err, false)),
         }
     }
 
+    /// Request proxy server to listen for an inbound connection (SOCKS5 BIND)
+    ///
+    /// Replies of the request should be read from the returned stream
+    pub async fn bind_proxied(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        addr: &Address,
+    ) -> io::Result<ProxyStream> {
+        debug!(
+            "bind for {} via {} ({}) (proxied)",
+            addr,
+            svr_cfg.addr(),
+            svr_cfg.external_addr()
+        );
+
+        // Sends a SOCKS5 request header instead of `Address`,
+        // server distinguishes them by the first byte
+        let header = TcpRequestHeader::new(Command::TcpBind, addr.clone());
+        let mut header_buf = BytesMut::with_capacity(header.serialized_len());
+        header.write_to_buf(&mut header_buf);
+
+        let (proxy_stream, idle) = connect_proxy_server_with_header(&context, svr_cfg, &header_buf, true, None).await?;
+
+        Ok(ProxyStream::Proxied {
+            stream: proxy_stream,
+            context,
+            stat: None,
+            pending: None,
+            retry: if idle {
+                Some(PooledRetry::new(svr_cfg, &header_buf))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Waits for the first payload from `client`, and sends it with the address header in the same chunk
+    ///
+    /// The address header is sent alone if nothing is received in `first_payload_window`.
+    /// Nothing will be read if the header has already been sent.
+    pub async fn send_first_payload<R>(&mut self, client: &mut R) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        match self.read_first_payload(client).await? {
+            Some(payload) => self.write_first_payload(&payload).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Same as `send_first_payload`, but fails over to the next server in `servers` if the payload couldn't be sent
+    ///
+    /// Connect errors of `fast_open` and handshake errors with `first_payload_window` are only returned from
+    /// the first write. Nothing has been relayed back to `client` then, so the payload could be sent to another server.
+    pub async fn send_first_payload_balanced<S, R>(
+        self,
+        servers: &PingBalancer<S>,
+        server: SharedServerStatistic<S>,
+        addr: &Address,
+        client: &mut R,
+    ) -> io::Result<(SharedServerStatistic<S>, ProxyStream)>
+    where
+        S: ServerData,
+        R: AsyncRead + Unpin,
+    {
+        let payload = match self.read_first_payload(client).await? {
+            Some(p) => p,
+            None => return Ok((server, self)),
+        };
+
+        let max_attempts = servers.context().config().failover_attempts;
+
+        let mut server = server;
+        let mut result = Ok(self);
+        let mut tried = Vec::new();
+
+        loop {
+            let err = match result {
+                Ok(mut stream) => match stream.write_first_payload(&payload).await {
+                    Ok(..) => return Ok((server, stream)),
+                    Err(err) => {
+                        server.report_failure().await;
+                        err
+                    }
+                },
+                Err(err) => err,
+            };
+
+            if tried.len() + 1 >= max_attempts {
+                return Err(err);
+            }
+
+            tried.push(server);
+            server = match servers.pick_next_server(addr, &tried) {
+                Some(s) => s,
+                None => return Err(err),
+            };
+
+            debug!(
+                "failed to send first payload to {} via {}, failing over to {}, error: {}",
+                addr,
+                tried[tried.len() - 1].server_config().addr(),
+                server.server_config().addr(),
+                err
+            );
+
+            result = ProxyStream::connect_proxied(
+                server.clone_context(),
+                server.server_config(),
+                addr,
+                Some(server.data()),
+            )
+            .await;
+        }
+    }
+
+    /// Reads the first payload from `client` in `first_payload_window`
+    ///
+    /// Returns `None` if the address header has already been sent,
+    /// the payload is empty if nothing is received in the window.
+    async fn read_first_payload<R>(&self, client: &mut R) -> io::Result<Option<Vec<u8>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let window = match *self {
+            ProxyStream::Proxied {
+                ref context,
+                pending: Some(..),
+                ..
+            } => context.config().first_payload_window,
+            _ => None,
+        };
+
+        let window = match window {
+            Some(w) => w,
+            None => return Ok(None),
+        };
+
+        let mut buf = vec![0u8; FIRST_PAYLOAD_BUFFER_SIZE];
+        match time::timeout(window, client.read(&mut buf)).await {
+            Ok(Ok(n)) => {
+                trace!("got first payload {} bytes", n);
+                buf.truncate(n);
+            }
+            Ok(Err(err)) => return Err(err),
+            Err(..) => {
+                trace!("no payload received in {:?}, sending address header alone", window);
+                buf.clear();
+            }
+        }
+
+        Ok(Some(buf))
+    }
+
+    /// Sends the first payload with the pending address header
+    async fn write_first_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_all(payload).await?;
+
+        // Header is still pending if client closed without sending anything
+        self.flush().await
+    }
+
     /// Split into reader and writer
     pub fn split(self) -> (ReadHalf<ProxyStream>, WriteHalf<ProxyStream>) {
         use tokio::io::split;
@@ -161,13 +601,14 @@ impl ProxyStream {
         match *self {
             ProxyStream::Direct { ref stream, .. } => stream.get_ref().local_addr(),
             ProxyStream::Proxied { ref stream, .. } => stream.get_ref().get_ref().local_addr(),
+            ProxyStream::Multiplexed { ref stream, .. } => Ok(stream.local_addr()),
         }
     }
 
     /// Check if the underlying connection is proxied
     pub fn is_proxied(&self) -> bool {
         match *self {
-            ProxyStream::Proxied { .. } => true,
+            ProxyStream::Proxied { .. } | ProxyStream::Multiplexed { .. } => true,
             _ => false,
         }
     }
@@ -177,24 +618,82 @@ impl ProxyStream {
         match *self {
             ProxyStream::Direct { ref context, .. } => &context,
             ProxyStream::Proxied { ref context, .. } => &context,
+            ProxyStream::Multiplexed { ref context, .. } => &context,
         }
     }
 }
 
 impl Unpin for ProxyStream {}
 
+impl PlainTcpStream for ProxyStream {
+    fn plain_tcp_stream(&self) -> Option<&TcpStream> {
+        match *self {
+            // Nothing should have been buffered, it would be lost while relaying with the socket
+            ProxyStream::Direct { ref stream, .. } if stream.buffer().is_empty() => Some(stream.get_ref()),
+            _ => None,
+        }
+    }
+}
+
 macro_rules! forward_call {
     ($self:expr, $method:ident $(, $param:expr)*) => {
         match *$self {
             ProxyStream::Direct { ref mut stream, .. } => Pin::new(stream).$method($($param),*),
             ProxyStream::Proxied { ref mut stream, .. } => Pin::new(stream).$method($($param),*),
+            ProxyStream::Multiplexed { ref mut stream, .. } => Pin::new(stream).$method($($param),*),
         }
     };
 }
 
 impl AsyncRead for ProxyStream {
     fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
-        let p = forward_call!(self, poll_read, cx, buf);
+        let p = loop {
+            let p = match self.poll_retry(cx) {
+                Poll::Ready(Ok(())) => forward_call!(self, poll_read, cx, buf),
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            // Idle connection closed by the server before it is taken is closed or reset without any data
+            let failed = match p {
+                Poll::Ready(Ok(0)) => !buf.is_empty(),
+                Poll::Ready(Err(ref err)) => is_stream_failure(err),
+                _ => false,
+            };
+            if failed && self.start_retry() {
+                continue;
+            }
+            break p;
+        };
+
+        // Servers reset connections without responding if keys or handshakes are wrong,
+        // which could only be told before receiving any data.
+        //
+        // EOF is not a failure, targets may close connections without sending anything
+        if let ProxyStream::Proxied {
+            ref mut stat,
+            ref mut retry,
+            ..
+        } = *self
+        {
+            match p {
+                Poll::Ready(Ok(n)) if n > 0 => {
+                    *retry = None;
+                    if let Some(stat) = stat.take() {
+                        stat.report_success();
+                    }
+                }
+                Poll::Ready(Ok(..)) if !buf.is_empty() => {
+                    stat.take();
+                }
+                Poll::Ready(Err(ref err)) if is_stream_failure(err) => {
+                    if let Some(stat) = stat.take() {
+                        stat.report_failure_deferred();
+                    }
+                }
+                _ => {}
+            }
+        }
 
         // Flow statistic for Android client
         if cfg!(target_os = "android") && self.is_proxied() {
@@ -209,7 +708,36 @@ impl AsyncRead for ProxyStream {
 
 impl AsyncWrite for ProxyStream {
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        let p = forward_call!(self, poll_write, cx, buf);
+        let p = loop {
+            match self.poll_retry(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => break Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let p = match *self {
+                ProxyStream::Proxied {
+                    ref mut stream,
+                    pending: ref mut pending @ Some(..),
+                    ..
+                } => {
+                    let header = pending.as_mut().unwrap();
+                    let p = header.poll_write(cx, stream, buf);
+                    if header.is_sent() {
+                        *pending = None;
+                    }
+                    p
+                }
+                _ => forward_call!(self, poll_write, cx, buf),
+            };
+
+            match p {
+                Poll::Ready(Ok(n)) => self.record_written(&buf[..n]),
+                Poll::Ready(Err(ref err)) if is_stream_failure(err) && self.start_retry() => continue,
+                _ => {}
+            }
+            break p;
+        };
 
         // Flow statistic for Android client
         if cfg!(target_os = "android") && self.is_proxied() {
@@ -222,14 +750,114 @@ impl AsyncWrite for ProxyStream {
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
-        forward_call!(self, poll_flush, cx)
+        loop {
+            ready!(self.poll_retry(cx))?;
+
+            let p = match self.poll_write_pending_header(cx) {
+                Poll::Ready(Ok(())) => forward_call!(self, poll_flush, cx),
+                p => p,
+            };
+            match p {
+                Poll::Ready(Err(ref err)) if is_stream_failure(err) && self.start_retry() => continue,
+                p => return p,
+            }
+        }
     }
 
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
-        forward_call!(self, poll_shutdown, cx)
+        loop {
+            ready!(self.poll_retry(cx))?;
+
+            let p = match self.poll_write_pending_header(cx) {
+                Poll::Ready(Ok(())) => forward_call!(self, poll_shutdown, cx),
+                p => p,
+            };
+            match p {
+                Poll::Ready(Err(ref err)) if is_stream_failure(err) && self.start_retry() => continue,
+                p => return p,
+            }
+        }
     }
 }
 
+impl ProxyStream {
+    /// Sends the pending address header, with the first payload if it has been buffered
+    fn poll_write_pending_header(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if let ProxyStream::Proxied {
+            ref mut stream,
+            ref mut pending,
+            ..
+        } = *self
+        {
+            if let Some(ref mut header) = *pending {
+                ready!(header.poll_flush_buf(cx, stream))?;
+            }
+            *pending = None;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Starts retrying with a new connection if the stream is on an idle connection taken from the pool,
+    /// and nothing has been received. Returns `false` if it couldn't be retried.
+    ///
+    /// The pending header is dropped, it is written to the new connection with the payloads.
+    fn start_retry(&mut self) -> bool {
+        if let ProxyStream::Proxied {
+            ref context,
+            ref mut pending,
+            retry: Some(ref mut retry),
+            ..
+        } = *self
+        {
+            if retry.connecting.is_none() {
+                retry.start(context.clone());
+                *pending = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Waits for the new connection of retrying, which replaces the failed one. Connections are only retried once.
+    ///
+    /// Relays poll both halves of the stream in the same task, so the task is woken up no matter which half polled it.
+    fn poll_retry(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if let ProxyStream::Proxied {
+            ref mut stream,
+            ref mut retry,
+            ..
+        } = *self
+        {
+            if let Some(connecting) = retry.as_mut().and_then(|r| r.connecting.as_mut()) {
+                let fut = connecting.get_mut().unwrap_or_else(|err| err.into_inner());
+                let result = ready!(fut.as_mut().poll(cx));
+                *retry = None;
+                *stream = result?;
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Keeps payloads written to the idle connection for retrying
+    fn record_written(&mut self, payload: &[u8]) {
+        if let ProxyStream::Proxied { ref mut retry, .. } = *self {
+            let full = match *retry {
+                Some(ref mut r) => !r.record(payload),
+                None => false,
+            };
+            if full {
+                *retry = None;
+            }
+        }
+    }
+}
+
+fn is_stream_failure(err: &io::Error) -> bool {
+    err.kind() != ErrorKind::Interrupted && err.kind() != ErrorKind::WouldBlock
+}
+
 async fn connect_proxy_server_internal(
     context: &Context,
     orig_svr_addr: &ServerAddr,
@@ -273,15 +901,77 @@ async fn connect_proxy_server_internal(
     }
 }
 
-/// Connect to proxy server with `ServerConfig`
-async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::Result<STcpStream> {
+/// Connect to proxy server with `ServerConfig`, and writes `header` to the encrypted stream
+///
+/// Takes an idle connection from the pool if `pooled` and `idle_connections` is set. It may have been closed by
+/// the server right before it is taken, so it is retried with a new connection once if writing fails.
+/// Returns the stream, and whether it is an idle connection taken from the pool.
+async fn connect_proxy_server_with_header(
+    context: &SharedContext,
+    svr_cfg: &ServerConfig,
+    header: &[u8],
+    pooled: bool,
+    stat: Option<&SharedServerStatisticData>,
+) -> io::Result<(CryptoStream<STcpStream>, bool)> {
+    // NOTE: Headers are very small in most cases, so they will be sent with the IV/Nonce data.
+    //
+    // For lower latency, first packet should be sent back quickly,
+    // so TCP_NODELAY should be kept enabled until the first data packet is received.
+    // https://github.com/shadowsocks/shadowsocks-libev/pull/746
+    if pooled {
+        if let Some(server_stream) = pool::take_connection(context, svr_cfg) {
+            trace!("got idle connection to proxy {} from pool", svr_cfg.addr());
+
+            let mut stream = CryptoStream::new(context.clone(), server_stream, svr_cfg, StreamType::Client);
+            match stream.write_all(header).await {
+                Ok(..) => return Ok((stream, true)),
+                Err(err) => debug!(
+                    "failed to write to idle connection to proxy {}, retrying with a new connection, error: {}",
+                    svr_cfg.addr(),
+                    err
+                ),
+            }
+        }
+    }
+
+    let server_stream = connect_proxy_server(context, svr_cfg, stat).await?;
+    let mut stream = CryptoStream::new(context.clone(), server_stream, svr_cfg, StreamType::Client);
+    stream.write_all(header).await?;
+    Ok((stream, false))
+}
+
+/// Connect to proxy server with a new connection, its connect time is reported to `stat` as a latency
+///
+/// Connections with `fast_open` are not measured.
+async fn connect_proxy_server(
+    context: &SharedContext,
+    svr_cfg: &ServerConfig,
+    stat: Option<&SharedServerStatisticData>,
+) -> io::Result<STcpStream> {
+    let start = Instant::now();
+    let stream = connect_proxy_server_new(context, svr_cfg).await?;
+
+    // Connections with TCP Fast Open are not established until the first write, their connect time is meaningless
+    if let (Some(stat), false) = (stat, context.config().fast_open) {
+        let elapsed = Instant::now() - start;
+        let elapsed = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()); // Converted to ms
+        stat.report_connect_latency(elapsed).await;
+    }
+
+    Ok(stream)
+}
+
+/// Connect to proxy server with a new connection
+pub(super) async fn connect_proxy_server_new(context: &Context, svr_cfg: &ServerConfig) -> io::Result<STcpStream> {
     let timeout = svr_cfg.timeout().or(context.config().timeout);
 
     let svr_addr = match context.config().config_type {
         ConfigType::Server => svr_cfg.addr(),
-        ConfigType::Socks5Local | ConfigType::TunnelLocal | ConfigType::HttpLocal | ConfigType::RedirLocal => {
-            svr_cfg.external_addr()
-        }
+        ConfigType::Socks5Local
+        | ConfigType::TunnelLocal
+        | ConfigType::HttpLocal
+        | ConfigType::RedirLocal
+        | ConfigType::MixedLocal => svr_cfg.external_addr(),
         ConfigType::Manager => unreachable!("ConfigType::Manager shouldn't need to connect to proxy server"),
     };
 
@@ -336,32 +1026,280 @@ async fn connect_proxy_server(context: &Context, svr_cfg: &ServerConfig) -> io::
     Err(last_err)
 }
 
-/// Handshake logic for ShadowSocks Client
-async fn proxy_server_handshake(
-    context: SharedContext,
-    remote_stream: STcpStream,
+/// Opens a stream to `addr` in a multiplexing session to the server, a new session is created if necessary
+///
+/// Returns `None` if the server doesn't support multiplexing, or the pool is full, then a normal connection should be
+/// used. Sessions are closed if no streams are opened in them for `mux::SESSION_IDLE_TIMEOUT`.
+async fn connect_multiplexed(
+    context: &SharedContext,
     svr_cfg: &ServerConfig,
-    relay_addr: &Address,
-) -> io::Result<CryptoStream<STcpStream>> {
-    let mut stream = CryptoStream::new(context, remote_stream, svr_cfg);
+    addr: &Address,
+    stat: Option<&SharedServerStatisticData>,
+) -> io::Result<Option<MuxStream>> {
+    let pool = context.mux_pool(svr_cfg);
+    if pool.is_disabled() {
+        return Ok(None);
+    }
+
+    let slot = match pool.pick() {
+        Some(PickedSession::Existing(session)) => match session.open(addr) {
+            Ok(s) => {
+                debug!("connect to {} via {} (multiplexed)", addr, svr_cfg.addr());
+                return Ok(Some(s));
+            }
+            Err(err) => {
+                trace!("failed to open stream in multiplexing session, error: {}", err);
+                return Ok(None);
+            }
+        },
+        Some(PickedSession::New(slot)) => slot,
+        None => {
+            trace!("multiplexing sessions to {} are full", svr_cfg.addr());
+            return Ok(None);
+        }
+    };
 
-    trace!("got encrypt stream and going to send addr: {:?}", relay_addr);
+    debug!("creating multiplexing session to {}", svr_cfg.addr());
 
-    // Send relay address to remote
-    //
-    // NOTE: `Address` handshake packets are very small in most cases,
-    // so it will be sent with the IV/Nonce data (implemented inside `CryptoStream`).
-    //
-    // For lower latency, first packet should be sent back quickly,
-    // so TCP_NODELAY should be kept enabled until the first data packet is received.
-    let mut addr_buf = BytesMut::with_capacity(relay_addr.serialized_len());
-    relay_addr.write_to_buf(&mut addr_buf);
-    stream.write_all(&addr_buf).await?;
+    let (mut stream, idle) = connect_proxy_server_with_header(context, svr_cfg, &[], true, stat).await?;
+    let mut accepted = mux::request_session(&mut stream).await;
 
-    // Here we should keep the TCP_NODELAY set until the first packet is received.
-    // https://github.com/shadowsocks/shadowsocks-libev/pull/746
-    //
-    // Reset TCP_NODELAY after the first packet is received and sent back.
+    // Idle connection closed by the server couldn't be told from a server refusing multiplexing
+    let failed = match accepted {
+        Ok(ok) => !ok,
+        Err(..) => true,
+    };
+    if failed && idle {
+        debug!(
+            "failed to request multiplexing session on idle connection to proxy {}, retrying with a new connection",
+            svr_cfg.addr()
+        );
 
-    Ok(stream)
+        let (s, _) = connect_proxy_server_with_header(context, svr_cfg, &[], false, stat).await?;
+        stream = s;
+        accepted = mux::request_session(&mut stream).await;
+    }
+    let local_addr = stream.get_ref().get_ref().local_addr()?;
+
+    if !accepted? {
+        // Server doesn't know the extended command, or multiplexing is not enabled
+        info!(
+            "server {} refused multiplexing, fallback to normal connections",
+            svr_cfg.addr()
+        );
+        pool.disable();
+        return Ok(None);
+    }
+
+    if let Some(stat) = stat {
+        stat.report_success();
+    }
+
+    let (session, fut) = Session::client(stream, local_addr);
+    let idle_session = session.clone();
+    let svr_addr = svr_cfg.addr().clone();
+    tokio::spawn(async move {
+        let idle = idle_session.close_when_idle(mux::SESSION_IDLE_TIMEOUT);
+        pin_mut!(fut, idle);
+
+        match future::select(fut, idle).await {
+            Either::Left((Ok(..), ..)) => debug!("multiplexing session to {} closed", svr_addr),
+            Either::Left((Err(err), ..)) => {
+                debug!("multiplexing session to {} closed with error: {}", svr_addr, err)
+            }
+            Either::Right(..) => debug!("multiplexing session to {} closed because it is idle", svr_addr),
+        }
+    });
+
+    let stream = session.open(addr)?;
+    slot.push(session);
+
+    debug!("connect to {} via {} (multiplexed)", addr, svr_cfg.addr());
+    Ok(Some(stream))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::future;
+    use tokio::runtime::Builder;
+
+    /// Writes at most 3 bytes at once, and returns `Pending` before every write
+    #[derive(Default)]
+    struct PartialWriter {
+        data: Vec<u8>,
+        ready: bool,
+    }
+
+    impl AsyncWrite for PartialWriter {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            if !self.ready {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn header_bytes(addr: &Address) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        addr.write_to_buf(&mut buf);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn test_pending_header_write() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+
+        rt.block_on(async move {
+            let addr = Address::DomainNameAddress("example.com".to_owned(), 80);
+            let mut header = PendingHeader::new(&addr);
+            let mut writer = PartialWriter::default();
+
+            // The first payload is done once it is buffered, even if the stream is not writable
+            match future::poll_fn(|cx| Poll::Ready(header.poll_write(cx, &mut writer, b"HEllo"))).await {
+                Poll::Ready(Ok(n)) => assert_eq!(n, 5),
+                _ => panic!("the first payload is expected to be buffered"),
+            }
+            assert!(writer.data.is_empty());
+            assert!(!header.is_sent());
+
+            // Buffer is written before the following payload
+            let n = future::poll_fn(|cx| header.poll_write(cx, &mut writer, b" WORld"))
+                .await
+                .unwrap();
+            assert!(header.is_sent());
+
+            let mut expected = header_bytes(&addr);
+            expected.extend_from_slice(b"HEllo");
+            expected.extend_from_slice(&b" WORld"[..n]);
+            assert_eq!(writer.data, expected);
+        });
+    }
+
+    #[test]
+    fn test_pending_header_flush() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+
+        rt.block_on(async move {
+            let addr = Address::SocketAddress("127.0.0.1:80".parse().unwrap());
+
+            // Header is sent alone if nothing is written
+            let mut header = PendingHeader::new(&addr);
+            let mut writer = PartialWriter::default();
+            future::poll_fn(|cx| header.poll_flush_buf(cx, &mut writer))
+                .await
+                .unwrap();
+            assert!(header.is_sent());
+            assert_eq!(writer.data, header_bytes(&addr));
+
+            // Buffered payload is sent by flushing
+            let mut header = PendingHeader::new(&addr);
+            let mut writer = PartialWriter::default();
+            match future::poll_fn(|cx| Poll::Ready(header.poll_write(cx, &mut writer, b"HEllo WORld"))).await {
+                Poll::Ready(Ok(n)) => assert_eq!(n, 11),
+                _ => panic!("the first payload is expected to be buffered"),
+            }
+            future::poll_fn(|cx| header.poll_flush_buf(cx, &mut writer))
+                .await
+                .unwrap();
+            assert!(header.is_sent());
+
+            let mut expected = header_bytes(&addr);
+            expected.extend_from_slice(b"HEllo WORld");
+            assert_eq!(writer.data, expected);
+        });
+    }
+
+    #[test]
+    fn test_pooled_connection_retry() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        use tokio::net::TcpListener;
+
+        use crate::{config::Config, context::ServerState, crypto::CipherType};
+
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let svr_cfg = ServerConfig::basic(
+                listener.local_addr().unwrap(),
+                "test-password".to_owned(),
+                CipherType::Aes256Gcm,
+            );
+
+            let mut config = Config::new(ConfigType::Socks5Local);
+            config.server = vec![svr_cfg.clone()];
+            config.idle_connections = 1;
+            config.timeout = Some(Duration::from_secs(10));
+            let state = ServerState::new_shared(&config, rt_handle.clone()).await;
+            let context = Context::new_shared(config, state);
+
+            let svr_config = Config::new(ConfigType::Server);
+            let svr_state = ServerState::new_shared(&svr_config, rt_handle).await;
+            let svr_context = Context::new_shared(svr_config, svr_state);
+
+            // The first connection is the idle one, which is closed by the server after it is taken.
+            // Others respond to "hello" with "world"
+            let accepted = Arc::new(AtomicUsize::new(0));
+            let counter = accepted.clone();
+            let server_cfg = svr_cfg.clone();
+            tokio::spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    let idx = counter.fetch_add(1, Ordering::SeqCst);
+                    let svr_context = svr_context.clone();
+                    let svr_cfg = server_cfg.clone();
+                    tokio::spawn(async move {
+                        let socket = STcpStream::new(socket, None);
+                        let mut stream = CryptoStream::new(svr_context, socket, &svr_cfg, StreamType::Server);
+                        let _ = Address::read_from(&mut stream).await;
+                        if idx == 0 {
+                            return;
+                        }
+
+                        let mut buf = [0u8; 5];
+                        stream.read_exact(&mut buf).await.unwrap();
+                        assert_eq!(&buf, b"hello");
+                        stream.write_all(b"world").await.unwrap();
+                        stream.flush().await.unwrap();
+                    });
+                }
+            });
+
+            // Pool is filled after it is used for the first time
+            assert!(pool::take_connection(&context, &svr_cfg).is_none());
+            time::delay_for(Duration::from_millis(300)).await;
+            assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+            let target = Address::DomainNameAddress("example.com".to_owned(), 80);
+            let mut stream = ProxyStream::connect_proxied_dedicated(context.clone(), &svr_cfg, &target, None)
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+
+            // Responded on a new connection
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+        });
+    }
 }
@@ -5,55 +5,59 @@ use std::{
     net::SocketAddr,
 };
 
-use futures::future::{self, Either};
-use log::{debug, error, info, trace};
+use futures::future::Either;
+use log::{debug, error, info, trace, warn};
 use tokio::net::{TcpListener, TcpStream};
 
 use crate::{
+    config::LocalConfig,
     context::SharedContext,
     relay::{
-        loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
+        loadbalancing::server::{PingBalancer, ServerData, SharedServerStatistic},
         redir::{TcpListenerRedirExt, TcpStreamRedirExt},
         socks5::Address,
+        sys::set_tcp_fastopen,
     },
 };
 
-use super::ProxyStream;
+use super::{splice, ProxyStream};
 
 /// Established Client Transparent Proxy
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
-async fn establish_client_tcp_redir<'a>(
-    server: &SharedPlainServerStatistic,
+async fn establish_client_tcp_redir<'a, S: ServerData>(
+    server: &SharedServerStatistic<S>,
     mut s: TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
     let svr_cfg = server.server_config();
 
-    let svr_s = ProxyStream::connect(server.clone_context(), svr_cfg, addr).await?;
-    let (mut svr_r, mut svr_w) = svr_s.split();
+    let mut svr_s = ProxyStream::connect(server.clone_context(), svr_cfg, addr, server.data()).await?;
 
-    let (mut r, mut w) = s.split();
+    // Bypassed connections are not counted for the server
+    let _connection = if svr_s.is_proxied() {
+        Some(server.track_connection())
+    } else {
+        None
+    };
 
-    use tokio::io::copy;
-
-    let rhalf = copy(&mut r, &mut svr_w);
-    let whalf = copy(&mut svr_r, &mut w);
+    // Sends the first payload with the address header
+    svr_s.send_first_payload(&mut s).await?;
 
     debug!("REDIR relay established {} <-> {}", client_addr, addr);
 
-    match future::select(rhalf, whalf).await {
-        Either::Left((Ok(..), _)) => trace!("REDIR relay {} -> {} closed", client_addr, addr),
-        Either::Left((Err(err), _)) => {
+    match splice::relay(server.context(), &mut s, svr_s).await {
+        Either::Left(Ok(..)) => trace!("REDIR relay {} -> {} closed", client_addr, addr),
+        Either::Left(Err(err)) => {
             if let ErrorKind::TimedOut = err.kind() {
                 trace!("REDIR relay {} -> {} closed with error {}", client_addr, addr, err);
             } else {
                 error!("REDIR relay {} -> {} closed with error {}", client_addr, addr, err);
             }
         }
-        Either::Right((Ok(..), _)) => trace!("REDIR relay {} <- {} closed", client_addr, addr),
-        Either::Right((Err(err), _)) => {
+        Either::Right(Ok(..)) => trace!("REDIR relay {} <- {} closed", client_addr, addr),
+        Either::Right(Err(err)) => {
             if let ErrorKind::TimedOut = err.kind() {
                 trace!("REDIR relay {} <- {} closed with error {}", client_addr, addr, err);
             } else {
@@ -67,7 +71,11 @@ async fn establish_client_tcp_redir<'a>(
     Ok(())
 }
 
-async fn handle_redir_client(server: &SharedPlainServerStatistic, s: TcpStream, daddr: SocketAddr) -> io::Result<()> {
+async fn handle_redir_client<S: ServerData>(
+    server: &SharedServerStatistic<S>,
+    s: TcpStream,
+    daddr: SocketAddr,
+) -> io::Result<()> {
     let svr_cfg = server.server_config();
 
     if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
@@ -87,8 +95,11 @@ async fn handle_redir_client(server: &SharedPlainServerStatistic, s: TcpStream,
     establish_client_tcp_redir(server, s, client_addr, &target_addr).await
 }
 
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let local_addr = context.config().local.as_ref().expect("local config");
+pub async fn run<S>(context: SharedContext, local_config: LocalConfig, servers: PingBalancer<S>) -> io::Result<()>
+where
+    S: ServerData + 'static,
+{
+    let local_addr = &local_config.addr;
     let bind_addr = local_addr.bind_addr(&*context).await?;
 
     let redir_ty = context.config().tcp_redir;
@@ -97,19 +108,25 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         .await
         .unwrap_or_else(|err| panic!("Failed to listen on {}, {}", local_addr, err));
 
+    if context.config().fast_open {
+        if let Err(err) = set_tcp_fastopen(&listener) {
+            warn!("failed to set TCP_FASTOPEN on listener {}, error: {}", local_addr, err);
+        }
+    }
+
     let actual_local_addr = listener.local_addr().expect("determine port bound to");
 
-    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
     info!("shadowsocks TCP redirect listening on {}", actual_local_addr);
 
     loop {
         let (socket, peer_addr) = listener.accept().await?;
-        let server = servers.pick_server();
 
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
+        let servers = servers.clone();
+        let active = context.new_active_connection();
         tokio::spawn(async move {
+            let _active = active;
             let dst_addr = match socket.destination_addr(redir_ty) {
                 Ok(d) => d,
                 Err(err) => {
@@ -121,6 +138,10 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 }
             };
 
+            // Destination is known only after accepted
+            let server = servers.pick_server(&Address::SocketAddress(dst_addr));
+            trace!("picked proxy server: {:?}", server.server_config());
+
             if let Err(err) = handle_redir_client(&server, socket, dst_addr).await {
                 error!("TCP redirect client, error: {:?}", err);
             }
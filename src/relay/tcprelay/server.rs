@@ -1,6 +1,6 @@
 //! Relay for TCP server that running on the server side
 
-use std::{io, io::ErrorKind, net::SocketAddr};
+use std::{io, io::ErrorKind, net::SocketAddr, time::Duration};
 
 use futures::{
     future::{self, Either},
@@ -9,93 +9,69 @@ use futures::{
 use log::{debug, error, info, trace, warn};
 use tokio::{
     self,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
     net::{TcpListener, TcpStream},
 };
 
 use crate::{
     config::ServerConfig,
-    context::SharedContext,
+    context::{Context, SharedContext},
     relay::{
-        flow::{SharedMultiServerFlowStatistic, SharedServerFlowStatistic},
-        socks5::Address,
+        flow::{ServerQuota, SharedMultiServerFlowStatistic, SharedServerFlowStatistic},
+        rate_limit::ConnectionRateLimiter,
+        socks5::{self, Address, Command, Reply, TcpRequestHeader, TcpResponseHeader},
+        sys::tcp_listener_bind,
         utils::try_timeout,
     },
 };
 
-use super::{monitor::TcpMonStream, utils::connect_tcp_stream, CryptoStream, STcpStream};
-
-#[allow(clippy::cognitive_complexity)]
-async fn handle_client(
-    context: SharedContext,
-    flow_stat: SharedServerFlowStatistic,
-    svr_cfg: &ServerConfig,
-    socket: TcpStream,
-    peer_addr: SocketAddr,
-) -> io::Result<()> {
-    let timeout = svr_cfg.timeout().or(context.config().timeout);
-
-    if let Err(err) = socket.set_keepalive(timeout) {
-        error!("failed to set keep alive: {:?}", err);
-    }
-
-    trace!("got connection addr {} with proxy server {:?}", peer_addr, svr_cfg);
-
-    let mut stream = STcpStream::new(socket, timeout);
-    stream.set_nodelay(context.config().no_delay)?;
-
-    // Wrap with a data transfer monitor
-    let stream = TcpMonStream::new(flow_stat, stream);
-
-    // Do server-client handshake
-    // Perform encryption IV exchange
-    let mut stream = CryptoStream::new(context.clone(), stream, svr_cfg);
-
-    // Read remote Address
-    let remote_addr = match Address::read_from(&mut stream).await {
-        Ok(o) => o,
-        Err(err) => {
-            error!(
-                "failed to decode Address, may be wrong method or key, from client {}, error: {}",
-                peer_addr, err
-            );
-            return Err(From::from(err));
-        }
-    };
-
-    debug!("RELAY {} <-> {} establishing", peer_addr, remote_addr);
-
-    // Check if remote_addr matches any ACL rules
-    if context.check_outbound_blocked(&remote_addr) {
-        warn!("outbound {} is blocked by ACL rules", remote_addr);
-        return Ok(());
-    }
+use super::{
+    monitor::{RateLimitedStream, TcpMonStream},
+    mux::Session,
+    utils::connect_tcp_stream,
+    CryptoStream,
+    STcpStream,
+    StreamType,
+    DEFAULT_BIND_TIMEOUT,
+};
 
+async fn connect_remote(context: &Context, remote_addr: &Address, timeout: Option<Duration>) -> io::Result<TcpStream> {
     let bind_addr = match context.config().local {
         None => None,
         Some(ref addr) => {
-            let ba = addr.bind_addr(&*context).await?;
+            let ba = addr.bind_addr(context).await?;
             Some(ba)
         }
     };
 
-    let mut remote_stream = match remote_addr {
+    match *remote_addr {
         Address::SocketAddress(ref saddr) => {
             // NOTE: ACL is already checked above, connect directly
 
-            match try_timeout(connect_tcp_stream(saddr, &bind_addr), timeout).await {
+            match try_timeout(
+                connect_tcp_stream(saddr, &bind_addr, context.config().fast_open),
+                timeout,
+            )
+            .await
+            {
                 Ok(s) => {
                     debug!("connected to remote {}", saddr);
-                    s
+                    Ok(s)
                 }
                 Err(err) => {
                     error!("failed to connect remote {}, {}", saddr, err);
-                    return Err(err);
+                    Err(err)
                 }
             }
         }
         Address::DomainNameAddress(ref dname, port) => {
-            let result = lookup_outbound_then!(&*context, dname.as_str(), port, |addr| {
-                match try_timeout(connect_tcp_stream(&addr, &bind_addr), timeout).await {
+            let result = lookup_outbound_then!(context, dname.as_str(), port, |addr| {
+                match try_timeout(
+                    connect_tcp_stream(&addr, &bind_addr, context.config().fast_open),
+                    timeout,
+                )
+                .await
+                {
                     Ok(s) => Ok(s),
                     Err(err) => {
                         debug!(
@@ -110,19 +86,317 @@ async fn handle_client(
             match result {
                 Ok((addr, s)) => {
                     trace!("connected remote {}:{} (resolved: {})", dname, port, addr);
-                    s
+                    Ok(s)
                 }
                 Err(err) => {
                     error!("failed to connect remote {}:{}, {}", dname, port, err);
-                    return Err(err);
+                    Err(err)
                 }
             }
         }
+    }
+}
+
+/// Handles BIND command
+///
+/// Client sends a SOCKS5 `TcpRequestHeader` instead of an `Address` through the encrypted channel.
+/// Server listens on a new port and sends back 2 `TcpResponseHeader`s, the first one is sent after
+/// the listener is created, the second one is sent after the inbound connection is accepted.
+///
+/// Returns the accepted connection and its peer address, or `None` if the request is rejected.
+async fn handle_bind<S>(
+    context: &Context,
+    stream: &mut S,
+    server_addr: SocketAddr,
+    bind_target: &Address,
+    timeout: Option<Duration>,
+) -> io::Result<Option<(Address, TcpStream)>>
+where
+    S: AsyncWrite + Unpin,
+{
+    // Check if bind_target matches any ACL rules
+    if context.check_outbound_blocked(bind_target) {
+        warn!("BIND {} is blocked by ACL rules", bind_target);
+        let rh = TcpResponseHeader::new(Reply::ConnectionNotAllowed, bind_target.clone());
+        rh.write_to(stream).await?;
+        return Ok(None);
+    }
+
+    // Listens on the outbound address if specified
+    let bind_addr = match context.config().local {
+        None => SocketAddr::new(server_addr.ip(), 0),
+        Some(ref addr) => {
+            let mut ba = addr.bind_addr(context).await?;
+            ba.set_port(0);
+            ba
+        }
     };
 
+    let mut listener = match TcpListener::bind(&bind_addr).await {
+        Ok(l) => l,
+        Err(err) => {
+            error!("BIND {} failed to listen on {}, {}", bind_target, bind_addr, err);
+            let rh = TcpResponseHeader::new(Reply::GeneralFailure, bind_target.clone());
+            rh.write_to(stream).await?;
+            return Err(err);
+        }
+    };
+
+    let mut listen_addr = listener.local_addr()?;
+    if listen_addr.ip().is_unspecified() {
+        // Tell client the address that it is actually connected to
+        listen_addr.set_ip(server_addr.ip());
+    }
+
+    debug!("BIND {} listening on {}", bind_target, listen_addr);
+
+    // The first reply
+    let rh = TcpResponseHeader::new(Reply::Succeeded, Address::SocketAddress(listen_addr));
+    rh.write_to(stream).await?;
+
+    let (remote_stream, remote_addr) =
+        match try_timeout(listener.accept(), Some(timeout.unwrap_or(DEFAULT_BIND_TIMEOUT))).await {
+            Ok(r) => r,
+            Err(err) => {
+                error!("BIND {} failed to accept on {}, {}", bind_target, listen_addr, err);
+                let rh = TcpResponseHeader::new(Reply::TtlExpired, Address::SocketAddress(listen_addr));
+                rh.write_to(stream).await?;
+                return Err(err);
+            }
+        };
+
+    // Only accepts the host that client is expecting
+    let mut allowed = !context.check_resolved_outbound_blocked(&remote_addr);
+    if let Address::SocketAddress(ref expected) = *bind_target {
+        if !expected.ip().is_unspecified() && expected.ip() != remote_addr.ip() {
+            allowed = false;
+        }
+    }
+
+    if !allowed {
+        warn!("BIND {} rejected inbound connection from {}", bind_target, remote_addr);
+        let rh = TcpResponseHeader::new(Reply::ConnectionNotAllowed, Address::SocketAddress(remote_addr));
+        rh.write_to(stream).await?;
+        return Ok(None);
+    }
+
+    // The second reply
+    let rh = TcpResponseHeader::new(Reply::Succeeded, Address::SocketAddress(remote_addr));
+    rh.write_to(stream).await?;
+
+    Ok(Some((Address::SocketAddress(remote_addr), remote_stream)))
+}
+
+#[allow(clippy::cognitive_complexity)]
+async fn handle_client(
+    context: SharedContext,
+    flow_stat: SharedServerFlowStatistic,
+    svr_cfg: &ServerConfig,
+    svr_idx: usize,
+    socket: TcpStream,
+    peer_addr: SocketAddr,
+) -> io::Result<()> {
+    // Refuse new connections if traffic quota is used up or server is expired
+    if let Err(err) = flow_stat.check_quota(svr_cfg) {
+        warn!("refused connection from {}, {}", peer_addr, err);
+        return Err(err);
+    }
+
+    let timeout = svr_cfg.timeout().or(context.config().timeout);
+
+    if let Err(err) = socket.set_keepalive(timeout) {
+        error!("failed to set keep alive: {:?}", err);
+    }
+
+    trace!("got connection addr {} with proxy server {:?}", peer_addr, svr_cfg);
+
+    let server_addr = socket.local_addr()?;
+
+    let mut stream = STcpStream::new(socket, timeout);
+    stream.set_nodelay(context.config().no_delay)?;
+
+    // Wrap with a data transfer monitor
+    //
+    // Established connections will also be closed when the quota is used up
+    let mut stream = TcpMonStream::new(flow_stat.clone(), stream);
+    stream.set_quota(ServerQuota::new(svr_cfg));
+    stream.set_rate_limiter(ConnectionRateLimiter::new(&*context, svr_idx));
+
+    // Do server-client handshake
+    // Perform encryption IV exchange
+    let mut stream = CryptoStream::new(context.clone(), stream, svr_cfg, StreamType::Server);
+
+    // Read the first byte for determining the request type
+    //
+    // `Address` starts with ATYP, which never collides with the SOCKS5 version
+    let mut first_byte = [0u8; 1];
+    if let Err(err) = stream.read_exact(&mut first_byte).await {
+        error!(
+            "failed to decode Address, may be wrong method or key, from client {}, error: {}",
+            peer_addr, err
+        );
+        return Err(err);
+    }
+
+    // Count traffic for the user identified in handshake
+    if let Some(user) = stream.user() {
+        debug!("client {} authenticated as user {}", peer_addr, user.name());
+
+        let user_flow_stat = flow_stat.user(user.name());
+        stream.get_mut().set_user_flow_stat(user_flow_stat);
+    }
+
+    let (remote_addr, mut remote_stream) = if first_byte[0] == socks5::SOCKS5_VERSION {
+        // Extended request with a SOCKS5 request header
+        let header = match TcpRequestHeader::read_from(&mut (&first_byte[..]).chain(&mut stream)).await {
+            Ok(h) => h,
+            Err(err) => {
+                error!(
+                    "failed to decode TcpRequestHeader from client {}, error: {}",
+                    peer_addr, err
+                );
+                return Err(From::from(err));
+            }
+        };
+
+        match header.command {
+            Command::TcpBind if svr_cfg.allow_bind() => {
+                debug!("BIND {} for client {}", header.address, peer_addr);
+
+                match handle_bind(&*context, &mut stream, server_addr, &header.address, timeout).await? {
+                    Some(r) => r,
+                    None => return Ok(()),
+                }
+            }
+            Command::Multiplex if svr_cfg.mux() => {
+                debug!("MUX session from client {}", peer_addr);
+
+                let rh = TcpResponseHeader::new(Reply::Succeeded, header.address);
+                rh.write_to(&mut stream).await?;
+
+                // Bandwidth is limited for each stream, session itself is only limited by limiters of them
+                stream.get_mut().set_rate_limiter(ConnectionRateLimiter::default());
+
+                return serve_multiplexed(context, flow_stat, svr_idx, stream, server_addr, peer_addr, timeout).await;
+            }
+            cmd => {
+                error!("unsupported command {:?} from client {}", cmd, peer_addr);
+                let rh = TcpResponseHeader::new(Reply::CommandNotSupported, header.address);
+                rh.write_to(&mut stream).await?;
+                return Ok(());
+            }
+        }
+    } else {
+        // Read remote Address
+        let remote_addr = match Address::read_from(&mut (&first_byte[..]).chain(&mut stream)).await {
+            Ok(o) => o,
+            Err(err) => {
+                error!(
+                    "failed to decode Address, may be wrong method or key, from client {}, error: {}",
+                    peer_addr, err
+                );
+                return Err(From::from(err));
+            }
+        };
+
+        debug!("RELAY {} <-> {} establishing", peer_addr, remote_addr);
+
+        // Check if remote_addr matches any ACL rules
+        if context.check_outbound_blocked(&remote_addr) {
+            warn!("outbound {} is blocked by ACL rules", remote_addr);
+            return Ok(());
+        }
+
+        let remote_stream = connect_remote(&*context, &remote_addr, timeout).await?;
+        (remote_addr, remote_stream)
+    };
+
+    relay_remote(peer_addr, &remote_addr, stream, &mut remote_stream).await;
+
+    Ok(())
+}
+
+/// Serves streams of a multiplexing session, until the session is closed
+///
+/// Each stream is counted as an active connection, and limited as a connection of the server
+async fn serve_multiplexed<S>(
+    context: SharedContext,
+    flow_stat: SharedServerFlowStatistic,
+    svr_idx: usize,
+    stream: S,
+    server_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut incoming, fut) = Session::server(stream, server_addr);
+
+    let accept = async {
+        while let Some((stream, remote_addr)) = incoming.recv().await {
+            // Refuse new streams if traffic quota is used up or server is expired
+            if let Err(err) = flow_stat.check_quota(context.server_config(svr_idx)) {
+                warn!("refused stream from {}, {}", peer_addr, err);
+                continue;
+            }
+
+            let context = context.clone();
+            let active = context.new_active_connection();
+            let stream = RateLimitedStream::new(stream, ConnectionRateLimiter::new(&*context, svr_idx));
+
+            tokio::spawn(async move {
+                // Counted as an active connection until it is closed
+                let _active = active;
+
+                // Error is ignored because it is already logged
+                let _ = handle_multiplexed_stream(&*context, stream, peer_addr, remote_addr, timeout).await;
+            });
+        }
+    };
+
+    // Streams are not accepted anymore after the session is closed
+    let (result, _) = future::join(fut, accept).await;
+
+    match result {
+        Ok(..) => debug!("MUX session from client {} closed", peer_addr),
+        Err(ref err) => debug!("MUX session from client {} closed with error {}", peer_addr, err),
+    }
+    result
+}
+
+async fn handle_multiplexed_stream<S>(
+    context: &Context,
+    stream: S,
+    peer_addr: SocketAddr,
+    remote_addr: Address,
+    timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("RELAY {} <-> {} establishing (multiplexed)", peer_addr, remote_addr);
+
+    // Check if remote_addr matches any ACL rules
+    if context.check_outbound_blocked(&remote_addr) {
+        warn!("outbound {} is blocked by ACL rules", remote_addr);
+        return Ok(());
+    }
+
+    let mut remote_stream = connect_remote(context, &remote_addr, timeout).await?;
+    relay_remote(peer_addr, &remote_addr, stream, &mut remote_stream).await;
+
+    Ok(())
+}
+
+/// Relays between client's `stream` and `remote_stream`, until either direction is finished
+async fn relay_remote<S>(peer_addr: SocketAddr, remote_addr: &Address, stream: S, remote_stream: &mut TcpStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     debug!("RELAY {} <-> {} established", peer_addr, remote_addr);
 
-    let (mut cr, mut cw) = stream.split();
+    let (mut cr, mut cw) = tokio::io::split(stream);
     let (mut sr, mut sw) = remote_stream.split();
 
     use tokio::io::copy;
@@ -153,8 +427,6 @@ async fn handle_client(
     }
 
     debug!("RELAY {} <-> {} closing", peer_addr, remote_addr);
-
-    Ok(())
 }
 
 /// Runs the server
@@ -166,7 +438,7 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
             let addr = svr_cfg.external_addr();
             let addr = addr.bind_addr(&*context).await?;
 
-            let listener = TcpListener::bind(&addr)
+            let listener = tcp_listener_bind(&addr, &*context)
                 .await
                 .unwrap_or_else(|err| panic!("failed to listen on {}, {}", addr, err));
 
@@ -195,15 +467,19 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
 
                         let flow_stat = flow_stat.clone();
                         let context = context.clone();
+                        let active = context.new_active_connection();
 
                         tokio::spawn(async move {
+                            // Counted as an active connection until it is closed
+                            let _active = active;
+
                             // Retrieve server config reference from context again
                             //
                             // Because the svr_cfg outside doesn't live long enough. WHAT??
                             let svr_cfg = context.server_config(idx);
 
                             // Error is ignored because it is already logged
-                            let _ = handle_client(context.clone(), flow_stat, svr_cfg, socket, peer_addr).await;
+                            let _ = handle_client(context.clone(), flow_stat, svr_cfg, idx, socket, peer_addr).await;
                         });
                     }
                     Err(err) => {
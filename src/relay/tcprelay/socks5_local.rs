@@ -1,66 +1,82 @@
-//! Local server that accepts SOCKS 5 protocol
+//! Local server that accepts SOCKS 5 protocol, and also SOCKS 4/4a on the same port
 
 use std::{
     io::{self, ErrorKind},
     net::{IpAddr, Ipv4Addr, SocketAddr},
 };
 
-use futures::future::{self, Either};
+use futures::future::Either;
 use log::{debug, error, info, trace, warn};
 use tokio::{
     self,
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
 };
 
 use crate::{
-    context::SharedContext,
+    config::{LocalConfig, ServerConfig},
+    context::{Context, SharedContext},
     relay::{
-        loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
-        socks5::{self, Address, HandshakeRequest, HandshakeResponse, TcpRequestHeader, TcpResponseHeader},
+        loadbalancing::server::{PingBalancer, ServerData},
+        socks4,
+        socks5::{
+            self,
+            Address,
+            HandshakeRequest,
+            HandshakeResponse,
+            PasswdAuthRequest,
+            PasswdAuthResponse,
+            Reply,
+            TcpRequestHeader,
+            TcpResponseHeader,
+        },
+        sys::tcp_listener_bind,
+        utils::try_timeout,
     },
 };
 
-use super::{ignore_until_end, ProxyStream};
+use super::{
+    ignore_until_end,
+    splice::{self, PlainTcpStream},
+    ProxyStream,
+    DEFAULT_BIND_TIMEOUT,
+};
 
 #[derive(Debug, Clone)]
-struct UdpConfig {
-    enable_udp: bool,
-    client_addr: SocketAddr,
+pub(super) struct SocksConfig {
+    /// CONNECT and BIND commands are enabled
+    pub enable_tcp: bool,
+    /// UDP ASSOCIATE command is enabled
+    pub enable_udp: bool,
+    /// UDP relay's address, replied to UDP ASSOCIATE command
+    pub client_addr: SocketAddr,
+}
+
+fn set_keepalive(s: &TcpStream, svr_cfg: &ServerConfig) {
+    if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
+        error!("failed to set keep alive: {:?}", err);
+    }
 }
 
-async fn handle_socks5_connect<'a>(
-    server: &SharedPlainServerStatistic,
+async fn handle_socks5_connect<'a, S: ServerData>(
+    servers: &PingBalancer<S>,
     stream: &mut TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
-    let context = server.context();
-    let svr_cfg = server.server_config();
-
-    let svr_s = match ProxyStream::connect(server.clone_context(), svr_cfg, addr).await {
-        Ok(svr_s) => {
+    let (server, svr_s) = match ProxyStream::connect_balanced(servers, addr).await {
+        Ok((server, svr_s)) => {
             // Tell the client that we are ready
-            let header = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(svr_s.local_addr()?));
+            let header = TcpResponseHeader::new(Reply::Succeeded, Address::SocketAddress(svr_s.local_addr()?));
             header.write_to(stream).await?;
 
             trace!("sent header: {:?}", header);
 
-            svr_s
+            (server, svr_s)
         }
         Err(perr) => {
-            use crate::relay::socks5::Reply;
-
-            if perr.is_proxied() {
-                // Report to global statistic
-                server.report_failure().await;
-            }
-
             let err = perr.into_inner();
-            let reply = match err.kind() {
-                ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
-                ErrorKind::ConnectionAborted => Reply::HostUnreachable,
-                _ => Reply::NetworkUnreachable,
-            };
+            let reply = error_to_reply(&err);
 
             let dummy_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
             let header = TcpResponseHeader::new(reply, Address::SocketAddress(dummy_address));
@@ -70,8 +86,33 @@ async fn handle_socks5_connect<'a>(
         }
     };
 
-    let (mut svr_r, mut svr_w) = svr_s.split();
+    // Client sends data after it is replied, sends its first payload with the address header
+    let (server, svr_s) = svr_s.send_first_payload_balanced(servers, server, addr, stream).await?;
+
+    let context = server.context();
+    set_keepalive(stream, server.server_config());
+
+    // Bypassed connections are not counted for the server
+    let _connection = if svr_s.is_proxied() {
+        Some(server.track_connection())
+    } else {
+        None
+    };
+
+    relay_established(context, stream, svr_s, client_addr, addr, "CONNECT").await
+}
 
+async fn relay_established<S>(
+    context: &Context,
+    stream: &mut TcpStream,
+    svr_s: S,
+    client_addr: SocketAddr,
+    addr: &Address,
+    cmd: &str,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + PlainTcpStream + Unpin,
+{
     // Reset `TCP_NODELAY` after Socks5 handshake
     if !context.config().no_delay {
         if let Err(err) = stream.set_nodelay(false) {
@@ -79,51 +120,223 @@ async fn handle_socks5_connect<'a>(
         }
     }
 
-    let (mut r, mut w) = stream.split();
-
-    use tokio::io::copy;
-
-    let rhalf = copy(&mut r, &mut svr_w);
-    let whalf = copy(&mut svr_r, &mut w);
-
-    debug!("CONNECT relay established {} <-> {}", client_addr, addr,);
+    debug!("{} relay established {} <-> {}", cmd, client_addr, addr);
 
-    match future::select(rhalf, whalf).await {
-        Either::Left((Ok(..), _)) => trace!("CONNECT relay {} -> {} closed", client_addr, addr),
-        Either::Left((Err(err), _)) => {
+    match splice::relay(context, stream, svr_s).await {
+        Either::Left(Ok(..)) => trace!("{} relay {} -> {} closed", cmd, client_addr, addr),
+        Either::Left(Err(err)) => {
             if let ErrorKind::TimedOut = err.kind() {
-                trace!("CONNECT relay {} -> {} closed with error {}", client_addr, addr, err);
+                trace!("{} relay {} -> {} closed with error {}", cmd, client_addr, addr, err);
             } else {
-                error!("CONNECT relay {} -> {} closed with error {}", client_addr, addr, err);
+                error!("{} relay {} -> {} closed with error {}", cmd, client_addr, addr, err);
             }
         }
-        Either::Right((Ok(..), _)) => trace!("CONNECT relay {} <- {} closed", client_addr, addr),
-        Either::Right((Err(err), _)) => {
+        Either::Right(Ok(..)) => trace!("{} relay {} <- {} closed", cmd, client_addr, addr),
+        Either::Right(Err(err)) => {
             if let ErrorKind::TimedOut = err.kind() {
-                trace!("CONNECT relay {} <- {} closed with error {}", client_addr, addr, err);
+                trace!("{} relay {} <- {} closed with error {}", cmd, client_addr, addr, err);
             } else {
-                error!("CONNECT relay {} <- {} closed with error {}", client_addr, addr, err);
+                error!("{} relay {} <- {} closed with error {}", cmd, client_addr, addr, err);
             }
         }
     }
 
-    debug!("CONNECT relay {} <-> {} closed", client_addr, addr);
+    debug!("{} relay {} <-> {} closed", cmd, client_addr, addr);
 
     Ok(())
 }
 
-#[allow(clippy::cognitive_complexity)]
-async fn handle_socks5_client(
-    server: &SharedPlainServerStatistic,
-    mut s: TcpStream,
-    udp_conf: UdpConfig,
+fn error_to_reply(err: &io::Error) -> Reply {
+    match err.kind() {
+        ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+        ErrorKind::ConnectionAborted => Reply::HostUnreachable,
+        _ => Reply::NetworkUnreachable,
+    }
+}
+
+async fn handle_socks5_bind<S: ServerData>(
+    servers: &PingBalancer<S>,
+    stream: &mut TcpStream,
+    client_addr: SocketAddr,
+    addr: &Address,
 ) -> io::Result<()> {
+    let server = servers.pick_server(addr);
+    trace!("picked proxy server: {:?}", server.server_config());
+
+    let context = server.context();
     let svr_cfg = server.server_config();
+    set_keepalive(stream, svr_cfg);
 
-    if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
-        error!("failed to set keep alive: {:?}", err);
+    let dummy_address = Address::SocketAddress(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+
+    if context.check_target_bypassed(addr).await {
+        // Bypassed, listens on the same interface that client is connected to
+        let bind_addr = SocketAddr::new(stream.local_addr()?.ip(), 0);
+
+        let mut listener = match TcpListener::bind(&bind_addr).await {
+            Ok(l) => l,
+            Err(err) => {
+                let header = TcpResponseHeader::new(Reply::GeneralFailure, dummy_address);
+                header.write_to(stream).await?;
+                return Err(err);
+            }
+        };
+
+        let listen_addr = listener.local_addr()?;
+        debug!("BIND {} listening on {} (bypassed)", addr, listen_addr);
+
+        // The first reply
+        let header = TcpResponseHeader::new(Reply::Succeeded, Address::SocketAddress(listen_addr));
+        header.write_to(stream).await?;
+
+        let timeout = context.config().timeout.unwrap_or(DEFAULT_BIND_TIMEOUT);
+        let (remote_s, remote_addr) = match try_timeout(listener.accept(), Some(timeout)).await {
+            Ok(r) => r,
+            Err(err) => {
+                let header = TcpResponseHeader::new(Reply::TtlExpired, Address::SocketAddress(listen_addr));
+                header.write_to(stream).await?;
+                return Err(err);
+            }
+        };
+
+        // Only accepts the host that client is expecting
+        if let Address::SocketAddress(ref expected) = *addr {
+            if !expected.ip().is_unspecified() && expected.ip() != remote_addr.ip() {
+                use std::io::Error;
+
+                let header = TcpResponseHeader::new(Reply::ConnectionNotAllowed, Address::SocketAddress(remote_addr));
+                header.write_to(stream).await?;
+
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("unexpected inbound connection from {}", remote_addr),
+                ));
+            }
+        }
+
+        // The second reply
+        let header = TcpResponseHeader::new(Reply::Succeeded, Address::SocketAddress(remote_addr));
+        header.write_to(stream).await?;
+
+        relay_established(context, stream, remote_s, client_addr, addr, "BIND").await
+    } else {
+        let mut svr_s = match ProxyStream::bind_proxied(server.clone_context(), svr_cfg, addr).await {
+            Ok(s) => s,
+            Err(err) => {
+                // Report to global statistic
+                server.report_failure().await;
+
+                let header = TcpResponseHeader::new(error_to_reply(&err), dummy_address);
+                header.write_to(stream).await?;
+
+                return Err(err);
+            }
+        };
+
+        // Forwards the first and the second replies from server
+        for _ in 0..2 {
+            let header = match TcpResponseHeader::read_from(&mut svr_s).await {
+                Ok(h) => h,
+                Err(err) => {
+                    let header = TcpResponseHeader::new(Reply::GeneralFailure, dummy_address);
+                    header.write_to(stream).await?;
+                    return Err(From::from(err));
+                }
+            };
+
+            trace!("BIND {} got reply {:?}", addr, header);
+            header.write_to(stream).await?;
+
+            if let Reply::Succeeded = header.reply {
+                continue;
+            }
+
+            use std::io::Error;
+            return Err(Error::new(ErrorKind::Other, format!("server replied {}", header.reply)));
+        }
+
+        let _connection = server.track_connection();
+        relay_established(context, stream, svr_s, client_addr, addr, "BIND").await
+    }
+}
+
+async fn handle_socks5_auth(context: &Context, s: &mut TcpStream, handshake_req: &HandshakeRequest) -> io::Result<()> {
+    use std::io::Error;
+
+    let auth = match context.config().local_auth {
+        Some(ref auth) if !auth.is_empty() => auth,
+        _ => {
+            if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_NONE) {
+                let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+                resp.write_to(s).await?;
+
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "authentication is not configured, but client does not support NO AUTHENTICATION",
+                ));
+            }
+
+            // Reply to client
+            let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
+            trace!("Reply handshake {:?}", resp);
+            resp.write_to(s).await?;
+
+            return Ok(());
+        }
+    };
+
+    if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_PASSWORD) {
+        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+        resp.write_to(s).await?;
+
+        return Err(Error::new(
+            ErrorKind::Other,
+            "client does not support USERNAME/PASSWORD authentication",
+        ));
     }
 
+    // Reply to client
+    let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_PASSWORD);
+    trace!("Reply handshake {:?}", resp);
+    resp.write_to(s).await?;
+
+    // RFC1929 sub-negotiation
+    let auth_req = PasswdAuthRequest::read_from(s).await?;
+
+    let passed = match (
+        std::str::from_utf8(&auth_req.uname),
+        std::str::from_utf8(&auth_req.passwd),
+    ) {
+        (Ok(uname), Ok(passwd)) => auth.check_user(uname, passwd),
+        _ => false,
+    };
+
+    if !passed {
+        let resp = PasswdAuthResponse::new(socks5::SOCKS5_AUTH_PASSWORD_FAILED);
+        resp.write_to(s).await?;
+
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "USERNAME/PASSWORD authentication failed, username: {}",
+                String::from_utf8_lossy(&auth_req.uname)
+            ),
+        ));
+    }
+
+    let resp = PasswdAuthResponse::new(socks5::SOCKS5_AUTH_PASSWORD_SUCCEEDED);
+    trace!("Reply password authentication {:?}", resp);
+    resp.write_to(s).await?;
+
+    Ok(())
+}
+
+#[allow(clippy::cognitive_complexity)]
+async fn handle_socks5_client<S: ServerData>(
+    servers: &PingBalancer<S>,
+    mut s: TcpStream,
+    socks_conf: SocksConfig,
+) -> io::Result<()> {
     // Enable TCP_NODELAY for quick handshaking
     if let Err(err) = s.set_nodelay(true) {
         error!("failed to set TCP_NODELAY on accepted socket, error: {:?}", err);
@@ -136,22 +349,7 @@ async fn handle_socks5_client(
     // Socks5 handshakes
     trace!("socks5 {:?}", handshake_req);
 
-    if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_NONE) {
-        use std::io::Error;
-
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
-        resp.write_to(&mut s).await?;
-
-        return Err(Error::new(
-            ErrorKind::Other,
-            "currently shadowsocks-rust does not support authentication",
-        ));
-    } else {
-        // Reply to client
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
-        trace!("Reply handshake {:?}", resp);
-        resp.write_to(&mut s).await?;
-    }
+    handle_socks5_auth(servers.context(), &mut s, &handshake_req).await?;
 
     // Fetch headers
     let header = match TcpRequestHeader::read_from(&mut s).await {
@@ -169,11 +367,10 @@ async fn handle_socks5_client(
     let addr = header.address;
     match header.command {
         socks5::Command::TcpConnect => {
-            let enable_tcp = server.config().mode.enable_tcp();
-            if enable_tcp {
+            if socks_conf.enable_tcp {
                 debug!("CONNECT {}", addr);
 
-                match handle_socks5_connect(server, &mut s, client_addr, &addr).await {
+                match handle_socks5_connect(servers, &mut s, client_addr, &addr).await {
                     Ok(..) => Ok(()),
                     Err(err) => Err(io::Error::new(
                         err.kind(),
@@ -189,18 +386,41 @@ async fn handle_socks5_client(
             }
         }
         socks5::Command::TcpBind => {
-            warn!("BIND is not supported");
-            let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
-            rh.write_to(&mut s).await?;
+            if socks_conf.enable_tcp {
+                debug!("BIND {}", addr);
 
-            Ok(())
+                match handle_socks5_bind(servers, &mut s, client_addr, &addr).await {
+                    Ok(..) => Ok(()),
+                    Err(err) => Err(io::Error::new(
+                        err.kind(),
+                        format!("BIND {} failed with error \"{}\"", addr, err),
+                    )),
+                }
+            } else {
+                warn!("BIND is not enabled");
+                let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
+                rh.write_to(&mut s).await?;
+
+                Ok(())
+            }
         }
         socks5::Command::UdpAssociate => {
-            if udp_conf.enable_udp {
+            if socks_conf.enable_udp {
                 debug!("UDP ASSOCIATE {}", addr);
-                let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, From::from(udp_conf.client_addr));
+                let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, From::from(socks_conf.client_addr));
                 rh.write_to(&mut s).await?;
 
+                // Packets from this client are accepted by the UDP relay only while this connection is alive
+                //
+                // Only from the port in the request if it is specified, host is always the peer of this connection
+                let udp_port = match addr {
+                    Address::SocketAddress(ref a) => a.port(),
+                    Address::DomainNameAddress(..) => 0,
+                };
+                let _authenticated = servers
+                    .context()
+                    .add_authenticated_client(SocketAddr::new(client_addr.ip(), udp_port));
+
                 // Hold the connection until it ends by its own
                 ignore_until_end(&mut s).await?;
 
@@ -213,40 +433,179 @@ async fn handle_socks5_client(
                 Ok(())
             }
         }
+        socks5::Command::Multiplex => {
+            // Only used between sslocal and ssserver
+            warn!("unsupported command {:?} from client {}", header.command, client_addr);
+            let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
+            rh.write_to(&mut s).await?;
+
+            Ok(())
+        }
+    }
+}
+
+async fn handle_socks4_connect<S: ServerData>(
+    servers: &PingBalancer<S>,
+    stream: &mut TcpStream,
+    client_addr: SocketAddr,
+    addr: &Address,
+) -> io::Result<()> {
+    let (server, svr_s) = match ProxyStream::connect_balanced(servers, addr).await {
+        Ok((server, svr_s)) => {
+            // Tell the client that we are ready
+            let resp = socks4::HandshakeResponse::new(socks4::ResultCode::RequestGranted);
+            resp.write_to(stream).await?;
+
+            trace!("sent socks4 response: {:?}", resp);
+
+            (server, svr_s)
+        }
+        Err(perr) => {
+            let resp = socks4::HandshakeResponse::new(socks4::ResultCode::RequestRejectedOrFailed);
+            resp.write_to(stream).await?;
+
+            return Err(perr.into_inner());
+        }
+    };
+
+    // Client sends data after it is replied, sends its first payload with the address header
+    let (server, svr_s) = svr_s.send_first_payload_balanced(servers, server, addr, stream).await?;
+
+    let context = server.context();
+    set_keepalive(stream, server.server_config());
+
+    // Bypassed connections are not counted for the server
+    let _connection = if svr_s.is_proxied() {
+        Some(server.track_connection())
+    } else {
+        None
+    };
+
+    relay_established(context, stream, svr_s, client_addr, addr, "SOCKS4 CONNECT").await
+}
+
+async fn handle_socks4_client<S: ServerData>(
+    servers: &PingBalancer<S>,
+    mut s: TcpStream,
+    socks_conf: SocksConfig,
+) -> io::Result<()> {
+    // Enable TCP_NODELAY for quick handshaking
+    if let Err(err) = s.set_nodelay(true) {
+        error!("failed to set TCP_NODELAY on accepted socket, error: {:?}", err);
+    }
+
+    let client_addr = s.peer_addr()?;
+
+    let handshake_req = socks4::HandshakeRequest::read_from(&mut s).await?;
+
+    trace!("socks4 {:?}", handshake_req);
+
+    // SOCKS4 only have an USERID, which couldn't be used for authentication
+    if servers.context().local_auth_required() {
+        use std::io::Error;
+
+        let resp = socks4::HandshakeResponse::new(socks4::ResultCode::RequestRejectedOrFailed);
+        resp.write_to(&mut s).await?;
+
+        return Err(Error::new(
+            ErrorKind::Other,
+            "SOCKS4 is not allowed because authentication is required",
+        ));
+    }
+
+    let addr = handshake_req.dst;
+    match handshake_req.cd {
+        socks4::Command::Connect => {
+            if socks_conf.enable_tcp {
+                debug!("SOCKS4 CONNECT {}", addr);
+
+                match handle_socks4_connect(servers, &mut s, client_addr, &addr).await {
+                    Ok(..) => Ok(()),
+                    Err(err) => Err(io::Error::new(
+                        err.kind(),
+                        format!("SOCKS4 CONNECT {} failed with error \"{}\"", addr, err),
+                    )),
+                }
+            } else {
+                warn!("CONNECT is not enabled");
+                let resp = socks4::HandshakeResponse::new(socks4::ResultCode::RequestRejectedOrFailed);
+                resp.write_to(&mut s).await?;
+
+                Ok(())
+            }
+        }
+        socks4::Command::Bind => {
+            warn!("SOCKS4 BIND is not supported");
+            let resp = socks4::HandshakeResponse::new(socks4::ResultCode::RequestRejectedOrFailed);
+            resp.write_to(&mut s).await?;
+
+            Ok(())
+        }
+    }
+}
+
+/// Serves a SOCKS client, protocol version is detected by the first byte
+pub(super) async fn handle_socks_client<S: ServerData>(
+    servers: &PingBalancer<S>,
+    mut s: TcpStream,
+    socks_conf: SocksConfig,
+) -> io::Result<()> {
+    // Detect protocol version by the first byte
+    let mut version_buf = [0u8; 1];
+    let n = s.peek(&mut version_buf).await?;
+    if n == 0 {
+        // Closed without sending anything
+        return Ok(());
+    }
+
+    match version_buf[0] {
+        socks4::SOCKS4_VERSION => handle_socks4_client(servers, s, socks_conf).await,
+        socks5::SOCKS5_VERSION => handle_socks5_client(servers, s, socks_conf).await,
+        ver => {
+            use std::io::Error;
+
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported socks version {:#x}", ver),
+            ))
+        }
     }
 }
 
 /// Starts a TCP local server with Socks5 proxy protocol
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let local_addr = context.config().local.as_ref().expect("local config");
+pub async fn run<S>(context: SharedContext, local_config: LocalConfig, servers: PingBalancer<S>) -> io::Result<()>
+where
+    S: ServerData + 'static,
+{
+    let local_addr = &local_config.addr;
     let bind_addr = local_addr.bind_addr(&*context).await?;
 
-    let mut listener = TcpListener::bind(&bind_addr)
+    let mut listener = tcp_listener_bind(&bind_addr, &*context)
         .await
         .unwrap_or_else(|err| panic!("failed to listen on {}, {}", local_addr, err));
 
     let actual_local_addr = listener.local_addr().expect("determine port bound to");
 
-    let udp_conf = UdpConfig {
-        enable_udp: context.config().mode.enable_udp(),
+    let socks_conf = SocksConfig {
+        enable_tcp: local_config.mode.enable_tcp(),
+        enable_udp: local_config.mode.enable_udp(),
         client_addr: actual_local_addr,
     };
 
-    let servers = PlainPingBalancer::new(context, ServerType::Tcp).await;
-
     info!("shadowsocks TCP listening on {}", actual_local_addr);
 
     loop {
         let (socket, peer_addr) = listener.accept().await?;
-        let server = servers.pick_server();
 
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
-        let udp_conf = udp_conf.clone();
+        let servers = servers.clone();
+        let socks_conf = socks_conf.clone();
+        let active = context.new_active_connection();
         tokio::spawn(async move {
-            if let Err(err) = handle_socks5_client(&server, socket, udp_conf).await {
-                error!("TCP socks5 client exited with error: {}", err);
+            let _active = active;
+            if let Err(err) = handle_socks_client(&servers, socket, socks_conf).await {
+                error!("TCP socks client exited with error: {}", err);
             }
         });
     }
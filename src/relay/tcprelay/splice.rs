@@ -0,0 +1,422 @@
+//! Relay between a local client and its remote
+//!
+//! On Linux, plain TCP streams (for example, bypassed connections) are relayed with `splice(2)`,
+//! data are moved between sockets through a pipe, without being copied into user-space buffers.
+//! The others are copied with `tokio::io::copy`.
+
+use std::io;
+
+use futures::future::{self, Either};
+use tokio::{
+    io::{copy, AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::context::Context;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use self::linux::relay_spliced;
+
+/// Streams that could be relayed with `splice(2)`
+pub trait PlainTcpStream {
+    /// The underlying socket, if data are transferred as is (without encryption or buffering)
+    fn plain_tcp_stream(&self) -> Option<&TcpStream>;
+}
+
+impl PlainTcpStream for TcpStream {
+    fn plain_tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+/// Relays data between `local` and `remote`, until either direction is finished
+///
+/// Returns result of `local -> remote` in `Left`, or result of `remote -> local` in `Right`
+pub async fn relay<S>(context: &Context, local: &mut TcpStream, remote: S) -> Either<io::Result<u64>, io::Result<u64>>
+where
+    S: AsyncRead + AsyncWrite + PlainTcpStream + Unpin,
+{
+    if let Some(plain) = remote.plain_tcp_stream() {
+        if let Some(res) = relay_spliced(context, local, plain).await {
+            return res;
+        }
+    }
+
+    let (mut svr_r, mut svr_w) = tokio::io::split(remote);
+    let (mut r, mut w) = local.split();
+
+    let rhalf = copy(&mut r, &mut svr_w);
+    let whalf = copy(&mut svr_r, &mut w);
+
+    match future::select(rhalf, whalf).await {
+        Either::Left((res, _)) => Either::Left(res),
+        Either::Right((res, _)) => Either::Right(res),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+async fn relay_spliced(
+    _context: &Context,
+    _local: &TcpStream,
+    _remote: &TcpStream,
+) -> Option<Either<io::Result<u64>, io::Result<u64>>> {
+    // `splice(2)` is only available on Linux
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::{
+        io::{self, ErrorKind},
+        net::TcpStream as StdTcpStream,
+        os::unix::io::{AsRawFd, FromRawFd, RawFd},
+        ptr,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use futures::{
+        future::{self, poll_fn, Either},
+        pin_mut,
+        ready,
+    };
+    use log::{debug, error};
+    use tokio::{io::PollEvented, net::TcpStream};
+
+    use crate::{context::Context as SsContext, relay::utils::try_timeout};
+
+    /// Bytes moved by one `splice(2)` call, the default capacity of pipes
+    const SPLICE_SIZE: usize = 64 * 1024;
+
+    /// Data moved from one socket are kept in a pipe, until they are moved to the other
+    struct Pipe {
+        r: RawFd,
+        w: RawFd,
+    }
+
+    impl Pipe {
+        fn new() -> io::Result<Pipe> {
+            let mut fds = [0; 2];
+            let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Pipe { r: fds[0], w: fds[1] })
+        }
+    }
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.r);
+                libc::close(self.w);
+            }
+        }
+    }
+
+    fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+        let ret = unsafe {
+            libc::splice(
+                fd_in,
+                ptr::null_mut(),
+                fd_out,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    /// Registers a duplicated socket of `s` for polling its readiness, which is not exposed by `TcpStream`
+    fn register(s: &TcpStream) -> io::Result<PollEvented<mio::net::TcpStream>> {
+        let fd = unsafe { libc::fcntl(s.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let stream = unsafe { StdTcpStream::from_raw_fd(fd) };
+        PollEvented::new(mio::net::TcpStream::from_stream(stream)?)
+    }
+
+    /// One direction of relay, moves data from `r` to `w`
+    struct SpliceCopy {
+        r: PollEvented<mio::net::TcpStream>,
+        w: PollEvented<mio::net::TcpStream>,
+        pipe: Pipe,
+    }
+
+    impl SpliceCopy {
+        fn new(r: &TcpStream, w: &TcpStream) -> io::Result<SpliceCopy> {
+            Ok(SpliceCopy {
+                r: register(r)?,
+                w: register(w)?,
+                pipe: Pipe::new()?,
+            })
+        }
+
+        fn poll_splice_in(&self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+            ready!(self.r.poll_read_ready(cx, mio::Ready::readable()))?;
+
+            match splice(self.r.get_ref().as_raw_fd(), self.pipe.w, SPLICE_SIZE) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.r.clear_read_ready(cx, mio::Ready::readable())?;
+                    Poll::Pending
+                }
+                x => Poll::Ready(x),
+            }
+        }
+
+        fn poll_splice_out(&self, cx: &mut Context<'_>, len: usize) -> Poll<io::Result<usize>> {
+            ready!(self.w.poll_write_ready(cx))?;
+
+            match splice(self.pipe.r, self.w.get_ref().as_raw_fd(), len) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.w.clear_write_ready(cx)?;
+                    Poll::Pending
+                }
+                x => Poll::Ready(x),
+            }
+        }
+
+        /// Moves data until EOF of `r`
+        ///
+        /// Waiting for `r` to be readable longer than `read_timeout`, or `w` to be writable longer than `write_timeout` fails
+        async fn copy(&self, read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> io::Result<u64> {
+            let mut amt = 0u64;
+
+            loop {
+                let n = try_timeout(poll_fn(|cx| self.poll_splice_in(cx)), read_timeout).await?;
+                if n == 0 {
+                    return Ok(amt);
+                }
+
+                // Pipe is drained before moving more data in
+                let mut remaining = n;
+                while remaining > 0 {
+                    let n = try_timeout(poll_fn(|cx| self.poll_splice_out(cx, remaining)), write_timeout).await?;
+                    if n == 0 {
+                        return Err(ErrorKind::WriteZero.into());
+                    }
+
+                    remaining -= n;
+                    amt += n as u64;
+                }
+            }
+        }
+    }
+
+    /// Relay between two sockets with `splice(2)`
+    struct Splice {
+        l2r: SpliceCopy,
+        r2l: SpliceCopy,
+    }
+
+    impl Splice {
+        /// Sets up pipes and registers sockets, nothing has been moved if it fails
+        fn new(local: &TcpStream, remote: &TcpStream) -> io::Result<Splice> {
+            Ok(Splice {
+                l2r: SpliceCopy::new(local, remote)?,
+                r2l: SpliceCopy::new(remote, local)?,
+            })
+        }
+
+        /// Relays until either direction is finished
+        ///
+        /// Same as copying with the remote wrapped in `Connection`, reading from or writing to the remote
+        /// times out if it is blocked for `timeout`
+        async fn relay(self, timeout: Option<Duration>) -> Either<io::Result<u64>, io::Result<u64>> {
+            let rhalf = self.l2r.copy(None, timeout);
+            let whalf = self.r2l.copy(timeout, None);
+            pin_mut!(rhalf, whalf);
+
+            match future::select(rhalf, whalf).await {
+                Either::Left((res, _)) => Either::Left(res),
+                Either::Right((res, _)) => Either::Right(res),
+            }
+        }
+    }
+
+    /// Relays with `splice(2)`, returns `None` if it couldn't be set up
+    pub async fn relay_spliced(
+        context: &SsContext,
+        local: &TcpStream,
+        remote: &TcpStream,
+    ) -> Option<Either<io::Result<u64>, io::Result<u64>>> {
+        let splice = match Splice::new(local, remote) {
+            Ok(s) => s,
+            Err(err) => {
+                debug!("failed to relay with splice, fallback to copy, error: {}", err);
+                return None;
+            }
+        };
+
+        // Reset `TCP_NODELAY`, it was kept for the first packet
+        if !context.config().no_delay {
+            if let Err(err) = remote.set_nodelay(false) {
+                error!("failed to reset TCP_NODELAY on socket, error: {:?}", err);
+            }
+        }
+
+        Some(splice.relay(context.config().timeout).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::{
+        net::TcpListener,
+        prelude::*,
+        runtime::{Builder, Handle},
+        time::{self, Duration},
+    };
+
+    use crate::{
+        config::{Config, ConfigType},
+        context::{ServerState, SharedContext},
+    };
+
+    use super::super::{Connection, ProxyStream};
+
+    /// Connected sockets on loopback
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let c = TcpStream::connect(addr).await.unwrap();
+        let (s, _) = listener.accept().await.unwrap();
+        (c, s)
+    }
+
+    async fn new_context(timeout: Duration, rt_handle: Handle) -> SharedContext {
+        let mut config = Config::new(ConfigType::Socks5Local);
+        config.timeout = Some(timeout);
+        let state = ServerState::new_shared(&config, rt_handle).await;
+        Context::new_shared(config, state)
+    }
+
+    #[test]
+    fn test_relay_plain_stream() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let context = new_context(Duration::from_secs(5), rt_handle).await;
+            let (mut client, mut local) = tcp_pair().await;
+            let (remote, mut target) = tcp_pair().await;
+
+            let relay = tokio::spawn(async move { relay(&context, &mut local, remote).await });
+
+            client.write_all(b"HEllo WORld").await.unwrap();
+            let mut buf = [0u8; 11];
+            target.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"HEllo WORld");
+
+            target.write_all(b"WORld HEllo").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"WORld HEllo");
+
+            // Client closes the connection
+            drop(client);
+            match relay.await.unwrap() {
+                Either::Left(Ok(n)) => assert_eq!(n, 11),
+                res => panic!("unexpected relay result {:?}", res),
+            }
+        });
+    }
+
+    // Remote sockets are only timed out by `Connection` while copying
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_relay_remote_read_timeout() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let context = new_context(Duration::from_secs(1), rt_handle).await;
+            let (_client, mut local) = tcp_pair().await;
+            let (remote, _target) = tcp_pair().await;
+
+            let res = time::timeout(Duration::from_secs(5), relay(&context, &mut local, remote))
+                .await
+                .expect("idle remote is expected to time out");
+            match res {
+                Either::Right(Err(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+                res => panic!("unexpected relay result {:?}", res),
+            }
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_relay_remote_write_timeout() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let context = new_context(Duration::from_secs(1), rt_handle).await;
+            let (mut client, mut local) = tcp_pair().await;
+            let (remote, mut target) = tcp_pair().await;
+
+            // Remote keeps sending but never reads, writes to it are blocked once socket buffers are full
+            tokio::spawn(async move {
+                while target.write_all(b"x").await.is_ok() {
+                    time::delay_for(Duration::from_millis(100)).await;
+                }
+            });
+            tokio::spawn(async move {
+                let data = vec![0u8; 1024 * 1024];
+                while client.write_all(&data).await.is_ok() {}
+            });
+
+            let res = time::timeout(Duration::from_secs(30), relay(&context, &mut local, remote))
+                .await
+                .expect("blocked remote is expected to time out");
+            match res {
+                Either::Left(Err(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+                res => panic!("unexpected relay result {:?}", res),
+            }
+        });
+    }
+
+    #[test]
+    fn test_relay_buffered_stream() {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let rt_handle = rt.handle().clone();
+
+        rt.block_on(async move {
+            let context = new_context(Duration::from_secs(5), rt_handle).await;
+            let (mut client, mut local) = tcp_pair().await;
+            let (remote, mut target) = tcp_pair().await;
+
+            // Data read by `Connection` but not consumed, they are lost if the socket is spliced
+            target.write_all(b"HEllo WORld").await.unwrap();
+            let mut remote = ProxyStream::Direct {
+                stream: Connection::new(remote, None),
+                context: context.clone(),
+            };
+            let mut buf = [0u8; 5];
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"HEllo");
+            assert!(remote.plain_tcp_stream().is_none());
+
+            tokio::spawn(async move { relay(&context, &mut local, remote).await });
+
+            let mut buf = [0u8; 6];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b" WORld");
+
+            client.write_all(b"HEllo").await.unwrap();
+            let mut buf = [0u8; 5];
+            target.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"HEllo");
+        });
+    }
+}
@@ -7,13 +7,15 @@ use std::{
 
 use futures::future::{self, Either};
 use log::{debug, error, info, trace};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
 
 use crate::{
+    config::LocalConfig,
     context::SharedContext,
     relay::{
-        loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
+        loadbalancing::server::{PingBalancer, ServerData, SharedServerStatistic},
         socks5::Address,
+        sys::tcp_listener_bind,
     },
 };
 
@@ -22,8 +24,8 @@ use super::ProxyStream;
 /// Established Client Tunnel
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
-async fn establish_client_tcp_tunnel<'a>(
-    server: &SharedPlainServerStatistic,
+async fn establish_client_tcp_tunnel<'a, S: ServerData>(
+    server: &SharedServerStatistic<S>,
     mut s: TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
@@ -31,7 +33,11 @@ async fn establish_client_tcp_tunnel<'a>(
     let svr_cfg = server.server_config();
 
     // NOTE: TUNNEL doesn't need to check ACL, just forward everything to proxy server
-    let svr_s = ProxyStream::connect_proxied(server.clone_context(), svr_cfg, addr).await?;
+    let mut svr_s = ProxyStream::connect_proxied(server.clone_context(), svr_cfg, addr, Some(server.data())).await?;
+    let _connection = server.track_connection();
+
+    // Sends the first payload with the address header
+    svr_s.send_first_payload(&mut s).await?;
     let (mut svr_r, mut svr_w) = svr_s.split();
 
     let (mut r, mut w) = s.split();
@@ -67,7 +73,11 @@ async fn establish_client_tcp_tunnel<'a>(
     Ok(())
 }
 
-async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream) -> io::Result<()> {
+async fn handle_tunnel_client<S: ServerData>(
+    server: &SharedServerStatistic<S>,
+    s: TcpStream,
+    target_addr: &Address,
+) -> io::Result<()> {
     let svr_cfg = server.server_config();
 
     if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
@@ -82,30 +92,25 @@ async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream)
 
     let client_addr = s.peer_addr()?;
 
-    // forward must not be None, it is already checked in local.rs
-    let target_addr = server.config().forward.as_ref().unwrap();
-
     establish_client_tcp_tunnel(server, s, client_addr, target_addr).await
 }
 
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    assert!(
-        context.config().mode.enable_tcp(),
-        "TCP relay must be enabled for TUNNEL"
-    );
+pub async fn run<S>(context: SharedContext, local_config: LocalConfig, servers: PingBalancer<S>) -> io::Result<()>
+where
+    S: ServerData + 'static,
+{
+    assert!(local_config.mode.enable_tcp(), "TCP relay must be enabled for TUNNEL");
 
-    let local_addr = context.config().local.as_ref().expect("local config");
+    let local_addr = &local_config.addr;
     let bind_addr = local_addr.bind_addr(&*context).await?;
 
-    let mut listener = TcpListener::bind(&bind_addr)
+    let mut listener = tcp_listener_bind(&bind_addr, &*context)
         .await
         .unwrap_or_else(|err| panic!("failed to listen on {}, {}", local_addr, err));
 
     let actual_local_addr = listener.local_addr().expect("determine port bound to");
 
-    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
-
-    let forward_addr = context.config().forward.as_ref().expect("`forward` address in config");
+    let forward_addr = local_config.forward.expect("`forward` address in config");
     info!(
         "shadowsocks TCP tunnel listening on {}, forward to {}",
         actual_local_addr, forward_addr
@@ -113,13 +118,16 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     loop {
         let (socket, peer_addr) = listener.accept().await?;
-        let server = servers.pick_server();
+        let server = servers.pick_server(&forward_addr);
 
         trace!("got connection {}", peer_addr);
         trace!("picked proxy server: {:?}", server.server_config());
 
+        let forward_addr = forward_addr.clone();
+        let active = context.new_active_connection();
         tokio::spawn(async move {
-            if let Err(err) = handle_tunnel_client(&server, socket).await {
+            let _active = active;
+            if let Err(err) = handle_tunnel_client(&server, socket, &forward_addr).await {
                 error!("TCP tunnel client exited with error: {:?}", err);
             }
         });
@@ -6,44 +6,58 @@ use log::trace;
 use socket2::{Domain, SockAddr, Socket, Type};
 use tokio::net::TcpStream;
 
+use crate::relay::sys::{set_tcp_fastopen_connect, warn_tcp_fastopen_connect};
+
 /// Connecting to a specific target with TCP protocol
 ///
-/// Optionally we can bind to a local address for connecting
-pub async fn connect_tcp_stream(addr: &SocketAddr, outbound_addr: &Option<SocketAddr>) -> io::Result<TcpStream> {
-    match *outbound_addr {
-        None => {
-            trace!("connecting {}", addr);
-
-            // Connect with tokio's default API directly
-            TcpStream::connect(addr).await
-        }
-        Some(ref bind_addr) => {
-            // Create TcpStream manually from socket
-            // These functions may not behave exactly the same as tokio's TcpStream::connect
-
-            trace!("connecting {} from {}", addr, bind_addr);
-
-            let socket = match *addr {
-                SocketAddr::V4(..) => Socket::new(Domain::ipv4(), Type::stream(), None)?,
-                SocketAddr::V6(..) => Socket::new(Domain::ipv6(), Type::stream(), None)?,
-            };
-
-            // Bind to local outbound address
-            //
-            // Common failure: EADDRINUSE
-            let bind_addr = SockAddr::from(*bind_addr);
-            socket.bind(&bind_addr)?;
-
-            // Connect to the target
-            //
-            // FIXME: This function is not documented as it may be deleted in the future
-            //
-            // mio 0.6.x (tokio 0.2.x is depending on it) will set stream into non-block mode
-            // unix: https://github.com/tokio-rs/mio/blob/v0.6.x/src/sys/unix/tcp.rs#L28
-            // windows: https://github.com/tokio-rs/mio/blob/v0.6.x/src/sys/windows/tcp.rs#L118
-            //
-            // We have to let tokio calls connect for us. Because we don't have a chance to wait until the socket is actually connected
-            TcpStream::connect_std(socket.into_tcp_stream(), addr).await
+/// Optionally we can bind to a local address for connecting, and send the first write in SYN with TCP Fast Open.
+/// Connect errors are only returned from the first write or read with `fast_open`.
+pub async fn connect_tcp_stream(
+    addr: &SocketAddr,
+    outbound_addr: &Option<SocketAddr>,
+    fast_open: bool,
+) -> io::Result<TcpStream> {
+    if outbound_addr.is_none() && !fast_open {
+        trace!("connecting {}", addr);
+
+        // Connect with tokio's default API directly
+        return TcpStream::connect(addr).await;
+    }
+
+    // Create TcpStream manually from socket
+    // These functions may not behave exactly the same as tokio's TcpStream::connect
+
+    let socket = match *addr {
+        SocketAddr::V4(..) => Socket::new(Domain::ipv4(), Type::stream(), None)?,
+        SocketAddr::V6(..) => Socket::new(Domain::ipv6(), Type::stream(), None)?,
+    };
+
+    if let Some(ref bind_addr) = *outbound_addr {
+        trace!("connecting {} from {}", addr, bind_addr);
+
+        // Bind to local outbound address
+        //
+        // Common failure: EADDRINUSE
+        let bind_addr = SockAddr::from(*bind_addr);
+        socket.bind(&bind_addr)?;
+    } else {
+        trace!("connecting {}", addr);
+    }
+
+    if fast_open {
+        if let Err(err) = set_tcp_fastopen_connect(&socket) {
+            warn_tcp_fastopen_connect(&err);
         }
     }
+
+    // Connect to the target
+    //
+    // FIXME: This function is not documented as it may be deleted in the future
+    //
+    // mio 0.6.x (tokio 0.2.x is depending on it) will set stream into non-block mode
+    // unix: https://github.com/tokio-rs/mio/blob/v0.6.x/src/sys/unix/tcp.rs#L28
+    // windows: https://github.com/tokio-rs/mio/blob/v0.6.x/src/sys/windows/tcp.rs#L118
+    //
+    // We have to let tokio calls connect for us. Because we don't have a chance to wait until the socket is actually connected
+    TcpStream::connect_std(socket.into_tcp_stream(), addr).await
 }
@@ -7,12 +7,14 @@
 use std::{
     io::{self, Cursor, Read},
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
 };
 
 use async_trait::async_trait;
 use bytes::BytesMut;
 use futures::future;
 use log::{debug, error, warn};
+use spin::Mutex;
 use tokio::{
     self,
     net::udp::{RecvHalf, SendHalf},
@@ -23,14 +25,15 @@ use crate::{
     config::{ServerAddr, ServerConfig},
     context::Context,
     relay::{
-        loadbalancing::server::{ServerData, SharedServerStatistic},
+        loadbalancing::server::{ServerData, SharedServerStatistic, SharedServerStatisticData},
+        metrics::GaugeGuard,
         socks5::Address,
         sys::create_udp_socket_with_context,
     },
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, encrypt_payload, SharedUdpSession, UdpSession},
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
 
@@ -39,9 +42,70 @@ pub trait ProxySend {
     async fn send_packet(&mut self, data: Vec<u8>) -> io::Result<()>;
 }
 
+enum ReportState {
+    Idle,
+    Sent,
+    Reported,
+}
+
+/// Reports the first outcome of proxied packets to the server's statistic
+///
+/// Latency is not recorded, it isn't comparable with probes because targets respond at their own pace
+struct ServerReporter {
+    stat: SharedServerStatisticData,
+    state: Mutex<ReportState>,
+}
+
+type SharedServerReporter = Arc<ServerReporter>;
+
+impl ServerReporter {
+    fn new_shared(stat: SharedServerStatisticData) -> SharedServerReporter {
+        Arc::new(ServerReporter {
+            stat,
+            state: Mutex::new(ReportState::Idle),
+        })
+    }
+
+    fn sent(&self) {
+        let mut state = self.state.lock();
+        if let ReportState::Idle = *state {
+            *state = ReportState::Sent;
+        }
+    }
+
+    fn received(&self) {
+        {
+            let mut state = self.state.lock();
+            if let ReportState::Sent = *state {
+                *state = ReportState::Reported;
+            } else {
+                return;
+            }
+        }
+
+        self.stat.report_success();
+    }
+
+    async fn failed(&self) {
+        {
+            let mut state = self.state.lock();
+            if let ReportState::Reported = *state {
+                return;
+            }
+            *state = ReportState::Reported;
+        }
+
+        self.stat.report_failure().await;
+    }
+}
+
 pub struct ProxyAssociation {
     tx: mpsc::Sender<(Address, Vec<u8>)>,
     watchers: Vec<oneshot::Sender<()>>,
+    // Counted as an active association until dropped
+    active: GaugeGuard,
+    // Counted as a connection of the server, `None` if all packets are bypassed
+    connection: Option<GaugeGuard>,
 }
 
 impl ProxyAssociation {
@@ -58,6 +122,8 @@ impl ProxyAssociation {
         let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
 
         let remote_udp = create_udp_socket_with_context(&local_addr, server.context()).await?;
+        let active = server.context().metrics().udp_associations().track();
+        let connection = Some(server.track_connection());
         let remote_bind_addr = remote_udp.local_addr().expect("determine port bound to");
 
         debug!("created UDP association {} <-> {}", src_addr, remote_bind_addr);
@@ -69,15 +135,27 @@ impl ProxyAssociation {
         // Splits socket into sender and receiver
         let (remote_receiver, remote_sender) = remote_udp.split();
 
+        let session = UdpSession::new_shared_client();
+        let reporter = ServerReporter::new_shared(server.data().clone());
+
         // LOCAL -> REMOTE task
         // All packets will be sent directly to proxy
-        tokio::spawn(Self::l2r_packet_proxied(src_addr, server.clone(), rx, remote_sender));
+        tokio::spawn(Self::l2r_packet_proxied(
+            src_addr,
+            server.clone(),
+            session.clone(),
+            reporter.clone(),
+            rx,
+            remote_sender,
+        ));
 
         // REMOTE <- LOCAL task
         let (remote_watcher_tx, remote_watcher_rx) = oneshot::channel::<()>();
         tokio::spawn(Self::r2l_packet(
             src_addr,
             server,
+            session,
+            Some(reporter),
             sender,
             remote_receiver,
             remote_watcher_rx,
@@ -85,7 +163,12 @@ impl ProxyAssociation {
 
         let watchers = vec![remote_watcher_tx];
 
-        Ok(ProxyAssociation { tx, watchers })
+        Ok(ProxyAssociation {
+            tx,
+            watchers,
+            active,
+            connection,
+        })
     }
 
     pub async fn associate_bypassed<S, H>(
@@ -101,6 +184,7 @@ impl ProxyAssociation {
         let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
 
         let remote_udp = create_udp_socket_with_context(&local_addr, server.context()).await?;
+        let active = server.context().metrics().udp_associations().track();
         let remote_bind_addr = remote_udp.local_addr().expect("determine port bound to");
 
         debug!("created UDP association {} <-> {}", src_addr, remote_bind_addr);
@@ -121,6 +205,8 @@ impl ProxyAssociation {
         tokio::spawn(Self::r2l_packet(
             src_addr,
             server,
+            UdpSession::new_shared_client(),
+            None,
             sender,
             remote_receiver,
             remote_watcher_rx,
@@ -128,7 +214,12 @@ impl ProxyAssociation {
 
         let watchers = vec![remote_watcher_tx];
 
-        Ok(ProxyAssociation { tx, watchers })
+        Ok(ProxyAssociation {
+            tx,
+            watchers,
+            active,
+            connection: None,
+        })
     }
 
     pub async fn associate_with_acl<S, H>(
@@ -144,6 +235,8 @@ impl ProxyAssociation {
         let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
 
         let remote_udp = create_udp_socket_with_context(&local_addr, server.context()).await?;
+        let active = server.context().metrics().udp_associations().track();
+        let connection = Some(server.track_connection());
         let remote_bind_addr = remote_udp.local_addr().expect("determine port bound to");
 
         // A socket for bypassed
@@ -166,9 +259,14 @@ impl ProxyAssociation {
         // LOCAL -> REMOTE task
         // Packets may be sent via proxy decided by acl rules
 
+        let session = UdpSession::new_shared_client();
+        let reporter = ServerReporter::new_shared(server.data().clone());
+
         tokio::spawn(Self::l2r_packet_acl(
             src_addr,
             server.clone(),
+            session.clone(),
+            reporter.clone(),
             rx,
             bypass_sender,
             remote_sender,
@@ -178,6 +276,8 @@ impl ProxyAssociation {
         tokio::spawn(Self::r2l_packet(
             src_addr,
             server.clone(),
+            session.clone(),
+            None,
             sender.clone(),
             bypass_receiver,
             bypass_watcher_rx,
@@ -188,6 +288,8 @@ impl ProxyAssociation {
         tokio::spawn(Self::r2l_packet(
             src_addr,
             server,
+            session,
+            Some(reporter),
             sender,
             remote_receiver,
             remote_watcher_rx,
@@ -195,7 +297,12 @@ impl ProxyAssociation {
 
         let watchers = vec![bypass_watcher_tx, remote_watcher_tx];
 
-        Ok(ProxyAssociation { tx, watchers })
+        Ok(ProxyAssociation {
+            tx,
+            watchers,
+            active,
+            connection,
+        })
     }
 
     pub async fn send(&mut self, target: Address, payload: Vec<u8>) {
@@ -208,6 +315,8 @@ impl ProxyAssociation {
     async fn l2r_packet_acl<S>(
         src_addr: SocketAddr,
         server: SharedServerStatistic<S>,
+        session: SharedUdpSession,
+        reporter: SharedServerReporter,
         mut rx: mpsc::Receiver<(Address, Vec<u8>)>,
         mut bypass_sender: SendHalf,
         mut remote_sender: SendHalf,
@@ -224,7 +333,22 @@ impl ProxyAssociation {
             let res = if is_bypassed {
                 Self::send_packet_bypassed(src_addr, context, &addr, &payload, &mut bypass_sender).await
             } else {
-                Self::send_packet_proxied(src_addr, context, svr_cfg, &addr, &payload, &mut remote_sender).await
+                let res = Self::send_packet_proxied(
+                    src_addr,
+                    context,
+                    svr_cfg,
+                    &session,
+                    &addr,
+                    &payload,
+                    &mut remote_sender,
+                )
+                .await;
+
+                match res {
+                    Ok(..) => reporter.sent(),
+                    Err(..) => reporter.failed().await,
+                }
+                res
             };
 
             if let Err(err) = res {
@@ -241,6 +365,8 @@ impl ProxyAssociation {
     async fn l2r_packet_proxied<S>(
         src_addr: SocketAddr,
         server: SharedServerStatistic<S>,
+        session: SharedUdpSession,
+        reporter: SharedServerReporter,
         mut rx: mpsc::Receiver<(Address, Vec<u8>)>,
         mut remote_sender: SendHalf,
     ) where
@@ -250,10 +376,23 @@ impl ProxyAssociation {
         let svr_cfg = server.server_config();
 
         while let Some((addr, payload)) = rx.recv().await {
-            let res = Self::send_packet_proxied(src_addr, context, svr_cfg, &addr, &payload, &mut remote_sender).await;
-
-            if let Err(err) = res {
-                error!("UDP association send packet {} -> {}, error: {}", src_addr, addr, err);
+            let res = Self::send_packet_proxied(
+                src_addr,
+                context,
+                svr_cfg,
+                &session,
+                &addr,
+                &payload,
+                &mut remote_sender,
+            )
+            .await;
+
+            match res {
+                Ok(..) => reporter.sent(),
+                Err(err) => {
+                    error!("UDP association send packet {} -> {}, error: {}", src_addr, addr, err);
+                    reporter.failed().await;
+                }
             }
         }
 
@@ -285,6 +424,7 @@ impl ProxyAssociation {
         src_addr: SocketAddr,
         context: &Context,
         svr_cfg: &ServerConfig,
+        session: &SharedUdpSession,
         target: &Address,
         payload: &[u8],
         socket: &mut SendHalf,
@@ -295,7 +435,14 @@ impl ProxyAssociation {
         send_buf.extend_from_slice(payload);
 
         let mut encrypt_buf = BytesMut::new();
-        encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf)?;
+        encrypt_payload(
+            context,
+            svr_cfg.method(),
+            svr_cfg.key(),
+            &mut session.lock(),
+            &send_buf,
+            &mut encrypt_buf,
+        )?;
 
         let send_len = match svr_cfg.addr() {
             ServerAddr::SocketAddr(ref remote_addr) => socket.send_to(&encrypt_buf[..], remote_addr).await?,
@@ -357,6 +504,8 @@ impl ProxyAssociation {
     async fn r2l_packet<S, H>(
         src_addr: SocketAddr,
         server: SharedServerStatistic<S>,
+        session: SharedUdpSession,
+        reporter: Option<SharedServerReporter>,
         mut sender: H,
         mut socket: RecvHalf,
         watcher_rx: oneshot::Receiver<()>,
@@ -370,14 +519,22 @@ impl ProxyAssociation {
             let svr_cfg = server.server_config();
 
             loop {
-                match Self::recv_packet_proxied(context, svr_cfg, &mut socket).await {
+                match Self::recv_packet_proxied(context, svr_cfg, &session, &mut socket).await {
                     Ok(data) => {
+                        if let Some(ref reporter) = reporter {
+                            reporter.received();
+                        }
+
                         if let Err(err) = sender.send_packet(data).await {
                             error!("UDP association send {} <- .., error: {}", src_addr, err);
                         }
                     }
                     Err(err) => {
                         error!("UDP association recv {} <- .., error: {}", src_addr, err);
+
+                        if let Some(ref reporter) = reporter {
+                            reporter.failed().await;
+                        }
                     }
                 }
             }
@@ -396,6 +553,7 @@ impl ProxyAssociation {
     async fn recv_packet_proxied(
         context: &Context,
         svr_cfg: &ServerConfig,
+        session: &SharedUdpSession,
         socket: &mut RecvHalf,
     ) -> io::Result<Vec<u8>> {
         // Waiting for response from server SERVER -> CLIENT
@@ -404,7 +562,14 @@ impl ProxyAssociation {
 
         let (recv_n, _) = socket.recv_from(&mut recv_buf).await?;
 
-        let decrypt_buf = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &recv_buf[..recv_n])? {
+        let decrypted = decrypt_payload(
+            context,
+            svr_cfg.method(),
+            svr_cfg.key(),
+            &mut session.lock(),
+            &recv_buf[..recv_n],
+        )?;
+        let decrypt_buf = match decrypted {
             None => {
                 error!("UDP packet too short, received length {}", recv_n);
                 let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
@@ -18,7 +18,7 @@ use crate::{
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, encrypt_payload, UdpSession},
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
@@ -29,6 +29,7 @@ pub struct ServerClient {
     method: CipherType,
     key: Bytes,
     server_addr: ServerAddr,
+    session: UdpSession,
 }
 
 impl ServerClient {
@@ -40,6 +41,7 @@ impl ServerClient {
             method: svr_cfg.method(),
             key: svr_cfg.clone_key(),
             server_addr: svr_cfg.addr().clone(),
+            session: UdpSession::new_client(),
         })
     }
 
@@ -59,7 +61,14 @@ impl ServerClient {
         send_buf.extend_from_slice(payload);
 
         let mut encrypt_buf = BytesMut::new();
-        encrypt_payload(context, self.method, &self.key, &send_buf, &mut encrypt_buf)?;
+        encrypt_payload(
+            context,
+            self.method,
+            &self.key,
+            &mut self.session,
+            &send_buf,
+            &mut encrypt_buf,
+        )?;
 
         let send_len = match self.server_addr {
             ServerAddr::SocketAddr(ref remote_addr) => {
@@ -85,7 +94,8 @@ impl ServerClient {
         let mut recv_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let (recv_n, ..) = try_timeout(self.socket.recv_from(&mut recv_buf), Some(timeout)).await?;
 
-        let decrypt_buf = match decrypt_payload(context, self.method, &self.key, &recv_buf[..recv_n])? {
+        let decrypted = decrypt_payload(context, self.method, &self.key, &mut self.session, &recv_buf[..recv_n])?;
+        let decrypt_buf = match decrypted {
             None => {
                 error!("UDP packet too short, received length {}", recv_n);
                 let err = io::Error::new(io::ErrorKind::InvalidData, "packet too short");
@@ -19,28 +19,208 @@
 //! | Fixed  | Variable  |   Fixed   |
 //! +--------+-----------+-----------+
 //! ```
+//!
+//! Payload with AEAD 2022 cipher (SIP022)
+//!
+//! ```plain
+//! Packet body (before encryption)
+//! +------+-----------+-------------------+-------------+---------+------+
+//! | TYPE | TIMESTAMP | CLIENT SESSION ID | PADDING_LEN | PADDING | DATA |
+//! +------+-----------+-------------------+-------------+---------+------+
+//! |  1   |     8     |  8 (server only)  |      2      | Variable| Var. |
+//! +------+-----------+-------------------+-------------+---------+------+
+//!
+//! AES ciphers (after encryption, *ciphertext*)
+//! +---------------------------+----------+-----------+
+//! | *SESSION ID + PACKET ID*  |  *Body*  |  Body_TAG |
+//! +---------------------------+----------+-----------+
+//! |  16, AES block encrypted  | Variable |   Fixed   |
+//! +---------------------------+----------+-----------+
+//!
+//! 2022-blake3-chacha20-poly1305 (after encryption, *ciphertext*)
+//! +--------+----------------------------------+-----------+
+//! | NONCE  | *SESSION ID + PACKET ID + Body*  |    TAG    |
+//! +--------+----------------------------------+-----------+
+//! |   24   |             Variable             |   Fixed   |
+//! +--------+----------------------------------+-----------+
+//! ```
 
-use std::{io, slice};
+use std::{io, slice, sync::Arc};
 
 use byte_string::ByteStr;
-use bytes::{BufMut, BytesMut};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
 use log::{debug, trace};
+use rand::{self, RngCore};
+use spin::Mutex;
 
 use crate::{
     context::Context,
-    crypto::{self, CipherCategory, CipherType, CryptoMode},
+    crypto::{self, aead2022, CipherCategory, CipherResult, CipherType, CryptoMode},
 };
 
+/// Packet TYPE of AEAD 2022 packets sent by clients
+const AEAD2022_CLIENT_PACKET_TYPE: u8 = 0;
+/// Packet TYPE of AEAD 2022 packets sent by servers
+const AEAD2022_SERVER_PACKET_TYPE: u8 = 1;
+
+/// Size of the sliding window for detecting replayed PacketIDs
+const PACKET_WINDOW_SIZE: u64 = 64;
+
+/// Sliding window filter of received PacketIDs
+#[derive(Debug)]
+struct PacketWindow {
+    max_packet_id: u64,
+    bitmap: u64,
+}
+
+impl PacketWindow {
+    fn new(packet_id: u64) -> PacketWindow {
+        PacketWindow {
+            max_packet_id: packet_id,
+            bitmap: 1,
+        }
+    }
+
+    /// Check and mark `packet_id` as received, returns `false` if it is replayed or too old
+    fn check_and_set(&mut self, packet_id: u64) -> bool {
+        if packet_id > self.max_packet_id {
+            let shift = packet_id - self.max_packet_id;
+            self.bitmap = if shift >= PACKET_WINDOW_SIZE {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.max_packet_id = packet_id;
+            return true;
+        }
+
+        let diff = self.max_packet_id - packet_id;
+        if diff >= PACKET_WINDOW_SIZE {
+            return false;
+        }
+
+        let bit = 1u64 << diff;
+        if self.bitmap & bit != 0 {
+            return false;
+        }
+        self.bitmap |= bit;
+        true
+    }
+}
+
+/// Session of the remote peer
+#[derive(Debug)]
+struct UdpPeerSession {
+    session_id: u64,
+    session_key: Bytes,
+    window: PacketWindow,
+}
+
+/// State of an UDP session in AEAD 2022 protocol
+///
+/// Each side of an association has a random SessionID, and packets sent in this session have increasing PacketIDs.
+/// Other ciphers don't use it.
+#[derive(Debug)]
+pub struct UdpSession {
+    packet_type: u8,
+    session_id: u64,
+    session_key: Option<Bytes>,
+    packet_id: u64,
+    peer: Option<UdpPeerSession>,
+    // Peer's previous session, packets sent before the peer changed session may still arrive
+    last_peer: Option<UdpPeerSession>,
+}
+
+/// `UdpSession` shared by both directions of an association
+pub type SharedUdpSession = Arc<Mutex<UdpSession>>;
+
+impl UdpSession {
+    fn new(packet_type: u8) -> UdpSession {
+        UdpSession {
+            packet_type,
+            session_id: rand::thread_rng().next_u64(),
+            session_key: None,
+            packet_id: 0,
+            peer: None,
+            last_peer: None,
+        }
+    }
+
+    /// Create a session for sending packets to servers
+    pub fn new_client() -> UdpSession {
+        UdpSession::new(AEAD2022_CLIENT_PACKET_TYPE)
+    }
+
+    /// Create a session for responding packets to a client
+    pub fn new_server() -> UdpSession {
+        UdpSession::new(AEAD2022_SERVER_PACKET_TYPE)
+    }
+
+    /// Create a `SharedUdpSession` for sending packets to servers
+    pub fn new_shared_client() -> SharedUdpSession {
+        Arc::new(Mutex::new(UdpSession::new_client()))
+    }
+
+    /// Create a `SharedUdpSession` for responding packets to a client
+    pub fn new_shared_server() -> SharedUdpSession {
+        Arc::new(Mutex::new(UdpSession::new_server()))
+    }
+
+    fn is_server(&self) -> bool {
+        self.packet_type == AEAD2022_SERVER_PACKET_TYPE
+    }
+
+    fn find_peer(&self, session_id: u64) -> Option<&UdpPeerSession> {
+        self.peer
+            .iter()
+            .chain(self.last_peer.iter())
+            .find(|p| p.session_id == session_id)
+    }
+
+    /// Check and mark the authenticated packet as received, returns `false` if it is replayed
+    ///
+    /// Windows of the current and the previous session are both kept,
+    /// so packets of the previous session couldn't be replayed by switching sessions back and forth.
+    fn check_peer_packet(&mut self, session_id: u64, packet_id: u64, session_key: Bytes) -> bool {
+        let peer = self
+            .peer
+            .iter_mut()
+            .chain(self.last_peer.iter_mut())
+            .find(|p| p.session_id == session_id);
+
+        if let Some(peer) = peer {
+            return peer.window.check_and_set(packet_id);
+        }
+
+        // New session of peer
+        trace!("UDP packet got new session id {:016x}", session_id);
+
+        self.last_peer = self.peer.take();
+        self.peer = Some(UdpPeerSession {
+            session_id,
+            session_key,
+            window: PacketWindow::new(packet_id),
+        });
+        true
+    }
+}
+
 /// Encrypt payload into ShadowSocks UDP encrypted packet
+///
+/// `session` is only used by AEAD 2022 ciphers
 pub fn encrypt_payload(
     context: &Context,
     t: CipherType,
     key: &[u8],
+    session: &mut UdpSession,
     payload: &[u8],
     dst: &mut BytesMut,
 ) -> io::Result<()> {
     match t.category() {
         CipherCategory::Stream => encrypt_payload_stream(context, t, key, payload, dst),
+        CipherCategory::Aead if t.is_aead_2022() => encrypt_payload_aead_2022(t, key, session, payload, dst),
         CipherCategory::Aead => encrypt_payload_aead(context, t, key, payload, dst),
     }
 }
@@ -111,14 +291,140 @@ fn encrypt_payload_aead(
     Ok(())
 }
 
+fn encrypt_payload_aead_2022(
+    t: CipherType,
+    key: &[u8],
+    session: &mut UdpSession,
+    payload: &[u8],
+    dst: &mut BytesMut,
+) -> io::Result<()> {
+    let session_id = session.session_id;
+    let packet_id = session.packet_id;
+    session.packet_id = session.packet_id.wrapping_add(1);
+
+    let mut body = BytesMut::with_capacity(1 + 8 + 8 + 2 + payload.len());
+    body.put_u8(session.packet_type);
+    body.put_u64(aead2022::unix_timestamp());
+    if session.is_server() {
+        match session.peer {
+            Some(ref peer) => body.put_u64(peer.session_id),
+            None => {
+                use std::io::{Error, ErrorKind};
+
+                let err = Error::new(ErrorKind::Other, "UDP session of client is unknown");
+                return Err(err);
+            }
+        }
+    }
+    // No padding
+    body.put_u16(0);
+    body.put_slice(payload);
+
+    trace!("UDP packet session id {:016x}, packet id {}", session_id, packet_id);
+
+    match t {
+        CipherType::Aead2022Blake3ChaCha20Poly1305 => {
+            let mut nonce = [0u8; aead2022::UDP_XCHACHA20_NONCE_LEN];
+            rand::thread_rng().fill_bytes(&mut nonce);
+
+            let mut plain = BytesMut::with_capacity(aead2022::UDP_SEPARATE_HEADER_LEN + body.len());
+            plain.put_u64(session_id);
+            plain.put_u64(packet_id);
+            plain.put_slice(&body);
+
+            dst.reserve(nonce.len() + plain.len() + t.tag_size());
+            dst.put_slice(&nonce);
+            aead2022::encrypt_udp_packet(t, key, &nonce, &plain, dst);
+        }
+        _ => {
+            let mut header = [0u8; aead2022::UDP_SEPARATE_HEADER_LEN];
+            BigEndian::write_u64(&mut header[..8], session_id);
+            BigEndian::write_u64(&mut header[8..], packet_id);
+
+            let session_key = match session.session_key {
+                Some(ref k) => k.clone(),
+                None => {
+                    let k = aead2022::derive_session_key(t, key, &header[..8]);
+                    session.session_key = Some(k.clone());
+                    k
+                }
+            };
+
+            // Nonce is the last 12 bytes of the plain separate header
+            let mut nonce = [0u8; 12];
+            nonce.copy_from_slice(&header[4..]);
+
+            aead2022::encrypt_udp_separate_header(t, key, &mut header);
+
+            dst.reserve(header.len() + body.len() + t.tag_size());
+            dst.put_slice(&header);
+            aead2022::encrypt_udp_packet(t, &session_key, &nonce, &body, dst);
+        }
+    }
+
+    Ok(())
+}
+
 /// Decrypt payload from ShadowSocks UDP encrypted packet
-pub fn decrypt_payload(context: &Context, t: CipherType, key: &[u8], payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+///
+/// `session` is only used by AEAD 2022 ciphers
+pub fn decrypt_payload(
+    context: &Context,
+    t: CipherType,
+    key: &[u8],
+    session: &mut UdpSession,
+    payload: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
     match t.category() {
         CipherCategory::Stream => decrypt_payload_stream(context, t, key, payload),
+        CipherCategory::Aead if t.is_aead_2022() => decrypt_payload_aead_2022(context, t, key, session, payload),
         CipherCategory::Aead => decrypt_payload_aead(context, t, key, payload),
     }
 }
 
+/// Check if the AEAD encrypted `payload` could be decrypted with `key`, without any side effects
+///
+/// Used by multi-user servers for identifying users of packets
+pub fn check_payload_key(t: CipherType, key: &[u8], payload: &[u8]) -> bool {
+    let tag_size = t.tag_size();
+
+    match t {
+        CipherType::Aead2022Blake3ChaCha20Poly1305 => {
+            let nonce_size = aead2022::UDP_XCHACHA20_NONCE_LEN;
+            if payload.len() < nonce_size + aead2022::UDP_SEPARATE_HEADER_LEN + tag_size {
+                return false;
+            }
+
+            let (nonce, data) = payload.split_at(nonce_size);
+            aead2022::decrypt_udp_packet(t, key, nonce, data).is_ok()
+        }
+        _ if t.is_aead_2022() => {
+            if payload.len() < aead2022::UDP_SEPARATE_HEADER_LEN + tag_size {
+                return false;
+            }
+
+            let mut header = [0u8; aead2022::UDP_SEPARATE_HEADER_LEN];
+            header.copy_from_slice(&payload[..aead2022::UDP_SEPARATE_HEADER_LEN]);
+            aead2022::decrypt_udp_separate_header(t, key, &mut header);
+
+            let session_key = aead2022::derive_session_key(t, key, &header[..8]);
+            let data = &payload[aead2022::UDP_SEPARATE_HEADER_LEN..];
+            aead2022::decrypt_udp_packet(t, &session_key, &header[4..], data).is_ok()
+        }
+        _ => {
+            let salt_size = t.salt_size();
+            if t.category() != CipherCategory::Aead || payload.len() < tag_size + salt_size {
+                return false;
+            }
+
+            let (salt, data) = payload.split_at(salt_size);
+            let mut cipher = crypto::new_aead_decryptor(t, key, salt);
+            let mut plain = vec![0u8; data.len() - tag_size];
+            cipher.decrypt(data, &mut plain).is_ok()
+        }
+    }
+}
+
 fn decrypt_payload_stream(context: &Context, t: CipherType, key: &[u8], payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
     let iv_size = t.iv_size();
     if payload.len() < iv_size {
@@ -175,7 +481,163 @@ fn decrypt_payload_aead(context: &Context, t: CipherType, key: &[u8], payload: &
     let mut cipher = crypto::new_aead_decryptor(t, key, salt);
 
     let mut recv_payload = vec![0u8; data_length];
-    cipher.decrypt(data, &mut recv_payload)?;
+    check_decrypted(context, cipher.decrypt(data, &mut recv_payload))?;
 
     Ok(Some(recv_payload))
 }
+
+fn decrypt_payload_aead_2022(
+    context: &Context,
+    t: CipherType,
+    key: &[u8],
+    session: &mut UdpSession,
+    payload: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
+    use std::io::{Error, ErrorKind};
+
+    let tag_size = t.tag_size();
+
+    let (session_id, packet_id, session_key, body) = match t {
+        CipherType::Aead2022Blake3ChaCha20Poly1305 => {
+            let nonce_size = aead2022::UDP_XCHACHA20_NONCE_LEN;
+            if payload.len() < nonce_size + aead2022::UDP_SEPARATE_HEADER_LEN + tag_size {
+                return Ok(None);
+            }
+
+            let (nonce, data) = payload.split_at(nonce_size);
+            let mut plain = check_decrypted(context, aead2022::decrypt_udp_packet(t, key, nonce, data))?;
+
+            let session_id = BigEndian::read_u64(&plain[..8]);
+            let packet_id = BigEndian::read_u64(&plain[8..16]);
+            let body = plain.split_off(aead2022::UDP_SEPARATE_HEADER_LEN);
+            (session_id, packet_id, Bytes::new(), body)
+        }
+        _ => {
+            if payload.len() < aead2022::UDP_SEPARATE_HEADER_LEN + tag_size {
+                return Ok(None);
+            }
+
+            let mut header = [0u8; aead2022::UDP_SEPARATE_HEADER_LEN];
+            header.copy_from_slice(&payload[..aead2022::UDP_SEPARATE_HEADER_LEN]);
+            aead2022::decrypt_udp_separate_header(t, key, &mut header);
+
+            let session_id = BigEndian::read_u64(&header[..8]);
+            let packet_id = BigEndian::read_u64(&header[8..]);
+
+            let session_key = match session.find_peer(session_id) {
+                Some(peer) => peer.session_key.clone(),
+                None => aead2022::derive_session_key(t, key, &header[..8]),
+            };
+
+            let data = &payload[aead2022::UDP_SEPARATE_HEADER_LEN..];
+            let body = check_decrypted(
+                context,
+                aead2022::decrypt_udp_packet(t, &session_key, &header[4..], data),
+            )?;
+            (session_id, packet_id, session_key, body)
+        }
+    };
+
+    // TYPE, TIMESTAMP, CLIENT SESSION ID (server packets only), PADDING_LEN
+    let (expected_type, header_len) = if session.is_server() {
+        (AEAD2022_CLIENT_PACKET_TYPE, 1 + 8 + 2)
+    } else {
+        (AEAD2022_SERVER_PACKET_TYPE, 1 + 8 + 8 + 2)
+    };
+
+    if body.len() < header_len {
+        let err = Error::new(ErrorKind::InvalidData, "packet header too short");
+        return Err(err);
+    }
+
+    if body[0] != expected_type {
+        let err = Error::new(ErrorKind::InvalidData, "invalid packet type in header");
+        return Err(err);
+    }
+
+    let timestamp = BigEndian::read_u64(&body[1..9]);
+    if !aead2022::check_timestamp(timestamp) {
+        let err = Error::new(ErrorKind::InvalidData, "timestamp in header is out of range");
+        return Err(err);
+    }
+
+    if !session.is_server() && BigEndian::read_u64(&body[9..17]) != session.session_id {
+        let err = Error::new(ErrorKind::InvalidData, "client session id in header mismatched");
+        return Err(err);
+    }
+
+    let padding_len = BigEndian::read_u16(&body[header_len - 2..header_len]) as usize;
+    let data_pos = header_len + padding_len;
+    if data_pos > body.len() {
+        let err = Error::new(ErrorKind::InvalidData, "invalid padding length in header");
+        return Err(err);
+    }
+
+    // Packet is authenticated, check if it is replayed
+    if !session.check_peer_packet(session_id, packet_id, session_key) {
+        debug!(
+            "detected repeated packet id {} of session {:016x}",
+            packet_id, session_id
+        );
+
+        context.metrics().incr_replay_hits();
+
+        let err = Error::new(ErrorKind::Other, "detected repeated packet id");
+        return Err(err);
+    }
+
+    Ok(Some(body[data_pos..].to_vec()))
+}
+
+/// Counts failures of AEAD decryption in metrics
+fn check_decrypted<T>(context: &Context, res: CipherResult<T>) -> CipherResult<T> {
+    if res.is_err() {
+        context.metrics().incr_aead_decrypt_failures();
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_window() {
+        let mut window = PacketWindow::new(10);
+        assert!(!window.check_and_set(10));
+
+        // Out of order, but in window
+        assert!(window.check_and_set(12));
+        assert!(window.check_and_set(11));
+        assert!(!window.check_and_set(11));
+        assert!(!window.check_and_set(12));
+
+        // Slides the window
+        assert!(window.check_and_set(12 + PACKET_WINDOW_SIZE));
+        assert!(!window.check_and_set(12));
+        assert!(window.check_and_set(13));
+        assert!(!window.check_and_set(13));
+    }
+
+    #[test]
+    fn test_peer_session_replay() {
+        let mut session = UdpSession::new_server();
+
+        assert!(session.check_peer_packet(1, 0, Bytes::new()));
+        assert!(session.check_peer_packet(1, 1, Bytes::new()));
+        assert!(!session.check_peer_packet(1, 1, Bytes::new()));
+
+        // Peer changed session
+        assert!(session.check_peer_packet(2, 0, Bytes::new()));
+        assert_eq!(session.peer.as_ref().unwrap().session_id, 2);
+
+        // Delayed packets of the previous session are still accepted, but only once
+        assert!(session.check_peer_packet(1, 2, Bytes::new()));
+        assert!(!session.check_peer_packet(1, 0, Bytes::new()));
+        assert!(!session.check_peer_packet(1, 2, Bytes::new()));
+        assert!(!session.check_peer_packet(2, 0, Bytes::new()));
+
+        // Responses are sent to the current session
+        assert_eq!(session.peer.as_ref().unwrap().session_id, 2);
+    }
+}
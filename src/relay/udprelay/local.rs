@@ -2,17 +2,40 @@
 
 use std::io;
 
+use futures::{future::select_all, FutureExt};
+
 use super::{redir_local, socks5_local, tunnel_local};
-use crate::{config::ConfigType, context::SharedContext};
+use crate::{config::ConfigType, context::SharedContext, relay::loadbalancing::server::PlainPingBalancer};
+
+/// Starts UDP local servers
+///
+/// All local servers share the same load balancer
+pub(crate) async fn run(context: SharedContext, balancer: PlainPingBalancer) -> io::Result<()> {
+    let local_configs = context
+        .config()
+        .local_configs()
+        .into_iter()
+        .filter(|l| l.enable_udp())
+        .collect::<Vec<_>>();
 
-/// Starts a UDP local server
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    match context.config().config_type {
-        ConfigType::TunnelLocal => tunnel_local::run(context).await,
-        ConfigType::Socks5Local => socks5_local::run(context).await,
-        ConfigType::RedirLocal => redir_local::run(context).await,
-        ConfigType::HttpLocal => unreachable!(),
-        ConfigType::Server => unreachable!(),
-        ConfigType::Manager => unreachable!(),
+    let mut vf = Vec::with_capacity(local_configs.len());
+    for local_config in local_configs {
+        let context = context.clone();
+        let balancer = balancer.clone();
+
+        let fut = match local_config.config_type {
+            ConfigType::TunnelLocal => tunnel_local::run(context, local_config, balancer).boxed(),
+            ConfigType::Socks5Local | ConfigType::MixedLocal => {
+                socks5_local::run(context, local_config, balancer).boxed()
+            }
+            ConfigType::RedirLocal => redir_local::run(context, local_config, balancer).boxed(),
+            ConfigType::HttpLocal => unreachable!(),
+            ConfigType::Server => unreachable!(),
+            ConfigType::Manager => unreachable!(),
+        };
+        vf.push(fut);
     }
+
+    let (res, ..) = select_all(vf.into_iter()).await;
+    res
 }
@@ -8,13 +8,9 @@ use lru_time_cache::{Entry, LruCache};
 use tokio::{self, sync::Mutex, time};
 
 use crate::{
-    config::RedirType,
+    config::{LocalConfig, RedirType},
     context::SharedContext,
-    relay::{
-        loadbalancing::server::{PlainPingBalancer, ServerType},
-        redir::UdpSocketRedirExt,
-        socks5::Address,
-    },
+    relay::{loadbalancing::server::PlainPingBalancer, redir::UdpSocketRedirExt, socks5::Address},
 };
 
 use super::{
@@ -73,8 +69,8 @@ impl ProxySend for ProxyHandler {
 }
 
 /// Starts a UDP local server
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let local_addr = context.config().local.as_ref().expect("missing local config");
+pub async fn run(context: SharedContext, local_config: LocalConfig, balancer: PlainPingBalancer) -> io::Result<()> {
+    let local_addr = &local_config.addr;
     let bind_addr = local_addr.bind_addr(&*context).await?;
 
     let ty = context.config().udp_redir;
@@ -83,8 +79,6 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
     let mut l = UdpRedirSocket::bind(ty, &bind_addr)?;
     let local_addr = l.local_addr().expect("determine port bound to");
 
-    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Udp).await;
-
     info!("shadowsocks UDP redirect listening on {}", local_addr);
 
     // NOTE: Associations are only eliminated by expire time
@@ -144,7 +138,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 Entry::Occupied(oc) => oc.into_mut(),
                 Entry::Vacant(vc) => {
                     // Pick a server
-                    let server = balancer.pick_server();
+                    let server = balancer.pick_server(&target);
 
                     let sender = match ProxyHandler::new(ty, src, dst, cache_key, assoc_map.clone()) {
                         Ok(s) => s,
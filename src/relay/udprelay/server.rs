@@ -19,10 +19,12 @@ use tokio::{
 };
 
 use crate::{
-    config::ServerConfig,
+    config::{ServerConfig, ServerUser},
     context::{Context, SharedContext},
     relay::{
-        flow::{SharedMultiServerFlowStatistic, SharedServerFlowStatistic},
+        flow::{ServerQuota, SharedMultiServerFlowStatistic, SharedServerFlowStatistic},
+        metrics::GaugeGuard,
+        rate_limit::{ConnectionRateLimiter, RateLimiters},
         socks5::Address,
         sys::create_udp_socket,
         utils::try_timeout,
@@ -30,7 +32,7 @@ use crate::{
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{check_payload_key, decrypt_payload, encrypt_payload, SharedUdpSession, UdpSession},
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
@@ -46,15 +48,25 @@ struct UdpAssociation {
 
     // local <- remote task life watcher
     watcher: Arc<UdpAssociationWatcher>,
+
+    // Counted as an active association until all clones are dropped
+    active: Arc<GaugeGuard>,
+
+    // Flow statistic of the user, for multi-user servers
+    user_flow_stat: Option<SharedServerFlowStatistic>,
 }
 
 impl UdpAssociation {
     /// Create an association with addr
+    ///
+    /// Packets are encrypted with the key of `user` if it is identified in multi-user servers
     async fn associate(
         context: SharedContext,
         svr_idx: usize,
         src_addr: SocketAddr,
         mut response_tx: mpsc::Sender<(SocketAddr, BytesMut)>,
+        user: Option<Arc<ServerUser>>,
+        user_flow_stat: Option<SharedServerFlowStatistic>,
     ) -> io::Result<UdpAssociation> {
         // Create a socket for receiving packets
         let local_addr = match context.config().local {
@@ -72,6 +84,8 @@ impl UdpAssociation {
         let local_addr = remote_udp.local_addr().expect("could not determine port bound to");
         debug!("created UDP Association for {} from {}", src_addr, local_addr);
 
+        let active = Arc::new(context.metrics().udp_associations().track());
+
         // Create a channel for sending packets to remote
         // FIXME: Channel size 1024?
         let (tx, mut rx) = mpsc::channel::<Vec<u8>>(1024);
@@ -86,16 +100,38 @@ impl UdpAssociation {
 
         let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
 
+        let session = UdpSession::new_shared_server();
+
+        let key = match user {
+            Some(ref user) => user.clone_key(),
+            None => context.server_config(svr_idx).clone_key(),
+        };
+
+        let (mut upload_limiter, mut download_limiter) = ConnectionRateLimiter::new(&*context, svr_idx).split();
+
         // local -> remote
         {
             let context = context.clone();
+            let session = session.clone();
+            let key = key.clone();
             tokio::spawn(async move {
                 let svr_cfg = context.server_config(svr_idx);
 
                 while let Some(pkt) = rx.recv().await {
+                    upload_limiter.acquire(pkt.len()).await;
+
                     // pkt is already a raw packet, so just send it
-                    if let Err(err) =
-                        UdpAssociation::relay_l2r(&*context, src_addr, &mut sender, &pkt[..], timeout, svr_cfg).await
+                    if let Err(err) = UdpAssociation::relay_l2r(
+                        &*context,
+                        src_addr,
+                        &mut sender,
+                        &pkt[..],
+                        timeout,
+                        svr_cfg,
+                        &key,
+                        &session,
+                    )
+                    .await
                     {
                         error!("failed to relay packet, {} -> ..., error: {}", src_addr, err);
 
@@ -114,7 +150,17 @@ impl UdpAssociation {
 
                 loop {
                     // Read and send back to source
-                    match UdpAssociation::relay_r2l(&*context, src_addr, &mut receiver, &mut response_tx, svr_cfg).await
+                    match UdpAssociation::relay_r2l(
+                        &*context,
+                        src_addr,
+                        &mut receiver,
+                        &mut response_tx,
+                        svr_cfg,
+                        &key,
+                        &session,
+                        &mut download_limiter,
+                    )
+                    .await
                     {
                         Ok(..) => {}
                         Err(err) => {
@@ -139,10 +185,13 @@ impl UdpAssociation {
         Ok(UdpAssociation {
             tx,
             watcher: close_flag,
+            active,
+            user_flow_stat,
         })
     }
 
     /// Relay packets from local to remote
+    #[allow(clippy::too_many_arguments)]
     async fn relay_l2r(
         context: &Context,
         src: SocketAddr,
@@ -150,9 +199,12 @@ impl UdpAssociation {
         pkt: &[u8],
         timeout: Duration,
         svr_cfg: &ServerConfig,
+        key: &[u8],
+        session: &SharedUdpSession,
     ) -> io::Result<()> {
         // First of all, decrypt payload CLIENT -> SERVER
-        let decrypted_pkt = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), pkt) {
+        let decrypted = decrypt_payload(context, svr_cfg.method(), key, &mut session.lock(), pkt);
+        let decrypted_pkt = match decrypted {
             Ok(Some(pkt)) => pkt,
             Ok(None) => {
                 error!("failed to decrypt pkt in UDP relay, packet too short");
@@ -227,12 +279,16 @@ impl UdpAssociation {
     }
 
     /// Relay packets from remote to local
+    #[allow(clippy::too_many_arguments)]
     async fn relay_r2l(
         context: &Context,
         src_addr: SocketAddr,
         remote_udp: &mut RecvHalf,
         response_tx: &mut mpsc::Sender<(SocketAddr, BytesMut)>,
         svr_cfg: &ServerConfig,
+        key: &[u8],
+        session: &SharedUdpSession,
+        download_limiter: &mut RateLimiters,
     ) -> io::Result<()> {
         // Waiting for response from server SERVER -> CLIENT
         // Packet length is limited by MAXIMUM_UDP_PAYLOAD_SIZE, excess bytes will be discarded.
@@ -253,7 +309,16 @@ impl UdpAssociation {
         send_buf.extend_from_slice(&remote_buf[..remote_recv_len]);
 
         let mut encrypt_buf = BytesMut::new();
-        encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf)?;
+        encrypt_payload(
+            context,
+            svr_cfg.method(),
+            key,
+            &mut session.lock(),
+            &send_buf,
+            &mut encrypt_buf,
+        )?;
+
+        download_limiter.acquire(encrypt_buf.len()).await;
 
         // Send back to src_addr
         if let Err(err) = response_tx.send((src_addr, encrypt_buf)).await {
@@ -276,6 +341,53 @@ impl UdpAssociation {
     }
 }
 
+/// Find the user of multi-user server by trying keys on the packet
+///
+/// User found for the last association from the same IP is tried first, clients usually open many associations.
+/// Returns `Ok(None)` if the packet is encrypted with the key of server itself
+fn identify_user(
+    svr_cfg: &ServerConfig,
+    user_cache: &mut LruCache<IpAddr, Arc<ServerUser>>,
+    src: &SocketAddr,
+    pkt: &[u8],
+) -> io::Result<Option<Arc<ServerUser>>> {
+    let method = svr_cfg.method();
+    if check_payload_key(method, svr_cfg.key(), pkt) {
+        return Ok(None);
+    }
+
+    if let Some(user) = user_cache.get(&src.ip()).cloned() {
+        // User may be removed or replaced by manager after it was cached
+        let is_current = match svr_cfg.users().get_user(user.name()) {
+            Some(u) => Arc::ptr_eq(&u, &user),
+            None => false,
+        };
+
+        if is_current && check_payload_key(method, user.key(), pkt) {
+            return Ok(Some(user));
+        }
+    }
+
+    match svr_cfg
+        .users()
+        .users()
+        .into_iter()
+        .find(|u| check_payload_key(method, u.key(), pkt))
+    {
+        Some(user) => {
+            user_cache.insert(src.ip(), user.clone());
+            Ok(Some(user))
+        }
+        None => {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no user matches, may be wrong method or key",
+            );
+            Err(err)
+        }
+    }
+}
+
 async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, svr_idx: usize) -> io::Result<()> {
     let svr_cfg = context.server_config(svr_idx);
     let listen_addr = svr_cfg.addr().bind_addr(&*context).await?;
@@ -291,6 +403,9 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
     let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
     let assoc_map = Arc::new(Mutex::new(LruCache::with_expiry_duration(timeout)));
 
+    // Packets are dropped if traffic quota is used up or server is expired
+    let quota = ServerQuota::new(svr_cfg);
+
     // FIXME: Channel size 1024?
     let (tx, mut rx) = mpsc::channel::<(SocketAddr, BytesMut)>(1024);
 
@@ -303,18 +418,26 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
         tokio::spawn(async move {
             while let Some((src, pkt)) = rx.recv().await {
                 let cache_key = src.to_string();
-                {
+                let user_flow_stat = {
                     let mut amap = assoc_map.lock().await;
 
                     // Check or update expire time
-                    if amap.get(&cache_key).is_none() {
-                        debug!(
-                            "UDP association {} <-> ... is already expired, throwing away packet {} bytes",
-                            src,
-                            pkt.len()
-                        );
-                        continue;
+                    match amap.get(&cache_key) {
+                        Some(assoc) => assoc.user_flow_stat.clone(),
+                        None => {
+                            debug!(
+                                "UDP association {} <-> ... is already expired, throwing away packet {} bytes",
+                                src,
+                                pkt.len()
+                            );
+                            continue;
+                        }
                     }
+                };
+
+                if let Err(err) = quota.check(&flow_stat) {
+                    trace!("UDP association {} <-> ... throwing away packet, {}", src, err);
+                    continue;
                 }
 
                 if let Err(err) = w.send_to(&pkt, &src).await {
@@ -323,12 +446,18 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
                 }
 
                 flow_stat.udp().incr_tx(pkt.len() as u64);
+                if let Some(user_flow_stat) = user_flow_stat {
+                    user_flow_stat.udp().incr_tx(pkt.len() as u64);
+                }
             }
 
             // FIXME: How to stop the outer listener Future?
         });
     }
 
+    // Users of multi-user server, identified by the first packets of associations
+    let mut user_cache = LruCache::with_expiry_duration(timeout);
+
     let mut pkt_buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
 
     loop {
@@ -347,6 +476,12 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
         let pkt = &pkt_buf[..recv_len];
 
         trace!("received UDP packet from {}, length {} bytes", src, recv_len);
+
+        if let Err(err) = quota.check(&flow_stat) {
+            trace!("throwing away UDP packet from {}, {}", src, err);
+            continue;
+        }
+
         flow_stat.udp().incr_rx(pkt.len() as u64);
 
         if recv_len == 0 {
@@ -374,11 +509,31 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
             // Get or create an association
             let assoc = match assoc_map.entry(src.to_string()) {
                 Entry::Occupied(oc) => oc.into_mut(),
-                Entry::Vacant(vc) => vc.insert(
-                    UdpAssociation::associate(context.clone(), svr_idx, src, tx.clone())
-                        .await
-                        .expect("create udp association"),
-                ),
+                Entry::Vacant(vc) => {
+                    // Multi-user server, identify user by the first packet of association
+                    let user = if svr_cfg.users().is_empty() {
+                        None
+                    } else {
+                        match identify_user(svr_cfg, &mut user_cache, &src, pkt) {
+                            Ok(user) => user,
+                            Err(err) => {
+                                error!("failed to identify user of UDP packet from {}, error: {}", src, err);
+                                continue;
+                            }
+                        }
+                    };
+
+                    if let Some(ref user) = user {
+                        debug!("UDP client {} authenticated as user {}", src, user.name());
+                    }
+                    let user_flow_stat = user.as_ref().map(|u| flow_stat.user(u.name()));
+
+                    vc.insert(
+                        UdpAssociation::associate(context.clone(), svr_idx, src, tx.clone(), user, user_flow_stat)
+                            .await
+                            .expect("create udp association"),
+                    )
+                }
             };
 
             // Clone the handle and release the lock.
@@ -386,6 +541,10 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
             assoc.clone()
         };
 
+        if let Some(ref user_flow_stat) = assoc.user_flow_stat {
+            user_flow_stat.udp().incr_rx(pkt.len() as u64);
+        }
+
         // Send to local -> remote task
         assoc.send(pkt.to_vec()).await;
     }
@@ -8,7 +8,7 @@ use std::{
 
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use lru_time_cache::{Entry, LruCache};
 use tokio::{
     self,
@@ -17,9 +17,10 @@ use tokio::{
 };
 
 use crate::{
+    config::LocalConfig,
     context::SharedContext,
     relay::{
-        loadbalancing::server::{PlainPingBalancer, ServerType},
+        loadbalancing::server::PlainPingBalancer,
         socks5::{Address, UdpAssociateHeader},
         sys::create_udp_socket,
     },
@@ -82,15 +83,13 @@ fn assemble_packet(addr: Address, pkt: &[u8]) -> Bytes {
 }
 
 /// Starts a UDP local server
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let local_addr = context.config().local.as_ref().expect("local config");
+pub async fn run(context: SharedContext, local_config: LocalConfig, balancer: PlainPingBalancer) -> io::Result<()> {
+    let local_addr = &local_config.addr;
     let bind_addr = local_addr.bind_addr(&*context).await?;
 
     let l = create_udp_socket(&bind_addr).await?;
     let local_addr = l.local_addr().expect("determine port bound to");
 
-    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Udp).await;
-
     let (mut r, mut w) = l.split();
 
     info!("shadowsocks UDP listening on {}", local_addr);
@@ -164,6 +163,15 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
             continue;
         }
 
+        // UDP ASSOCIATE requires an authenticated TCP connection from the same client
+        if !context.check_client_authenticated(&src) {
+            warn!(
+                "dropped UDP packet from {}, length {} bytes, client is not authenticated",
+                src, recv_len
+            );
+            continue;
+        }
+
         // Parse it for validating
         let (target, payload) = match parse_packet(pkt).await {
             Ok(t) => t,
@@ -186,7 +194,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 Entry::Occupied(oc) => oc.into_mut(),
                 Entry::Vacant(vc) => {
                     // Pick a server
-                    let server = balancer.pick_server();
+                    let server = balancer.pick_server(&target);
 
                     let sender = ProxyHandler {
                         src_addr: src,
@@ -12,11 +12,9 @@ use tokio::{
 };
 
 use crate::{
+    config::LocalConfig,
     context::SharedContext,
-    relay::{
-        loadbalancing::server::{PlainPingBalancer, ServerType},
-        sys::create_udp_socket,
-    },
+    relay::{loadbalancing::server::PlainPingBalancer, sys::create_udp_socket},
 };
 
 use super::{
@@ -42,18 +40,16 @@ impl ProxySend for ProxyHandler {
 }
 
 /// Starts a UDP local server
-pub async fn run(context: SharedContext) -> io::Result<()> {
-    let local_addr = context.config().local.as_ref().expect("local config");
+pub async fn run(context: SharedContext, local_config: LocalConfig, balancer: PlainPingBalancer) -> io::Result<()> {
+    let local_addr = &local_config.addr;
     let bind_addr = local_addr.bind_addr(&*context).await?;
 
     let l = create_udp_socket(&bind_addr).await?;
     let local_addr = l.local_addr().expect("could not determine port bound to");
 
-    let balancer = PlainPingBalancer::new(context.clone(), ServerType::Udp).await;
-
     let (mut r, mut w) = l.split();
 
-    let forward_target = context.config().forward.clone().expect("`forward` address in config");
+    let forward_target = local_config.forward.clone().expect("`forward` address in config");
 
     info!(
         "shadowsocks UDP tunnel listening on {}, forward to {}",
@@ -137,7 +133,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 Entry::Occupied(oc) => oc.into_mut(),
                 Entry::Vacant(vc) => {
                     // Pick a server
-                    let server = balancer.pick_server();
+                    let server = balancer.pick_server(&forward_target);
 
                     let sender = ProxyHandler {
                         src_addr: src,
@@ -1,10 +1,14 @@
 use std::{
     future::Future,
-    io::{self, Error},
+    io::{self, Error, ErrorKind},
     time::Duration,
 };
 
-use tokio::time;
+use futures::future::{self, select_all, BoxFuture, Either, FutureExt};
+use log::{error, info, warn};
+use tokio::{sync::oneshot, time};
+
+use crate::context::Context;
 
 pub async fn try_timeout<T, E, F>(fut: F, timeout: Option<Duration>) -> io::Result<T>
 where
@@ -42,3 +46,67 @@ pub fn set_nofile(_nofile: u64) -> io::Result<()> {
     // Windows' limit of opening files is the size of HANDLE (32-bits), so it is unlimited
     Ok(())
 }
+
+/// Resolves when `shutdown_rx` receives a request, never resolves if it is `None` or its sender is dropped
+pub async fn wait_shutdown(shutdown_rx: Option<oneshot::Receiver<()>>) {
+    if let Some(rx) = shutdown_rx {
+        if rx.await.is_ok() {
+            return;
+        }
+    }
+
+    future::pending().await
+}
+
+/// Runs `servers` and `plugins` until one of them exits, or shutdown is requested by `shutdown_rx`
+///
+/// While shutting down, listeners are closed immediately, and then waits for active connections
+/// to finish until `shutdown_timeout` elapsed. Plugins keep running until draining finishes.
+pub async fn serve_until_shutdown(
+    context: &Context,
+    servers: Vec<BoxFuture<'static, io::Result<()>>>,
+    plugins: Option<BoxFuture<'static, io::Result<()>>>,
+    shutdown_rx: Option<oneshot::Receiver<()>>,
+) -> io::Result<()> {
+    let plugins = plugins.unwrap_or_else(|| future::pending().boxed());
+    let shutdown = wait_shutdown(shutdown_rx).boxed();
+
+    match future::select(select_all(servers), future::select(plugins, shutdown)).await {
+        Either::Left(((res, ..), ..)) => {
+            error!("one of servers exited unexpectly, result: {:?}", res);
+        }
+        Either::Right((Either::Left((res, ..)), ..)) => {
+            error!("plugin exited unexpectly, result: {:?}", res);
+        }
+        Either::Right((Either::Right((_, plugins)), servers)) => {
+            // Close all listeners
+            drop(servers);
+
+            // Tells all detached tasks to exit
+            context.set_server_stopped();
+
+            if let Some(timeout) = context.config().shutdown_timeout {
+                info!(
+                    "shutting down, waiting for {} active connections to finish",
+                    context.active_connections()
+                );
+
+                let remaining = context.drain_connections(timeout).await;
+                if remaining > 0 {
+                    warn!(
+                        "{} connections are still active after {:?}, closing",
+                        remaining, timeout
+                    );
+                }
+            }
+
+            drop(plugins);
+            return Ok(());
+        }
+    }
+
+    // Tells all detached tasks to exit
+    context.set_server_stopped();
+
+    Err(Error::new(ErrorKind::Other, "server exited unexpectly"))
+}
@@ -0,0 +1,109 @@
+use tokio::{
+    net::UdpSocket,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerConfig},
+    crypto::CipherType,
+    run_local,
+    run_server,
+};
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+fn get_svr_config(server_addr: &str) -> Config {
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerConfig::basic(
+        server_addr.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn get_cli_config(server_addr: &str, local_addr: &str, control_addr: &str) -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(local_addr.parse().unwrap());
+    cfg.server = vec![ServerConfig::basic(
+        server_addr.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg.control_address = Some(control_addr.parse().unwrap());
+    cfg
+}
+
+#[test]
+fn balancer_control() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8102";
+    const LOCAL_ADDR: &str = "127.0.0.1:8202";
+    const CONTROL_ADDR: &str = "127.0.0.1:8303";
+
+    async fn send_command(socket: &mut UdpSocket, cmd: &str) -> String {
+        socket.send_to(cmd.as_bytes(), CONTROL_ADDR).await.unwrap();
+
+        let mut buf = [0u8; 65536];
+        let (n, _) = socket.recv_from(&mut buf).await.unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        tokio::spawn(run_server(get_svr_config(SERVER_ADDR), rt_handle.clone()));
+        tokio::spawn(run_local(
+            get_cli_config(SERVER_ADDR, LOCAL_ADDR, CONTROL_ADDR),
+            rt_handle,
+        ));
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let resp = send_command(&mut socket, "list").await;
+        assert!(resp.contains("\"server\":\"127.0.0.1:8102\""), "{}", resp);
+        assert!(resp.contains("\"pinned\":false"), "{}", resp);
+
+        let resp = send_command(&mut socket, "pin: {\"server\": \"127.0.0.1:8102\"}").await;
+        assert_eq!(resp, "ok\n");
+
+        let resp = send_command(&mut socket, "list").await;
+        assert!(resp.contains("\"pinned\":true"), "{}", resp);
+
+        let resp = send_command(&mut socket, "pin: {\"server\": \"127.0.0.1:1\"}").await;
+        assert!(resp.contains("not found"), "{}", resp);
+
+        let resp = send_command(&mut socket, "unpin").await;
+        assert_eq!(resp, "ok\n");
+    });
+}
+
+#[test]
+fn balancer_control_public_address() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8106";
+    const LOCAL_ADDR: &str = "127.0.0.1:8206";
+    const CONTROL_ADDR: &str = "0.0.0.0:8304";
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let cli_cfg = get_cli_config(SERVER_ADDR, LOCAL_ADDR, CONTROL_ADDR);
+
+        // Control socket without authentication couldn't be exposed to other hosts
+        let res = time::timeout(Duration::from_secs(5), run_local(cli_cfg, rt_handle))
+            .await
+            .expect("sslocal keeps running with a public control socket");
+        assert!(res.is_err());
+    });
+}
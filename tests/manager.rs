@@ -0,0 +1,137 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    io,
+    net::{TcpListener, UdpSocket},
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerConfig},
+    crypto::CipherType,
+    relay::socks5::Address,
+    run_local,
+    run_manager,
+    Socks5Client,
+};
+
+const MANAGER_ADDR: &str = "127.0.0.1:6101";
+const SERVER_ADDR: &str = "127.0.0.1:8196";
+const LOCAL_ADDR: &str = "127.0.0.1:8296";
+const ECHO_SERVER_ADDR: &str = "127.0.0.1:50501";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+const USERNAME: &str = "test-user";
+const USER_PASSWORD: &str = "test-user-password";
+
+async fn send_command(cmd: &str) -> String {
+    let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let manager_addr = MANAGER_ADDR.parse::<SocketAddr>().unwrap();
+    socket.send_to(cmd.as_bytes(), &manager_addr).await.unwrap();
+
+    let mut buf = vec![0u8; 65536];
+    let (n, _) = time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+fn start_tcp_echo_server() {
+    tokio::spawn(async {
+        let mut listener = TcpListener::bind(ECHO_SERVER_ADDR).await.unwrap();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+/// Sends a message through sslocal, returns `true` if it is echoed back
+async fn check_echo(local_addr: &SocketAddr) -> bool {
+    let target = Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap());
+    let mut c = match Socks5Client::connect(target, local_addr).await {
+        Ok(c) => c,
+        Err(..) => return false,
+    };
+
+    let message = b"HEllo WORld";
+    if c.write_all(message).await.is_err() {
+        return false;
+    }
+
+    let mut buf = [0u8; 11];
+    match time::timeout(Duration::from_secs(5), c.read_exact(&mut buf)).await {
+        Ok(Ok(..)) => &buf == message,
+        _ => false,
+    }
+}
+
+#[test]
+fn manager_add_remove_user() {
+    let _ = env_logger::try_init();
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut mgr_config = Config::new(ConfigType::Manager);
+        mgr_config.manager_address = Some(MANAGER_ADDR.parse().unwrap());
+        mgr_config.mode = Mode::TcpOnly;
+        tokio::spawn(run_manager(mgr_config, rt_handle.clone()));
+
+        // Client connects with the key of user
+        let mut cli_config = Config::new(ConfigType::Socks5Local);
+        cli_config.local = Some(LOCAL_ADDR.parse().unwrap());
+        cli_config.server = vec![ServerConfig::basic(
+            SERVER_ADDR.parse().unwrap(),
+            USER_PASSWORD.to_owned(),
+            METHOD,
+        )];
+        cli_config.mode = Mode::TcpOnly;
+        tokio::spawn(run_local(cli_config, rt_handle));
+
+        start_tcp_echo_server();
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let resp = send_command(&format!(
+            r#"add: {{"server_port":8196,"password":"{}","method":"{}"}}"#,
+            PASSWORD, METHOD
+        ))
+        .await;
+        assert_eq!(resp, "ok\n");
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let local_addr = LOCAL_ADDR.parse::<SocketAddr>().unwrap();
+        assert!(!check_echo(&local_addr).await);
+
+        let resp = send_command(&format!(
+            r#"add: {{"server_port":8196,"user":"{}","password":"{}"}}"#,
+            USERNAME, USER_PASSWORD
+        ))
+        .await;
+        assert_eq!(resp, "ok\n");
+
+        let resp = send_command("list").await;
+        assert!(resp.contains(USERNAME), "{}", resp);
+
+        assert!(check_echo(&local_addr).await);
+
+        let resp = send_command(&format!(r#"remove: {{"server_port":8196,"user":"{}"}}"#, USERNAME)).await;
+        assert_eq!(resp, "ok\n");
+
+        let resp = send_command("list").await;
+        assert!(!resp.contains(USERNAME), "{}", resp);
+
+        assert!(!check_echo(&local_addr).await);
+    });
+}
@@ -0,0 +1,111 @@
+use tokio::{
+    io,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerConfig},
+    crypto::CipherType,
+    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    run_local,
+    run_server,
+};
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+fn start_tcp_echo_server(addr: &'static str) {
+    tokio::spawn(async move {
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+async fn fetch_metrics(addr: &str) -> String {
+    let mut s = TcpStream::connect(addr).await.unwrap();
+
+    let req = b"GET /metrics HTTP/1.0\r\n\r\n";
+    s.write_all(req).await.unwrap();
+    s.flush().await.unwrap();
+
+    let mut buf = Vec::new();
+    s.read_to_end(&mut buf).await.unwrap();
+
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn metrics_export() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8101";
+    const LOCAL_ADDR: &str = "127.0.0.1:8201";
+    const SERVER_METRICS_ADDR: &str = "127.0.0.1:8301";
+    const LOCAL_METRICS_ADDR: &str = "127.0.0.1:8302";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50604";
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr_cfg = Config::new(ConfigType::Server);
+        svr_cfg.server = vec![ServerConfig::basic(
+            SERVER_ADDR.parse().unwrap(),
+            PASSWORD.to_owned(),
+            METHOD,
+        )];
+        svr_cfg.mode = Mode::TcpOnly;
+        svr_cfg.metrics_addr = Some(SERVER_METRICS_ADDR.parse().unwrap());
+
+        let mut cli_cfg = Config::new(ConfigType::Socks5Local);
+        cli_cfg.local = Some(LOCAL_ADDR.parse().unwrap());
+        cli_cfg.server = svr_cfg.server.clone();
+        cli_cfg.mode = Mode::TcpOnly;
+        cli_cfg.metrics_addr = Some(LOCAL_METRICS_ADDR.parse().unwrap());
+
+        tokio::spawn(run_server(svr_cfg, rt_handle.clone()));
+        tokio::spawn(run_local(cli_cfg, rt_handle));
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let mut c = Socks5Client::connect(
+            Address::SocketAddress(ECHO_SERVER_ADDR.parse().unwrap()),
+            &LOCAL_ADDR.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        c.write_all(b"HEllo WORld").await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = [0u8; 11];
+        c.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HEllo WORld");
+
+        let metrics = fetch_metrics(SERVER_METRICS_ADDR).await;
+        assert!(metrics.starts_with("HTTP/1.0 200"), "{}", metrics);
+        assert!(
+            metrics.contains("shadowsocks_server_rx_bytes_total{server=\"127.0.0.1:8101\",protocol=\"tcp\"}"),
+            "{}",
+            metrics
+        );
+        assert!(metrics.contains("shadowsocks_active_tcp_connections"), "{}", metrics);
+
+        let metrics = fetch_metrics(LOCAL_METRICS_ADDR).await;
+        assert!(
+            metrics.contains("shadowsocks_balancer_tcp_server_score{server=\"127.0.0.1:8101\"}"),
+            "{}",
+            metrics
+        );
+    });
+}
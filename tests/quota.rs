@@ -0,0 +1,154 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration as StdDuration, SystemTime},
+};
+
+use tokio::{
+    io,
+    net::TcpListener,
+    prelude::*,
+    runtime::Builder,
+    time::{self, Duration},
+};
+
+use shadowsocks::{
+    config::{Config, ConfigType, Mode, ServerConfig},
+    crypto::CipherType,
+    relay::socks5::Address,
+    run_local,
+    run_server,
+    Socks5Client,
+};
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherType = CipherType::Aes256Gcm;
+
+fn get_svr_config(server_addr: &str) -> Config {
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerConfig::basic(
+        server_addr.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn get_cli_config(server_addr: &str, local_addr: &str) -> Config {
+    let mut cfg = Config::new(ConfigType::Socks5Local);
+    cfg.local = Some(local_addr.parse().unwrap());
+    cfg.server = vec![ServerConfig::basic(
+        server_addr.parse().unwrap(),
+        PASSWORD.to_owned(),
+        METHOD,
+    )];
+    cfg.mode = Mode::TcpOnly;
+    cfg
+}
+
+fn start_tcp_echo_server(addr: &'static str) {
+    tokio::spawn(async move {
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+/// Connects to the echo server through sslocal, the connection is checked by an echoed message
+async fn connect_echo(local_addr: &SocketAddr, echo_addr: &str) -> Socks5Client {
+    let target = Address::SocketAddress(echo_addr.parse().unwrap());
+    let mut c = Socks5Client::connect(target, local_addr).await.unwrap();
+
+    let message = b"HEllo WORld";
+    c.write_all(message).await.unwrap();
+
+    let mut buf = [0u8; 11];
+    c.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, message);
+
+    c
+}
+
+/// Checks if the idle connection is closed by server in `timeout`
+async fn is_closed(c: &mut Socks5Client, timeout: Duration) -> bool {
+    let mut buf = [0u8; 1];
+    match time::timeout(timeout, c.read(&mut buf)).await {
+        Ok(Ok(0)) | Ok(Err(..)) => true,
+        Ok(Ok(..)) => panic!("received unexpected data"),
+        Err(..) => false,
+    }
+}
+
+#[test]
+fn quota_expire_idle_connection() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8197";
+    const LOCAL_ADDR: &str = "127.0.0.1:8297";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50502";
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr_config = get_svr_config(SERVER_ADDR);
+        svr_config.server[0].set_expire_at(SystemTime::now() + StdDuration::from_secs(3));
+
+        tokio::spawn(run_server(svr_config, rt_handle.clone()));
+        tokio::spawn(run_local(get_cli_config(SERVER_ADDR, LOCAL_ADDR), rt_handle));
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let local_addr = LOCAL_ADDR.parse::<SocketAddr>().unwrap();
+        let mut c = connect_echo(&local_addr, ECHO_SERVER_ADDR).await;
+
+        // Connection is kept open before the server expires, and closed after that without any traffic
+        assert!(!is_closed(&mut c, Duration::from_secs(1)).await);
+        assert!(is_closed(&mut c, Duration::from_secs(5)).await);
+    });
+}
+
+#[test]
+fn quota_traffic_limit_idle_connection() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8198";
+    const LOCAL_ADDR: &str = "127.0.0.1:8298";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50503";
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr_config = get_svr_config(SERVER_ADDR);
+        svr_config.server[0].set_traffic_limit(64 * 1024);
+
+        tokio::spawn(run_server(svr_config, rt_handle.clone()));
+        tokio::spawn(run_local(get_cli_config(SERVER_ADDR, LOCAL_ADDR), rt_handle));
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        time::delay_for(Duration::from_secs(1)).await;
+
+        let local_addr = LOCAL_ADDR.parse::<SocketAddr>().unwrap();
+        let mut idle = connect_echo(&local_addr, ECHO_SERVER_ADDR).await;
+        assert!(!is_closed(&mut idle, Duration::from_secs(1)).await);
+
+        // Another connection uses up the quota
+        let mut busy = connect_echo(&local_addr, ECHO_SERVER_ADDR).await;
+        tokio::spawn(async move {
+            let data = vec![0u8; 128 * 1024];
+            let _ = busy.write_all(&data).await;
+
+            let mut buf = Vec::new();
+            let _ = busy.read_to_end(&mut buf).await;
+        });
+
+        assert!(is_closed(&mut idle, Duration::from_secs(5)).await);
+    });
+}
@@ -1,15 +1,35 @@
 use std::net::{SocketAddr, ToSocketAddrs};
 
 use tokio::{
+    io,
+    net::{TcpListener, TcpStream},
     prelude::*,
     runtime::{Builder, Handle},
     time::{self, Duration},
 };
 
 use shadowsocks::{
-    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig},
+    config::{Config, ConfigType, LocalAuthConfig, LocalConfig, Mode, ServerAddr, ServerConfig, ServerUser},
     crypto::CipherType,
-    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    relay::{
+        socks4::{
+            self,
+            Command as Socks4Command,
+            HandshakeRequest as Socks4Request,
+            HandshakeResponse as Socks4Response,
+        },
+        socks5::{
+            self,
+            Address,
+            Command as Socks5Command,
+            HandshakeRequest as Socks5HandshakeRequest,
+            HandshakeResponse as Socks5HandshakeResponse,
+            Reply,
+            TcpRequestHeader,
+            TcpResponseHeader,
+        },
+        tcprelay::client::Socks5Client,
+    },
     run_local,
     run_server,
 };
@@ -131,3 +151,569 @@ fn socks5_relay_aead() {
         println!("Got reply from server: {}", String::from_utf8(buf).unwrap());
     });
 }
+
+#[test]
+fn socks5_relay_aead_2022() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8180";
+    const LOCAL_ADDR: &str = "127.0.0.1:8280";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50605";
+
+    const PASSWORD: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const METHOD: CipherType = CipherType::Aead2022Blake3ChaCha20Poly1305;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.run(rt_handle).await;
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        // Payloads are echoed back only if both directions of the handshake succeed
+        assert!(check_echo_connections(svr.client_addr(), ECHO_SERVER_ADDR, 2).await);
+    });
+}
+
+#[test]
+fn socks5_relay_multi_user() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8190";
+    const LOCAL_ADDR: &str = "127.0.0.1:8290";
+    const OWNER_LOCAL_ADDR: &str = "127.0.0.1:8281";
+    const WRONG_KEY_LOCAL_ADDR: &str = "127.0.0.1:8282";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50606";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    const USERNAME: &str = "test-user";
+    const USER_PASSWORD: &str = "test-user-password";
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+
+        // Server accepts keys of its users, besides its own password
+        svr.svr_config.server[0]
+            .users()
+            .add_user(ServerUser::new(USERNAME, USER_PASSWORD, METHOD));
+        svr.cli_config.server = vec![ServerConfig::basic(
+            SERVER_ADDR.parse().unwrap(),
+            USER_PASSWORD.to_owned(),
+            METHOD,
+        )];
+
+        // Locals with the server's own password and with a key that the server doesn't know
+        let local_with_password = |local_addr: &str, password: &str| {
+            let mut cfg = svr.cli_config.clone();
+            cfg.local = Some(ServerAddr::from(local_addr.parse::<SocketAddr>().unwrap()));
+            cfg.server = vec![ServerConfig::basic(
+                SERVER_ADDR.parse().unwrap(),
+                password.to_owned(),
+                METHOD,
+            )];
+            cfg
+        };
+        tokio::spawn(run_local(
+            local_with_password(OWNER_LOCAL_ADDR, PASSWORD),
+            rt_handle.clone(),
+        ));
+        tokio::spawn(run_local(
+            local_with_password(WRONG_KEY_LOCAL_ADDR, "wrong-password"),
+            rt_handle.clone(),
+        ));
+
+        svr.run(rt_handle).await;
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        assert!(check_echo_connections(svr.client_addr(), ECHO_SERVER_ADDR, 2).await);
+        assert!(check_echo_connections(&OWNER_LOCAL_ADDR.parse().unwrap(), ECHO_SERVER_ADDR, 2).await);
+        assert!(!check_echo_connections(&WRONG_KEY_LOCAL_ADDR.parse().unwrap(), ECHO_SERVER_ADDR, 1).await);
+    });
+}
+
+#[test]
+fn socks5_relay_password_auth() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8120";
+    const LOCAL_ADDR: &str = "127.0.0.1:8220";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    const USERNAME: &str = "test-user";
+    const USER_PASSWORD: &str = "test-user-password";
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+
+        let mut auth = LocalAuthConfig::new();
+        auth.add_user(USERNAME, USER_PASSWORD);
+        svr.cli_config.local_auth = Some(auth);
+
+        svr.run(rt_handle).await;
+
+        // Clients without authentication are rejected
+        assert!(Socks5Client::connect(
+            Address::DomainNameAddress("www.example.com".to_owned(), 80),
+            svr.client_addr(),
+        )
+        .await
+        .is_err());
+
+        // Clients with a wrong password are rejected
+        assert!(Socks5Client::connect_with_password(
+            Address::DomainNameAddress("www.example.com".to_owned(), 80),
+            svr.client_addr(),
+            USERNAME,
+            "wrong-password",
+        )
+        .await
+        .is_err());
+
+        let mut c = Socks5Client::connect_with_password(
+            Address::DomainNameAddress("www.example.com".to_owned(), 80),
+            svr.client_addr(),
+            USERNAME,
+            USER_PASSWORD,
+        )
+        .await
+        .unwrap();
+
+        let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+        c.write_all(req).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+
+        println!("Got reply from server: {}", String::from_utf8(buf).unwrap());
+    });
+}
+
+#[test]
+fn socks4a_relay_connect() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8130";
+    const LOCAL_ADDR: &str = "127.0.0.1:8230";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.run(rt_handle).await;
+
+        let mut c = TcpStream::connect(svr.client_addr()).await.unwrap();
+
+        let req = Socks4Request::new(
+            Socks4Command::Connect,
+            Address::DomainNameAddress("www.example.com".to_owned(), 80),
+            Vec::new(),
+        );
+        req.write_to(&mut c).await.unwrap();
+
+        let resp = Socks4Response::read_from(&mut c).await.unwrap();
+        match resp.cd {
+            socks4::ResultCode::RequestGranted => (),
+            cd => panic!("SOCKS4a CONNECT failed with {}", cd),
+        }
+
+        let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+        c.write_all(req).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+
+        println!("Got reply from server: {}", String::from_utf8(buf).unwrap());
+    });
+}
+
+#[test]
+fn mixed_relay_socks5_and_http() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8140";
+    const LOCAL_ADDR: &str = "127.0.0.1:8240";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.cli_config.config_type = ConfigType::MixedLocal;
+        svr.run(rt_handle).await;
+
+        // SOCKS5 CONNECT
+        let mut c = Socks5Client::connect(
+            Address::DomainNameAddress("www.example.com".to_owned(), 80),
+            svr.client_addr(),
+        )
+        .await
+        .unwrap();
+
+        let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+        c.write_all(req).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+
+        println!("Got reply from server: {}", String::from_utf8(buf).unwrap());
+
+        // HTTP proxy on the same port
+        let mut c = TcpStream::connect(svr.client_addr()).await.unwrap();
+
+        let req = b"GET http://www.example.com/ HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+        c.write_all(req).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+
+        let resp = String::from_utf8(buf).unwrap();
+        assert!(resp.starts_with("HTTP/1."));
+
+        println!("Got reply from server: {}", resp);
+    });
+}
+
+#[test]
+fn multiple_locals_relay() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8150";
+    const LOCAL_ADDR: &str = "127.0.0.1:8250";
+    const HTTP_LOCAL_ADDR: &str = "127.0.0.1:8251";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.cli_config.locals = vec![
+            LocalConfig::new(
+                ServerAddr::from(LOCAL_ADDR.parse::<SocketAddr>().unwrap()),
+                ConfigType::Socks5Local,
+                Mode::TcpOnly,
+            ),
+            LocalConfig::new(
+                ServerAddr::from(HTTP_LOCAL_ADDR.parse::<SocketAddr>().unwrap()),
+                ConfigType::HttpLocal,
+                Mode::TcpOnly,
+            ),
+        ];
+        svr.run(rt_handle).await;
+
+        // SOCKS5 local
+        let mut c = Socks5Client::connect(
+            Address::DomainNameAddress("www.example.com".to_owned(), 80),
+            svr.client_addr(),
+        )
+        .await
+        .unwrap();
+
+        let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+        c.write_all(req).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+
+        println!("Got reply from server: {}", String::from_utf8(buf).unwrap());
+
+        // HTTP local in the same process
+        let mut c = TcpStream::connect(HTTP_LOCAL_ADDR).await.unwrap();
+
+        let req = b"GET http://www.example.com/ HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+        c.write_all(req).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+
+        let resp = String::from_utf8(buf).unwrap();
+        assert!(resp.starts_with("HTTP/1."));
+
+        println!("Got reply from server: {}", resp);
+    });
+}
+
+#[test]
+fn socks5_relay_bind() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8104";
+    const LOCAL_ADDR: &str = "127.0.0.1:8204";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    async fn request_bind(local_addr: &SocketAddr) -> (TcpStream, TcpResponseHeader) {
+        let mut c = TcpStream::connect(local_addr).await.unwrap();
+
+        let req = Socks5HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
+        req.write_to(&mut c).await.unwrap();
+        let resp = Socks5HandshakeResponse::read_from(&mut c).await.unwrap();
+        assert_eq!(resp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        // Expecting the inbound connection from 127.0.0.1
+        let req = TcpRequestHeader::new(
+            Socks5Command::TcpBind,
+            "127.0.0.1:0".parse::<SocketAddr>().unwrap().into(),
+        );
+        req.write_to(&mut c).await.unwrap();
+
+        let resp = TcpResponseHeader::read_from(&mut c).await.unwrap();
+        (c, resp)
+    }
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.svr_config.server[0].set_allow_bind(true);
+        svr.run(rt_handle).await;
+
+        let (mut c, resp) = request_bind(svr.client_addr()).await;
+        let listen_addr = match (resp.reply, resp.address) {
+            (Reply::Succeeded, Address::SocketAddress(addr)) => addr,
+            (reply, addr) => panic!("BIND failed with {}, address {}", reply, addr),
+        };
+
+        // Inbound connection to the port listening on the server
+        let mut inbound = TcpStream::connect(listen_addr).await.unwrap();
+
+        let resp = TcpResponseHeader::read_from(&mut c).await.unwrap();
+        match resp.reply {
+            Reply::Succeeded => (),
+            reply => panic!("BIND failed to accept with {}", reply),
+        }
+        assert_eq!(resp.address, Address::SocketAddress(inbound.local_addr().unwrap()));
+
+        inbound.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        c.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        c.write_all(b"world").await.unwrap();
+        inbound.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    });
+}
+
+#[test]
+fn socks5_relay_bind_not_allowed() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8105";
+    const LOCAL_ADDR: &str = "127.0.0.1:8205";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        // BIND is not allowed by default
+        let svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.run(rt_handle).await;
+
+        let mut c = TcpStream::connect(svr.client_addr()).await.unwrap();
+
+        let req = Socks5HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
+        req.write_to(&mut c).await.unwrap();
+        Socks5HandshakeResponse::read_from(&mut c).await.unwrap();
+
+        let req = TcpRequestHeader::new(
+            Socks5Command::TcpBind,
+            "127.0.0.1:0".parse::<SocketAddr>().unwrap().into(),
+        );
+        req.write_to(&mut c).await.unwrap();
+
+        let resp = TcpResponseHeader::read_from(&mut c).await.unwrap();
+        match resp.reply {
+            Reply::CommandNotSupported => (),
+            reply => panic!("BIND is expected to be refused, but got {}", reply),
+        }
+    });
+}
+
+fn start_tcp_echo_server(addr: &'static str) {
+    tokio::spawn(async move {
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+}
+
+/// Server resets connections right after they are accepted
+fn start_tcp_reset_server(addr: &'static str) {
+    tokio::spawn(async move {
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = stream.set_linger(Some(Duration::from_secs(0)));
+        }
+    });
+}
+
+/// Sends messages through sslocal on new connections, returns `true` if all of them are echoed back
+async fn check_echo_connections(local_addr: &SocketAddr, echo_addr: &str, count: usize) -> bool {
+    for _ in 0..count {
+        let target = Address::SocketAddress(echo_addr.parse().unwrap());
+        let mut c = match Socks5Client::connect(target, local_addr).await {
+            Ok(c) => c,
+            Err(..) => return false,
+        };
+
+        let message = b"HEllo WORld";
+        if c.write_all(message).await.is_err() {
+            return false;
+        }
+
+        let mut buf = [0u8; 11];
+        match time::timeout(Duration::from_secs(5), c.read_exact(&mut buf)).await {
+            Ok(Ok(..)) if &buf == message => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[test]
+fn socks5_relay_failover() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8107";
+    const DEAD_SERVER_ADDR: &str = "127.0.0.1:8307";
+    const LOCAL_ADDR: &str = "127.0.0.1:8207";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50504";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        // Nothing is listening on the dead server, connecting to it is refused
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.cli_config.server.insert(
+            0,
+            ServerConfig::basic(DEAD_SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+        );
+        svr.run(rt_handle).await;
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        assert!(check_echo_connections(svr.client_addr(), ECHO_SERVER_ADDR, 4).await);
+    });
+}
+
+#[test]
+fn socks5_relay_failover_first_payload() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8108";
+    const DEAD_SERVER_ADDR: &str = "127.0.0.1:8308";
+    const LOCAL_ADDR: &str = "127.0.0.1:8208";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50505";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        // Connecting to the dead server succeeds, the failure is only returned while sending the first payload
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.cli_config.server.insert(
+            0,
+            ServerConfig::basic(DEAD_SERVER_ADDR.parse().unwrap(), PASSWORD.to_owned(), METHOD),
+        );
+        svr.cli_config.first_payload_window = Some(Duration::from_millis(500));
+        start_tcp_reset_server(DEAD_SERVER_ADDR);
+        svr.run(rt_handle).await;
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        assert!(check_echo_connections(svr.client_addr(), ECHO_SERVER_ADDR, 4).await);
+    });
+}
+
+#[test]
+fn socks5_relay_mux() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8109";
+    const LOCAL_ADDR: &str = "127.0.0.1:8209";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50506";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.svr_config.server[0].set_mux(true);
+        svr.cli_config.server[0].set_mux(true);
+        svr.run(rt_handle).await;
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        assert!(check_echo_connections(svr.client_addr(), ECHO_SERVER_ADDR, 4).await);
+    });
+}
+
+#[test]
+fn socks5_relay_mux_fallback() {
+    let _ = env_logger::try_init();
+
+    const SERVER_ADDR: &str = "127.0.0.1:8309";
+    const LOCAL_ADDR: &str = "127.0.0.1:8409";
+    const ECHO_SERVER_ADDR: &str = "127.0.0.1:50507";
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherType = CipherType::Aes256Gcm;
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let rt_handle = rt.handle().clone();
+
+    rt.block_on(async move {
+        // Server doesn't enable multiplexing, normal connections are used
+        let mut svr = Socks5TestServer::new(SERVER_ADDR, LOCAL_ADDR, PASSWORD, METHOD, false);
+        svr.cli_config.server[0].set_mux(true);
+        svr.run(rt_handle).await;
+        start_tcp_echo_server(ECHO_SERVER_ADDR);
+
+        assert!(check_echo_connections(svr.client_addr(), ECHO_SERVER_ADDR, 4).await);
+    });
+}
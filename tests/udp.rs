@@ -14,7 +14,7 @@ use tokio::{
 };
 
 use shadowsocks::{
-    config::{Config, ConfigType, Mode, ServerConfig},
+    config::{Config, ConfigType, Mode, ServerConfig, ServerUser},
     crypto::CipherType,
     relay::{
         socks5::{Address, UdpAssociateHeader},
@@ -24,57 +24,47 @@ use shadowsocks::{
     run_server,
 };
 
-const SERVER_ADDR: &str = "127.0.0.1:8093";
-const LOCAL_ADDR: &str = "127.0.0.1:8291";
-
-const UDP_ECHO_SERVER_ADDR: &str = "127.0.0.1:50403";
-const UDP_LOCAL_ADDR: &str = "127.0.0.1:9011";
-
 const PASSWORD: &str = "test-password";
 const METHOD: CipherType = CipherType::Aes128Gcm;
 
-fn get_svr_config() -> Config {
+/// Addresses of servers in one test
+struct UdpTestAddrs {
+    server: &'static str,
+    local: &'static str,
+    echo_server: &'static str,
+    udp_local: &'static str,
+}
+
+fn get_svr_config(addrs: &UdpTestAddrs, password: &str, method: CipherType) -> Config {
     let mut cfg = Config::new(ConfigType::Server);
     cfg.server = vec![ServerConfig::basic(
-        SERVER_ADDR.parse().unwrap(),
-        PASSWORD.to_owned(),
-        METHOD,
+        addrs.server.parse().unwrap(),
+        password.to_owned(),
+        method,
     )];
     cfg.mode = Mode::UdpOnly;
     cfg
 }
 
-fn get_cli_config() -> Config {
+fn get_cli_config(addrs: &UdpTestAddrs, password: &str, method: CipherType) -> Config {
     let mut cfg = Config::new(ConfigType::Socks5Local);
-    cfg.local = Some(LOCAL_ADDR.parse().unwrap());
+    cfg.local = Some(addrs.local.parse().unwrap());
     cfg.server = vec![ServerConfig::basic(
-        SERVER_ADDR.parse().unwrap(),
-        PASSWORD.to_owned(),
-        METHOD,
+        addrs.server.parse().unwrap(),
+        password.to_owned(),
+        method,
     )];
     cfg.mode = Mode::UdpOnly;
     cfg
 }
 
-fn get_client_addr() -> SocketAddr {
-    LOCAL_ADDR.parse().unwrap()
-}
-
-fn start_server(handle: Handle) {
-    tokio::spawn(run_server(get_svr_config(), handle));
-}
-
-fn start_local(handle: Handle) {
-    tokio::spawn(run_local(get_cli_config(), handle));
-}
-
-fn start_udp_echo_server() {
+fn start_udp_echo_server(addr: &'static str) {
     use tokio::net::UdpSocket;
 
-    tokio::spawn(async {
-        let mut l = UdpSocket::bind(UDP_ECHO_SERVER_ADDR).await.unwrap();
+    tokio::spawn(async move {
+        let mut l = UdpSocket::bind(addr).await.unwrap();
 
-        debug!("UDP echo server started {}", UDP_ECHO_SERVER_ADDR);
+        debug!("UDP echo server started {}", addr);
 
         let mut buf = vec![0u8; 65536];
         let (amt, src) = l.recv_from(&mut buf).await.unwrap();
@@ -87,10 +77,10 @@ fn start_udp_echo_server() {
     });
 }
 
-fn start_udp_request_holder(addr: Address) {
+fn start_udp_request_holder(local_addr: SocketAddr, addr: Address) {
     tokio::spawn(async move {
-        let (mut c, addr) = Socks5Client::udp_associate(addr, &get_client_addr()).await?;
-        assert_eq!(addr, Address::SocketAddress(LOCAL_ADDR.parse().unwrap()));
+        let (mut c, addr) = Socks5Client::udp_associate(addr, &local_addr).await?;
+        assert_eq!(addr, Address::SocketAddress(local_addr));
 
         debug!("TCP sent UDP associate {} request", addr);
 
@@ -102,47 +92,54 @@ fn start_udp_request_holder(addr: Address) {
     });
 }
 
-#[test]
-fn udp_relay() {
+fn run_udp_relay(addrs: UdpTestAddrs, password: &str, method: CipherType) {
+    let svr_config = get_svr_config(&addrs, password, method);
+    let cli_config = get_cli_config(&addrs, password, method);
+
+    let payload = b"HEllo WORld";
+    let echoed = run_udp_echo(addrs, svr_config, cli_config, payload);
+    assert_eq!(echoed.as_ref().map(|v| &v[..]), Some(&payload[..]));
+}
+
+/// Sends `payload` to the echo server through sslocal and ssserver, returns `None` if nothing is echoed back
+fn run_udp_echo(addrs: UdpTestAddrs, svr_config: Config, cli_config: Config, payload: &[u8]) -> Option<Vec<u8>> {
     use tokio::net::UdpSocket;
 
     let _ = env_logger::try_init();
 
-    let remote_addr = Address::SocketAddress(UDP_ECHO_SERVER_ADDR.parse().unwrap());
+    let remote_addr = Address::SocketAddress(addrs.echo_server.parse().unwrap());
+    let local_addr = addrs.local.parse::<SocketAddr>().unwrap();
 
     let mut rt = Builder::new().basic_scheduler().enable_all().build().unwrap();
     let rt_handle = rt.handle().clone();
 
     rt.block_on(async move {
-        start_server(rt_handle.clone());
-        start_local(rt_handle);
+        tokio::spawn(run_server(svr_config, rt_handle.clone()));
+        tokio::spawn(run_local(cli_config, rt_handle));
 
-        start_udp_echo_server();
+        start_udp_echo_server(addrs.echo_server);
 
         // Wait until all server starts
         time::delay_for(Duration::from_secs(1)).await;
 
-        start_udp_request_holder(remote_addr.clone());
+        start_udp_request_holder(local_addr, remote_addr.clone());
 
-        let mut l = UdpSocket::bind(UDP_LOCAL_ADDR).await.unwrap();
+        let mut l = UdpSocket::bind(addrs.udp_local).await.unwrap();
 
         let header = UdpAssociateHeader::new(0, remote_addr);
         let mut buf = BytesMut::with_capacity(header.serialized_len());
         header.write_to_buf(&mut buf);
 
-        let payload = b"HEllo WORld";
-
         buf.reserve(payload.len());
         buf.put_slice(payload);
 
-        let local_addr = LOCAL_ADDR.parse::<SocketAddr>().unwrap();
         l.send_to(&buf[..], &local_addr).await.unwrap();
 
         let mut buf = vec![0u8; 65536];
-        let (amt, _) = time::timeout(Duration::from_secs(5), l.recv_from(&mut buf))
-            .await
-            .unwrap()
-            .unwrap();
+        let (amt, _) = match time::timeout(Duration::from_secs(5), l.recv_from(&mut buf)).await {
+            Ok(r) => r.unwrap(),
+            Err(..) => return None,
+        };
         println!("Received buf size={} {:?}", amt, &buf[..amt]);
 
         let mut cur = Cursor::new(buf[..amt].to_vec());
@@ -150,8 +147,93 @@ fn udp_relay() {
         println!("{:?}", header);
         let header_len = cur.position() as usize;
         let buf = cur.into_inner();
-        let buf = &buf[header_len..];
+        Some(buf[header_len..].to_vec())
+    })
+}
 
-        assert_eq!(buf, payload);
-    });
+#[test]
+fn udp_relay() {
+    let addrs = UdpTestAddrs {
+        server: "127.0.0.1:8093",
+        local: "127.0.0.1:8291",
+        echo_server: "127.0.0.1:50403",
+        udp_local: "127.0.0.1:9011",
+    };
+    run_udp_relay(addrs, PASSWORD, METHOD);
+}
+
+#[test]
+fn udp_relay_aead_2022() {
+    let addrs = UdpTestAddrs {
+        server: "127.0.0.1:8094",
+        local: "127.0.0.1:8292",
+        echo_server: "127.0.0.1:50404",
+        udp_local: "127.0.0.1:9012",
+    };
+
+    // AES ciphers encrypt SessionID and PacketID in a separate header
+    run_udp_relay(addrs, "AAECAwQFBgcICQoLDA0ODw==", CipherType::Aead2022Blake3Aes128Gcm);
+}
+
+#[test]
+fn udp_relay_aead_2022_chacha20() {
+    let addrs = UdpTestAddrs {
+        server: "127.0.0.1:8095",
+        local: "127.0.0.1:8293",
+        echo_server: "127.0.0.1:50405",
+        udp_local: "127.0.0.1:9013",
+    };
+
+    // ChaCha20 encrypts the whole packet with an extended nonce
+    run_udp_relay(
+        addrs,
+        "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+        CipherType::Aead2022Blake3ChaCha20Poly1305,
+    );
+}
+
+#[test]
+fn udp_relay_multi_user() {
+    let addrs = UdpTestAddrs {
+        server: "127.0.0.1:8096",
+        local: "127.0.0.1:8294",
+        echo_server: "127.0.0.1:50406",
+        udp_local: "127.0.0.1:9014",
+    };
+
+    const USER_PASSWORD: &str = "test-user-password";
+
+    // Server accepts keys of its users, besides its own password
+    let svr_config = get_svr_config(&addrs, PASSWORD, METHOD);
+    svr_config.server[0]
+        .users()
+        .add_user(ServerUser::new("test-user-1", "test-user-1-password", METHOD));
+    svr_config.server[0]
+        .users()
+        .add_user(ServerUser::new("test-user-2", USER_PASSWORD, METHOD));
+    let cli_config = get_cli_config(&addrs, USER_PASSWORD, METHOD);
+
+    let payload = b"HEllo WORld";
+    let echoed = run_udp_echo(addrs, svr_config, cli_config, payload);
+    assert_eq!(echoed.as_ref().map(|v| &v[..]), Some(&payload[..]));
+}
+
+#[test]
+fn udp_relay_multi_user_wrong_key() {
+    let addrs = UdpTestAddrs {
+        server: "127.0.0.1:8097",
+        local: "127.0.0.1:8295",
+        echo_server: "127.0.0.1:50407",
+        udp_local: "127.0.0.1:9015",
+    };
+
+    let svr_config = get_svr_config(&addrs, PASSWORD, METHOD);
+    svr_config.server[0]
+        .users()
+        .add_user(ServerUser::new("test-user", "test-user-password", METHOD));
+    let cli_config = get_cli_config(&addrs, "not-a-user-password", METHOD);
+
+    // Packets are dropped by server
+    let echoed = run_udp_echo(addrs, svr_config, cli_config, b"HEllo WORld");
+    assert!(echoed.is_none());
 }